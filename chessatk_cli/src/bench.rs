@@ -0,0 +1,145 @@
+use chessatk_lib::board::{Move, State};
+use chessatk_lib::engine::{analyze, SearchLimits};
+use chessatk_lib::epd::run_epd_suite;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Positions for `bench`: the start position, a busy middlegame and a few endgames
+const BENCH_FENS: [&str; 5] = [
+   "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+   "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+   "r1bq1rk1/pp2bppp/2n1pn2/3p4/2PP4/2N2N2/PP2BPPP/R2QKB1R w KQ - 0 8",
+   "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+   "8/8/1k6/8/2PK4/8/8/8 w - - 0 1",
+];
+
+const BENCH_DEPTH: u64 = 5;
+
+fn perft(state: &mut State, depth: u64) -> u64 {
+   if depth == 0 {
+      return 1;
+   }
+   let mut moves = Vec::new();
+   state.gen_moves(&mut moves);
+   if depth == 1 {
+      return moves.len() as u64;
+   }
+   let mut nodes = 0;
+   for a_move in moves {
+      let undo = state.make_move(a_move.extract());
+      nodes += perft(state, depth - 1);
+      state.unmake_move(undo);
+   }
+   nodes
+}
+
+/// The perft count under each legal move
+fn perft_divide(state: &mut State, depth: u64) -> Vec<(Move, u64)> {
+   let mut moves = Vec::new();
+   state.gen_moves(&mut moves);
+   moves
+      .into_iter()
+      .map(|a_move| {
+         let undo = state.make_move(a_move.extract());
+         let nodes = perft(state, depth.saturating_sub(1));
+         state.unmake_move(undo);
+         (a_move.extract(), nodes)
+      })
+      .collect()
+}
+
+pub fn run_perft(mut state: State, depth: u64) {
+   if depth == 0 {
+      println!("\nNodes searched: 1");
+      return;
+   }
+   let start = Instant::now();
+   let divide = perft_divide(&mut state, depth);
+   for (a_move, nodes) in divide.iter() {
+      println!("{}: {}", a_move, nodes);
+   }
+   let total: u64 = divide.iter().map(|x| x.1).sum();
+   println!("\nNodes searched: {}", total);
+   println!("Time: {}ms", start.elapsed().as_millis());
+}
+
+pub fn run_bench() {
+   let start = Instant::now();
+   let mut total_nodes = 0;
+   for fen in BENCH_FENS.iter() {
+      let state = State::from_fen(fen).unwrap();
+      let position_start = Instant::now();
+      let result = analyze(
+         &state,
+         SearchLimits {
+            depth: Some(BENCH_DEPTH),
+            deterministic: true,
+            ..SearchLimits::default()
+         },
+      );
+      let best_move = result.best_move.map(|x| x.to_string()).unwrap_or_else(|| "(none)".into());
+      println!(
+         "{}: {} nodes, best move {}, {}ms",
+         fen,
+         result.nodes,
+         best_move,
+         position_start.elapsed().as_millis()
+      );
+      total_nodes += result.nodes;
+   }
+   let elapsed = start.elapsed();
+   println!("\nTotal nodes: {}", total_nodes);
+   println!("Time: {}ms", elapsed.as_millis());
+   println!("Nodes/second: {}", (total_nodes as f64 / elapsed.as_secs_f64()) as u64);
+   // the helper threads make the node counts vary from run to run, so only the time is worth comparing
+   let parallel_start = Instant::now();
+   for fen in BENCH_FENS.iter() {
+      let limits = SearchLimits {
+         depth: Some(BENCH_DEPTH),
+         ..SearchLimits::default()
+      };
+      analyze(&State::from_fen(fen).unwrap(), limits);
+   }
+   let parallel_elapsed = parallel_start.elapsed();
+   println!(
+      "Time on every core: {}ms ({:.2}x)",
+      parallel_elapsed.as_millis(),
+      elapsed.as_secs_f64() / parallel_elapsed.as_secs_f64()
+   );
+}
+
+pub fn run_epd(path: &Path, depth: u64, movetime: Option<Duration>) -> Result<(), String> {
+   let limits = SearchLimits {
+      depth: Some(depth),
+      movetime,
+      ..SearchLimits::default()
+   };
+   let start = Instant::now();
+   let report = run_epd_suite(path, &limits)?;
+   for position in report.positions.iter() {
+      let best_move = position.best_move.map(|x| x.to_string()).unwrap_or_else(|| "(none)".into());
+      println!(
+         "{}: {} ({})",
+         position.id.as_deref().unwrap_or(&position.fen),
+         if position.solved { "solved" } else { "failed" },
+         best_move
+      );
+   }
+   println!("\nSolved: {}/{}", report.solved(), report.total());
+   println!("Time: {}ms", start.elapsed().as_millis());
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+   use crate::bench::*;
+
+   #[test]
+   fn perft_start_position() {
+      let mut state = State::from_start();
+      let divide = perft_divide(&mut state, 3);
+      assert_eq!(divide.len(), 20);
+      assert_eq!(divide.iter().map(|x| x.1).sum::<u64>(), 8902);
+      assert_eq!(perft(&mut state, 3), 8902);
+   }
+}
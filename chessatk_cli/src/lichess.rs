@@ -1,17 +1,32 @@
-use chessatk_lib::board::{Color, Move, State};
+use chessatk_lib::board::{Color, Move, Position, State, BISHOP, BLACK, KNIGHT, PAWN, QUEEN, ROOK, WHITE};
+use chessatk_lib::engine::moves_to_mate;
+use chessatk_lib::game::Game;
 use chessatk_lib::messages::{EngineMessage, InterfaceMessage};
+use crate::time::move_budget;
 use futures::stream::TryStreamExt;
 use fxhash::FxHashSet;
 use log::{error, info, trace, warn};
 use rand::seq::SliceRandom;
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::env;
+use std::fmt::Display;
+use std::future::Future;
 use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
 use tokio::io::AsyncBufReadExt;
 use tokio_util::io::StreamReader;
 
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How close to 0 (in centipawns) our eval has to be for us to take a draw
+const DRAW_ACCEPT_MARGIN: i32 = 50;
+/// How far behind (in centipawns) we have to be before we offer a draw in a dead drawn position
+const DRAW_OFFER_DEFICIT: i32 = 200;
+/// Evals posted in chat are clamped to this many centipawns either way. Past it the game is simply won
+const MAX_EVAL_TEXT: i32 = 9999;
+
 const RESPONSES: [&str; 14] = [
    "if you think i'm moving righteous then",
    "i think i'm moving backwards and",
@@ -68,8 +83,8 @@ struct GameStart {
 #[allow(non_camel_case_types)]
 enum Event {
    challenge(ChallengeOuter),
-   challengeCanceled(ChallengeOuter),
-   challengeDeclined(ChallengeOuter),
+   challengeCanceled(#[allow(dead_code)] ChallengeOuter),
+   challengeDeclined(#[allow(dead_code)] ChallengeOuter),
    gameStart(GameStart),
    gameFinish(GameStart),
 }
@@ -96,7 +111,16 @@ struct GameState {
    moves: String,
    wtime: u64,
    btime: u64,
+   #[serde(default)]
+   winc: u64,
+   #[serde(default)]
+   binc: u64,
    status: String,
+   winner: Option<String>,
+   #[serde(default)]
+   wdraw: bool,
+   #[serde(default)]
+   bdraw: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -135,6 +159,38 @@ enum GameEvent {
    gameFull(GameFull),
    gameState(GameState),
    chatLine(ChatLine),
+   opponentGone(OpponentGone),
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OpponentGone {
+   gone: bool,
+   claimWinInSeconds: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+enum GameAction {
+   Play,
+   /// The opponent has left, and we can claim the win after this long
+   ClaimVictory(Duration),
+   Finish,
+}
+
+/// Who (if anyone) to challenge once we're connected, and with what clock
+#[derive(Debug, PartialEq)]
+pub struct ChallengeConfig {
+   pub ai_level: Option<u8>,
+   pub user: Option<String>,
+   pub clock_limit: u16,
+   pub clock_increment: u8,
+}
+
+#[derive(Debug, PartialEq)]
+enum DrawAction {
+   Accept,
+   Offer,
+   Nothing,
 }
 
 type EngineInterface = Arc<Mutex<(mpsc::Sender<InterfaceMessage>, mpsc::Receiver<EngineMessage>)>>;
@@ -150,12 +206,153 @@ fn read_api_token() -> Result<String, std::io::Error> {
    Ok(line_buf)
 }
 
+/// Decides what to do about draws, given our eval in centipawns (+ is good for us)
+fn draw_decision(our_eval: i32, offer_present: bool, dead_drawn: bool) -> DrawAction {
+   if offer_present {
+      if our_eval.abs() <= DRAW_ACCEPT_MARGIN || (dead_drawn && our_eval < 0) {
+         DrawAction::Accept
+      } else {
+         DrawAction::Nothing
+      }
+   } else if dead_drawn && our_eval <= -DRAW_OFFER_DEFICIT {
+      DrawAction::Offer
+   } else {
+      DrawAction::Nothing
+   }
+}
+
+/// Formats an eval in centipawns (+ for white) as lichess shows them: `+1.35` in pawns, or `#-4` when black mates
+fn eval_text(centipawns: i32) -> String {
+   if let Some(moves) = moves_to_mate(f64::from(centipawns) / 100.0) {
+      return format!("#{}", moves);
+   }
+   let clamped = centipawns.clamp(-MAX_EVAL_TEXT, MAX_EVAL_TEXT);
+   let sign = match clamped.cmp(&0) {
+      Ordering::Greater => "+",
+      Ordering::Less => "-",
+      Ordering::Equal => "",
+   };
+   format!("{}{}.{:02}", sign, clamped.abs() / 100, clamped.abs() % 100)
+}
+
+/// What to do given the game's status, and how long until we can claim victory if the opponent has left
+fn game_action(status: &str, opponent_gone: Option<u64>) -> GameAction {
+   match status {
+      "created" | "started" => match opponent_gone {
+         Some(secs) => GameAction::ClaimVictory(Duration::from_secs(secs)),
+         None => GameAction::Play,
+      },
+      // mate, resign, outoftime, aborted, draw, etc. - lichess has already decided the game
+      _ => GameAction::Finish,
+   }
+}
+
+/// No pawns or major pieces and at most one minor piece per side - neither side can force mate
+fn is_dead_drawn(position: &Position) -> bool {
+   let pieces = &position.squares.pieces;
+   let heavy = pieces[WHITE][PAWN]
+      | pieces[BLACK][PAWN]
+      | pieces[WHITE][ROOK]
+      | pieces[BLACK][ROOK]
+      | pieces[WHITE][QUEEN]
+      | pieces[BLACK][QUEEN];
+   heavy == 0
+      && [WHITE, BLACK]
+         .iter()
+         .all(|&color| (pieces[color][KNIGHT] | pieces[color][BISHOP]).count_ones() <= 1)
+}
+
+/// Brings `game` up to date with `moves`, which lichess always sends in full. Usually that's a move or so more
+/// than we had, which are returned. After a takeback it can be fewer, or different, and None is returned, as
+/// the engine then has to be given the whole state again
+fn sync_game(game: &mut Game, moves: &str) -> Result<Option<Vec<Move>>, String> {
+   let moves = moves
+      .split_whitespace()
+      .map(|x| x.parse().map_err(|e| format!("move {} couldn't be parsed: {}", x, e)))
+      .collect::<Result<Vec<Move>, String>>()?;
+   let common = game.moves().iter().zip(moves.iter()).take_while(|(a, b)| a == b).count();
+   let taken_back = game.moves().len() > common;
+   while game.moves().len() > common {
+      game.pop();
+   }
+   for a_move in moves[common..].iter() {
+      game.push(*a_move)?;
+   }
+   Ok(if taken_back { None } else { Some(moves[common..].to_vec()) })
+}
+
+impl GameState {
+   /// Our remaining time and increment
+   fn clock(&self, color: Color) -> (Duration, Duration) {
+      let (time, inc) = match color {
+         Color::White => (self.wtime, self.winc),
+         Color::Black => (self.btime, self.binc),
+      };
+      (Duration::from_millis(time), Duration::from_millis(inc))
+   }
+}
+
+/// In centipawns, + for white
+fn query_eval(ei: &EngineInterface) -> i32 {
+   let ei = ei.lock().unwrap();
+   ei.0.send(InterfaceMessage::QueryEval).unwrap();
+   loop {
+      match ei.1.recv().unwrap() {
+         EngineMessage::CurrentEval(e) => break e,
+         EngineMessage::Info { .. } | EngineMessage::SearchStats { .. } => (),
+         _ => panic!("expected current eval from the engine!"),
+      }
+   }
+}
+
 fn convert_err(err: reqwest::Error) -> std::io::Error {
-   panic!("{}", err)
+   std::io::Error::other(err)
 }
 
-pub async fn main_loop(sender: mpsc::Sender<InterfaceMessage>, receiver: mpsc::Receiver<EngineMessage>) {
+fn next_backoff(backoff: Duration) -> Duration {
+   std::cmp::min(backoff * 2, MAX_BACKOFF)
+}
+
+/// Keeps calling `f` until it succeeds, sleeping with exponential backoff between failed attempts
+async fn retry_with_backoff<T, E, F, Fut>(description: &str, initial_backoff: Duration, mut f: F) -> T
+where
+   E: Display,
+   F: FnMut() -> Fut,
+   Fut: Future<Output = Result<T, E>>,
+{
+   let mut backoff = initial_backoff;
+   loop {
+      match f().await {
+         Ok(val) => return val,
+         Err(e) => {
+            warn!("{} failed ({}), retrying in {:?}", description, e, backoff);
+            tokio::time::sleep(backoff).await;
+            backoff = next_backoff(backoff);
+         }
+      }
+   }
+}
+
+async fn open_stream(
+   client: &reqwest::Client,
+   url: &str,
+   api_token: &str,
+) -> Result<reqwest::Response, reqwest::Error> {
+   client.get(url).bearer_auth(api_token).send().await?.error_for_status()
+}
+
+pub async fn main_loop(
+   sender: mpsc::Sender<InterfaceMessage>,
+   receiver: mpsc::Receiver<EngineMessage>,
+   challenge_config: ChallengeConfig,
+) {
    let engine_interface: EngineInterface = Arc::new(Mutex::new((sender, receiver)));
+   play(&engine_interface, challenge_config).await;
+   engine_interface.lock().unwrap().0.send(InterfaceMessage::Quit).unwrap();
+}
+
+/// Plays on lichess until something goes wrong that can't be retried
+async fn play(engine_interface: &EngineInterface, challenge_config: ChallengeConfig) {
 
    let env_api_token = match env::var("LICHESS_API_TOKEN") {
       Ok(token) => Some(token),
@@ -210,17 +407,16 @@ pub async fn main_loop(sender: mpsc::Sender<InterfaceMessage>, receiver: mpsc::R
       }
    }
 
-   let challenge_ai: Option<u8> = None;
-
-   if let Some(level) = challenge_ai {
+   if let Some(level) = challenge_config.ai_level {
+      info!("Challenging the level {} AI", level);
       client
          .post("https://lichess.org/api/challenge/ai")
          .bearer_auth(&api_token)
          .json(&AiChallenge {
             level,
             clock: Clock {
-               limit: 900,
-               increment: 0,
+               limit: challenge_config.clock_limit,
+               increment: challenge_config.clock_increment,
             },
             color: "white".into(),
             variant: "standard".into(),
@@ -231,17 +427,16 @@ pub async fn main_loop(sender: mpsc::Sender<InterfaceMessage>, receiver: mpsc::R
          .unwrap();
    }
 
-   let challenge_bot: Option<&'static str> = Some("sargon-1ply");
-
-   if let Some(name) = challenge_bot {
+   if let Some(name) = challenge_config.user.as_ref() {
+      info!("Challenging {}", name);
       client
-         .post(&format!("https://lichess.org/api/challenge/{}", name))
+         .post(format!("https://lichess.org/api/challenge/{}", name))
          .bearer_auth(&api_token)
          .json(&AcctChallenge {
             rated: false,
             clock: Clock {
-               limit: 900,
-               increment: 0,
+               limit: challenge_config.clock_limit,
+               increment: challenge_config.clock_increment,
             },
             variant: "standard".into(),
          })
@@ -251,21 +446,31 @@ pub async fn main_loop(sender: mpsc::Sender<InterfaceMessage>, receiver: mpsc::R
    }
 
    let games_in_progress = Arc::new(Mutex::new(FxHashSet::with_hasher(Default::default())));
+   let mut stream_backoff = INITIAL_BACKOFF;
    // Accept first challenge
-   // TODO: we are silently ignoring errors by being flat
    loop {
       let challenge_stream = StreamReader::new(
-         client
-            .get("https://lichess.org/api/stream/event")
-            .bearer_auth(&api_token)
-            .send()
-            .await
-            .unwrap()
-            .bytes_stream()
-            .map_err(convert_err),
+         retry_with_backoff("connecting to the event stream", INITIAL_BACKOFF, || {
+            open_stream(&client, "https://lichess.org/api/stream/event", &api_token)
+         })
+         .await
+         .bytes_stream()
+         .map_err(convert_err),
       );
       let mut lines = challenge_stream.lines();
-      while let Some(line) = lines.next_line().await.unwrap() {
+      loop {
+         let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+               warn!("Event stream ended, reconnecting in {:?}", stream_backoff);
+               break;
+            }
+            Err(e) => {
+               warn!("Event stream failed ({}), reconnecting in {:?}", e, stream_backoff);
+               break;
+            }
+         };
+         stream_backoff = INITIAL_BACKOFF;
          let line = line.trim();
          if line.is_empty() {
             continue;
@@ -282,7 +487,7 @@ pub async fn main_loop(sender: mpsc::Sender<InterfaceMessage>, receiver: mpsc::R
                if !accpetable_challenge
                {
                   let challenge_reject_res = client
-                     .post(&format!("https://lichess.org/api/challenge/{}/decline", challenge_id))
+                     .post(format!("https://lichess.org/api/challenge/{}/decline", challenge_id))
                      .bearer_auth(&api_token)
                      .send()
                      .await
@@ -294,11 +499,11 @@ pub async fn main_loop(sender: mpsc::Sender<InterfaceMessage>, receiver: mpsc::R
                }
                // todo: this is now hard set to 1. 1 engine to multiple games doesn't work due to engine optimizations
                // to fix, need to have 1 engine per game ongoing? seems better
-               if games_in_progress.lock().unwrap().len() >= 1 {
+               if !games_in_progress.lock().unwrap().is_empty() {
                   continue;
                }
                let challenge_accept_res = client
-                  .post(&format!("https://lichess.org/api/challenge/{}/accept", challenge_id))
+                  .post(format!("https://lichess.org/api/challenge/{}/accept", challenge_id))
                   .bearer_auth(&api_token)
                   .send()
                   .await
@@ -327,11 +532,16 @@ pub async fn main_loop(sender: mpsc::Sender<InterfaceMessage>, receiver: mpsc::R
                   manage_game(cc, game_outer.game.id, atc, uc, uidc, eic, gipc).await;
                });
             }
-            Event::gameFinish(_game_outer) => {}
+            Event::gameFinish(game_outer) => {
+               // manage_game normally cleans up after itself, but make sure we don't wait on a dead game
+               games_in_progress.lock().unwrap().remove(&game_outer.game.id);
+            }
             Event::challengeDeclined(_) => {}
             Event::challengeCanceled(_) => {}
          }
       }
+      tokio::time::sleep(stream_backoff).await;
+      stream_backoff = next_backoff(stream_backoff);
    }
 }
 
@@ -344,20 +554,33 @@ async fn manage_game(
    ei: EngineInterface,
    games_in_progress: Arc<Mutex<FxHashSet<String>>>,
 ) {
+   let game_stream_url = format!("https://lichess.org/api/bot/game/stream/{}", game_id);
    let game_stream = StreamReader::new(
-      client
-         .get(&format!("https://lichess.org/api/bot/game/stream/{}", game_id))
-         .bearer_auth(&api_token)
-         .send()
-         .await
-         .unwrap()
-         .bytes_stream()
-         .map_err(convert_err),
+      retry_with_backoff("connecting to the game stream", INITIAL_BACKOFF, || {
+         open_stream(&client, &game_stream_url, &api_token)
+      })
+      .await
+      .bytes_stream()
+      .map_err(convert_err),
    );
    let mut us_color = Color::Black;
-   let mut initial_game_state = State::from_start();
+   let mut status = String::from("created");
+   // what the engine has been told of the game
+   let mut game = Game::default();
    let mut game_stream_lines = game_stream.lines();
-   while let Some(line) = game_stream_lines.next_line().await.unwrap() {
+   loop {
+      // if the stream drops we leave the game, and rejoin it when the event stream tells us it's still going
+      let line = match game_stream_lines.next_line().await {
+         Ok(Some(line)) => line,
+         Ok(None) => {
+            warn!("Game stream for {} ended unexpectedly", game_id);
+            break;
+         }
+         Err(e) => {
+            warn!("Game stream for {} failed ({})", game_id, e);
+            break;
+         }
+      };
       let line = line.trim();
       if line.is_empty() {
          continue;
@@ -365,7 +588,9 @@ async fn manage_game(
       let game_event = serde_json::from_str(line).unwrap();
       match game_event {
          GameEvent::gameFull(full_game) => {
-            if full_game.state.status != "created" && full_game.state.status != "started" {
+            status = full_game.state.status.clone();
+            if game_action(&status, None) == GameAction::Finish {
+               info!("Game {} is already over: {}", game_id, status);
                break;
             }
 
@@ -373,61 +598,133 @@ async fn manage_game(
             if full_game.white.id.as_ref() == Some(&user_id) {
                us_color = Color::White;
             }
-            initial_game_state = if full_game.initialFen == "startpos" {
+            game = Game::new(if full_game.initialFen == "startpos" {
                State::from_start()
             } else {
                State::from_fen(&full_game.initialFen).unwrap()
-            };
-            let remaining_time = Duration::from_millis(match us_color {
-               Color::White => full_game.state.wtime,
-               Color::Black => full_game.state.btime,
             });
-            let cur_game_state = initial_game_state.apply_moves_from_uci(&full_game.state.moves);
+            let (remaining_time, increment) = full_game.state.clock(us_color);
+            if let Err(e) = sync_game(&mut game, &full_game.state.moves) {
+               error!("Can't follow game {}: {}", game_id, e);
+               break;
+            }
+            let cur_game_state = game.current().clone();
             {
                let ei = ei.lock().unwrap();
                ei.0.send(InterfaceMessage::SetState(cur_game_state.clone())).unwrap();
             }
             if cur_game_state.position.side_to_move == us_color {
-               think_and_move(&client, &game_id, &api_token, &ei, remaining_time).await;
+               let budget = move_budget(remaining_time, increment, cur_game_state.fullmove_number, None);
+               if let Some(our_move) = think_and_move(&client, &game_id, &api_token, &ei, budget).await {
+                  game.push(our_move).unwrap();
+               }
             }
          }
          GameEvent::gameState(game_state_json) => {
-            if game_state_json.status != "created" && game_state_json.status != "started" {
+            status = game_state_json.status.clone();
+            if game_action(&status, None) == GameAction::Finish {
+               match game_state_json.winner {
+                  Some(winner) => info!("Game {} finished: {}, {} wins", game_id, status, winner),
+                  None => info!("Game {} finished: {}", game_id, status),
+               }
                break;
             }
 
-            let remaining_time = Duration::from_millis(match us_color {
-               Color::White => game_state_json.wtime,
-               Color::Black => game_state_json.btime,
-            });
-            let cur_game_state = initial_game_state.apply_moves_from_uci(&game_state_json.moves);
-            if cur_game_state.position.side_to_move == us_color {
-               let last_move: Option<Move> = game_state_json
-                  .moves
-                  .split_whitespace()
-                  .last()
-                  .map(|x| x.parse().unwrap());
-               if let Some(m) = last_move {
-                  let ei = ei.lock().unwrap();
-                  ei.0.send(InterfaceMessage::ApplyMove(m)).unwrap();
+            let (remaining_time, increment) = game_state_json.clock(us_color);
+            let synced = match sync_game(&mut game, &game_state_json.moves) {
+               Ok(synced) => synced,
+               Err(e) => {
+                  error!("Can't follow game {}: {}", game_id, e);
+                  break;
+               }
+            };
+            {
+               let ei = ei.lock().unwrap();
+               match synced {
+                  Some(new_moves) => {
+                     for a_move in new_moves {
+                        ei.0.send(InterfaceMessage::ApplyMove(a_move)).unwrap();
+                     }
+                  }
+                  None => {
+                     info!("Moves were taken back in game {}", game_id);
+                     ei.0.send(InterfaceMessage::SetState(game.current().clone())).unwrap();
+                  }
+               }
+            }
+            let cur_game_state = game.current().clone();
+            let (our_offer, their_offer) = match us_color {
+               Color::White => (game_state_json.wdraw, game_state_json.bdraw),
+               Color::Black => (game_state_json.bdraw, game_state_json.wdraw),
+            };
+            let our_turn = cur_game_state.position.side_to_move == us_color;
+            if their_offer || (our_turn && !our_offer) {
+               // the engine reports + for white
+               let eval = match us_color {
+                  Color::White => query_eval(&ei),
+                  Color::Black => -query_eval(&ei),
+               };
+               let action = draw_decision(eval, their_offer, is_dead_drawn(&cur_game_state.position));
+               if action != DrawAction::Nothing {
+                  trace!("{:?} draw in game {} at eval {}", action, game_id, eval);
+                  // the same endpoint both offers and accepts
+                  let draw_res = client
+                     .post(format!("https://lichess.org/api/bot/game/{}/draw/yes", game_id))
+                     .bearer_auth(&api_token)
+                     .send()
+                     .await;
+                  match draw_res {
+                     Ok(res) if res.status() == StatusCode::OK => {
+                        if action == DrawAction::Accept {
+                           // the game is over, wait for the final game state
+                           continue;
+                        }
+                     }
+                     Ok(res) => warn!("Draw request for game {} was rejected: {}", game_id, res.status()),
+                     Err(e) => warn!("Draw request for game {} failed: {}", game_id, e),
+                  }
+               }
+            }
+            if our_turn {
+               let budget = move_budget(remaining_time, increment, cur_game_state.fullmove_number, None);
+               if let Some(our_move) = think_and_move(&client, &game_id, &api_token, &ei, budget).await {
+                  game.push(our_move).unwrap();
                }
-               think_and_move(&client, &game_id, &api_token, &ei, remaining_time).await;
+            }
+         }
+         GameEvent::opponentGone(opponent_gone) => {
+            let claim_in = if opponent_gone.gone {
+               opponent_gone.claimWinInSeconds
+            } else {
+               None
+            };
+            if let GameAction::ClaimVictory(wait) = game_action(&status, claim_in) {
+               info!("Opponent left game {}, claiming victory in {:?}", game_id, wait);
+               let cc = client.clone();
+               let atc = api_token.clone();
+               let gidc = game_id.clone();
+               tokio::spawn(async move {
+                  tokio::time::sleep(wait).await;
+                  // if they came back in the meantime lichess will just refuse
+                  let claim_res = cc
+                     .post(format!("https://lichess.org/api/bot/game/{}/claim-victory", gidc))
+                     .bearer_auth(&atc)
+                     .send()
+                     .await;
+                  match claim_res {
+                     Ok(res) if res.status() == StatusCode::OK => info!("Claimed victory in game {}", gidc),
+                     Ok(res) => warn!("Claiming victory in game {} was rejected: {}", gidc, res.status()),
+                     Err(e) => warn!("Claiming victory in game {} failed: {}", gidc, e),
+                  }
+               });
             }
          }
          GameEvent::chatLine(chat_line) => {
             if chat_line.text == "!eval" {
-               let eval = {
-                  let ei = ei.lock().unwrap();
-                  ei.0.send(InterfaceMessage::QueryEval).unwrap();
-                  let msg = { ei.1.recv().unwrap() };
-                  match msg {
-                     EngineMessage::CurrentEval(e) => e,
-                     _ => panic!("expected current eval from the engine!"),
-                  }
-               };
-               let body = [("room", &chat_line.room), ("text", &eval.to_string())];
+               let eval = eval_text(query_eval(&ei));
+               let body = [("room", &chat_line.room), ("text", &eval)];
                let _chat_res = client
-                  .post(&format!("https://lichess.org/api/bot/game/{}/chat", game_id))
+                  .post(format!("https://lichess.org/api/bot/game/{}/chat", game_id))
                   .bearer_auth(&api_token)
                   .form(&body)
                   .send()
@@ -437,7 +734,7 @@ async fn manage_game(
                let chat_saying = RESPONSES.choose(&mut rand::thread_rng()).unwrap();
                let body = [("room", "player"), ("text", chat_saying)];
                let _chat_res = client
-                  .post(&format!("https://lichess.org/api/bot/game/{}/chat", game_id))
+                  .post(format!("https://lichess.org/api/bot/game/{}/chat", game_id))
                   .bearer_auth(&api_token)
                   .form(&body)
                   .send()
@@ -451,36 +748,45 @@ async fn manage_game(
    games_in_progress.lock().unwrap().remove(&game_id);
 }
 
+/// Returns the move we played, which the engine has already applied
 async fn think_and_move(
    client: &reqwest::Client,
    game_id: &str,
    api_token: &str,
    ei: &EngineInterface,
-   remaining_time: Duration,
-) {
+   budget: Duration,
+) -> Option<Move> {
    let e_move = {
       let ei = ei.lock().unwrap();
-      ei.0.send(InterfaceMessage::GoTime(remaining_time / 20)).unwrap();
+      ei.0.send(InterfaceMessage::GoTime(budget)).unwrap();
       trace!("Our move! Thinking...");
-      let msg = { ei.1.recv().unwrap() };
-      match msg {
-         EngineMessage::BestMove(best_move_opt) => {
-            if let Some(best_move) = best_move_opt {
-               ei.0.send(InterfaceMessage::ApplyMove(best_move)).unwrap();
-               best_move
-            } else {
-               // probably end of game
-               // could be bug in the engine
-               return;
+      loop {
+         match ei.1.recv().unwrap() {
+            EngineMessage::BestMove(best_move_opt) => {
+               if let Some(best_move) = best_move_opt {
+                  ei.0.send(InterfaceMessage::ApplyMove(best_move)).unwrap();
+                  break best_move;
+               } else {
+                  // probably end of game
+                  // could be bug in the engine
+                  return None;
+               }
             }
+            EngineMessage::Info { .. } => (),
+            EngineMessage::SearchStats {
+               depth,
+               nodes,
+               nps,
+               time_ms,
+            } => info!("Searched {} nodes to depth {} in {}ms ({} nps)", nodes, depth, time_ms, nps),
+            _ => panic!("expected a move in response from the engine!"),
          }
-         _ => panic!("expected a move in response from the engine!"),
       }
    };
    trace!("Decided on {}", e_move);
    let make_move_res = client
-      .post(&format!("https://lichess.org/api/bot/game/{}/move/{}", game_id, e_move))
-      .bearer_auth(&api_token)
+      .post(format!("https://lichess.org/api/bot/game/{}/move/{}", game_id, e_move))
+      .bearer_auth(api_token)
       .send()
       .await
       .unwrap();
@@ -490,10 +796,129 @@ async fn think_and_move(
          e_move
       );
       let _resign_res = client
-         .post(&format!("https://lichess.org/api/bot/game/{}/resign", game_id))
-         .bearer_auth(&api_token)
+         .post(format!("https://lichess.org/api/bot/game/{}/resign", game_id))
+         .bearer_auth(api_token)
          .send()
          .await
          .unwrap();
+      return None;
+   }
+   Some(e_move)
+}
+
+#[cfg(test)]
+mod tests {
+   use crate::lichess::*;
+   use chessatk_lib::engine::MATE;
+   use std::cell::Cell;
+
+   #[tokio::test]
+   async fn retries_until_success() {
+      let attempts = Cell::new(0);
+      let result = retry_with_backoff("flaky operation", Duration::from_millis(1), || {
+         attempts.set(attempts.get() + 1);
+         let attempt = attempts.get();
+         async move {
+            if attempt < 3 {
+               Err(format!("attempt {} failed", attempt))
+            } else {
+               Ok(attempt)
+            }
+         }
+      })
+      .await;
+      assert_eq!(result, 3);
+      assert_eq!(attempts.get(), 3);
+   }
+
+   #[test]
+   fn draw_decisions() {
+      assert_eq!(draw_decision(20, true, false), DrawAction::Accept);
+      assert_eq!(draw_decision(-40, true, false), DrawAction::Accept);
+      assert_eq!(draw_decision(300, true, false), DrawAction::Nothing);
+      assert_eq!(draw_decision(-300, true, false), DrawAction::Nothing);
+      assert_eq!(draw_decision(-300, true, true), DrawAction::Accept);
+      assert_eq!(draw_decision(300, true, true), DrawAction::Nothing);
+      assert_eq!(draw_decision(0, false, false), DrawAction::Nothing);
+      assert_eq!(draw_decision(-300, false, false), DrawAction::Nothing);
+      assert_eq!(draw_decision(-300, false, true), DrawAction::Offer);
+      assert_eq!(draw_decision(-100, false, true), DrawAction::Nothing);
+   }
+
+   #[test]
+   fn eval_texts() {
+      assert_eq!(eval_text(135), "+1.35");
+      assert_eq!(eval_text(7), "+0.07");
+      assert_eq!(eval_text(-270), "-2.70");
+      assert_eq!(eval_text(-5), "-0.05");
+      assert_eq!(eval_text(0), "0.00");
+      assert_eq!(eval_text(123_456), "+99.99");
+      assert_eq!(eval_text(-123_456), "-99.99");
+      // mate in 3 for white, and black mating in 4
+      assert_eq!(eval_text((MATE * 100.0) as i32 - 500), "#3");
+      assert_eq!(eval_text(-(MATE * 100.0) as i32 + 800), "#-4");
+   }
+
+   #[test]
+   fn dead_drawn_positions() {
+      let dead_drawn = |fen: &str| is_dead_drawn(&State::from_fen(fen).unwrap().position);
+      assert!(dead_drawn("8/8/4k3/8/8/2B5/4K3/8 w - - 0 1"));
+      assert!(dead_drawn("8/5n2/4k3/8/8/2B5/4K3/8 w - - 0 1"));
+      assert!(!dead_drawn("8/8/4k3/8/8/2BB4/4K3/8 w - - 0 1"));
+      assert!(!dead_drawn("8/8/4k3/8/8/2R5/4K3/8 w - - 0 1"));
+      assert!(!is_dead_drawn(&State::from_start().position));
+   }
+
+   #[test]
+   fn game_actions() {
+      assert_eq!(game_action("started", None), GameAction::Play);
+      assert_eq!(game_action("created", None), GameAction::Play);
+      assert_eq!(game_action("started", Some(8)), GameAction::ClaimVictory(Duration::from_secs(8)));
+      assert_eq!(game_action("started", Some(0)), GameAction::ClaimVictory(Duration::from_secs(0)));
+      for status in ["mate", "resign", "outoftime", "timeout", "aborted", "draw", "stalemate"] {
+         assert_eq!(game_action(status, None), GameAction::Finish);
+         // a finished game is never claimed, even if the opponent has left
+         assert_eq!(game_action(status, Some(0)), GameAction::Finish);
+      }
+   }
+
+   #[test]
+   fn parses_opponent_gone() {
+      let event = serde_json::from_str(r#"{"type":"opponentGone","gone":true,"claimWinInSeconds":8}"#).unwrap();
+      assert!(matches!(
+         event,
+         GameEvent::opponentGone(OpponentGone {
+            gone: true,
+            claimWinInSeconds: Some(8)
+         })
+      ));
+   }
+
+   #[test]
+   fn takebacks() {
+      let mut game = Game::default();
+      let new_moves = sync_game(&mut game, "e2e4 e7e5").unwrap();
+      assert_eq!(new_moves, Some(vec!["e2e4".parse().unwrap(), "e7e5".parse().unwrap()]));
+      let new_moves = sync_game(&mut game, "e2e4 e7e5 g1f3").unwrap();
+      assert_eq!(new_moves, Some(vec!["g1f3".parse().unwrap()]));
+
+      // our last move and their reply are taken back
+      assert_eq!(sync_game(&mut game, "e2e4").unwrap(), None);
+      assert!(*game.current() == State::from_start().apply_uci_moves_checked("e2e4").unwrap());
+      // and a different move played instead
+      assert_eq!(sync_game(&mut game, "e2e4 c7c5").unwrap(), Some(vec!["c7c5".parse().unwrap()]));
+      assert_eq!(sync_game(&mut game, "d2d4").unwrap(), None);
+      assert!(*game.current() == State::from_start().apply_uci_moves_checked("d2d4").unwrap());
+      assert_eq!(sync_game(&mut game, "").unwrap(), None);
+      assert!(*game.current() == State::from_start());
+
+      assert!(sync_game(&mut game, "e2e5").is_err());
+      assert!(sync_game(&mut game, "nonsense").is_err());
+   }
+
+   #[test]
+   fn backoff_is_capped() {
+      assert_eq!(next_backoff(INITIAL_BACKOFF), INITIAL_BACKOFF * 2);
+      assert_eq!(next_backoff(MAX_BACKOFF), MAX_BACKOFF);
    }
 }
@@ -1,8 +1,10 @@
-#![feature(let_chains)]
-
+mod bench;
 mod lichess;
+mod time;
 mod uci;
 
+use chessatk_lib::board::{ParseError, State};
+use std::path::PathBuf;
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -21,30 +23,180 @@ struct Opt {
    /// monte carlo tree search w/ ucb1 instead of negamax
    #[structopt(long = "mcts")]
    mcts: bool,
+   /// Number of search threads (1-256), for negamax and mcts alike. Defaults to one per core for negamax,
+   /// and to the mcts default for mcts
+   #[structopt(long = "threads", parse(try_from_str = parse_threads))]
+   threads: Option<usize>,
+   /// After each mcts search, write the top of the tree to mcts.html in the working directory
+   #[structopt(long = "debug-tree")]
+   debug_tree: bool,
+   /// Trace what each depth of a negamax search finds, when RUST_LOG allows trace logging
+   #[structopt(long = "trace-search")]
+   trace_search: bool,
+   /// Position to start from instead of the standard starting position
+   #[structopt(long = "fen")]
+   fen: Option<String>,
+   /// Lichess mode: challenge the lichess AI at this level (1-8) on startup
+   #[structopt(long = "challenge-ai", parse(try_from_str = parse_ai_level))]
+   challenge_ai: Option<u8>,
+   /// Lichess mode: challenge this user on startup
+   #[structopt(long = "challenge-user")]
+   challenge_user: Option<String>,
+   /// Lichess mode: clock limit in seconds for our challenges (0-10800)
+   #[structopt(long = "clock-limit", default_value = "900", parse(try_from_str = parse_clock_limit))]
+   clock_limit: u16,
+   /// Lichess mode: clock increment in seconds for our challenges (0-60)
+   #[structopt(long = "clock-increment", default_value = "0", parse(try_from_str = parse_clock_increment))]
+   clock_increment: u8,
+   #[structopt(subcommand)]
+   command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+   /// Counts the leaf nodes of the move tree, move by move
+   Perft {
+      /// Defaults to the standard starting position
+      #[structopt(long = "fen")]
+      fen: Option<String>,
+      #[structopt(long = "depth")]
+      depth: u64,
+   },
+   /// Searches a fixed set of positions and reports the speed
+   Bench,
+   /// Runs an EPD test suite, reporting which positions the engine solves
+   Epd {
+      #[structopt(parse(from_os_str))]
+      path: PathBuf,
+      #[structopt(long = "depth", default_value = "5")]
+      depth: u64,
+      /// Milliseconds per position. Ends the search early if it runs out before the depth is reached
+      #[structopt(long = "movetime")]
+      movetime: Option<u64>,
+   },
+}
+
+impl Opt {
+   fn initial_state(&self) -> Result<Option<State>, ParseError> {
+      self.fen.as_deref().map(State::from_fen).transpose()
+   }
+
+   fn challenge_config(&self) -> lichess::ChallengeConfig {
+      lichess::ChallengeConfig {
+         ai_level: self.challenge_ai,
+         user: self.challenge_user.clone(),
+         clock_limit: self.clock_limit,
+         clock_increment: self.clock_increment,
+      }
+   }
+}
+
+fn parse_in_range<T: std::str::FromStr + PartialOrd + std::fmt::Display>(s: &str, min: T, max: T) -> Result<T, String> {
+   let val: T = s.parse().map_err(|_| format!("{} is not a number", s))?;
+   if val < min || val > max {
+      return Err(format!("{} is not between {} and {}", val, min, max));
+   }
+   Ok(val)
+}
+
+fn parse_ai_level(s: &str) -> Result<u8, String> {
+   parse_in_range(s, 1, 8)
+}
+
+fn parse_clock_limit(s: &str) -> Result<u16, String> {
+   parse_in_range(s, 0, 10800)
+}
+
+fn parse_clock_increment(s: &str) -> Result<u8, String> {
+   parse_in_range(s, 0, 60)
+}
+
+fn parse_threads(s: &str) -> Result<usize, String> {
+   parse_in_range(s, 1, 256)
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
    pretty_env_logger::init();
    let opt = Opt::from_args();
+   let initial_state = match opt.initial_state() {
+      Ok(state) => state,
+      Err(e) => {
+         eprintln!("Invalid --fen: {}", e);
+         std::process::exit(1);
+      }
+   };
+
+   match opt.command {
+      Some(Command::Perft { ref fen, depth }) => {
+         let state = match fen.as_deref().map(State::from_fen).transpose() {
+            Ok(state) => state.unwrap_or_else(State::from_start),
+            Err(e) => {
+               eprintln!("Invalid --fen: {}", e);
+               std::process::exit(1);
+            }
+         };
+         bench::run_perft(state, depth);
+         return;
+      }
+      Some(Command::Bench) => {
+         bench::run_bench();
+         return;
+      }
+      Some(Command::Epd {
+         ref path,
+         depth,
+         movetime,
+      }) => {
+         if let Err(e) = bench::run_epd(path, depth, movetime.map(Duration::from_millis)) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+         }
+         return;
+      }
+      None => (),
+   }
 
    let (ite_tx, ite_rx) = mpsc::channel(); // Interface to Engine
    let (eti_tx, eti_rx) = mpsc::channel(); // Engine to Interface
 
-   if opt.mcts {
+   let engine = if opt.mcts {
       thread::spawn(move || {
          chessatk_lib::mcts::start(ite_rx, eti_tx);
-      });
+      })
    } else {
       thread::spawn(move || {
          chessatk_lib::engine::start(ite_rx, eti_tx);
-      });
+      })
+   };
+
+   if opt.debug_tree {
+      let params = chessatk_lib::mcts::MctsParams {
+         debug_tree: Some("mcts.html".into()),
+         ..chessatk_lib::mcts::MctsParams::default()
+      };
+      ite_tx
+         .send(chessatk_lib::messages::InterfaceMessage::SetMctsParams(params))
+         .unwrap();
+   }
+
+   // after the mcts params, which would otherwise put the thread count back
+   if let Some(threads) = opt.threads {
+      ite_tx
+         .send(chessatk_lib::messages::InterfaceMessage::SetThreads(threads))
+         .unwrap();
+   }
+
+   if opt.trace_search {
+      ite_tx
+         .send(chessatk_lib::messages::InterfaceMessage::SetVerbosity(true))
+         .unwrap();
    }
 
    if opt.profiling {
-      let state =
-         chessatk_lib::board::State::from_fen("rnbqkbnr/ppppp2p/5p2/6p1/4P3/P7/1PPP1PPP/RNBQKBNR w KQkq - 0 3")
-            .unwrap();
+      let state = initial_state.unwrap_or_else(|| {
+         State::from_fen("rnbqkbnr/ppppp2p/5p2/6p1/4P3/P7/1PPP1PPP/RNBQKBNR w KQkq - 0 3").unwrap()
+      });
       ite_tx
          .send(chessatk_lib::messages::InterfaceMessage::SetState(state))
          .unwrap();
@@ -53,14 +205,125 @@ async fn main() {
             30,
          )))
          .unwrap();
-      let _ = eti_rx.recv().unwrap();
-
+      while !matches!(eti_rx.recv().unwrap(), chessatk_lib::messages::EngineMessage::BestMove(_)) {}
+      ite_tx.send(chessatk_lib::messages::InterfaceMessage::Quit).unwrap();
+      engine.join().unwrap();
       return;
    }
 
+   if let Some(state) = initial_state {
+      ite_tx
+         .send(chessatk_lib::messages::InterfaceMessage::SetState(state))
+         .unwrap();
+   }
+
    if opt.lichess {
-      lichess::main_loop(ite_tx, eti_rx).await;
+      let challenge_config = opt.challenge_config();
+      lichess::main_loop(ite_tx, eti_rx, challenge_config).await;
    } else {
       uci::main_loop(ite_tx, eti_rx);
    }
+   // the frontends tell the engine to quit once they're done
+   engine.join().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+   use crate::*;
+
+   #[test]
+   fn parses_challenge_options() {
+      let opt = Opt::from_iter_safe(&[
+         "chessatk",
+         "--lichess",
+         "--challenge-ai",
+         "3",
+         "--challenge-user",
+         "sargon-1ply",
+         "--clock-limit",
+         "300",
+         "--clock-increment",
+         "5",
+      ])
+      .unwrap();
+      assert_eq!(
+         opt.challenge_config(),
+         lichess::ChallengeConfig {
+            ai_level: Some(3),
+            user: Some("sargon-1ply".into()),
+            clock_limit: 300,
+            clock_increment: 5,
+         }
+      );
+   }
+
+   #[test]
+   fn challenge_defaults() {
+      let opt = Opt::from_iter_safe(&["chessatk", "--lichess"]).unwrap();
+      assert_eq!(
+         opt.challenge_config(),
+         lichess::ChallengeConfig {
+            ai_level: None,
+            user: None,
+            clock_limit: 900,
+            clock_increment: 0,
+         }
+      );
+   }
+
+   #[test]
+   fn parses_fen() {
+      let opt = Opt::from_iter_safe(&["chessatk", "--fen", "8/8/4k3/8/8/8/4K3/8 w - - 0 1"]).unwrap();
+      let state = opt.initial_state().unwrap().unwrap();
+      assert!(state == State::from_fen("8/8/4k3/8/8/8/4K3/8 w - - 0 1").unwrap());
+
+      let opt = Opt::from_iter_safe(&["chessatk", "--fen", "not a fen"]).unwrap();
+      assert!(opt.initial_state().is_err());
+
+      let opt = Opt::from_iter_safe(&["chessatk"]).unwrap();
+      assert!(opt.initial_state().unwrap().is_none());
+   }
+
+   #[test]
+   fn parses_subcommands() {
+      let opt = Opt::from_iter_safe(&["chessatk", "perft", "--depth", "3"]).unwrap();
+      assert!(matches!(opt.command, Some(Command::Perft { fen: None, depth: 3 })));
+      let opt = Opt::from_iter_safe(&["chessatk", "bench"]).unwrap();
+      assert!(matches!(opt.command, Some(Command::Bench)));
+      let opt = Opt::from_iter_safe(&["chessatk", "epd", "wac.epd", "--movetime", "100"]).unwrap();
+      assert!(matches!(
+         opt.command,
+         Some(Command::Epd {
+            depth: 5,
+            movetime: Some(100),
+            ..
+         })
+      ));
+   }
+
+   #[test]
+   fn parses_threads() {
+      let opt = Opt::from_iter_safe(&["chessatk", "--mcts", "--threads", "4"]).unwrap();
+      assert_eq!(opt.threads, Some(4));
+      assert!(!opt.debug_tree);
+      let opt = Opt::from_iter_safe(&["chessatk", "--mcts", "--debug-tree"]).unwrap();
+      assert!(opt.debug_tree);
+      // negamax searches on threads too
+      assert_eq!(Opt::from_iter_safe(&["chessatk", "--threads", "2"]).unwrap().threads, Some(2));
+      assert!(Opt::from_iter_safe(&["chessatk", "--threads", "0"]).is_err());
+   }
+
+   #[test]
+   fn parses_trace_search() {
+      assert!(!Opt::from_iter_safe(&["chessatk"]).unwrap().trace_search);
+      assert!(Opt::from_iter_safe(&["chessatk", "--trace-search"]).unwrap().trace_search);
+   }
+
+   #[test]
+   fn rejects_out_of_range_options() {
+      assert!(Opt::from_iter_safe(&["chessatk", "--challenge-ai", "9"]).is_err());
+      assert!(Opt::from_iter_safe(&["chessatk", "--challenge-ai", "0"]).is_err());
+      assert!(Opt::from_iter_safe(&["chessatk", "--clock-limit", "10801"]).is_err());
+      assert!(Opt::from_iter_safe(&["chessatk", "--clock-increment", "61"]).is_err());
+   }
 }
@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+/// Time we never plan to use, to cover network lag
+const CLOCK_SAFETY_BUFFER: Duration = Duration::from_secs(1);
+/// We plan as if the game will last this many moves...
+const EXPECTED_GAME_LENGTH: u64 = 50;
+/// ...but always as if there are at least this many moves left
+const MIN_MOVES_TO_GO: u64 = 20;
+
+/// How long to think about our next move. `moves_to_go` is how many moves are left until the next time control,
+/// when there is one. Otherwise we guess from `move_number`
+pub fn move_budget(remaining: Duration, increment: Duration, move_number: u64, moves_to_go: Option<u64>) -> Duration {
+   let moves_to_go = match moves_to_go {
+      Some(moves) => moves.max(1),
+      None => std::cmp::max(EXPECTED_GAME_LENGTH.saturating_sub(move_number), MIN_MOVES_TO_GO),
+   };
+   let usable = remaining.saturating_sub(CLOCK_SAFETY_BUFFER);
+   // we get most of the increment back after moving, so it's (mostly) free to spend
+   let budget = usable / moves_to_go as u32 + increment * 3 / 4;
+   // never sink too much of the clock into one move, but always take a moment even when nearly flagging
+   std::cmp::max(std::cmp::min(budget, usable / 2), remaining / 100)
+}
+
+#[cfg(test)]
+mod tests {
+   use crate::time::*;
+
+   #[test]
+   fn move_budgets() {
+      let secs = Duration::from_secs;
+      // sudden death, early on: spread the clock over the rest of the game
+      assert_eq!(move_budget(secs(301), secs(0), 10, None), secs(300) / 40);
+      // late in the game we still assume there are some moves left
+      assert_eq!(move_budget(secs(61), secs(0), 80, None), secs(3));
+      // increment is mostly spent
+      assert_eq!(move_budget(secs(61), secs(4), 80, None), secs(6));
+      // but not to the point of running down a low clock
+      assert_eq!(move_budget(secs(3), secs(10), 30, None), secs(1));
+      // and with almost nothing left we still take a moment
+      assert_eq!(move_budget(Duration::from_millis(500), secs(0), 30, None), Duration::from_millis(5));
+      // the clock is spread over the moves until the next time control, however late in the game
+      assert_eq!(move_budget(secs(61), secs(0), 80, Some(10)), secs(6));
+      assert_eq!(move_budget(secs(61), secs(0), 80, Some(0)), secs(30));
+   }
+}
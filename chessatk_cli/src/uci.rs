@@ -1,6 +1,480 @@
+use chessatk_lib::board::{Color, Move, State};
+use chessatk_lib::engine::moves_to_mate;
+use chessatk_lib::eval::to_centipawns;
 use chessatk_lib::messages::{EngineMessage, InterfaceMessage};
-use std::sync::mpsc;
+use chessatk_lib::tt::DEFAULT_HASH_MB;
+use crate::time::move_budget;
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::RangeInclusive;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 
-pub fn main_loop(_sender: mpsc::Sender<InterfaceMessage>, _receiver: mpsc::Receiver<EngineMessage>) {
-   unimplemented!()
+// When go doesn't say how long to think
+const DEFAULT_DEPTH: u64 = 5;
+
+// How often to check for a finished search while waiting on input
+const SEARCH_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// What setoption accepts. Values outside of these are clamped
+const HASH_MB_RANGE: RangeInclusive<i64> = 1..=4096;
+const THREADS_RANGE: RangeInclusive<i64> = 1..=256;
+const MULTI_PV_RANGE: RangeInclusive<i64> = 1..=256;
+
+pub fn main_loop(sender: mpsc::Sender<InterfaceMessage>, receiver: mpsc::Receiver<EngineMessage>) {
+   let stdout = io::stdout();
+   run(BufReader::new(io::stdin()), stdout.lock(), &sender, &receiver).unwrap();
+}
+
+fn run<R: BufRead + Send + 'static, W: Write>(
+   input: R,
+   mut output: W,
+   sender: &mpsc::Sender<InterfaceMessage>,
+   receiver: &mpsc::Receiver<EngineMessage>,
+) -> io::Result<()> {
+   // Input is read on its own thread, so that we can keep an eye on the engine during a search
+   let (line_tx, line_rx) = mpsc::channel();
+   thread::spawn(move || {
+      for line in input.lines() {
+         if line_tx.send(line).is_err() {
+            break;
+         }
+      }
+   });
+
+   let mut state = State::from_start();
+   let mut searching = false;
+   // the best line so far, for the move to ponder on
+   let mut pv = Vec::new();
+   loop {
+      let line = if searching {
+         if let Ok(message) = receiver.try_recv() {
+            searching = !write_engine_message(&mut output, message, &mut pv)?;
+            continue;
+         }
+         match line_rx.recv_timeout(SEARCH_POLL_INTERVAL) {
+            Ok(line) => line?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => {
+               stop_search(&mut output, sender, receiver, &mut pv)?;
+               break;
+            }
+         }
+      } else {
+         match line_rx.recv() {
+            Ok(line) => line?,
+            Err(_) => break,
+         }
+      };
+      let mut tokens = line.split_whitespace();
+      match tokens.next() {
+         Some("uci") => {
+            writeln!(output, "id name chessatk")?;
+            writeln!(output, "id author Richard McCormack")?;
+            writeln!(output, "option name Ponder type check default false")?;
+            write_spin_option(&mut output, "Hash", DEFAULT_HASH_MB, HASH_MB_RANGE)?;
+            let threads = thread::available_parallelism().map_or(1, |x| x.get());
+            write_spin_option(&mut output, "Threads", threads, THREADS_RANGE)?;
+            write_spin_option(&mut output, "MultiPV", 1, MULTI_PV_RANGE)?;
+            writeln!(output, "uciok")?;
+         }
+         Some("isready") => {
+            writeln!(output, "readyok")?;
+         }
+         Some("setoption") => set_option(tokens, &mut output, sender)?,
+         Some("ucinewgame") => {
+            state = State::from_start();
+            sender.send(InterfaceMessage::NewGame).unwrap();
+         }
+         Some("position") => match parse_position(tokens) {
+            Ok(new_state) => {
+               state = new_state;
+               sender.send(InterfaceMessage::SetState(state.clone())).unwrap();
+            }
+            Err(e) => writeln!(output, "info string {}", e)?,
+         },
+         Some("go") => {
+            pv.clear();
+            let (message, search_moves) = parse_go(tokens, &state);
+            if let Some(moves) = search_moves {
+               sender.send(InterfaceMessage::RestrictNextSearch(moves)).unwrap();
+            }
+            sender.send(message).unwrap();
+            searching = true;
+         }
+         Some("ponderhit") if searching => {
+            sender.send(InterfaceMessage::PonderHit).unwrap();
+         }
+         Some("stop") if searching => {
+            stop_search(&mut output, sender, receiver, &mut pv)?;
+            searching = false;
+         }
+         Some("quit") => {
+            if searching {
+               stop_search(&mut output, sender, receiver, &mut pv)?;
+            }
+            break;
+         }
+         _ => {
+            // Unknown commands are ignored, per the UCI spec
+         }
+      }
+      output.flush()?;
+   }
+   sender.send(InterfaceMessage::Quit).unwrap();
+   Ok(())
+}
+
+fn stop_search<W: Write>(
+   output: &mut W,
+   sender: &mpsc::Sender<InterfaceMessage>,
+   receiver: &mpsc::Receiver<EngineMessage>,
+   pv: &mut Vec<Move>,
+) -> io::Result<()> {
+   sender.send(InterfaceMessage::Stop).unwrap();
+   while !write_engine_message(output, receiver.recv().unwrap(), pv)? {}
+   Ok(())
+}
+
+/// Returns whether this was the best move, which ends the search.
+/// `pv` keeps the best line seen, to suggest a move to ponder on
+fn write_engine_message<W: Write>(output: &mut W, message: EngineMessage, pv: &mut Vec<Move>) -> io::Result<bool> {
+   let done = match message {
+      EngineMessage::BestMove(Some(m)) => {
+         match pv.get(1) {
+            Some(reply) if pv[0] == m => writeln!(output, "bestmove {} ponder {}", m, reply)?,
+            _ => writeln!(output, "bestmove {}", m)?,
+         }
+         true
+      }
+      EngineMessage::BestMove(None) => {
+         writeln!(output, "bestmove 0000")?;
+         true
+      }
+      EngineMessage::Info {
+         depth,
+         multipv,
+         score,
+         pv: line,
+      } => {
+         let line_text: Vec<String> = line.iter().map(|x| x.to_string()).collect();
+         let score_text = match moves_to_mate(score) {
+            Some(moves) => format!("mate {}", moves),
+            None => format!("cp {}", to_centipawns(score)),
+         };
+         writeln!(
+            output,
+            "info depth {} multipv {} score {} pv {}",
+            depth,
+            multipv,
+            score_text,
+            line_text.join(" ")
+         )?;
+         if multipv == 1 {
+            *pv = line;
+         }
+         false
+      }
+      EngineMessage::SearchStats {
+         depth,
+         nodes,
+         nps,
+         time_ms,
+      } => {
+         writeln!(output, "info depth {} nodes {} nps {} time {}", depth, nodes, nps, time_ms)?;
+         false
+      }
+      EngineMessage::CurrentEval(_) => false,
+   };
+   output.flush()?;
+   Ok(done)
+}
+
+fn write_spin_option<W: Write>(
+   output: &mut W,
+   name: &str,
+   default: usize,
+   range: RangeInclusive<i64>,
+) -> io::Result<()> {
+   writeln!(
+      output,
+      "option name {} type spin default {} min {} max {}",
+      name,
+      default,
+      range.start(),
+      range.end()
+   )
+}
+
+// setoption name <id> [value <x>]
+fn set_option<'a, W: Write>(
+   mut tokens: impl Iterator<Item = &'a str>,
+   output: &mut W,
+   sender: &mpsc::Sender<InterfaceMessage>,
+) -> io::Result<()> {
+   if tokens.next() != Some("name") {
+      return writeln!(output, "info string Expected name");
+   }
+   // the name can have spaces in it, and ends at "value"
+   let name: Vec<&str> = tokens.by_ref().take_while(|x| *x != "value").collect();
+   let name = name.join(" ");
+   let (range, message): (_, fn(usize) -> InterfaceMessage) = match name.to_lowercase().as_str() {
+      "hash" => (HASH_MB_RANGE, InterfaceMessage::SetHashSize),
+      "threads" => (THREADS_RANGE, InterfaceMessage::SetThreads),
+      "multipv" => (MULTI_PV_RANGE, InterfaceMessage::SetMultiPv),
+      // we ponder whenever go says to, whether or not the option is on
+      "ponder" => return Ok(()),
+      _ => return writeln!(output, "info string Unknown option {}", name),
+   };
+   let requested = match tokens.next().map(str::parse::<i64>) {
+      Some(Ok(x)) => x,
+      _ => return writeln!(output, "info string Expected a number for {}", name),
+   };
+   let value = requested.clamp(*range.start(), *range.end());
+   if value != requested {
+      writeln!(
+         output,
+         "info string {} {} is out of range, using {}",
+         name, requested, value
+      )?;
+   }
+   sender.send(message(value as usize)).unwrap();
+   Ok(())
+}
+
+// position [startpos | fen <fen>] [moves <move>...]
+fn parse_position<'a>(mut tokens: impl Iterator<Item = &'a str>) -> Result<State, String> {
+   let state = match tokens.next() {
+      Some("startpos") => {
+         if let Some(x) = tokens.next() {
+            if x != "moves" {
+               return Err(format!("Expected moves, got {}", x));
+            }
+         }
+         State::from_start()
+      }
+      Some("fen") => {
+         // the fen ends either at the end of the line or at "moves"
+         let fen: Vec<&str> = tokens.by_ref().take_while(|x| *x != "moves").collect();
+         State::from_fen(&fen.join(" "))?
+      }
+      other => return Err(format!("Expected startpos or fen, got {:?}", other)),
+   };
+   // a bad move rejects the whole command, rather than leaving us partway through the game
+   let moves: Vec<&str> = tokens.collect();
+   state.apply_uci_moves_checked(&moves.join(" "))
+}
+
+// go [ponder] [searchmoves <move>...]
+//    [infinite | depth <plies> | movetime <ms> | wtime <ms> btime <ms> [winc <ms>] [binc <ms>] [movestogo <moves>]]
+// Returns the search to start, and the root moves to restrict it to, if any
+fn parse_go<'a>(
+   tokens: impl Iterator<Item = &'a str>,
+   state: &State,
+) -> (InterfaceMessage, Option<Vec<Move>>) {
+   let side_to_move = state.position.side_to_move;
+   let mut tokens = tokens.peekable();
+   let mut remaining_time = None;
+   let mut increment = 0;
+   let mut moves_to_go = None;
+   let mut ponder = false;
+   let mut message = None;
+   let mut search_moves = None;
+   while let Some(token) = tokens.next() {
+      match token {
+         "infinite" => message = Some(InterfaceMessage::GoInfinite),
+         "ponder" => ponder = true,
+         "searchmoves" => {
+            // the moves go on until the next keyword
+            let mut moves = Vec::new();
+            while let Some(a_move) = tokens.peek().and_then(|x| x.parse::<Move>().ok()) {
+               moves.push(a_move);
+               tokens.next();
+            }
+            search_moves = Some(moves);
+         }
+         _ => {
+            let value = tokens.next().and_then(|x| x.parse::<u64>().ok());
+            match (token, value) {
+               ("depth", Some(depth)) => message = Some(InterfaceMessage::GoDepth(depth)),
+               ("movetime", Some(ms)) => message = Some(InterfaceMessage::GoTime(Duration::from_millis(ms))),
+               ("wtime", Some(ms)) if side_to_move == Color::White => remaining_time = Some(ms),
+               ("btime", Some(ms)) if side_to_move == Color::Black => remaining_time = Some(ms),
+               ("winc", Some(ms)) if side_to_move == Color::White => increment = ms,
+               ("binc", Some(ms)) if side_to_move == Color::Black => increment = ms,
+               ("movestogo", Some(moves)) => moves_to_go = Some(moves),
+               _ => (),
+            }
+         }
+      }
+   }
+   let message = message.unwrap_or(match remaining_time {
+      // Same budgeting as lichess mode
+      Some(ms) => InterfaceMessage::GoTime(move_budget(
+         Duration::from_millis(ms),
+         Duration::from_millis(increment),
+         state.fullmove_number,
+         moves_to_go,
+      )),
+      None => InterfaceMessage::GoDepth(DEFAULT_DEPTH),
+   });
+   let message = if ponder {
+      // searches until stopped or the ponder hit, after which any time limit applies
+      match message {
+         InterfaceMessage::GoTime(budget) => InterfaceMessage::GoPonder(Some(budget)),
+         _ => InterfaceMessage::GoPonder(None),
+      }
+   } else {
+      message
+   };
+   (message, search_moves)
+}
+
+#[cfg(test)]
+mod tests {
+   use crate::uci::*;
+   use std::time::Instant;
+
+   fn run_script(script: &'static str) -> String {
+      let (ite_tx, ite_rx) = mpsc::channel();
+      let (eti_tx, eti_rx) = mpsc::channel();
+      thread::spawn(move || {
+         chessatk_lib::engine::start(ite_rx, eti_tx);
+      });
+      let mut output = Vec::new();
+      run(script.as_bytes(), &mut output, &ite_tx, &eti_rx).unwrap();
+      String::from_utf8(output).unwrap()
+   }
+
+   fn best_move(output: &str) -> Move {
+      let line = output.lines().find(|x| x.starts_with("bestmove ")).unwrap();
+      line.split(' ').nth(1).unwrap().parse().unwrap()
+   }
+
+   fn is_legal(state: &State, a_move: Move) -> bool {
+      let mut moves = Vec::new();
+      state.gen_moves(&mut moves);
+      moves.iter().any(|x| x.extract() == a_move)
+   }
+
+   #[test]
+   fn plays_from_startpos() {
+      let output = run_script("uci\nisready\nucinewgame\nposition startpos\ngo depth 2\nquit\n");
+      assert!(output.contains("uciok\nreadyok\n"));
+      assert!(is_legal(&State::from_start(), best_move(&output)));
+   }
+
+   #[test]
+   fn plays_after_moves() {
+      let output = run_script("position startpos moves e2e4 e7e5 g1f3\ngo depth 1\nquit\n");
+      let state = State::from_start().apply_moves_from_uci("e2e4 e7e5 g1f3");
+      assert!(is_legal(&state, best_move(&output)));
+
+      let output = run_script("position fen 4k3/8/8/8/8/8/4P3/4K3 w - - 0 1 moves e2e4\ngo movetime 50\nquit\n");
+      let state = State::from_fen("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1").unwrap().apply_moves_from_uci("e2e4");
+      assert!(is_legal(&state, best_move(&output)));
+   }
+
+   #[test]
+   fn position_moves() {
+      let state = parse_position("startpos moves e2e4 e7e5 g1f3".split_whitespace()).unwrap();
+      assert_eq!(state.to_fen(), "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2");
+      let state = parse_position("fen 4k3/8/8/8/8/8/4P3/4K3 w - - 0 1 moves e2e4 e8d7".split_whitespace()).unwrap();
+      assert_eq!(state.to_fen(), "8/3k4/8/8/4P3/8/8/4K3 w - - 1 2");
+      assert!(parse_position("startpos moves e2e5".split_whitespace()).is_err());
+      assert!(parse_position("startpos moves e2e4 banana".split_whitespace()).is_err());
+   }
+
+   #[test]
+   fn bad_position_keeps_the_last_one() {
+      let output = run_script("position startpos moves e2e4\nposition startpos moves e7e5\ngo depth 1\nquit\n");
+      assert!(output.contains("info string move 0 (e7e5) is illegal in "));
+      let state = State::from_start().apply_moves_from_uci("e2e4");
+      assert!(is_legal(&state, best_move(&output)));
+   }
+
+   #[test]
+   fn set_options() {
+      let output = run_script("uci\nsetoption name Hash value 64\nisready\nquit\n");
+      assert!(output.contains("option name Hash type spin default 16 min 1 max 4096\n"));
+      assert!(output.ends_with("uciok\nreadyok\n"));
+
+      let output = run_script("setoption name Hash value 0\nsetoption name MultiPV value 1000\nisready\nquit\n");
+      assert!(output.contains("info string Hash 0 is out of range, using 1\n"));
+      assert!(output.contains("info string MultiPV 1000 is out of range, using 256\n"));
+      assert!(output.ends_with("readyok\n"));
+
+      let output = run_script("setoption name MultiPV value 2\nsetoption name Threads value 2\ngo depth 1\nquit\n");
+      assert!(output.contains("info depth 1 multipv 2 "));
+      assert!(is_legal(&State::from_start(), best_move(&output)));
+   }
+
+   #[test]
+   fn search_moves() {
+      // Nc4 just hangs the knight to the queen, but it's the only move allowed
+      let output = run_script("position fen 4k3/8/8/3q4/8/4N3/8/4K3 w - - 0 1\ngo searchmoves e3c4 depth 1\nquit\n");
+      assert_eq!(best_move(&output), "e3c4".parse().unwrap());
+      assert!(output.contains("info depth 1 multipv 1 score cp -"));
+
+      let start = State::from_start();
+      let (message, search_moves) = parse_go("searchmoves e2e4 d2d4 depth 3".split_whitespace(), &start);
+      assert!(matches!(message, InterfaceMessage::GoDepth(3)));
+      assert_eq!(search_moves, Some(vec!["e2e4".parse().unwrap(), "d2d4".parse().unwrap()]));
+      assert!(parse_go("depth 3".split_whitespace(), &start).1.is_none());
+   }
+
+   #[test]
+   fn clock_budgets() {
+      let budget = |fen: &str, go: &str| match parse_go(go.split_whitespace(), &State::from_fen(fen).unwrap()) {
+         (InterfaceMessage::GoTime(budget), None) => budget,
+         _ => panic!("expected a timed search"),
+      };
+      let white = "4k3/8/8/8/8/8/8/4K3 w - - 0 80";
+      let black = "4k3/8/8/8/8/8/8/4K3 b - - 0 80";
+      // the same budgeting as lichess mode, with our own clock and increment
+      assert_eq!(budget(white, "wtime 61000 btime 1000 winc 4000 binc 0"), Duration::from_secs(6));
+      assert_eq!(budget(black, "wtime 1000 btime 61000 winc 0 binc 4000"), Duration::from_secs(6));
+      assert_eq!(budget(white, "wtime 61000 btime 61000"), Duration::from_secs(3));
+      // the clock only has to last until the next time control
+      assert_eq!(budget(white, "wtime 61000 btime 61000 movestogo 10"), Duration::from_secs(6));
+   }
+
+   #[test]
+   fn reports_info() {
+      // depth 1 always finishes, even though quit stops the search
+      let output = run_script("position startpos\ngo depth 1\nquit\n");
+      let info = output.lines().find(|x| x.starts_with("info depth 1 multipv 1 score cp ")).unwrap();
+      let pv_start = info.find(" pv ").unwrap() + " pv ".len();
+      let first_move: Move = info[pv_start..].split(' ').next().unwrap().parse().unwrap();
+      assert_eq!(first_move, best_move(&output));
+   }
+
+   #[test]
+   fn reports_mate() {
+      let output = run_script("position fen 7k/8/6K1/8/8/8/8/RR6 w - - 0 1\ngo depth 1\nquit\n");
+      assert!(output.contains("info depth 1 multipv 1 score mate 1 pv "));
+   }
+
+   #[test]
+   fn ponder() {
+      let output = run_script("position startpos moves e2e4 e7e5\ngo ponder wtime 2000 btime 2000\nponderhit\nquit\n");
+      let state = State::from_start().apply_moves_from_uci("e2e4 e7e5");
+      assert!(is_legal(&state, best_move(&output)));
+
+      let line = output.lines().find(|x| x.starts_with("bestmove ")).unwrap();
+      let mut tokens = line.split(' ').skip(2);
+      if let Some(x) = tokens.next() {
+         assert_eq!(x, "ponder");
+         let reply: Move = tokens.next().unwrap().parse().unwrap();
+         assert!(is_legal(&state.apply_moves_from_uci(&best_move(&output).to_string()), reply));
+      }
+   }
+
+   #[test]
+   fn stop_ends_infinite_search() {
+      let start = Instant::now();
+      let output = run_script("position startpos\ngo infinite\nstop\nquit\n");
+      assert!(start.elapsed() < Duration::from_secs(2));
+      assert!(is_legal(&State::from_start(), best_move(&output)));
+   }
 }
@@ -1,6 +1,9 @@
-use smallvec::SmallVec;
+use crate::eval::{static_eval_white_pov, to_centipawns, EvalParams};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::fmt::{self, Write};
 use std::hint::unreachable_unchecked;
+use std::convert::TryFrom;
 use std::str::FromStr;
 
 pub const WHITE: usize = 0;
@@ -31,6 +34,9 @@ pub const RANK_6: u64 = 0xff0000000000;
 pub const RANK_7: u64 = 0xff000000000000;
 pub const RANK_8: u64 = 0xff00000000000000;
 
+/// a1, c1, ... h8
+pub const DARK_SQUARES: u64 = 0x55aa_55aa_55aa_55aa;
+
 const FILE_H: u64 = 0x8080808080808080;
 const FILE_G: u64 = 0x4040404040404040;
 //const FILE_F: u64 = 0x2020202020202020;
@@ -269,6 +275,110 @@ const fn gen_south_west_rays() -> [u64; 65] {
 
 pub const START_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
 
+struct ZobristKeys {
+   pieces: [[[u64; 64]; 6]; 2],
+   black_to_move: u64,
+   castling: [u64; 4],
+   en_passant_file: [u64; 8],
+}
+
+const ZOBRIST: ZobristKeys = gen_zobrist_keys();
+
+const WHITE_KINGSIDE: usize = 0;
+const WHITE_QUEENSIDE: usize = 1;
+const BLACK_KINGSIDE: usize = 2;
+const BLACK_QUEENSIDE: usize = 3;
+
+// indexes into `Position::castling_rook_files`
+const KINGSIDE: usize = 0;
+const QUEENSIDE: usize = 1;
+
+/// splitmix64, so that the keys are fixed across builds and platforms
+const fn splitmix64(state: &mut u64) -> u64 {
+   *state = state.wrapping_add(0x9e3779b97f4a7c15);
+   let mut z = *state;
+   z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+   z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+   z ^ (z >> 31)
+}
+
+const fn gen_zobrist_keys() -> ZobristKeys {
+   let mut keys = ZobristKeys {
+      pieces: [[[0; 64]; 6]; 2],
+      black_to_move: 0,
+      castling: [0; 4],
+      en_passant_file: [0; 8],
+   };
+   let mut rng_state: u64 = 0x63686573_7361746b; // "chessatk"
+
+   let mut color = 0;
+   while color < 2 {
+      let mut piece = 0;
+      while piece < 6 {
+         let mut square = 0;
+         while square < 64 {
+            keys.pieces[color][piece][square] = splitmix64(&mut rng_state);
+            square += 1;
+         }
+         piece += 1;
+      }
+      color += 1;
+   }
+
+   keys.black_to_move = splitmix64(&mut rng_state);
+
+   let mut i = 0;
+   while i < 4 {
+      keys.castling[i] = splitmix64(&mut rng_state);
+      i += 1;
+   }
+
+   let mut i = 0;
+   while i < 8 {
+      keys.en_passant_file[i] = splitmix64(&mut rng_state);
+      i += 1;
+   }
+
+   keys
+}
+
+/// Why a FEN, move or square failed to parse. Each variant carries a description for people
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+   /// The wrong number of sections, or a section of the wrong length or contents
+   BadSection(String),
+   /// A piece letter that isn't one of `pnbrqk`, in either case
+   BadPiece(String),
+   BadCastling(String),
+   /// Not a square in algebraic notation
+   BadSquare(String),
+   /// Too many or too few squares in a rank, or ranks on the board
+   OutOfRange(String),
+   /// Well formed, but not a position that can come up in a game
+   IllegalPosition(String),
+}
+
+impl fmt::Display for ParseError {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+         ParseError::BadSection(e)
+         | ParseError::BadPiece(e)
+         | ParseError::BadCastling(e)
+         | ParseError::BadSquare(e)
+         | ParseError::OutOfRange(e)
+         | ParseError::IllegalPosition(e) => write!(f, "{}", e),
+      }
+   }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for String {
+   fn from(e: ParseError) -> String {
+      e.to_string()
+   }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Square {
    Empty,
@@ -286,14 +396,25 @@ pub enum Square {
    WhiteKing,
 }
 
+/// Standard chess, or Chess960 (Fischer Random), where the back rank pieces start shuffled.
+/// In Chess960 castling moves are written as the king capturing its own rook, e.g. `e1h1`, as in UCI_Chess960
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Variant {
+   Standard,
+   Chess960,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Color {
    White,
    Black,
 }
 
 impl Color {
-   fn as_num(&self) -> usize {
+   /// Index into the per-color bitboards of `Board`, `WHITE` or `BLACK`
+   pub fn as_num(&self) -> usize {
       match self {
          Color::White => WHITE,
          Color::Black => BLACK,
@@ -329,6 +450,28 @@ pub enum Piece {
    King,
 }
 
+impl Piece {
+   /// Index into the per-piece bitboards of `Board`, one of `PAWN` through `KING`
+   ///
+   /// ```
+   /// use chessatk_lib::board::{Color, Piece, State};
+   ///
+   /// let board = State::from_start().position.squares;
+   /// let white_pawns = board.pieces[Color::White.as_num()][Piece::Pawn.as_index()];
+   /// assert_eq!(white_pawns.count_ones(), 8);
+   /// ```
+   pub fn as_index(self) -> usize {
+      match self {
+         Piece::Pawn => PAWN,
+         Piece::Rook => ROOK,
+         Piece::Knight => KNIGHT,
+         Piece::Bishop => BISHOP,
+         Piece::Queen => QUEEN,
+         Piece::King => KING,
+      }
+   }
+}
+
 impl Square {
    pub fn piece(self) -> Option<Piece> {
       match self {
@@ -342,6 +485,28 @@ impl Square {
       }
    }
 
+   fn from_parts(color: usize, kind: usize) -> Square {
+      match (color, kind) {
+         (WHITE, PAWN) => Square::WhitePawn,
+         (WHITE, KNIGHT) => Square::WhiteKnight,
+         (WHITE, BISHOP) => Square::WhiteBishop,
+         (WHITE, ROOK) => Square::WhiteRook,
+         (WHITE, QUEEN) => Square::WhiteQueen,
+         (WHITE, _) => Square::WhiteKing,
+         (_, PAWN) => Square::BlackPawn,
+         (_, KNIGHT) => Square::BlackKnight,
+         (_, BISHOP) => Square::BlackBishop,
+         (_, ROOK) => Square::BlackRook,
+         (_, QUEEN) => Square::BlackQueen,
+         (_, _) => Square::BlackKing,
+      }
+   }
+
+   /// The color and kind indexes of the piece on the square, as used by the bitboards
+   fn parts(self) -> Option<(usize, usize)> {
+      Some((self.color()?.as_num(), self.piece()?.as_index()))
+   }
+
    pub fn color(self) -> Option<Color> {
       match self {
          Square::Empty => None,
@@ -362,6 +527,7 @@ impl Square {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum PromotionTarget {
    None,
    Knight,
@@ -370,6 +536,31 @@ pub enum PromotionTarget {
    Queen,
 }
 
+/// Which promotions move generation emits. A rook or bishop is almost never better than a queen, so searches
+/// can leave them out to cut down the branching factor. Anything that needs every legal move, like perft or
+/// checking that a move is legal, wants `All`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Promotions {
+   All,
+   QueenAndKnight,
+}
+
+impl Promotions {
+   fn targets(self) -> &'static [PromotionTarget] {
+      match self {
+         Promotions::All => &PROMOTION_TARGETS,
+         Promotions::QueenAndKnight => &QUEEN_AND_KNIGHT,
+      }
+   }
+}
+
+/// What a move does to the other side, for interfaces to show. A mate is always a check too
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MoveFlags {
+   pub check: bool,
+   pub mate: bool,
+}
+
 impl PromotionTarget {
    fn as_num(&self) -> u16 {
       match self {
@@ -391,6 +582,17 @@ impl PromotionTarget {
          _ => unsafe { unreachable_unchecked() },
       }
    }
+
+   /// The piece a pawn turns into, or `None` if this isn't a promotion
+   fn piece_kind(&self) -> Option<usize> {
+      match self {
+         PromotionTarget::None => None,
+         PromotionTarget::Knight => Some(KNIGHT),
+         PromotionTarget::Bishop => Some(BISHOP),
+         PromotionTarget::Rook => Some(ROOK),
+         PromotionTarget::Queen => Some(QUEEN),
+      }
+   }
 }
 
 impl fmt::Display for PromotionTarget {
@@ -407,20 +609,28 @@ impl fmt::Display for PromotionTarget {
 }
 
 impl FromStr for PromotionTarget {
-   type Err = String;
+   type Err = ParseError;
 
-   fn from_str(s: &str) -> Result<PromotionTarget, String> {
+   fn from_str(s: &str) -> Result<PromotionTarget, ParseError> {
       match s {
          "n" => Ok(PromotionTarget::Knight),
          "b" => Ok(PromotionTarget::Bishop),
          "r" => Ok(PromotionTarget::Rook),
          "q" => Ok(PromotionTarget::Queen),
-         _ => Err(format!("Expected one of ASCII nbrq for promotion target, got {}", s)),
+         _ => Err(ParseError::BadPiece(format!(
+            "Expected one of ASCII nbrq for promotion target, got {}",
+            s
+         ))),
       }
    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+   feature = "serde",
+   derive(Serialize, Deserialize),
+   serde(into = "serde_repr::BoardRepr", try_from = "serde_repr::BoardRepr")
+)]
 pub struct Board {
    pub pieces: [[u64; 6]; 2],
    pub all_pieces: [u64; 2],
@@ -441,6 +651,21 @@ impl Board {
       }
    }
 
+   /// What's on the square at `index`, 0 (a1) to 63 (h8)
+   pub fn piece_at(&self, index: u8) -> Square {
+      let shifted = 1 << index;
+      if self.occupied & shifted == 0 {
+         return Square::Empty;
+      }
+      let color = (self.all_pieces[BLACK] & shifted != 0) as usize;
+      let kind = [PAWN, KNIGHT, BISHOP, ROOK, QUEEN]
+         .iter()
+         .copied()
+         .find(|x| self.pieces[color][*x] & shifted != 0)
+         .unwrap_or(KING);
+      Square::from_parts(color, kind)
+   }
+
    fn update_derived_bitboards(&mut self) {
       self.all_pieces[WHITE] = self.pieces[WHITE][PAWN]
          | self.pieces[WHITE][ROOK]
@@ -464,47 +689,164 @@ impl Board {
 
    fn remove_piece(&mut self, color: usize, piece: usize, index: u8) {
       let shifted = 1 << index;
+      debug_assert!(self.pieces[color][piece] & shifted != 0, "no such piece on {}", index);
 
-      self.pieces[color][piece] ^= shifted;
-      self.all_pieces[color] ^= shifted;
+      self.pieces[color][piece] &= !shifted;
+      self.all_pieces[color] &= !shifted;
+      self.attackable[color] &= !shifted;
 
-      self.occupied ^= shifted;
+      self.occupied &= !shifted;
       self.unoccupied = !self.occupied;
-
-      if piece != KING {
-         self.attackable[color] ^= shifted;
-      }
    }
 
    fn add_piece(&mut self, color: usize, piece: usize, index: u8) {
       let shifted = 1 << index;
+      debug_assert!(self.occupied & shifted == 0, "square {} is already occupied", index);
 
       self.pieces[color][piece] |= shifted;
       self.all_pieces[color] |= shifted;
+      if piece != KING {
+         self.attackable[color] |= shifted;
+      }
 
       self.occupied |= shifted;
       self.unoccupied = !self.occupied;
-
-      if piece != KING {
-         self.attackable[color] ^= shifted;
-      }
    }
 }
 
 // ----
 
+/// Everything `Position::make_move` overwrites that can't be worked out from the move itself
+#[derive(Clone, Copy, Debug)]
+pub struct Undo {
+   a_move: Move,
+   moved_piece: usize,
+   captured: Option<(usize, u8)>, // kind and square; the square differs from the destination for en passant
+   castled: Option<usize>,
+   white_kingside_castle: bool,
+   white_queenside_castle: bool,
+   black_kingside_castle: bool,
+   black_queenside_castle: bool,
+   en_passant_square: u64,
+   zobrist: u64,
+}
+
 #[derive(Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+   feature = "serde",
+   derive(Serialize, Deserialize),
+   serde(into = "serde_repr::PositionRepr", try_from = "serde_repr::PositionRepr")
+)]
 pub struct Position {
    pub squares: Board,
    pub white_kingside_castle: bool,
    pub white_queenside_castle: bool,
    pub black_kingside_castle: bool,
    pub black_queenside_castle: bool,
+   /// Files of the rooks each side castles with, kingside then queenside. Always h and a in standard chess
+   pub castling_rook_files: [[u8; 2]; 2],
+   pub variant: Variant,
    pub en_passant_square: u64,
    pub side_to_move: Color,
+   pub zobrist: u64,
 }
 
 impl Position {
+   /// A hash of the position, suitable for repetition detection and transposition tables.
+   /// Two positions that compare equal will always have the same key.
+   pub fn hash_key(&self) -> u64 {
+      self.zobrist
+   }
+
+   /// Like `hash_key`, but an en passant square that no pawn can capture on is left out,
+   /// as it doesn't make the position any different for the purpose of repetition
+   pub fn repetition_key(&self) -> u64 {
+      match self.en_passant_capturable() {
+         Ok(true) => self.zobrist,
+         _ => self.zobrist ^ self.en_passant_zobrist(),
+      }
+   }
+
+   /// The same position with the board flipped top to bottom and the colors swapped, castling rights and en passant
+   /// included. Black is to move wherever white was, so the two should always evaluate the same for the side to move
+   pub fn mirror_vertical(&self) -> Position {
+      let mut squares = Board::empty();
+      for color in [WHITE, BLACK] {
+         for piece in [PAWN, ROOK, KNIGHT, BISHOP, QUEEN, KING] {
+            // one byte per rank, so reversing the bytes reverses the ranks
+            squares.pieces[1 - color][piece] = self.squares.pieces[color][piece].swap_bytes();
+         }
+      }
+      squares.update_derived_bitboards();
+      let mut position = Position {
+         squares,
+         white_kingside_castle: self.black_kingside_castle,
+         white_queenside_castle: self.black_queenside_castle,
+         black_kingside_castle: self.white_kingside_castle,
+         black_queenside_castle: self.white_queenside_castle,
+         castling_rook_files: [self.castling_rook_files[BLACK], self.castling_rook_files[WHITE]],
+         variant: self.variant,
+         en_passant_square: self.en_passant_square.swap_bytes(),
+         side_to_move: !self.side_to_move,
+         zobrist: 0,
+      };
+      position.zobrist = position.compute_zobrist();
+      position
+   }
+
+   /// Computes the zobrist key from scratch. `apply_move` keeps `zobrist` up to date incrementally,
+   /// so this is only needed when building a position by other means.
+   fn compute_zobrist(&self) -> u64 {
+      let mut key = 0;
+      for color in [WHITE, BLACK] {
+         for piece in [PAWN, ROOK, KNIGHT, BISHOP, QUEEN, KING] {
+            let mut bb = self.squares.pieces[color][piece];
+            while bb > 0 {
+               key ^= ZOBRIST.pieces[color][piece][pop_lsb(&mut bb) as usize];
+            }
+         }
+      }
+      if self.side_to_move == Color::Black {
+         key ^= ZOBRIST.black_to_move;
+      }
+      key ^ self.castling_zobrist() ^ self.en_passant_zobrist()
+   }
+
+   fn castling_zobrist(&self) -> u64 {
+      let mut key = 0;
+      if self.white_kingside_castle {
+         key ^= ZOBRIST.castling[WHITE_KINGSIDE];
+      }
+      if self.white_queenside_castle {
+         key ^= ZOBRIST.castling[WHITE_QUEENSIDE];
+      }
+      if self.black_kingside_castle {
+         key ^= ZOBRIST.castling[BLACK_KINGSIDE];
+      }
+      if self.black_queenside_castle {
+         key ^= ZOBRIST.castling[BLACK_QUEENSIDE];
+      }
+      key
+   }
+
+   fn en_passant_zobrist(&self) -> u64 {
+      if self.en_passant_square == 0 {
+         0
+      } else {
+         ZOBRIST.en_passant_file[(self.en_passant_square.trailing_zeros() % 8) as usize]
+      }
+   }
+
+   fn remove_piece(&mut self, color: usize, piece: usize, index: u8) {
+      self.squares.remove_piece(color, piece, index);
+      self.zobrist ^= ZOBRIST.pieces[color][piece][index as usize];
+   }
+
+   fn add_piece(&mut self, color: usize, piece: usize, index: u8) {
+      self.squares.add_piece(color, piece, index);
+      self.zobrist ^= ZOBRIST.pieces[color][piece][index as usize];
+   }
+
    #[cfg(test)]
    pub fn from_moves(moves: &str) -> Result<Position, String> {
       let mut position = State::from_start().position;
@@ -516,71 +858,63 @@ impl Position {
    }
 
    fn apply_move(&mut self, a_move: Move) {
-      let shifted_origin: u64 = 1 << a_move.origin;
-      let shifted_destination: u64 = 1 << a_move.destination;
-
-      let piece_color = ((self.squares.all_pieces[BLACK] & shifted_origin) > 0) as usize;
-      let piece_kind = if (self.squares.pieces[piece_color][PAWN] & shifted_origin) > 0 {
-         PAWN
-      } else if (self.squares.pieces[piece_color][ROOK] & shifted_origin) > 0 {
-         ROOK
-      } else if (self.squares.pieces[piece_color][KNIGHT] & shifted_origin) > 0 {
-         KNIGHT
-      } else if (self.squares.pieces[piece_color][BISHOP] & shifted_origin) > 0 {
-         BISHOP
-      } else if (self.squares.pieces[piece_color][QUEEN] & shifted_origin) > 0 {
-         QUEEN
-      } else {
-         KING
+      self.make_move(a_move);
+   }
+
+   /// Applies the move in place, returning what `unmake_move` needs to take it back
+   pub fn make_move(&mut self, a_move: Move) -> Undo {
+      let mut undo = Undo {
+         a_move,
+         moved_piece: PAWN,
+         captured: None,
+         castled: None,
+         white_kingside_castle: self.white_kingside_castle,
+         white_queenside_castle: self.white_queenside_castle,
+         black_kingside_castle: self.black_kingside_castle,
+         black_queenside_castle: self.black_queenside_castle,
+         en_passant_square: self.en_passant_square,
+         zobrist: self.zobrist,
       };
+      if a_move.is_null() {
+         self.make_null_move();
+         return undo;
+      }
 
+      let (piece_color, piece_kind) = self.squares.piece_at(a_move.origin).parts().unwrap_or((WHITE, KING));
       let destination_piece_color = piece_color ^ 1;
-      let destination_piece_kind = if (self.squares.pieces[destination_piece_color][PAWN] & shifted_destination) > 0 {
-         Some(PAWN)
-      } else if (self.squares.pieces[destination_piece_color][ROOK] & shifted_destination) > 0 {
-         Some(ROOK)
-      } else if (self.squares.pieces[destination_piece_color][KNIGHT] & shifted_destination) > 0 {
-         Some(KNIGHT)
-      } else if (self.squares.pieces[destination_piece_color][BISHOP] & shifted_destination) > 0 {
-         Some(BISHOP)
-      } else if (self.squares.pieces[destination_piece_color][QUEEN] & shifted_destination) > 0 {
-         Some(QUEEN)
-      } else if (self.squares.pieces[destination_piece_color][KING] & shifted_destination) > 0 {
-         Some(KING)
-      } else {
-         None
+      // only their pieces count, as a Chess960 castle lands on our own rook
+      let destination_piece_kind = match self.squares.piece_at(a_move.destination).parts() {
+         Some((color, kind)) if color == destination_piece_color => Some(kind),
+         _ => None,
       };
+      undo.moved_piece = piece_kind;
+      undo.castled = self.castling_side(piece_color, a_move);
       // Piece movement
-      {
-         self.squares.remove_piece(piece_color, piece_kind, a_move.origin);
+      if let Some(side) = undo.castled {
+         // in Chess960 the king and rook may land on each other's squares, so lift both before placing either
+         let (king_to, rook_to) = castled_squares(piece_color, side);
+         self.remove_piece(piece_color, KING, a_move.origin);
+         self.remove_piece(piece_color, ROOK, self.castling_rook_square(piece_color, side));
+         self.add_piece(piece_color, KING, king_to);
+         self.add_piece(piece_color, ROOK, rook_to);
+      } else {
+         self.remove_piece(piece_color, piece_kind, a_move.origin);
 
          // If this was a capture, need to yeet prior piece
          if let Some(p) = destination_piece_kind {
-            self
-               .squares
-               .remove_piece(destination_piece_color, p, a_move.destination);
+            self.remove_piece(destination_piece_color, p, a_move.destination);
+            undo.captured = Some((p, a_move.destination));
          }
 
-         match a_move.promotion {
-            PromotionTarget::Knight => {
-               self.squares.add_piece(piece_color, KNIGHT, a_move.destination);
-            }
-            PromotionTarget::Bishop => {
-               self.squares.add_piece(piece_color, BISHOP, a_move.destination);
-            }
-            PromotionTarget::Rook => {
-               self.squares.add_piece(piece_color, ROOK, a_move.destination);
-            }
-            PromotionTarget::Queen => {
-               self.squares.add_piece(piece_color, QUEEN, a_move.destination);
-            }
-            PromotionTarget::None => {
-               self.squares.add_piece(piece_color, piece_kind, a_move.destination);
-            }
-         }
+         let placed_kind = a_move.promotion.piece_kind().unwrap_or(piece_kind);
+         self.add_piece(piece_color, placed_kind, a_move.destination);
       }
 
-      // If king moved, do castling rook movement (potentially) and revoke castling rights (always)
+      // castling rights and the en passant square are hashed as a whole,
+      // so take the old values out now and put the new ones back in at the end
+      self.zobrist ^= self.castling_zobrist() ^ self.en_passant_zobrist();
+
+      // If king moved, revoke castling rights
       // If pawn moved, do en-passant checking
       let old_eps = self.en_passant_square;
       self.en_passant_square = 0;
@@ -589,41 +923,26 @@ impl Position {
             (WHITE, KING) => {
                self.white_kingside_castle = false;
                self.white_queenside_castle = false;
-               if a_move.origin == 4 && a_move.destination == 2 {
-                  // WQC
-                  self.squares.remove_piece(WHITE, ROOK, 0);
-                  self.squares.add_piece(WHITE, ROOK, 3);
-               } else if a_move.origin == 4 && a_move.destination == 6 {
-                  // WKC
-                  self.squares.remove_piece(WHITE, ROOK, 7);
-                  self.squares.add_piece(WHITE, ROOK, 5);
-               }
             }
             (BLACK, KING) => {
                self.black_kingside_castle = false;
                self.black_queenside_castle = false;
-               if a_move.origin == 60 && a_move.destination == 62 {
-                  // BKC
-                  self.squares.remove_piece(BLACK, ROOK, 63);
-                  self.squares.add_piece(BLACK, ROOK, 61);
-               } else if a_move.origin == 60 && a_move.destination == 58 {
-                  // BQC
-                  self.squares.remove_piece(BLACK, ROOK, 56);
-                  self.squares.add_piece(BLACK, ROOK, 59);
-               }
             }
+            // only a pawn landing diagonally on the en passant square takes the pawn that skipped over it
             (WHITE, PAWN) => {
-               if a_move.destination - a_move.origin == 16 {
+               if a_move.destination == a_move.origin + 16 {
                   self.en_passant_square = 1 << (a_move.origin + 8);
-               } else if (1 << a_move.destination) == old_eps {
-                  self.squares.remove_piece(BLACK, PAWN, a_move.destination - 8);
+               } else if (1 << a_move.destination) == old_eps && a_move.destination % 8 != a_move.origin % 8 {
+                  self.remove_piece(BLACK, PAWN, a_move.destination - 8);
+                  undo.captured = Some((PAWN, a_move.destination - 8));
                }
             }
             (BLACK, PAWN) => {
-               if a_move.origin - a_move.destination == 16 {
+               if a_move.destination + 16 == a_move.origin {
                   self.en_passant_square = 1 << (a_move.origin - 8);
-               } else if (1 << a_move.destination) == old_eps {
-                  self.squares.remove_piece(WHITE, PAWN, a_move.destination + 8);
+               } else if (1 << a_move.destination) == old_eps && a_move.destination % 8 != a_move.origin % 8 {
+                  self.remove_piece(WHITE, PAWN, a_move.destination + 8);
+                  undo.captured = Some((PAWN, a_move.destination + 8));
                }
             }
             _ => {
@@ -632,139 +951,780 @@ impl Position {
          }
       }
 
-      // Revoke castling rights if rook moved or was captured
+      // Revoke castling rights if rook moved or was captured. A rook capturing a rook loses both sides a right
       {
-         if a_move.origin == 7 || a_move.destination == 7 {
+         let touches = |square: u8| a_move.origin == square || a_move.destination == square;
+         if touches(self.castling_rook_square(WHITE, KINGSIDE)) {
             self.white_kingside_castle = false;
-         } else if a_move.origin == 0 || a_move.destination == 0 {
+         }
+         if touches(self.castling_rook_square(WHITE, QUEENSIDE)) {
             self.white_queenside_castle = false;
-         } else if a_move.origin == 63 || a_move.destination == 63 {
+         }
+         if touches(self.castling_rook_square(BLACK, KINGSIDE)) {
             self.black_kingside_castle = false;
-         } else if a_move.origin == 56 || a_move.destination == 56 {
+         }
+         if touches(self.castling_rook_square(BLACK, QUEENSIDE)) {
             self.black_queenside_castle = false;
          }
       }
 
+      self.zobrist ^= self.castling_zobrist() ^ self.en_passant_zobrist();
+
+      self.side_to_move = !self.side_to_move;
+      self.zobrist ^= ZOBRIST.black_to_move;
+
+      undo
+   }
+
+   /// Takes back the move that produced `undo`, which must be the most recent one made
+   pub fn unmake_move(&mut self, undo: Undo) {
+      if undo.a_move.is_null() {
+         self.unmake_null_move(undo.en_passant_square);
+         return;
+      }
+      self.side_to_move = !self.side_to_move;
+      let color = self.side_to_move.as_num();
+      let a_move = undo.a_move;
+
+      // the key is restored wholesale below, so the board can be moved around directly
+      if let Some(side) = undo.castled {
+         let (king_to, rook_to) = castled_squares(color, side);
+         self.squares.remove_piece(color, KING, king_to);
+         self.squares.remove_piece(color, ROOK, rook_to);
+         self.squares.add_piece(color, KING, a_move.origin);
+         self.squares.add_piece(color, ROOK, self.castling_rook_square(color, side));
+      } else {
+         let placed_kind = a_move.promotion.piece_kind().unwrap_or(undo.moved_piece);
+         self.squares.remove_piece(color, placed_kind, a_move.destination);
+         self.squares.add_piece(color, undo.moved_piece, a_move.origin);
+         if let Some((kind, index)) = undo.captured {
+            self.squares.add_piece(color ^ 1, kind, index);
+         }
+      }
+
+      self.white_kingside_castle = undo.white_kingside_castle;
+      self.white_queenside_castle = undo.white_queenside_castle;
+      self.black_kingside_castle = undo.black_kingside_castle;
+      self.black_queenside_castle = undo.black_queenside_castle;
+      self.en_passant_square = undo.en_passant_square;
+      self.zobrist = undo.zobrist;
+   }
+
+   /// Passes the turn without moving anything. Returns the en passant square, for `unmake_null_move`
+   pub fn make_null_move(&mut self) -> u64 {
+      let en_passant_square = self.en_passant_square;
+      self.zobrist ^= self.en_passant_zobrist() ^ ZOBRIST.black_to_move;
+      self.en_passant_square = 0;
+      self.side_to_move = !self.side_to_move;
+      en_passant_square
+   }
+
+   pub fn unmake_null_move(&mut self, en_passant_square: u64) {
       self.side_to_move = !self.side_to_move;
+      self.en_passant_square = en_passant_square;
+      self.zobrist ^= self.en_passant_zobrist() ^ ZOBRIST.black_to_move;
+   }
+
+   fn may_castle(&self, color: usize, side: usize) -> bool {
+      match (color, side) {
+         (WHITE, KINGSIDE) => self.white_kingside_castle,
+         (WHITE, _) => self.white_queenside_castle,
+         (_, KINGSIDE) => self.black_kingside_castle,
+         _ => self.black_queenside_castle,
+      }
+   }
+
+   pub(crate) fn castling_rook_square(&self, color: usize, side: usize) -> u8 {
+      back_rank(color) + self.castling_rook_files[color][side]
+   }
+
+   /// The side `a_move` castles to, if it is a castling move
+   pub(crate) fn castling_side(&self, color: usize, a_move: Move) -> Option<usize> {
+      if self.squares.pieces[color][KING] & (1 << a_move.origin) == 0 {
+         return None;
+      }
+      let side = if a_move.destination > a_move.origin { KINGSIDE } else { QUEENSIDE };
+      let rook_square = self.castling_rook_square(color, side);
+      if self.squares.pieces[color][ROOK] & (1 << rook_square) == 0 {
+         return None;
+      }
+      let castles = match self.variant {
+         Variant::Standard => {
+            a_move.origin == back_rank(color) + 4 && a_move.destination == castled_squares(color, side).0
+         }
+         Variant::Chess960 => a_move.destination == rook_square,
+      };
+      if castles {
+         Some(side)
+      } else {
+         None
+      }
    }
 
    pub fn gen_moves_color(&self, color: Color, results: &mut Vec<CompressedMove>) {
+      self.gen_moves_color_with(color, Promotions::All, results)
+   }
+
+   /// The legal moves for `color`, in `gen_moves_color` order, each with whether it gives check or mate. Every move
+   /// gets played out to find out, so this is for interfaces rather than the search. If it isn't `color`'s turn,
+   /// the moves are the ones it would have if the other side passed
+   pub fn gen_moves_annotated(&self, color: Color) -> Vec<(Move, MoveFlags)> {
+      let mut position = self.clone();
+      if position.side_to_move != color {
+         position.make_null_move();
+      }
+      let mut moves = Vec::new();
+      position.gen_moves_color(color, &mut moves);
+      moves
+         .into_iter()
+         .map(|x| (x.extract(), position.move_flags(x.extract())))
+         .collect()
+   }
+
+   /// Whether a legal move for the side to move gives check, and whether that check is mate
+   fn move_flags(&self, a_move: Move) -> MoveFlags {
+      let mut after = self.clone();
+      after.apply_move(a_move);
+      let check = after.in_check(after.side_to_move);
+      MoveFlags {
+         check,
+         mate: check && MoveGen::new(&after).next().is_none(),
+      }
+   }
+
+   /// Like `gen_moves_color`, but only emitting the given promotions
+   pub fn gen_moves_color_with(&self, color: Color, promotions: Promotions, results: &mut Vec<CompressedMove>) {
       match color {
          Color::White => {
-            white_pawn_movegen(self, results);
+            let targets = !self.squares.all_pieces[WHITE];
+            white_pawn_movegen(self, promotions, results);
             white_king_movegen(self, results);
-            knight_movegen(self, WHITE, results);
-            bishop_movegen(self, WHITE, results);
-            rook_movegen(self, WHITE, results);
-            queen_movegen(self, WHITE, results);
+            knight_movegen(self, WHITE, targets, results);
+            bishop_movegen(self, WHITE, targets, results);
+            rook_movegen(self, WHITE, targets, results);
+            queen_movegen(self, WHITE, targets, results);
          }
          Color::Black => {
-            black_pawn_movegen(self, results);
+            let targets = !self.squares.all_pieces[BLACK];
+            black_pawn_movegen(self, promotions, results);
             black_king_movegen(self, results);
-            knight_movegen(self, BLACK, results);
-            bishop_movegen(self, BLACK, results);
-            rook_movegen(self, BLACK, results);
-            queen_movegen(self, BLACK, results);
+            knight_movegen(self, BLACK, targets, results);
+            bishop_movegen(self, BLACK, targets, results);
+            rook_movegen(self, BLACK, targets, results);
+            queen_movegen(self, BLACK, targets, results);
          }
       }
    }
 
-   pub fn in_check(&self, color: Color) -> bool {
-      let kingdex = self.squares.pieces[color.as_num()][KING].trailing_zeros();
-      self.square_is_attacked(color, kingdex as usize)
+   /// Like `gen_moves_color`, but only captures (including en passant and capture-promotions)
+   pub fn gen_captures_color(&self, color: Color, results: &mut Vec<CompressedMove>) {
+      self.gen_captures_color_with(color, Promotions::All, results)
    }
 
-   pub fn square_is_attacked(&self, defender: Color, square: usize) -> bool {
-      let attacker = !defender;
-
-      if PAWN_ATTACKS[defender.as_num()][square] & self.squares.pieces[attacker.as_num()][PAWN] > 0 {
-         return true;
+   /// Like `gen_captures_color`, but only emitting the given capture-promotions
+   pub fn gen_captures_color_with(&self, color: Color, promotions: Promotions, results: &mut Vec<CompressedMove>) {
+      let targets = self.squares.all_pieces[(!color).as_num()];
+      match color {
+         Color::White => white_pawn_captures(self, promotions, results),
+         Color::Black => black_pawn_captures(self, promotions, results),
       }
+      king_movegen(self, color.as_num(), targets, results);
+      knight_movegen(self, color.as_num(), targets, results);
+      bishop_movegen(self, color.as_num(), targets, results);
+      rook_movegen(self, color.as_num(), targets, results);
+      queen_movegen(self, color.as_num(), targets, results);
+   }
 
-      if KNIGHT_ATTACKS[square] & self.squares.pieces[attacker.as_num()][KNIGHT] > 0 {
-         return true;
+   /// Like `gen_moves_color`, but only moves that check the other king, whether the moved piece gives the check
+   /// or uncovers a slider behind it. Castling and en passant count when the rook or an opened line gives check
+   pub fn gen_checks_color(&self, color: Color, results: &mut Vec<CompressedMove>) {
+      let us = color.as_num();
+      let king = self.squares.pieces[us ^ 1][KING];
+      if king == 0 {
+         return;
       }
+      let king = king.trailing_zeros() as usize;
+      // where each kind of piece has to land to attack the king, indexed like `pieces`
+      let diagonal = bishop_attacks(self, king);
+      let straight = rook_attacks(self, king);
+      let check_squares = [
+         PAWN_ATTACKS[us ^ 1][king],
+         straight,
+         KNIGHT_ATTACKS[king],
+         diagonal,
+         diagonal | straight,
+         0,
+      ];
+      // our pieces that alone stand between the king and one of our sliders
+      let discoverers = (0..8)
+         .filter_map(|direction| self.line_blocker(us ^ 1, us, direction))
+         .fold(0u64, |discoverers, (square, _)| discoverers | 1 << square);
+      let discovery_line = |square: usize| {
+         (0..8)
+            .filter_map(|direction| self.line_blocker(us ^ 1, us, direction))
+            .find(|(blocker, _)| *blocker == square)
+            .map_or(0, |(_, line)| line)
+      };
+
+      let mut moves = Vec::new();
+      self.gen_moves_color(color, &mut moves);
+      results.extend(moves.into_iter().filter(|x| {
+         let a_move = x.extract();
+         let (origin, destination) = (a_move.origin as usize, a_move.destination as usize);
+         let kind = self.piece_kind_at(us, a_move.origin).unwrap_or(KING);
+         let en_passant = kind == PAWN && 1 << destination == self.en_passant_square && origin % 8 != destination % 8;
+         if en_passant || self.castling_side(us, a_move).is_some() {
+            return self.checks_after(us, a_move, king);
+         }
+         // leaving the line between the king and our slider opens it
+         let discovered = discoverers & 1 << origin != 0 && discovery_line(origin) & 1 << destination == 0;
+         let direct = match a_move.promotion.piece_kind() {
+            // the pawn's old square may have been in the new piece's way
+            Some(promoted) => {
+               let occupied = self.squares.occupied & !(1 << origin) | 1 << destination;
+               let attacks = match promoted {
+                  KNIGHT => KNIGHT_ATTACKS[destination],
+                  BISHOP => diagonal_attacks(destination, occupied),
+                  ROOK => straight_attacks(destination, occupied),
+                  _ => diagonal_attacks(destination, occupied) | straight_attacks(destination, occupied),
+               };
+               attacks & 1 << king != 0
+            }
+            None => check_squares[kind] & 1 << destination != 0,
+         };
+         direct || discovered
+      }));
+   }
 
-      if KING_ATTACKS[square] & self.squares.pieces[attacker.as_num()][KING] > 0 {
-         return true;
+   /// Whether `color` attacks the other king on `king` after `a_move`, for castling and en passant, which move
+   /// or take a second piece. Knights and pawns other than the one that moved can't be checking already
+   fn checks_after(&self, color: usize, a_move: Move, king: usize) -> bool {
+      let mut pieces = self.squares.pieces[color];
+      let mut occupied = self.squares.occupied & !(1 << a_move.origin);
+      match self.castling_side(color, a_move) {
+         Some(side) => {
+            let (king_to, rook_to) = castled_squares(color, side);
+            let rook_from = self.castling_rook_square(color, side);
+            pieces[ROOK] = pieces[ROOK] & !(1 << rook_from) | 1 << rook_to;
+            occupied = occupied & !(1 << rook_from) | 1 << king_to | 1 << rook_to;
+         }
+         None => {
+            // the pawn taken en passant is level with ours, on the file we move to
+            let taken = a_move.origin / 8 * 8 + a_move.destination % 8;
+            pieces[PAWN] = pieces[PAWN] & !(1 << a_move.origin) | 1 << a_move.destination;
+            occupied = occupied & !(1 << taken) | 1 << a_move.destination;
+         }
       }
+      PAWN_ATTACKS[color ^ 1][king] & pieces[PAWN] != 0
+         || diagonal_attacks(king, occupied) & (pieces[BISHOP] | pieces[QUEEN]) != 0
+         || straight_attacks(king, occupied) & (pieces[ROOK] | pieces[QUEEN]) != 0
+   }
 
-      let bishops_and_queens =
-         self.squares.pieces[attacker.as_num()][BISHOP] | self.squares.pieces[attacker.as_num()][QUEEN];
-      let rooks_and_queens =
-         self.squares.pieces[attacker.as_num()][ROOK] | self.squares.pieces[attacker.as_num()][QUEEN];
+   fn piece_kind_at(&self, color: usize, index: u8) -> Option<usize> {
+      [PAWN, KNIGHT, BISHOP, ROOK, QUEEN, KING]
+         .iter()
+         .copied()
+         .find(|x| self.squares.pieces[color][*x] & (1 << index) != 0)
+   }
 
-      if (bishop_attacks(self, square) & bishops_and_queens) > 0 {
-         return true;
+   /// Castling rights as written in a FEN, e.g. `KQkq`, or `-` for none
+   fn castling_string(&self) -> String {
+      let mut buf = String::new();
+      for color in [WHITE, BLACK].iter().copied() {
+         for side in [KINGSIDE, QUEENSIDE].iter().copied() {
+            if !self.may_castle(color, side) {
+               continue;
+            }
+            // Shredder-FEN for Chess960, which can always tell the rooks apart
+            let letter = match (self.variant, side) {
+               (Variant::Standard, KINGSIDE) => 'k',
+               (Variant::Standard, _) => 'q',
+               (Variant::Chess960, _) => (b'a' + self.castling_rook_files[color][side]) as char,
+            };
+            buf.push(if color == WHITE { letter.to_ascii_uppercase() } else { letter });
+         }
       }
-
-      if (rook_attacks(self, square) & rooks_and_queens) > 0 {
-         return true;
+      if buf.is_empty() {
+         buf.push('-');
       }
+      buf
+   }
 
-      false
+   /// The en passant square as written in a FEN, or `-` for none
+   fn en_passant_string(&self) -> String {
+      if self.en_passant_square == 0 {
+         "-".into()
+      } else {
+         index_to_algebraic_string(self.en_passant_square.trailing_zeros() as u8)
+      }
    }
-}
 
-#[derive(Clone, PartialEq, Eq, Hash)]
-pub struct State {
-   pub position: Position,
-   pub prior_positions: SmallVec<[Position; 8]>,
-   pub halfmove_clock: u64,
-}
+   /// Formats a legal move in Standard Algebraic Notation, e.g. `Nbd2`, `exd6`, `O-O` or `e8=Q+`
+   pub fn move_to_san(&self, a_move: Move) -> String {
+      let us = self.side_to_move.as_num();
+      let piece_kind = self.piece_kind_at(us, a_move.origin).unwrap_or(PAWN);
+      let is_capture = self.squares.all_pieces[us ^ 1] & (1 << a_move.destination) != 0
+         || (piece_kind == PAWN && self.en_passant_square == 1 << a_move.destination);
+
+      let mut san = String::new();
+      if let Some(side) = self.castling_side(us, a_move) {
+         if side == KINGSIDE {
+            san.push_str("O-O");
+         } else {
+            san.push_str("O-O-O");
+         }
+      } else if piece_kind == PAWN {
+         if is_capture {
+            san.push_str(&index_to_algebraic_string(a_move.origin)[..1]);
+            san.push('x');
+         }
+         san.push_str(&index_to_algebraic_string(a_move.destination));
+         if let Some(promotion) = a_move.promotion() {
+            san.push('=');
+            san.push_str(&promotion.to_string().to_uppercase());
+         }
+      } else {
+         san.push(match piece_kind {
+            KNIGHT => 'N',
+            BISHOP => 'B',
+            ROOK => 'R',
+            QUEEN => 'Q',
+            _ => 'K',
+         });
+
+         // other pieces of the same kind that could also go there
+         let mut moves = Vec::new();
+         self.gen_moves_color(self.side_to_move, &mut moves);
+         let rivals: Vec<u8> = moves
+            .iter()
+            .map(|x| x.extract())
+            .filter(|x| x.destination == a_move.destination && x.origin != a_move.origin)
+            .filter(|x| self.piece_kind_at(us, x.origin) == Some(piece_kind))
+            .map(|x| x.origin)
+            .collect();
+         let origin = index_to_algebraic_string(a_move.origin);
+         if !rivals.is_empty() {
+            if rivals.iter().all(|x| x % 8 != a_move.origin % 8) {
+               san.push_str(&origin[..1]);
+            } else if rivals.iter().all(|x| x / 8 != a_move.origin / 8) {
+               san.push_str(&origin[1..]);
+            } else {
+               san.push_str(&origin);
+            }
+         }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-pub struct CompressedMove(u16);
+         if is_capture {
+            san.push('x');
+         }
+         san.push_str(&index_to_algebraic_string(a_move.destination));
+      }
 
-impl CompressedMove {
-   pub fn extract(&self) -> Move {
-      let promotion = PromotionTarget::from_num(self.0 & 0b111);
-      let destination = ((self.0 >> 3) & 0b11_1111) as u8;
-      let origin = ((self.0 >> 9) & 0b11_1111) as u8;
-      Move {
-         origin,
-         destination,
-         promotion,
+      let flags = self.move_flags(a_move);
+      if flags.mate {
+         san.push('#');
+      } else if flags.check {
+         san.push('+');
       }
+      san
    }
-}
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-pub struct Move {
-   pub origin: u8,
-   pub destination: u8,
-   pub promotion: PromotionTarget,
-}
+   /// Parses a move in Standard Algebraic Notation, which must be legal and unambiguous in this position.
+   /// Over-disambiguated moves like `Qh4xe1` and annotations like `+`, `!?` or `e.p.` are accepted
+   pub fn san_to_move(&self, san: &str) -> Result<Move, String> {
+      let body = san.trim().trim_end_matches("e.p.").trim_end();
+      let body = body.trim_end_matches(['+', '#', '!', '?']);
 
-impl Move {
-   pub fn compress(&self) -> CompressedMove {
-      debug_assert!(self.origin.count_ones() <= 6);
-      debug_assert!(self.destination.count_ones() <= 6);
-      let promotion = self.promotion.as_num();
-      debug_assert!(promotion.count_ones() <= 3);
-      CompressedMove((self.origin as u16) << 9 | (self.destination as u16) << 3 | promotion)
-   }
-}
+      let mut moves = Vec::new();
+      self.gen_moves_color(self.side_to_move, &mut moves);
+      let us = self.side_to_move.as_num();
+      let legal = moves.iter().map(|x| x.extract());
 
-impl fmt::Display for Move {
-   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-      index_to_algebraic(self.origin, f)?;
-      index_to_algebraic(self.destination, f)?;
-      write!(f, "{}", self.promotion)?;
-      Ok(())
-   }
-}
+      let candidates: Vec<Move> = if body == "O-O" || body == "0-0" || body == "O-O-O" || body == "0-0-0" {
+         let side = if body.len() == 3 { KINGSIDE } else { QUEENSIDE };
+         legal.filter(|x| self.castling_side(us, *x) == Some(side)).collect()
+      } else {
+         let (piece_kind, rest) = match body.chars().next() {
+            Some('N') => (KNIGHT, &body[1..]),
+            Some('B') => (BISHOP, &body[1..]),
+            Some('R') => (ROOK, &body[1..]),
+            Some('Q') => (QUEEN, &body[1..]),
+            Some('K') => (KING, &body[1..]),
+            Some(_) => (PAWN, body),
+            None => return Err("Empty SAN move".into()),
+         };
+
+         // promotion, either e8=Q or e8Q
+         let (rest, promotion) = match rest.char_indices().last() {
+            Some((i, c)) if piece_kind == PAWN && matches!(c, 'N' | 'B' | 'R' | 'Q') => {
+               let promotion: PromotionTarget = c.to_ascii_lowercase().to_string().parse()?;
+               (rest[..i].trim_end_matches('='), promotion)
+            }
+            _ => (rest, PromotionTarget::None),
+         };
 
-impl FromStr for Move {
-   type Err = String;
+         if rest.len() < 2 || !rest.is_char_boundary(rest.len() - 2) {
+            return Err(format!("{} is missing a destination square", san));
+         }
+         let destination = algebraic_to_index(&rest[rest.len() - 2..])?;
+         let mut from_file = None;
+         let mut from_rank = None;
+         for c in rest[..rest.len() - 2].chars() {
+            match c {
+               'a'..='h' => from_file = Some(c as u8 - b'a'),
+               '1'..='8' => from_rank = Some(c as u8 - b'1'),
+               'x' | ':' => (),
+               _ => return Err(format!("Unexpected {} in SAN move {}", c, san)),
+            }
+         }
 
-   fn from_str(s: &str) -> Result<Move, String> {
-      if s.len() < 4 || s.len() > 5 {
-         return Err(format!(
-            "A full move has to be 4-5 bytes long, got a move ({}) that was {} bytes long",
-            s,
-            s.len()
-         ));
+         legal
+            .filter(|x| x.destination == destination && x.promotion == promotion)
+            .filter(|x| self.piece_kind_at(us, x.origin) == Some(piece_kind))
+            .filter(|x| from_file.is_none_or(|file| x.origin % 8 == file))
+            .filter(|x| from_rank.is_none_or(|rank| x.origin / 8 == rank))
+            .collect()
+      };
+
+      match candidates.len() {
+         0 => Err(format!("{} is not a legal move", san)),
+         1 => Ok(candidates[0]),
+         _ => Err(format!("{} is ambiguous", san)),
+      }
+   }
+
+   /// Whether a pawn of the side to move is next to the pawn that just double pushed past the en passant square.
+   /// Errors if the square couldn't have been left by a double push
+   pub(crate) fn en_passant_capturable(&self) -> Result<bool, String> {
+      if self.en_passant_square == 0 {
+         return Ok(true);
+      }
+      let index = self.en_passant_square.trailing_zeros() as u8;
+      let us = self.side_to_move.as_num();
+      let (expected_rank, pushed_pawn, origin) = match self.side_to_move {
+         Color::White => (5, index - 8, index + 8),
+         Color::Black => (2, index + 8, index - 8),
+      };
+      if index / 8 != expected_rank {
+         return Err(format!(
+            "en passant square {} is on the wrong rank for the side to move",
+            index_to_algebraic_string(index)
+         ));
+      }
+      if self.squares.pieces[us ^ 1][PAWN] & (1 << pushed_pawn) == 0 {
+         return Err(format!(
+            "en passant square {} has no pawn in front of it that could have double pushed",
+            index_to_algebraic_string(index)
+         ));
+      }
+      if self.squares.occupied & (1 << index | 1 << origin) != 0 {
+         return Err(format!(
+            "en passant square {} or the square behind it is occupied",
+            index_to_algebraic_string(index)
+         ));
+      }
+      let neighbors = (1 << pushed_pawn << 1 & !FILE_A) | (1 << pushed_pawn >> 1 & !FILE_H);
+      Ok(self.squares.pieces[us][PAWN] & neighbors != 0)
+   }
+
+   /// Whether neither side has the material left to mate: bare kings, a lone minor piece, or only bishops that all
+   /// stand on the same color of square. A knight each doesn't count, as a mate is still possible with some help
+   pub fn insufficient_material(&self) -> bool {
+      let both = |kind: usize| self.squares.pieces[WHITE][kind] | self.squares.pieces[BLACK][kind];
+      let (bishops, knights) = (both(BISHOP), both(KNIGHT));
+      if self.squares.occupied & !both(KING) & !bishops & !knights != 0 {
+         return false;
+      }
+      if knights == 0 {
+         // the king would have to be mated on a square none of the bishops can reach
+         bishops & DARK_SQUARES == 0 || bishops & !DARK_SQUARES == 0
+      } else {
+         bishops == 0 && knights.count_ones() == 1
+      }
+   }
+
+   pub fn in_check(&self, color: Color) -> bool {
+      let kingdex = self.squares.pieces[color.as_num()][KING].trailing_zeros();
+      self.square_is_attacked(color, kingdex as usize)
+   }
+
+   /// Static evaluation with the default parameters, in centipawns for the side to move. No search, so a piece
+   /// that's about to be captured still counts
+   pub fn evaluate(&self) -> i32 {
+      to_centipawns(crate::eval::evaluate(self, self.side_to_move, &EvalParams::default()))
+   }
+
+   /// Like `evaluate`, but + for white and - for black
+   pub fn evaluate_white_pov(&self) -> i32 {
+      to_centipawns(static_eval_white_pov(self))
+   }
+
+   /// The squares a `color` `piece` standing on `square` attacks, given the pieces in the way
+   pub fn piece_attacks(&self, color: Color, piece: usize, square: usize) -> u64 {
+      match piece {
+         PAWN => PAWN_ATTACKS[color.as_num()][square],
+         KNIGHT => KNIGHT_ATTACKS[square],
+         BISHOP => bishop_attacks(self, square),
+         ROOK => rook_attacks(self, square),
+         QUEEN => bishop_attacks(self, square) | rook_attacks(self, square),
+         _ => KING_ATTACKS[square],
+      }
+   }
+
+   pub fn square_is_attacked(&self, defender: Color, square: usize) -> bool {
+      self.attackers_of(square, !defender) != 0
+   }
+
+   /// The pieces of `color` that can't leave the line between their king and an enemy slider without exposing it
+   pub fn pinned_pieces(&self, color: Color) -> u64 {
+      (0..8)
+         .filter_map(|direction| self.line_blocker(color.as_num(), color.as_num(), direction))
+         .fold(0, |pinned, (square, _)| pinned | 1 << square)
+   }
+
+   /// For a pinned piece, the squares it may still move to: from its king up to and including the pinning piece.
+   /// Empty if the piece on `square` isn't pinned, or there isn't one
+   pub fn pin_ray(&self, square: usize) -> u64 {
+      let color = match self.squares.piece_at(square as u8).parts() {
+         Some((color, _)) => color,
+         None => return 0,
+      };
+      (0..8)
+         .filter_map(|direction| self.line_blocker(color, color, direction))
+         .find(|(pinned, _)| *pinned == square)
+         .map_or(0, |(_, ray)| ray)
+   }
+
+   /// The piece of `blocker_color` that alone stands between the `king_color` king and an enemy slider along
+   /// `direction`, if any, and the squares from the king up to and including the slider. When the blocker is on
+   /// the king's side it's pinned, otherwise moving it off the line gives discovered check
+   fn line_blocker(&self, king_color: usize, blocker_color: usize, direction: usize) -> Option<(usize, u64)> {
+      let king = self.squares.pieces[king_color][KING];
+      if king == 0 {
+         return None;
+      }
+      let ray = RAYS[direction][king.trailing_zeros() as usize];
+      let nearest = |squares: u64| match direction {
+         NORTH | EAST | NORTH_EAST | NORTH_WEST => squares.trailing_zeros() as usize,
+         _ => 63 - squares.leading_zeros() as usize,
+      };
+      let blockers = ray & self.squares.occupied;
+      if blockers.count_ones() < 2 {
+         return None;
+      }
+      let pinned = nearest(blockers);
+      let pinner = nearest(blockers & !(1 << pinned));
+      let them = &self.squares.pieces[king_color ^ 1];
+      let sliders = match direction {
+         NORTH | SOUTH | EAST | WEST => them[ROOK] | them[QUEEN],
+         _ => them[BISHOP] | them[QUEEN],
+      };
+      if self.squares.all_pieces[blocker_color] & 1 << pinned == 0 || sliders & 1 << pinner == 0 {
+         return None;
+      }
+      Some((pinned, ray ^ RAYS[direction][pinner]))
+   }
+
+   /// Every piece of `by` that attacks `square`, whatever stands on it. Pinned pieces count too
+   pub fn attackers_of(&self, square: usize, by: Color) -> u64 {
+      let pieces = &self.squares.pieces[by.as_num()];
+      // a pawn attacks the squares that a pawn of the other color would attack it from
+      (PAWN_ATTACKS[(!by).as_num()][square] & pieces[PAWN])
+         | (KNIGHT_ATTACKS[square] & pieces[KNIGHT])
+         | (KING_ATTACKS[square] & pieces[KING])
+         | (bishop_attacks(self, square) & (pieces[BISHOP] | pieces[QUEEN]))
+         | (rook_attacks(self, square) & (pieces[ROOK] | pieces[QUEEN]))
+   }
+}
+
+/// An 8x8 board with rank 8 at the top, white in uppercase and black in lowercase,
+/// followed by the side to move, castling rights and en passant square
+impl fmt::Display for Position {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      for rank in (0..8).rev() {
+         for file in 0..8 {
+            let index = rank * 8 + file;
+            let letter = [WHITE, BLACK]
+               .iter()
+               .copied()
+               .find_map(|color| self.piece_kind_at(color, index).map(|kind| piece_letter(color, kind)))
+               .unwrap_or('.');
+            write!(f, "{}", letter)?;
+         }
+         writeln!(f)?;
+      }
+      let side_to_move = match self.side_to_move {
+         Color::White => "white",
+         Color::Black => "black",
+      };
+      write!(
+         f,
+         "{} to move, castling {}, en passant {}",
+         side_to_move,
+         self.castling_string(),
+         self.en_passant_string()
+      )
+   }
+}
+
+fn piece_letter(color: usize, kind: usize) -> char {
+   let letter = match kind {
+      PAWN => 'p',
+      KNIGHT => 'n',
+      BISHOP => 'b',
+      ROOK => 'r',
+      QUEEN => 'q',
+      _ => 'k',
+   };
+   if color == WHITE {
+      letter.to_ascii_uppercase()
+   } else {
+      letter
+   }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct State {
+   pub position: Position,
+   // the repetition key of every position before this one, oldest first. Unlike the halfmove clock,
+   // this isn't reset by irreversible moves, so it covers the whole game since the state was set up
+   pub position_keys: Vec<u64>,
+   pub halfmove_clock: u64,
+   pub fullmove_number: u64, // Starts at 1, and goes up after each black move
+}
+
+/// Everything `State::make_move` overwrites
+#[derive(Clone, Copy, Debug)]
+pub struct StateUndo {
+   undo: Undo,
+   halfmove_clock: u64,
+}
+
+/// A `Move` packed into 16 bits, for move lists and the transposition table. From the high bit down:
+/// 1 unused bit (always 0), 6 bits of origin square, 6 bits of destination square, and 3 bits of promotion
+/// target (0 for none, then knight, bishop, rook, queen). `a_move.compress().extract() == a_move` for every move
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CompressedMove(pub(crate) u16);
+
+impl TryFrom<u16> for CompressedMove {
+   type Error = String;
+
+   fn try_from(packed: u16) -> Result<CompressedMove, String> {
+      if packed >> 15 != 0 {
+         return Err(format!("{:#06x} has the unused high bit set", packed));
+      }
+      if packed & 0b111 > 4 {
+         return Err(format!("{:#06x} has promotion target {}, expected 0..=4", packed, packed & 0b111));
+      }
+      Ok(CompressedMove(packed))
+   }
+}
+
+impl From<CompressedMove> for u16 {
+   fn from(a_move: CompressedMove) -> u16 {
+      a_move.0
+   }
+}
+
+impl CompressedMove {
+   pub fn extract(&self) -> Move {
+      let promotion = PromotionTarget::from_num(self.0 & 0b111);
+      let destination = ((self.0 >> 3) & 0b11_1111) as u8;
+      let origin = ((self.0 >> 9) & 0b11_1111) as u8;
+      Move {
+         origin,
+         destination,
+         promotion,
+      }
+   }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Move {
+   pub origin: u8,
+   pub destination: u8,
+   pub promotion: PromotionTarget,
+}
+
+impl Move {
+   /// Squares are indexed 0 (a1) to 63 (h8)
+   pub fn new(origin: u8, destination: u8, promotion: Option<PromotionTarget>) -> Result<Move, String> {
+      if origin > 63 {
+         return Err(format!("Origin square {} is off the board, expected 0..=63", origin));
+      }
+      if destination > 63 {
+         return Err(format!("Destination square {} is off the board, expected 0..=63", destination));
+      }
+      Ok(Move {
+         origin,
+         destination,
+         promotion: promotion.unwrap_or(PromotionTarget::None),
+      })
+   }
+
+   /// The null move, `0000` in UCI, which passes the turn
+   pub fn null() -> Move {
+      Move {
+         origin: 0,
+         destination: 0,
+         promotion: PromotionTarget::None,
+      }
+   }
+
+   pub fn is_null(&self) -> bool {
+      *self == Move::null()
+   }
+
+   pub fn origin(&self) -> u8 {
+      self.origin
+   }
+
+   pub fn destination(&self) -> u8 {
+      self.destination
+   }
+
+   pub fn promotion(&self) -> Option<PromotionTarget> {
+      match self.promotion {
+         PromotionTarget::None => None,
+         x => Some(x),
+      }
+   }
+
+   pub fn compress(&self) -> CompressedMove {
+      debug_assert!(self.origin.count_ones() <= 6);
+      debug_assert!(self.destination.count_ones() <= 6);
+      let promotion = self.promotion.as_num();
+      debug_assert!(promotion.count_ones() <= 3);
+      CompressedMove((self.origin as u16) << 9 | (self.destination as u16) << 3 | promotion)
+   }
+}
+
+impl fmt::Display for Move {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      if self.is_null() {
+         return write!(f, "0000");
+      }
+      index_to_algebraic(self.origin, f)?;
+      index_to_algebraic(self.destination, f)?;
+      write!(f, "{}", self.promotion)?;
+      Ok(())
+   }
+}
+
+impl FromStr for Move {
+   type Err = ParseError;
+
+   fn from_str(s: &str) -> Result<Move, ParseError> {
+      if s == "0000" {
+         return Ok(Move::null());
+      }
+      if s.len() < 4 || s.len() > 5 {
+         return Err(ParseError::BadSection(format!(
+            "A full move has to be 4-5 bytes long, got a move ({}) that was {} bytes long",
+            s,
+            s.len()
+         )));
       }
       let origin_square = &s[..2];
       let dest_square = &s[2..4];
@@ -822,9 +1782,12 @@ fn index_to_algebraic_string(index: u8) -> String {
    f
 }
 
-fn algebraic_to_index(algebraic: &str) -> Result<u8, String> {
+fn algebraic_to_index(algebraic: &str) -> Result<u8, ParseError> {
    if algebraic.len() != 2 {
-      return Err(format!("{} not a valid algebraic location; too long", algebraic));
+      return Err(ParseError::BadSquare(format!(
+         "{} not a valid algebraic location; too long",
+         algebraic
+      )));
    }
    let col: u8 = match algebraic.as_bytes()[0] {
       b'a' => 0,
@@ -835,7 +1798,12 @@ fn algebraic_to_index(algebraic: &str) -> Result<u8, String> {
       b'f' => 5,
       b'g' => 6,
       b'h' => 7,
-      file => return Err(format!("{} is not a valid algebraic file, expected a..=h", file)),
+      file => {
+         return Err(ParseError::BadSquare(format!(
+            "{} is not a valid algebraic file, expected a..=h",
+            file
+         )))
+      }
    };
    let row = match algebraic.as_bytes()[1] {
       b'1' => 0,
@@ -846,7 +1814,12 @@ fn algebraic_to_index(algebraic: &str) -> Result<u8, String> {
       b'6' => 5,
       b'7' => 6,
       b'8' => 7,
-      rank => return Err(format!("{} is not a valid algebraic rank, expected 1..=8", rank)),
+      rank => {
+         return Err(ParseError::BadSquare(format!(
+            "{} is not a valid algebraic rank, expected 1..=8",
+            rank
+         )))
+      }
    };
    Ok((row * 8) + col)
 }
@@ -874,137 +1847,208 @@ impl State {
       state
    }
 
+   /// Like `apply_moves_from_uci`, but each move has to be legal in the position it's played in.
+   /// On failure, says which move (counting from 0) was wrong
+   pub fn apply_uci_moves_checked(&self, moves: &str) -> Result<State, String> {
+      let mut state = self.clone();
+      for (i, token) in moves.split_whitespace().enumerate() {
+         let a_move: Move = token
+            .parse()
+            .map_err(|e| format!("move {} ({}) couldn't be parsed: {}", i, token, e))?;
+         if !state.is_legal(a_move) {
+            return Err(format!("move {} ({}) is illegal in {}", i, token, state.to_fen()));
+         }
+         state.apply_move(a_move);
+      }
+      Ok(state)
+   }
+
+   /// Applies the move in place, keeping the clocks and repetition history up to date
    pub fn apply_move(&mut self, a_move: Move) {
-      let is_capture = (self.position.squares.occupied & (1 << a_move.destination)) != 0;
+      self.make_move(a_move);
+   }
+
+   /// The state after `a_move`, leaving this one as it is
+   #[must_use]
+   pub fn after_move(&self, a_move: Move) -> State {
+      let mut new_state = self.clone();
+      new_state.apply_move(a_move);
+      new_state
+   }
+
+   /// Applies the move in place, returning what `unmake_move` needs to take it back
+   pub fn make_move(&mut self, a_move: Move) -> StateUndo {
+      let them = (!self.position.side_to_move).as_num();
+      // checked against their pieces only, as a Chess960 castle lands on our own rook
+      let is_capture = !a_move.is_null() && (self.position.squares.all_pieces[them] & (1 << a_move.destination)) != 0;
       let is_pawn_move = ((self.position.squares.pieces[WHITE][PAWN] | self.position.squares.pieces[BLACK][PAWN])
          & (1 << a_move.origin))
          != 0;
 
+      let halfmove_clock = self.halfmove_clock;
+      if self.position.side_to_move == Color::Black {
+         self.fullmove_number += 1;
+      }
       if is_capture | is_pawn_move {
-         self.prior_positions.clear();
          self.halfmove_clock = 0;
       } else {
-         self.prior_positions.push(self.position.clone());
          self.halfmove_clock += 1;
-      };
+      }
+      self.position_keys.push(self.position.repetition_key());
 
-      self.position.apply_move(a_move);
+      StateUndo {
+         undo: self.position.make_move(a_move),
+         halfmove_clock,
+      }
+   }
+
+   /// Takes back the move that produced `undo`, which must be the most recent one made
+   pub fn unmake_move(&mut self, undo: StateUndo) {
+      self.position.unmake_move(undo.undo);
+      self.position_keys.pop();
+      self.halfmove_clock = undo.halfmove_clock;
+      if self.position.side_to_move == Color::Black {
+         self.fullmove_number -= 1;
+      }
+   }
+
+   /// The legal moves, as `gen_moves` would give them, generated lazily
+   pub fn move_gen(&self) -> MoveGen<'_> {
+      MoveGen::new(&self.position)
    }
 
    pub fn gen_moves(&self, move_buf: &mut Vec<CompressedMove>) {
+      self.gen_moves_with(Promotions::All, move_buf)
+   }
+
+   /// Like `gen_moves`, but only emitting the given promotions
+   pub fn gen_moves_with(&self, promotions: Promotions, move_buf: &mut Vec<CompressedMove>) {
       move_buf.clear();
-      self.position.gen_moves_color(self.position.side_to_move, move_buf)
+      self.position.gen_moves_color_with(self.position.side_to_move, promotions, move_buf)
+   }
+
+   /// Each legal move, in `gen_moves` order, along with the state it leads to
+   pub fn legal_moves_with_states(&self) -> Vec<(Move, State)> {
+      let mut moves = Vec::new();
+      self.gen_moves(&mut moves);
+      moves
+         .into_iter()
+         .map(|x| (x.extract(), self.after_move(x.extract())))
+         .collect()
+   }
+
+   /// Parses a FEN, rejecting positions that can't come up in a game
+   pub fn from_fen(fen: &str) -> Result<State, ParseError> {
+      State::from_fen_variant(fen, Variant::Standard)
+   }
+
+   /// As `from_fen`. Chess960 positions take castling rights as either Shredder-FEN (`HAha`) or X-FEN (`KQkq`)
+   pub fn from_fen_variant(fen: &str, variant: Variant) -> Result<State, ParseError> {
+      let state = State::parse_fen(fen, variant, true)?;
+      let position = &state.position;
+      for (color, name) in [(WHITE, "white"), (BLACK, "black")].iter() {
+         let kings = position.squares.pieces[*color][KING].count_ones();
+         if kings != 1 {
+            return Err(ParseError::IllegalPosition(format!(
+               "illegal FEN; {} has {} kings, expected exactly 1",
+               name, kings
+            )));
+         }
+      }
+      if position.in_check(!position.side_to_move) {
+         return Err(ParseError::IllegalPosition("illegal FEN; the side that just moved is in check".into()));
+      }
+      Ok(state)
+   }
+
+   /// As `from_fen`, but into an existing state, keeping the buffer for its move history. Nothing is allocated
+   /// unless the FEN is bad, for tools that go through a great many positions. On an error, `state` is left as it was
+   pub fn from_fen_into(fen: &str, state: &mut State) -> Result<(), ParseError> {
+      let parsed = State::from_fen(fen)?;
+      state.position = parsed.position;
+      state.position_keys.clear();
+      state.halfmove_clock = parsed.halfmove_clock;
+      state.fullmove_number = parsed.fullmove_number;
+      Ok(())
+   }
+
+   /// Parses a FEN without checking that the position is legal, for tools that want to set up
+   /// impossible positions. The engine may misbehave on such a position
+   pub fn from_fen_unchecked(fen: &str) -> Result<State, ParseError> {
+      State::parse_fen(fen, Variant::Standard, false)
    }
 
-   pub fn from_fen(fen: &str) -> Result<State, String> {
+   /// When `strict`, an en passant square that no double pawn push could have left is an error.
+   /// Otherwise it is dropped
+   fn parse_fen(fen: &str, variant: Variant, strict: bool) -> Result<State, ParseError> {
       let mut board = Board::empty();
-      let mut index: u64 = 56;
       // TODO: add string index to error messages
-      let fen_sections: Vec<&str> = fen.split_whitespace().collect();
-      if fen_sections.len() != 6 {
-         return Err(format!(
-            "malformed FEN; expected 6 whitespace delimited sections, found {}",
-            fen_sections.len()
-         ));
-      }
+      let fen_sections: [&str; 6] = match exactly(fen.split_whitespace()) {
+         Ok(sections) => sections,
+         Err(found) => {
+            return Err(ParseError::BadSection(format!(
+               "malformed FEN; expected 6 whitespace delimited sections, found {}",
+               found
+            )));
+         }
+      };
 
-      if fen_sections[0].len() > 71 || fen_sections[0].len() < 15 {
-         return Err(format!("malformed FEN; length of piece placment section can't be larger than 71 or less than 15 and be a valid board, found length of {}", fen_sections[0].len()));
-      }
-      for ascii_char in fen_sections[0].bytes() {
-         if index > 64 {
-            return Err("malformed FEN; too many squares on board".into());
+      let ranks: [&str; 8] = match exactly(fen_sections[0].split('/')) {
+         Ok(ranks) => ranks,
+         Err(found) => {
+            return Err(ParseError::OutOfRange(format!(
+               "malformed FEN; expected 8 ranks in piece placement, found {}",
+               found
+            )));
          }
-         match ascii_char {
-            b'p' => {
-               board.pieces[BLACK][PAWN] |= 1 << index;
-               index += 1;
-            }
-            b'P' => {
-               board.pieces[WHITE][PAWN] |= 1 << index;
-               index += 1;
-            }
-            b'n' => {
-               board.pieces[BLACK][KNIGHT] |= 1 << index;
-               index += 1;
-            }
-            b'N' => {
-               board.pieces[WHITE][KNIGHT] |= 1 << index;
-               index += 1;
-            }
-            b'b' => {
-               board.pieces[BLACK][BISHOP] |= 1 << index;
-               index += 1;
-            }
-            b'B' => {
-               board.pieces[WHITE][BISHOP] |= 1 << index;
-               index += 1;
-            }
-            b'r' => {
-               board.pieces[BLACK][ROOK] |= 1 << index;
-               index += 1;
-            }
-            b'R' => {
-               board.pieces[WHITE][ROOK] |= 1 << index;
-               index += 1;
-            }
-            b'q' => {
-               board.pieces[BLACK][QUEEN] |= 1 << index;
-               index += 1;
-            }
-            b'Q' => {
-               board.pieces[WHITE][QUEEN] |= 1 << index;
-               index += 1;
-            }
-            b'k' => {
-               board.pieces[BLACK][KING] |= 1 << index;
-               index += 1;
-            }
-            b'K' => {
-               board.pieces[WHITE][KING] |= 1 << index;
-               index += 1;
-            }
-            b'1' => {
-               index += 1;
-            }
-            b'2' => {
-               index += 2;
-            }
-            b'3' => {
-               index += 3;
-            }
-            b'4' => {
-               index += 4;
-            }
-            b'5' => {
-               index += 5;
-            }
-            b'6' => {
-               index += 6;
-            }
-            b'7' => {
-               index += 7;
-            }
-            b'8' => {
-               index += 8;
-            }
-            b'/' => {
-               if index % 8 != 0 {
-                  return Err("malformed FEN; got to end of rank without all squares in rank accounted for".into());
+      };
+      // listed from the 8th rank down
+      for (rank, rank_section) in (0..8u64).rev().zip(ranks) {
+         let mut file: u64 = 0;
+         for ascii_char in rank_section.bytes() {
+            let piece = match ascii_char.to_ascii_lowercase() {
+               b'1'..=b'8' => {
+                  file += u64::from(ascii_char - b'0');
+                  None
                }
-               index -= 16;
+               b'p' => Some(PAWN),
+               b'n' => Some(KNIGHT),
+               b'b' => Some(BISHOP),
+               b'r' => Some(ROOK),
+               b'q' => Some(QUEEN),
+               b'k' => Some(KING),
+               _ => {
+                  return Err(ParseError::BadPiece(format!("malformed FEN; got unexpected byte {} (ASCII: {}) during piece placement, expecting one of ASCII pbnrqkPBNRQK12345678/", ascii_char, ascii_char as char)));
+               }
+            };
+            if let Some(piece) = piece {
+               let color = if ascii_char.is_ascii_uppercase() { WHITE } else { BLACK };
+               if file < 8 {
+                  board.pieces[color][piece] |= 1 << (rank * 8 + file);
+               }
+               file += 1;
             }
-            _ => {
-               return Err(format!("malformed FEN; got unexpected byte {} (ASCII: {}) during piece placement, expecting one of ASCII pbnrqkPBNRQK12345678/", ascii_char, ascii_char as char));
+            if file > 8 {
+               return Err(ParseError::OutOfRange(format!(
+                  "malformed FEN; rank {} has more than 8 squares",
+                  rank + 1
+               )));
             }
          }
+         if file < 8 {
+            return Err(ParseError::OutOfRange(format!(
+               "malformed FEN; rank {} has only {} squares",
+               rank + 1,
+               file
+            )));
+         }
       }
 
       if fen_sections[1].len() != 1 {
-         return Err(format!(
+         return Err(ParseError::BadSection(format!(
             "malformed FEN; expected length of 1 byte for player to move subsection, found length of {}",
             fen_sections[1].len()
-         ));
+         )));
       }
 
       let to_move = fen_sections[1].as_bytes()[0];
@@ -1012,69 +2056,80 @@ impl State {
          b'w' => Color::White,
          b'b' => Color::Black,
          _ => {
-            return Err(format!(
+            return Err(ParseError::BadSection(format!(
                "malformed FEN; got unexpected byte {} (ASCII: {}) parsing player to move. Expecting one of ASCII wb",
                to_move, to_move as char
-            ));
+            )));
          }
       };
 
       let castling = fen_sections[2].as_bytes();
       if castling.len() > 4 {
-         return Err(format!(
+         return Err(ParseError::BadCastling(format!(
             "malformed FEN; castling rights section shouldn't be longer than 4 bytes or less than 1, found {}",
             castling.len()
-         ));
+         )));
       }
 
-      let mut wkc = false;
-      let mut wqc = false;
-      let mut bkc = false;
-      let mut bqc = false;
+      // [color][side]
+      let mut castling_rights = [[false; 2]; 2];
+      let mut castling_rook_files = [[7, 0]; 2];
       if castling.first().cloned() != Some(b'-') {
          for ascii_char in castling.iter() {
-            match *ascii_char {
-               b'K' => {
-                  if wkc {
-                     return Err(
-                        "malformed FEN; encountered White Kingside castling rights twice when parsing castling rights"
-                           .into(),
-                     );
-                  }
-                  wkc = true;
+            let color = if ascii_char.is_ascii_uppercase() { WHITE } else { BLACK };
+            let color_name = if color == WHITE { "White" } else { "Black" };
+            let back_rank_bb = RANK_1 << back_rank(color);
+            let king_file = match board.pieces[color][KING] & back_rank_bb {
+               0 => None,
+               bb => Some(bb.trailing_zeros() as u8 % 8),
+            };
+            // as a bitboard of files
+            let rooks = (board.pieces[color][ROOK] & back_rank_bb) >> back_rank(color);
+            let (side, rook_file) = match (ascii_char.to_ascii_lowercase(), variant) {
+               (b'k', Variant::Standard) => (KINGSIDE, Some(7)),
+               (b'q', Variant::Standard) => (QUEENSIDE, Some(0)),
+               // X-FEN: the outermost rook on that side of the king
+               (b'k', Variant::Chess960) => (
+                  KINGSIDE,
+                  king_file.and_then(|k| (k + 1..8).rev().find(|x| rooks & (1 << x) != 0)),
+               ),
+               (b'q', Variant::Chess960) => (QUEENSIDE, king_file.and_then(|k| (0..k).find(|x| rooks & (1 << x) != 0))),
+               // Shredder-FEN: the file of the rook
+               (file @ b'a'..=b'h', Variant::Chess960) => {
+                  let file = file - b'a';
+                  let side = if king_file.is_some_and(|k| file < k) { QUEENSIDE } else { KINGSIDE };
+                  (side, Some(file))
                }
-               b'Q' => {
-                  if wqc {
-                     return Err(
-                        "malformed FEN; encountered White Queenside castling rights twice when parsing castling rights"
-                           .into(),
-                     );
-                  }
-                  wqc = true;
+               _ => {
+                  let expected = match variant {
+                     Variant::Standard => "KQkq",
+                     Variant::Chess960 => "KQkqA-Ha-h",
+                  };
+                  return Err(ParseError::BadCastling(format!(
+                     "malformed FEN; found byte {} (ASCII: {}) when parsing castling rights. Expected one of ASCII {}",
+                     ascii_char, *ascii_char as char, expected
+                  )));
                }
-               b'k' => {
-                  if bkc {
-                     return Err(
-                        "malformed FEN; encountered Black Kingside castling rights twice when parsing castling rights"
-                           .into(),
-                     );
+            };
+            let side_name = if side == KINGSIDE { "Kingside" } else { "Queenside" };
+            if castling_rights[color][side] {
+               return Err(ParseError::BadCastling(format!(
+                  "malformed FEN; encountered {} {} castling rights twice when parsing castling rights",
+                  color_name, side_name
+               )));
+            }
+            castling_rights[color][side] = true;
+            if variant == Variant::Chess960 {
+               match rook_file {
+                  Some(file) if king_file.is_some() && rooks & (1 << file) != 0 => {
+                     castling_rook_files[color][side] = file
                   }
-                  bkc = true;
-               }
-               b'q' => {
-                  if bqc {
-                     return Err(
-                        "malformed FEN; encountered Black Queenside castling rights twice when parsing castling rights"
-                           .into(),
-                     );
+                  _ => {
+                     return Err(ParseError::BadCastling(format!(
+                        "malformed FEN; {} {} castling rights without a king and rook on the back rank to castle with",
+                        color_name, side_name
+                     )));
                   }
-                  bqc = true;
-               }
-               _ => {
-                  return Err(format!(
-                  "malformed FEN; found byte {} (ASCII: {}) when parsing castling rights. Expected one of ASCII KQkq",
-                  ascii_char, *ascii_char as char
-               ));
                }
             }
          }
@@ -1082,20 +2137,20 @@ impl State {
 
       let en_passant_square_section = fen_sections[3];
       if en_passant_square_section.len() > 2 {
-         return Err(format!(
+         return Err(ParseError::BadSquare(format!(
             "malformed FEN; en passant square shouldn't be longer than 2 bytes or less than 1, found {}",
             en_passant_square_section.len()
-         ));
+         )));
       }
       let en_passant_square = match en_passant_square_section {
          "-" => 0,
          algebraic => match algebraic_to_index(algebraic) {
             Ok(index) => 1 << index,
             Err(e) => {
-               return Err(format!(
+               return Err(ParseError::BadSquare(format!(
                   "malformed FEN; en passant square was not valid algebraic notation: {}",
                   e
-               ));
+               )));
             }
          },
       };
@@ -1103,99 +2158,138 @@ impl State {
       let halfmove_clock: u64 = match fen_sections[4].parse() {
          Ok(val) => val,
          Err(e) => {
-            return Err(format!(
+            return Err(ParseError::BadSection(format!(
                "malformed FEN; halfmove clock value {} couldn't be parsed as a number: {}",
                fen_sections[4], e
-            ));
+            )));
+         }
+      };
+
+      let fullmove_number: u64 = match fen_sections[5].parse() {
+         Ok(val) => val,
+         Err(e) => {
+            return Err(ParseError::BadSection(format!(
+               "malformed FEN; fullmove number value {} couldn't be parsed as a number: {}",
+               fen_sections[5], e
+            )));
          }
       };
 
       board.update_derived_bitboards();
 
+      let mut position = Position {
+         squares: board,
+         white_kingside_castle: castling_rights[WHITE][KINGSIDE],
+         white_queenside_castle: castling_rights[WHITE][QUEENSIDE],
+         black_kingside_castle: castling_rights[BLACK][KINGSIDE],
+         black_queenside_castle: castling_rights[BLACK][QUEENSIDE],
+         castling_rook_files,
+         variant,
+         en_passant_square,
+         side_to_move,
+         zobrist: 0,
+      };
+      match position.en_passant_capturable() {
+         Ok(true) => (),
+         // some tools always write the square after a double push. Nothing can capture there,
+         // so drop it to keep this equal to the same position without it
+         Ok(false) => position.en_passant_square = 0,
+         Err(e) if strict => return Err(ParseError::IllegalPosition(format!("illegal FEN; {}", e))),
+         Err(_) => position.en_passant_square = 0,
+      }
+      position.zobrist = position.compute_zobrist();
+
       Ok(State {
-         position: Position {
-            squares: board,
-            white_kingside_castle: wkc,
-            white_queenside_castle: wqc,
-            black_kingside_castle: bkc,
-            black_queenside_castle: bqc,
-            en_passant_square,
-            side_to_move,
-         },
-         prior_positions: SmallVec::new(),
+         position,
+         position_keys: Vec::new(),
          halfmove_clock,
+         fullmove_number,
       })
    }
 
    pub fn to_fen(&self) -> String {
       let mut buf = String::new();
-      let mut acc: u8 = 0;
-      /*
-      for square in self.position.squares.legacy.iter() {
-         match square {
-            Square::Empty => buf.push('1'),
-            Square::BlackPawn => buf.push('p'),
-            Square::WhitePawn => buf.push('P'),
-            Square::BlackKnight => buf.push('n'),
-            Square::WhiteKnight => buf.push('N'),
-            Square::BlackBishop => buf.push('b'),
-            Square::WhiteBishop => buf.push('B'),
-            Square::BlackRook => buf.push('r'),
-            Square::WhiteRook => buf.push('R'),
-            Square::BlackQueen => buf.push('q'),
-            Square::WhiteQueen => buf.push('Q'),
-            Square::BlackKing => buf.push('k'),
-            Square::WhiteKing => buf.push('K'),
+      for rank in (0..8).rev() {
+         let mut empty = 0;
+         for file in 0..8 {
+            let index = rank * 8 + file;
+            let piece = [WHITE, BLACK].iter().copied().find_map(|color| {
+               self.position.piece_kind_at(color, index).map(|kind| (color, kind))
+            });
+            match piece {
+               Some((color, kind)) => {
+                  if empty > 0 {
+                     write!(buf, "{}", empty).unwrap();
+                     empty = 0;
+                  }
+                  buf.push(piece_letter(color, kind));
+               }
+               None => empty += 1,
+            }
          }
-         acc += 1;
-         if acc == 8 {
+         if empty > 0 {
+            write!(buf, "{}", empty).unwrap();
+         }
+         if rank > 0 {
             buf.push('/');
-            acc = 0
          }
-      } */
-      // TODO
+      }
       buf.push(' ');
       match self.position.side_to_move {
          Color::Black => buf.push('b'),
          Color::White => buf.push('w'),
       }
       buf.push(' ');
-      buf.push('-'); // TODO
+      buf.push_str(&self.position.castling_string());
       buf.push(' ');
-      buf.push('-'); // TODO
-      buf.push(' ');
-      write!(buf, "{}", self.halfmove_clock).unwrap();
-      buf.push(' ');
-      buf.push('0'); // TODO
+      buf.push_str(&self.position.en_passant_string());
+      write!(buf, " {} {}", self.halfmove_clock, self.fullmove_number).unwrap();
       buf
    }
 
-   pub fn status(&self, moves: &[CompressedMove]) -> GameStatus {
-      // KvK
-      if self.position.squares.occupied.count_ones() == 2 {
-         return GameStatus::Draw;
-      } else if self.position.squares.occupied.count_ones() == 3 {
-         // K+BvK || K+NvK
-         if (self.position.squares.pieces[WHITE][BISHOP] | self.position.squares.pieces[BLACK][BISHOP]).count_ones()
-            == 1
-            || (self.position.squares.pieces[WHITE][KNIGHT] | self.position.squares.pieces[BLACK][KNIGHT]).count_ones()
-               == 1
-         {
-            return GameStatus::Draw;
-         }
-      }
+   /// How many times the current position has come up before, with the same side to move
+   pub fn repetitions(&self) -> usize {
+      let key = self.position.repetition_key();
+      // nothing from before the last capture or pawn move can come up again
+      self.position_keys
+         .iter()
+         .rev()
+         .take(self.halfmove_clock as usize)
+         .skip(1)
+         .step_by(2)
+         .filter(|x| **x == key)
+         .count()
+   }
 
-      if moves.is_empty() {
-         if !self.position.in_check(self.position.side_to_move) {
-            // I have no moves, and I'm not in check - stalemate
-            GameStatus::Draw
-         } else {
-            // I have no moves, and I'm in check - I lose
-            GameStatus::Victory(!self.position.side_to_move)
-         }
-      } else if self.halfmove_clock >= 100 || self.prior_positions.iter().filter(|x| **x == self.position).count() >= 2
-      {
-         GameStatus::Draw
+   /// The side to move is in check and has no legal moves
+   pub fn is_checkmate(&self) -> bool {
+      self.position.in_check(self.position.side_to_move) && self.move_gen().next().is_none()
+   }
+
+   /// The side to move isn't in check, but has no legal moves
+   pub fn is_stalemate(&self) -> bool {
+      !self.position.in_check(self.position.side_to_move) && self.move_gen().next().is_none()
+   }
+
+   pub fn is_legal(&self, a_move: Move) -> bool {
+      self.move_gen().any(|x| x == a_move)
+   }
+
+   /// Where the game stands, given the legal `moves`, and why it's over if it is. A position repeated for the third
+   /// time is a draw, as is one where the fifty-move rule has run out
+   pub fn status(&self, moves: &[CompressedMove]) -> GameStatus {
+      if moves.is_empty() && self.position.in_check(self.position.side_to_move) {
+         // I have no moves, and I'm in check - I lose. This trumps every kind of draw
+         GameStatus::Victory(!self.position.side_to_move, VictoryReason::Checkmate)
+      } else if moves.is_empty() {
+         // I have no moves, and I'm not in check - stalemate
+         GameStatus::Draw(DrawReason::Stalemate)
+      } else if self.position.insufficient_material() {
+         GameStatus::Draw(DrawReason::InsufficientMaterial)
+      } else if self.halfmove_clock >= 100 {
+         GameStatus::Draw(DrawReason::FiftyMoveRule)
+      } else if self.repetitions() >= 2 {
+         GameStatus::Draw(DrawReason::Repetition)
       } else {
          GameStatus::Ongoing
       }
@@ -1204,11 +2298,68 @@ impl State {
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum GameStatus {
-   Draw,
-   Victory(Color),
+   Draw(DrawReason),
+   Victory(Color, VictoryReason),
    Ongoing,
 }
 
+/// How a game was won. `State::status` only ever finds checkmates, the rest are for games that ended off the board
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VictoryReason {
+   Checkmate,
+   Resignation,
+   Timeout,
+}
+
+/// How a game was drawn. `State::status` never finds an agreed draw
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrawReason {
+   Stalemate,
+   InsufficientMaterial,
+   FiftyMoveRule,
+   Repetition,
+   Agreement,
+}
+
+/// The `N` items, or how many there were if that isn't `N`. Saves collecting them
+fn exactly<'a, const N: usize>(items: impl Iterator<Item = &'a str>) -> Result<[&'a str; N], usize> {
+   let mut array = [""; N];
+   let mut found = 0;
+   for item in items {
+      if found < N {
+         array[found] = item;
+      }
+      found += 1;
+   }
+   if found == N {
+      Ok(array)
+   } else {
+      Err(found)
+   }
+}
+
+fn back_rank(color: usize) -> u8 {
+   if color == WHITE {
+      0
+   } else {
+      56
+   }
+}
+
+/// Where the king and rook end up after castling, which is the same in standard chess and Chess960
+fn castled_squares(color: usize, side: usize) -> (u8, u8) {
+   if side == KINGSIDE {
+      (back_rank(color) + 6, back_rank(color) + 5)
+   } else {
+      (back_rank(color) + 2, back_rank(color) + 3)
+   }
+}
+
+/// Every square from `a` to `b` inclusive, which must be on the same rank
+fn rank_span(a: u8, b: u8) -> u64 {
+   (u64::MAX >> (63 - a.max(b))) & (u64::MAX << a.min(b))
+}
+
 fn pop_lsb(board: &mut u64) -> u32 {
    debug_assert!(*board != 0);
    let lsb_index = board.trailing_zeros();
@@ -1216,13 +2367,13 @@ fn pop_lsb(board: &mut u64) -> u32 {
    lsb_index
 }
 
-fn white_pawn_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>) {
+fn white_pawn_movegen(cur_position: &Position, promotions: Promotions, results: &mut Vec<CompressedMove>) {
    // normal movement
    {
       let mut moved_pawns = cur_position.squares.pieces[WHITE][PAWN] << 8;
       moved_pawns &= cur_position.squares.unoccupied;
 
-      let mut promotions = moved_pawns & RANK_8;
+      let mut promoted_pawns = moved_pawns & RANK_8;
       moved_pawns &= !RANK_8;
 
       while moved_pawns > 0 {
@@ -1239,48 +2390,20 @@ fn white_pawn_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>
          );
       }
 
-      while promotions > 0 {
-         let to = pop_lsb(&mut promotions);
-         maybe_add_move(
-            Move {
-               origin: (to - 8) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Queen,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to - 8) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Bishop,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to - 8) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Knight,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to - 8) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Rook,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
+      while promoted_pawns > 0 {
+         let to = pop_lsb(&mut promoted_pawns);
+         for promotion in promotions.targets().iter().copied() {
+            maybe_add_move(
+               Move {
+                  origin: (to - 8) as u8,
+                  destination: to as u8,
+                  promotion,
+               },
+               cur_position,
+               WHITE,
+               results,
+            );
+         }
       }
    }
 
@@ -1304,13 +2427,17 @@ fn white_pawn_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>
       }
    }
 
+   white_pawn_captures(cur_position, promotions, results);
+}
+
+fn white_pawn_captures(cur_position: &Position, promotions: Promotions, results: &mut Vec<CompressedMove>) {
    // left attack
    {
       let mut left_regular_attacks =
          (cur_position.squares.pieces[WHITE][PAWN] << 7) & cur_position.squares.attackable[BLACK] & !FILE_H;
 
       let mut left_attack_promotions = left_regular_attacks & RANK_8;
-      left_attack_promotions &= !RANK_8;
+      left_regular_attacks &= !RANK_8;
 
       let left_en_passant = (cur_position.squares.pieces[WHITE][PAWN] << 7) & cur_position.en_passant_square & !FILE_H;
 
@@ -1330,46 +2457,18 @@ fn white_pawn_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>
 
       while left_attack_promotions > 0 {
          let to = pop_lsb(&mut left_attack_promotions);
-         maybe_add_move(
-            Move {
-               origin: (to - 7) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Queen,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to - 7) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Bishop,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to - 7) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Knight,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to - 7) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Rook,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
+         for promotion in promotions.targets().iter().copied() {
+            maybe_add_move(
+               Move {
+                  origin: (to - 7) as u8,
+                  destination: to as u8,
+                  promotion,
+               },
+               cur_position,
+               WHITE,
+               results,
+            );
+         }
       }
 
       if left_en_passant > 0 {
@@ -1393,7 +2492,7 @@ fn white_pawn_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>
          (cur_position.squares.pieces[WHITE][PAWN] << 9) & cur_position.squares.attackable[BLACK] & !FILE_A;
 
       let mut right_attack_promotions = right_regular_attacks & RANK_8;
-      right_attack_promotions &= !RANK_8;
+      right_regular_attacks &= !RANK_8;
 
       let right_en_passant = (cur_position.squares.pieces[WHITE][PAWN] << 9) & cur_position.en_passant_square & !FILE_A;
 
@@ -1413,46 +2512,18 @@ fn white_pawn_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>
 
       while right_attack_promotions > 0 {
          let to = pop_lsb(&mut right_attack_promotions);
-         maybe_add_move(
-            Move {
-               origin: (to - 9) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Queen,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to - 9) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Bishop,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to - 9) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Knight,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to - 9) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Rook,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
+         for promotion in promotions.targets().iter().copied() {
+            maybe_add_move(
+               Move {
+                  origin: (to - 9) as u8,
+                  destination: to as u8,
+                  promotion,
+               },
+               cur_position,
+               WHITE,
+               results,
+            );
+         }
       }
 
       if right_en_passant > 0 {
@@ -1471,13 +2542,13 @@ fn white_pawn_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>
    }
 }
 
-fn black_pawn_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>) {
+fn black_pawn_movegen(cur_position: &Position, promotions: Promotions, results: &mut Vec<CompressedMove>) {
    // normal movement
    {
       let mut moved_pawns = cur_position.squares.pieces[BLACK][PAWN] >> 8;
       moved_pawns &= cur_position.squares.unoccupied;
 
-      let mut promotions = moved_pawns & RANK_1;
+      let mut promoted_pawns = moved_pawns & RANK_1;
       moved_pawns &= !RANK_1;
 
       while moved_pawns > 0 {
@@ -1494,48 +2565,20 @@ fn black_pawn_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>
          );
       }
 
-      while promotions > 0 {
-         let to = pop_lsb(&mut promotions);
-         maybe_add_move(
-            Move {
-               origin: (to + 8) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Queen,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to + 8) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Bishop,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to + 8) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Knight,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to + 8) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Rook,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
+      while promoted_pawns > 0 {
+         let to = pop_lsb(&mut promoted_pawns);
+         for promotion in promotions.targets().iter().copied() {
+            maybe_add_move(
+               Move {
+                  origin: (to + 8) as u8,
+                  destination: to as u8,
+                  promotion,
+               },
+               cur_position,
+               BLACK,
+               results,
+            );
+         }
       }
    }
 
@@ -1559,13 +2602,17 @@ fn black_pawn_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>
       }
    }
 
+   black_pawn_captures(cur_position, promotions, results);
+}
+
+fn black_pawn_captures(cur_position: &Position, promotions: Promotions, results: &mut Vec<CompressedMove>) {
    // left attack
    {
       let mut left_regular_attacks =
          (cur_position.squares.pieces[BLACK][PAWN] >> 9) & cur_position.squares.attackable[WHITE] & !FILE_H;
 
       let mut left_attack_promotions = left_regular_attacks & RANK_1;
-      left_attack_promotions &= !RANK_1;
+      left_regular_attacks &= !RANK_1;
 
       let left_en_passant = (cur_position.squares.pieces[BLACK][PAWN] >> 9) & cur_position.en_passant_square & !FILE_H;
 
@@ -1585,46 +2632,18 @@ fn black_pawn_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>
 
       while left_attack_promotions > 0 {
          let to = pop_lsb(&mut left_attack_promotions);
-         maybe_add_move(
-            Move {
-               origin: (to + 9) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Queen,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to + 9) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Bishop,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to + 9) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Knight,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to + 9) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Rook,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
+         for promotion in promotions.targets().iter().copied() {
+            maybe_add_move(
+               Move {
+                  origin: (to + 9) as u8,
+                  destination: to as u8,
+                  promotion,
+               },
+               cur_position,
+               BLACK,
+               results,
+            );
+         }
       }
 
       if left_en_passant > 0 {
@@ -1647,8 +2666,8 @@ fn black_pawn_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>
       let mut right_regular_attacks =
          (cur_position.squares.pieces[BLACK][PAWN] >> 7) & cur_position.squares.attackable[WHITE] & !FILE_A;
 
-      let mut right_attack_promotions = right_regular_attacks & RANK_8;
-      right_attack_promotions &= !RANK_8;
+      let mut right_attack_promotions = right_regular_attacks & RANK_1;
+      right_regular_attacks &= !RANK_1;
 
       let right_en_passant = (cur_position.squares.pieces[BLACK][PAWN] >> 7) & cur_position.en_passant_square & !FILE_A;
 
@@ -1668,153 +2687,98 @@ fn black_pawn_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>
 
       while right_attack_promotions > 0 {
          let to = pop_lsb(&mut right_attack_promotions);
+         for promotion in promotions.targets().iter().copied() {
+            maybe_add_move(
+               Move {
+                  origin: (to + 7) as u8,
+                  destination: to as u8,
+                  promotion,
+               },
+               cur_position,
+               BLACK,
+               results,
+            );
+         }
+      }
+
+      if right_en_passant > 0 {
+         let to = right_en_passant.trailing_zeros();
          maybe_add_move(
             Move {
                origin: (to + 7) as u8,
                destination: to as u8,
-               promotion: PromotionTarget::Queen,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to + 7) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Bishop,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to + 7) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Knight,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
-         maybe_add_move(
-            Move {
-               origin: (to + 7) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::Rook,
+               promotion: PromotionTarget::None,
             },
             cur_position,
             BLACK,
             results,
          );
       }
-
-      if right_en_passant > 0 {
-         let to = right_en_passant.trailing_zeros();
-         maybe_add_move(
-            Move {
-               origin: (to + 7) as u8,
-               destination: to as u8,
-               promotion: PromotionTarget::None,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
-      }
-   }
-}
+   }
+}
 
 fn white_king_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>) {
-   king_movegen(cur_position, WHITE, results);
-
-   if cur_position.white_kingside_castle {
-      let path_bb: u64 = (1 << 5) | (1 << 6);
-      let squares_occupied = (path_bb & cur_position.squares.occupied) > 0;
-      let squares_attacked =
-         cur_position.square_is_attacked(Color::White, 5) | cur_position.square_is_attacked(Color::White, 6);
-
-      if !squares_occupied & !squares_attacked & !cur_position.in_check(Color::White) {
-         maybe_add_move(
-            Move {
-               origin: 4,
-               destination: 6,
-               promotion: PromotionTarget::None,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
-      }
-   }
+   king_movegen(cur_position, WHITE, !cur_position.squares.all_pieces[WHITE], results);
+   castling_movegen(cur_position, WHITE, results);
+}
 
-   if cur_position.white_queenside_castle {
-      let path_bb: u64 = (1 << 3) | (1 << 2) | (1 << 1);
-      let squares_occupied = (path_bb & cur_position.squares.occupied) > 0;
-      let squares_attacked =
-         cur_position.square_is_attacked(Color::White, 3) | cur_position.square_is_attacked(Color::White, 2);
+fn black_king_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>) {
+   king_movegen(cur_position, BLACK, !cur_position.squares.all_pieces[BLACK], results);
+   castling_movegen(cur_position, BLACK, results);
+}
 
-      if !squares_occupied & !squares_attacked & !cur_position.in_check(Color::White) {
-         maybe_add_move(
-            Move {
-               origin: 4,
-               destination: 2,
-               promotion: PromotionTarget::None,
-            },
-            cur_position,
-            WHITE,
-            results,
-         );
+fn castling_movegen(cur_position: &Position, color: usize, results: &mut Vec<CompressedMove>) {
+   for side in [KINGSIDE, QUEENSIDE].iter().copied() {
+      if let Some(a_move) = castling_move(cur_position, color, side) {
+         maybe_add_move(a_move, cur_position, color, results);
       }
    }
 }
 
-fn black_king_movegen(cur_position: &Position, results: &mut Vec<CompressedMove>) {
-   king_movegen(cur_position, BLACK, results);
-
-   if cur_position.black_kingside_castle {
-      let path_bb: u64 = (1 << 61) | (1 << 62);
-      let squares_occupied = (path_bb & cur_position.squares.occupied) > 0;
-      let squares_attacked =
-         cur_position.square_is_attacked(Color::Black, 61) | cur_position.square_is_attacked(Color::Black, 62);
-
-      if !squares_occupied & !squares_attacked & !cur_position.in_check(Color::Black) {
-         maybe_add_move(
-            Move {
-               origin: 60,
-               destination: 62,
-               promotion: PromotionTarget::None,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
-      }
+/// The castling move to the given side, if nothing is in the way. It may still leave the king in check
+fn castling_move(cur_position: &Position, color: usize, side: usize) -> Option<Move> {
+   let king_bb = cur_position.squares.pieces[color][KING];
+   if king_bb & (RANK_1 << back_rank(color)) == 0 {
+      return None;
    }
+   let king = king_bb.trailing_zeros() as u8;
 
-   if cur_position.black_queenside_castle {
-      let path_bb: u64 = (1 << 57) | (1 << 58) | (1 << 59);
-      let squares_occupied = (path_bb & cur_position.squares.occupied) > 0;
-      let squares_attacked =
-         cur_position.square_is_attacked(Color::Black, 58) | cur_position.square_is_attacked(Color::Black, 59);
+   if !cur_position.may_castle(color, side) {
+      return None;
+   }
+   let rook = cur_position.castling_rook_square(color, side);
+   if cur_position.squares.pieces[color][ROOK] & (1 << rook) == 0 {
+      return None;
+   }
+   let (king_to, rook_to) = castled_squares(color, side);
+
+   // everything the king and rook pass over must be empty, other than the two of them
+   let king_path = rank_span(king, king_to);
+   let path_bb = (king_path | rank_span(rook, rook_to)) & !(1 << king) & !(1 << rook);
+   let squares_occupied = (path_bb & cur_position.squares.occupied) > 0;
+   // the king can't castle out of, through or into check
+   let mut squares_attacked = false;
+   let mut king_path_left = king_path;
+   while king_path_left > 0 {
+      let square = pop_lsb(&mut king_path_left) as usize;
+      squares_attacked |= cur_position.square_is_attacked(Color::from_num(color), square);
+   }
 
-      if !squares_occupied & !squares_attacked & !cur_position.in_check(Color::Black) {
-         maybe_add_move(
-            Move {
-               origin: 60,
-               destination: 58,
-               promotion: PromotionTarget::None,
-            },
-            cur_position,
-            BLACK,
-            results,
-         );
-      }
+   if squares_occupied | squares_attacked {
+      return None;
    }
+   let destination = match cur_position.variant {
+      Variant::Standard => king_to,
+      Variant::Chess960 => rook,
+   };
+   Some(Move {
+      origin: king,
+      destination,
+      promotion: PromotionTarget::None,
+   })
 }
 
-fn king_movegen(cur_position: &Position, color: usize, results: &mut Vec<CompressedMove>) {
+fn king_movegen(cur_position: &Position, color: usize, targets: u64, results: &mut Vec<CompressedMove>) {
    let king_position = cur_position.squares.pieces[color][KING];
    if king_position == 0 {
       return;
@@ -1822,16 +2786,16 @@ fn king_movegen(cur_position: &Position, color: usize, results: &mut Vec<Compres
 
    let king_index = king_position.trailing_zeros();
 
-   let moves = KING_ATTACKS[king_index as usize] & !cur_position.squares.all_pieces[color];
+   let moves = KING_ATTACKS[king_index as usize] & targets;
 
    add_moves(cur_position, color, king_index as u8, moves, results);
 }
 
-fn knight_movegen(cur_position: &Position, color: usize, results: &mut Vec<CompressedMove>) {
+fn knight_movegen(cur_position: &Position, color: usize, targets: u64, results: &mut Vec<CompressedMove>) {
    let mut knights = cur_position.squares.pieces[color][KNIGHT];
    while knights > 0 {
       let origin = pop_lsb(&mut knights);
-      let moves = KNIGHT_ATTACKS[origin as usize] & !cur_position.squares.all_pieces[color];
+      let moves = KNIGHT_ATTACKS[origin as usize] & targets;
       add_moves(cur_position, color, origin as u8, moves, results);
    }
 }
@@ -1858,6 +2822,7 @@ fn negative_ray_attack(direction: usize, square: usize, blockers: u64) -> u64 {
    attacks
 }
 
+#[allow(dead_code)]
 fn bitboard_to_string(bb: u64) -> String {
    let mut s = String::new();
 
@@ -1881,45 +2846,55 @@ fn bitboard_to_string(bb: u64) -> String {
 }
 
 fn bishop_attacks(cur_position: &Position, square: usize) -> u64 {
-   positive_ray_attack(NORTH_WEST, square, cur_position.squares.occupied)
-      | positive_ray_attack(NORTH_EAST, square, cur_position.squares.occupied)
-      | negative_ray_attack(SOUTH_WEST, square, cur_position.squares.occupied)
-      | negative_ray_attack(SOUTH_EAST, square, cur_position.squares.occupied)
+   diagonal_attacks(square, cur_position.squares.occupied)
 }
 
 fn rook_attacks(cur_position: &Position, square: usize) -> u64 {
-   positive_ray_attack(NORTH, square, cur_position.squares.occupied)
-      | positive_ray_attack(EAST, square, cur_position.squares.occupied)
-      | negative_ray_attack(SOUTH, square, cur_position.squares.occupied)
-      | negative_ray_attack(WEST, square, cur_position.squares.occupied)
+   straight_attacks(square, cur_position.squares.occupied)
 }
 
-fn bishop_movegen(cur_position: &Position, color: usize, results: &mut Vec<CompressedMove>) {
+/// Bishop moves from `square` on a board where `occupied` is in the way, which needn't be the current board
+fn diagonal_attacks(square: usize, occupied: u64) -> u64 {
+   positive_ray_attack(NORTH_WEST, square, occupied)
+      | positive_ray_attack(NORTH_EAST, square, occupied)
+      | negative_ray_attack(SOUTH_WEST, square, occupied)
+      | negative_ray_attack(SOUTH_EAST, square, occupied)
+}
+
+/// Rook moves from `square` on a board where `occupied` is in the way, which needn't be the current board
+fn straight_attacks(square: usize, occupied: u64) -> u64 {
+   positive_ray_attack(NORTH, square, occupied)
+      | positive_ray_attack(EAST, square, occupied)
+      | negative_ray_attack(SOUTH, square, occupied)
+      | negative_ray_attack(WEST, square, occupied)
+}
+
+fn bishop_movegen(cur_position: &Position, color: usize, targets: u64, results: &mut Vec<CompressedMove>) {
    let mut bishops = cur_position.squares.pieces[color][BISHOP];
    while bishops > 0 {
       let origin = pop_lsb(&mut bishops);
       let mut moves = bishop_attacks(cur_position, origin as usize);
-      moves &= !cur_position.squares.all_pieces[color];
+      moves &= targets;
       add_moves(cur_position, color, origin as u8, moves, results);
    }
 }
 
-fn rook_movegen(cur_position: &Position, color: usize, results: &mut Vec<CompressedMove>) {
+fn rook_movegen(cur_position: &Position, color: usize, targets: u64, results: &mut Vec<CompressedMove>) {
    let mut rooks = cur_position.squares.pieces[color][ROOK];
    while rooks > 0 {
       let origin = pop_lsb(&mut rooks);
       let mut moves = rook_attacks(cur_position, origin as usize);
-      moves &= !cur_position.squares.all_pieces[color];
+      moves &= targets;
       add_moves(cur_position, color, origin as u8, moves, results);
    }
 }
 
-fn queen_movegen(cur_position: &Position, color: usize, results: &mut Vec<CompressedMove>) {
+fn queen_movegen(cur_position: &Position, color: usize, targets: u64, results: &mut Vec<CompressedMove>) {
    let mut queens = cur_position.squares.pieces[color][QUEEN];
    while queens > 0 {
       let origin = pop_lsb(&mut queens);
       let mut moves = bishop_attacks(cur_position, origin as usize) | rook_attacks(cur_position, origin as usize);
-      moves &= !cur_position.squares.all_pieces[color];
+      moves &= targets;
       add_moves(cur_position, color, origin as u8, moves, results);
    }
 }
@@ -1944,11 +2919,249 @@ fn add_moves(cur_position: &Position, color: usize, origin: u8, mut moves: u64,
 }
 
 fn maybe_add_move(a_move: Move, cur_position: &Position, color: usize, results: &mut Vec<CompressedMove>) {
+   if leaves_king_safe(a_move, cur_position, color) {
+      results.push(a_move.compress());
+   }
+}
+
+fn leaves_king_safe(a_move: Move, cur_position: &Position, color: usize) -> bool {
    let mut cloned_state = cur_position.clone();
    cloned_state.apply_move(a_move);
+   !cloned_state.in_check(Color::from_num(color))
+}
 
-   if !cloned_state.in_check(Color::from_num(color)) {
-      results.push(a_move.compress());
+/// In the order `gen_moves` tries them
+const PROMOTION_TARGETS: [PromotionTarget; 4] = [
+   PromotionTarget::Queen,
+   PromotionTarget::Bishop,
+   PromotionTarget::Knight,
+   PromotionTarget::Rook,
+];
+
+const QUEEN_AND_KNIGHT: [PromotionTarget; 2] = [PromotionTarget::Queen, PromotionTarget::Knight];
+
+/// Lazily yields the legal moves for the side to move, one piece at a time. Produces the same moves as
+/// `gen_moves`, though not in the same order, without needing a buffer
+pub struct MoveGen<'a> {
+   position: &'a Position,
+   color: usize,
+   // our pieces that haven't had their moves generated yet
+   origins: u64,
+   origin: u8,
+   origin_is_pawn: bool,
+   destinations: u64,
+   destination: u8,
+   // the promotions left to try on `destination`
+   promotions: &'static [PromotionTarget],
+   // castling is tried last, kingside and then queenside
+   castling_sides: std::ops::Range<usize>,
+}
+
+impl<'a> MoveGen<'a> {
+   pub fn new(position: &'a Position) -> MoveGen<'a> {
+      let color = position.side_to_move.as_num();
+      MoveGen {
+         position,
+         color,
+         origins: position.squares.all_pieces[color],
+         origin: 0,
+         origin_is_pawn: false,
+         destinations: 0,
+         destination: 0,
+         promotions: &[],
+         castling_sides: KINGSIDE..QUEENSIDE + 1,
+      }
+   }
+
+   /// Where the piece on `origin` could go, ignoring whether it leaves the king in check
+   fn destinations(&self, origin: u8) -> u64 {
+      let squares = &self.position.squares;
+      let them = self.color ^ 1;
+      let targets = !squares.all_pieces[self.color] & !squares.pieces[them][KING];
+      match self.position.piece_kind_at(self.color, origin) {
+         Some(PAWN) => {
+            let forward = |bb: u64| if self.color == WHITE { bb << 8 } else { bb >> 8 };
+            let single_push = forward(1 << origin) & squares.unoccupied;
+            let start_rank = if self.color == WHITE { RANK_2 } else { RANK_7 };
+            let double_push = if (1 << origin) & start_rank != 0 {
+               forward(single_push) & squares.unoccupied
+            } else {
+               0
+            };
+            let captures =
+               PAWN_ATTACKS[self.color][origin as usize] & (squares.attackable[them] | self.position.en_passant_square);
+            single_push | double_push | captures
+         }
+         Some(KNIGHT) => KNIGHT_ATTACKS[origin as usize] & targets,
+         Some(BISHOP) => bishop_attacks(self.position, origin as usize) & targets,
+         Some(ROOK) => rook_attacks(self.position, origin as usize) & targets,
+         Some(QUEEN) => {
+            (bishop_attacks(self.position, origin as usize) | rook_attacks(self.position, origin as usize)) & targets
+         }
+         _ => KING_ATTACKS[origin as usize] & targets,
+      }
+   }
+}
+
+impl<'a> Iterator for MoveGen<'a> {
+   type Item = Move;
+
+   fn next(&mut self) -> Option<Move> {
+      loop {
+         let candidate = if let Some((promotion, rest)) = self.promotions.split_first() {
+            self.promotions = rest;
+            Move {
+               origin: self.origin,
+               destination: self.destination,
+               promotion: *promotion,
+            }
+         } else if self.destinations > 0 {
+            let destination = pop_lsb(&mut self.destinations) as u8;
+            if self.origin_is_pawn && (1 << destination) & (RANK_1 | RANK_8) != 0 {
+               self.destination = destination;
+               self.promotions = &PROMOTION_TARGETS;
+               continue;
+            }
+            Move {
+               origin: self.origin,
+               destination,
+               promotion: PromotionTarget::None,
+            }
+         } else if self.origins > 0 {
+            self.origin = pop_lsb(&mut self.origins) as u8;
+            self.origin_is_pawn = self.position.squares.pieces[self.color][PAWN] & (1 << self.origin) != 0;
+            self.destinations = self.destinations(self.origin);
+            continue;
+         } else if let Some(side) = self.castling_sides.next() {
+            match castling_move(self.position, self.color, side) {
+               Some(a_move) => a_move,
+               None => continue,
+            }
+         } else {
+            return None;
+         };
+         if leaves_king_safe(candidate, self.position, self.color) {
+            return Some(candidate);
+         }
+      }
+   }
+}
+
+/// Serialized forms that don't depend on how pieces are numbered, or on anything that can be worked out from the
+/// rest. The derived bitboards and zobrist key are recomputed on the way back in
+#[cfg(feature = "serde")]
+mod serde_repr {
+   use super::*;
+
+   #[derive(Serialize, Deserialize)]
+   pub struct PieceBitboards {
+      pawns: u64,
+      knights: u64,
+      bishops: u64,
+      rooks: u64,
+      queens: u64,
+      kings: u64,
+   }
+
+   #[derive(Serialize, Deserialize)]
+   pub struct BoardRepr {
+      white: PieceBitboards,
+      black: PieceBitboards,
+   }
+
+   impl From<Board> for BoardRepr {
+      fn from(board: Board) -> BoardRepr {
+         let bitboards = |color: usize| PieceBitboards {
+            pawns: board.pieces[color][PAWN],
+            knights: board.pieces[color][KNIGHT],
+            bishops: board.pieces[color][BISHOP],
+            rooks: board.pieces[color][ROOK],
+            queens: board.pieces[color][QUEEN],
+            kings: board.pieces[color][KING],
+         };
+         BoardRepr {
+            white: bitboards(WHITE),
+            black: bitboards(BLACK),
+         }
+      }
+   }
+
+   impl TryFrom<BoardRepr> for Board {
+      type Error = String;
+
+      fn try_from(repr: BoardRepr) -> Result<Board, String> {
+         let mut board = Board::empty();
+         for (color, bitboards) in [(WHITE, repr.white), (BLACK, repr.black)] {
+            board.pieces[color][PAWN] = bitboards.pawns;
+            board.pieces[color][KNIGHT] = bitboards.knights;
+            board.pieces[color][BISHOP] = bitboards.bishops;
+            board.pieces[color][ROOK] = bitboards.rooks;
+            board.pieces[color][QUEEN] = bitboards.queens;
+            board.pieces[color][KING] = bitboards.kings;
+         }
+         let total: u32 = board.pieces.iter().flatten().map(|x| x.count_ones()).sum();
+         board.update_derived_bitboards();
+         if total != board.occupied.count_ones() {
+            return Err("More than one piece on the same square".into());
+         }
+         Ok(board)
+      }
+   }
+
+   #[derive(Serialize, Deserialize)]
+   pub struct PositionRepr {
+      squares: Board,
+      white_kingside_castle: bool,
+      white_queenside_castle: bool,
+      black_kingside_castle: bool,
+      black_queenside_castle: bool,
+      castling_rook_files: [[u8; 2]; 2],
+      variant: Variant,
+      en_passant_square: u64,
+      side_to_move: Color,
+   }
+
+   impl From<Position> for PositionRepr {
+      fn from(position: Position) -> PositionRepr {
+         PositionRepr {
+            squares: position.squares,
+            white_kingside_castle: position.white_kingside_castle,
+            white_queenside_castle: position.white_queenside_castle,
+            black_kingside_castle: position.black_kingside_castle,
+            black_queenside_castle: position.black_queenside_castle,
+            castling_rook_files: position.castling_rook_files,
+            variant: position.variant,
+            en_passant_square: position.en_passant_square,
+            side_to_move: position.side_to_move,
+         }
+      }
+   }
+
+   impl TryFrom<PositionRepr> for Position {
+      type Error = String;
+
+      fn try_from(repr: PositionRepr) -> Result<Position, String> {
+         if repr.castling_rook_files.iter().flatten().any(|x| *x > 7) {
+            return Err("Castling rook files must be from 0 to 7".into());
+         }
+         if repr.en_passant_square.count_ones() > 1 {
+            return Err("There can be at most one en passant square".into());
+         }
+         let mut position = Position {
+            squares: repr.squares,
+            white_kingside_castle: repr.white_kingside_castle,
+            white_queenside_castle: repr.white_queenside_castle,
+            black_kingside_castle: repr.black_kingside_castle,
+            black_queenside_castle: repr.black_queenside_castle,
+            castling_rook_files: repr.castling_rook_files,
+            variant: repr.variant,
+            en_passant_square: repr.en_passant_square,
+            side_to_move: repr.side_to_move,
+            zobrist: 0,
+         };
+         position.zobrist = position.compute_zobrist();
+         Ok(position)
+      }
    }
 }
 
@@ -2008,6 +3221,73 @@ mod tests {
       );
    }
 
+   #[test]
+   fn move_constructor() {
+      let a_move = Move::new(52, 60, Some(PromotionTarget::Queen)).unwrap();
+      assert_eq!(a_move.origin(), 52);
+      assert_eq!(a_move.destination(), 60);
+      assert_eq!(a_move.promotion(), Some(PromotionTarget::Queen));
+      assert_eq!(a_move.to_string(), "e7e8q");
+      assert_eq!(a_move.to_string().parse::<Move>(), Ok(a_move));
+
+      let a_move = Move::new(0, 63, None).unwrap();
+      assert_eq!(a_move.promotion(), None);
+      assert_eq!(a_move.to_string().parse::<Move>(), Ok(a_move));
+
+      assert!(Move::new(64, 0, None).is_err());
+      assert!(Move::new(0, 64, None).is_err());
+      assert!(Move::new(255, 255, None).is_err());
+   }
+
+   #[test]
+   fn compressed_moves_round_trip() {
+      let mut moves = Vec::new();
+      State::from_start().gen_moves(&mut moves);
+      moves.push("a7a8q".parse::<Move>().unwrap().compress());
+      moves.push("h2h1n".parse::<Move>().unwrap().compress());
+      for a_move in moves {
+         let extracted = a_move.extract();
+         assert_eq!(extracted.compress().extract(), extracted);
+         let packed: u16 = a_move.into();
+         assert_eq!(CompressedMove::try_from(packed), Ok(a_move));
+      }
+      let e2e4 = "e2e4".parse::<Move>().unwrap().compress();
+      assert_eq!(u16::from(e2e4), 12 << 9 | 28 << 3);
+
+      assert!(CompressedMove::try_from(0b101).is_err());
+      assert!(CompressedMove::try_from(1 << 15).is_err());
+   }
+
+   #[test]
+   fn null_move_parsing() {
+      assert_eq!("0000".parse::<Move>(), Ok(Move::null()));
+      assert_eq!(Move::null().to_string(), "0000");
+      assert!(Move::null().is_null());
+      assert!(!"e2e4".parse::<Move>().unwrap().is_null());
+      assert!("000".parse::<Move>().is_err());
+   }
+
+   #[test]
+   fn null_move_only_passes_the_turn() {
+      let mut state = State::from_moves("e2e4").unwrap();
+      let before = state.clone();
+      let undo = state.make_move(Move::null());
+      assert!(state.position.squares == before.position.squares);
+      assert_eq!(state.position.side_to_move, Color::White);
+      assert_eq!(state.position.en_passant_square, 0);
+      assert_eq!(state.position.white_kingside_castle, before.position.white_kingside_castle);
+      assert_eq!(state.position.zobrist, state.position.compute_zobrist());
+      state.unmake_move(undo);
+      assert!(state == before);
+
+      // the turn can be passed back and forth
+      state.apply_move(Move::null());
+      state.apply_move(Move::null());
+      assert!(state.position.squares == before.position.squares);
+      assert_eq!(state.position.side_to_move, Color::Black);
+      assert_eq!(state.position.en_passant_square, 0);
+   }
+
    #[test]
    fn moves_to_algebraic() {
       let letters = ["a", "b", "c", "d", "e", "f", "g", "h"];
@@ -2016,6 +3296,10 @@ mod tests {
             for letter_2 in letters.iter() {
                for j in 1..=8 {
                   let t_move = format!("{}{}{}{}", letter, i, letter_2, j);
+                  if t_move == "a1a1" {
+                     // that's the null move, which is written 0000
+                     continue;
+                  }
                   assert_eq!(format!("{}", t_move.parse::<Move>().unwrap()), t_move);
                }
             }
@@ -2037,6 +3321,348 @@ mod tests {
       }
    }
 
+   #[test]
+   fn rejects_illegal_fen() {
+      // black to move, but the white king is attacked by the rook
+      let fen = "4k3/8/8/8/8/8/8/r3K3 b - - 0 1";
+      assert!(State::from_fen(fen).is_err());
+      assert!(State::from_fen_unchecked(fen).is_ok());
+      assert!(State::from_fen("4k3/8/8/8/8/8/8/r3K3 w - - 0 1").is_ok());
+
+      let two_kings = "4k3/8/8/8/8/8/8/K3K3 w - - 0 1";
+      assert!(State::from_fen(two_kings).is_err());
+      assert!(State::from_fen_unchecked(two_kings).is_ok());
+      assert!(State::from_fen("8/8/8/8/8/8/8/4K3 w - - 0 1").is_err());
+   }
+
+   #[test]
+   fn parse_error_kinds() {
+      let kind = |fen: &str| match State::from_fen(fen) {
+         Err(ParseError::BadSection(_)) => "section",
+         Err(ParseError::BadPiece(_)) => "piece",
+         Err(ParseError::BadCastling(_)) => "castling",
+         Err(ParseError::BadSquare(_)) => "square",
+         Err(ParseError::OutOfRange(_)) => "range",
+         Err(ParseError::IllegalPosition(_)) => "illegal",
+         Ok(_) => "ok",
+      };
+      assert_eq!(kind("4k3/8/8/8/8/8/8/4K3 w - - 0"), "section");
+      assert_eq!(kind("4k3/8/8/8/8/8/8/4K3 x - - 0 1"), "section");
+      assert_eq!(kind("4k3/8/8/8/8/8/8/4X3 w - - 0 1"), "piece");
+      assert_eq!(kind("4k3/8/8/8/8/8/8/4K3 w KX - 0 1"), "castling");
+      assert_eq!(kind("4k3/8/8/8/8/8/8/4K3 w - z9 0 1"), "square");
+      assert_eq!(kind("4k3/8/8/8/8/8/7/4K3 w - - 0 1"), "range");
+      assert_eq!(kind("4k3/8/8/8/8/8/8/K3K3 w - - 0 1"), "illegal");
+
+      assert!(matches!("e2e".parse::<Move>(), Err(ParseError::BadSection(_))));
+      assert!(matches!("e2i4".parse::<Move>(), Err(ParseError::BadSquare(_))));
+      assert!(matches!("e7e8k".parse::<Move>(), Err(ParseError::BadPiece(_))));
+      assert!(matches!("x".parse::<PromotionTarget>(), Err(ParseError::BadPiece(_))));
+
+      // still readable for people
+      let message = State::from_fen("4k3/8/8/8/8/8/8/4X3 w - - 0 1").err().unwrap().to_string();
+      assert!(message.starts_with("malformed FEN"));
+   }
+
+   #[test]
+   fn fen_into_a_buffer() {
+      let mut state = State::from_start().apply_moves_from_uci("e2e4 e7e5 g1f3 b8c6");
+      let capacity = state.position_keys.capacity();
+      let fens = std::fs::read_to_string("tests/positions.fen").unwrap();
+      for _ in 0..10 {
+         for fen in fens.lines() {
+            State::from_fen_into(fen, &mut state).unwrap();
+            assert!(state == State::from_fen(fen).unwrap(), "{}", fen);
+         }
+      }
+      assert_eq!(state.position_keys.capacity(), capacity);
+
+      let before = state.clone();
+      assert!(State::from_fen_into("4k3/8/8/8/8/8/7/4K3 w - - 0 1", &mut state).is_err());
+      assert!(state == before);
+   }
+
+   #[test]
+   fn fen_rank_lengths() {
+      let message = |fen: &str| match State::from_fen(fen) {
+         Err(ParseError::OutOfRange(e)) => e,
+         _ => panic!("{} should have the wrong number of squares", fen),
+      };
+      assert_eq!(
+         message("4k3/8/8/8/8/8/8/4K4 w - - 0 1"),
+         "malformed FEN; rank 1 has more than 8 squares"
+      );
+      assert_eq!(
+         message("4k3/8/8/8/8/8/8/4K2R1 w - - 0 1"),
+         "malformed FEN; rank 1 has more than 8 squares"
+      );
+      assert_eq!(
+         message("4k3/8/8/8/3p3/8/8/4K3 w - - 0 1"),
+         "malformed FEN; rank 4 has only 7 squares"
+      );
+      assert_eq!(
+         message("4k3/8/8/8/8/8/4K3 w - - 0 1"),
+         "malformed FEN; expected 8 ranks in piece placement, found 7"
+      );
+      assert_eq!(
+         message("4k3/8/8/8/8/8/8/8/4K3 w - - 0 1"),
+         "malformed FEN; expected 8 ranks in piece placement, found 9"
+      );
+      assert_eq!(message("rnbqkbnrr/8/8/8/8/8/8/4K3 w - - 0 1"), "malformed FEN; rank 8 has more than 8 squares");
+   }
+
+   #[test]
+   fn checked_uci_moves() {
+      let state = State::from_start().apply_uci_moves_checked("e2e4 e7e5 g1f3").unwrap();
+      assert!(state == State::from_start().apply_moves_from_uci("e2e4 e7e5 g1f3"));
+
+      let err = State::from_start().apply_uci_moves_checked("e2e4 e7e5 e4e5").err().unwrap();
+      assert!(err.starts_with("move 2 (e4e5) is illegal"));
+      let err = State::from_start().apply_uci_moves_checked("e2e4 e7e9").err().unwrap();
+      assert!(err.starts_with("move 1 (e7e9) couldn't be parsed"));
+   }
+
+   #[test]
+   fn en_passant_square_validation() {
+      let bogus = "4k3/8/8/8/4P3/8/8/4K3 b - e4 0 1";
+      assert!(State::from_fen(bogus).is_err());
+      assert_eq!(State::from_fen_unchecked(bogus).unwrap().position.en_passant_square, 0);
+      // right rank, but there's no pawn that could have just pushed
+      assert!(State::from_fen("4k3/8/8/8/8/8/8/4K3 b - e3 0 1").is_err());
+
+      // after e2e4 with a black pawn on d4 ready to take
+      let fen = "rnbqkbnr/ppp1pppp/8/8/3pP3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 3";
+      let state = State::from_fen(fen).unwrap();
+      assert_eq!(state.position.en_passant_square, 1 << algebraic_to_index("e3").unwrap());
+      assert_eq!(state.to_fen(), fen);
+
+      // after plain e2e4 nothing can take, so the square is dropped
+      let state = State::from_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1").unwrap();
+      assert_eq!(state.position.en_passant_square, 0);
+      let without = State::from_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1").unwrap();
+      assert!(state.position == without.position);
+   }
+
+   #[test]
+   fn fen_round_trip() {
+      for fen in [
+         START_FEN,
+         "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+         "4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1",
+         "7k/8/6K1/8/8/8/8/R7 b - - 99 80",
+      ]
+      .iter()
+      {
+         assert_eq!(State::from_fen(fen).unwrap().to_fen(), *fen);
+      }
+
+      let state = State::from_moves("e2e4 c7c5 g1f3").unwrap();
+      assert_eq!(state.to_fen(), "rnbqkbnr/pp1ppppp/8/2p5/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2");
+   }
+
+   #[test]
+   fn piece_at_start() {
+      let board = State::from_start().position.squares;
+      assert_eq!(board.piece_at(0), Square::WhiteRook);
+      assert_eq!(board.piece_at(4), Square::WhiteKing);
+      assert_eq!(board.piece_at(12), Square::WhitePawn);
+      assert_eq!(board.piece_at(28), Square::Empty);
+      assert_eq!(board.piece_at(59), Square::BlackQueen);
+   }
+
+   #[test]
+   fn position_display() {
+      let shown = State::from_start().position.to_string();
+      let lines: Vec<&str> = shown.lines().collect();
+      assert_eq!(lines.len(), 9);
+      assert_eq!(lines[0], "rnbqkbnr");
+      assert_eq!(lines[4], "........");
+      assert_eq!(lines[7], "RNBQKBNR");
+      assert_eq!(lines[8], "white to move, castling KQkq, en passant -");
+
+      let shown = State::from_fen("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1").unwrap().position.to_string();
+      assert!(shown.ends_with("white to move, castling -, en passant d6"));
+   }
+
+   #[test]
+   fn move_gen_matches_gen_moves() {
+      fn assert_same_moves(state: &State) {
+         let mut expected: Vec<Move> = Vec::new();
+         let mut moves = Vec::new();
+         state.gen_moves(&mut moves);
+         expected.extend(moves.iter().map(CompressedMove::extract));
+         let mut lazy: Vec<Move> = state.move_gen().collect();
+         let key = |x: &Move| (x.origin, x.destination, x.promotion.as_num());
+         expected.sort_by_key(key);
+         lazy.sort_by_key(key);
+         assert_eq!(lazy, expected, "{}", state.to_fen());
+      }
+
+      let fens = std::fs::read_to_string("tests/positions.fen").unwrap();
+      for fen in fens.lines() {
+         let mut state = State::from_fen(fen).unwrap();
+         assert_same_moves(&state);
+         // and a ply deeper, for en passant and the like
+         let mut moves = Vec::new();
+         state.gen_moves(&mut moves);
+         for a_move in moves {
+            let undo = state.make_move(a_move.extract());
+            assert_same_moves(&state);
+            state.unmake_move(undo);
+         }
+      }
+      let chess960 = State::from_fen_variant("1r2k1r1/8/8/8/8/8/8/1R2K1R1 w GBgb - 0 1", Variant::Chess960).unwrap();
+      assert_same_moves(&chess960);
+   }
+
+   #[test]
+   fn checkmate_and_stalemate() {
+      // fool's mate
+      let mated = State::from_moves("f2f3 e7e5 g2g4 d8h4").unwrap();
+      assert!(mated.is_checkmate());
+      assert!(!mated.is_stalemate());
+
+      let stalemated = State::from_fen("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1").unwrap();
+      assert!(stalemated.is_stalemate());
+      assert!(!stalemated.is_checkmate());
+
+      let start = State::from_start();
+      assert!(!start.is_checkmate());
+      assert!(!start.is_stalemate());
+   }
+
+   #[test]
+   fn annotated_moves() {
+      // Ra8 mates, Ra7 and Rh1 don't even check
+      let position = State::from_fen("7k/8/6K1/8/8/8/8/R7 w - - 0 1").unwrap().position;
+      let annotated = position.gen_moves_annotated(Color::White);
+      let flags = |uci: &str| annotated.iter().find(|x| x.0 == uci.parse().unwrap()).unwrap().1;
+      assert_eq!(flags("a1a8"), MoveFlags { check: true, mate: true });
+      assert_eq!(flags("a1a7"), MoveFlags::default());
+      assert_eq!(flags("a1h1"), MoveFlags { check: true, mate: false });
+      let mut moves = Vec::new();
+      position.gen_moves_color(Color::White, &mut moves);
+      assert!(annotated.iter().map(|x| x.0).eq(moves.iter().map(|x| x.extract())));
+      // even when it isn't black's turn, its king has Kg8, which is no check
+      assert_eq!(
+         position.gen_moves_annotated(Color::Black),
+         vec![("h8g8".parse().unwrap(), MoveFlags::default())]
+      );
+   }
+
+   #[test]
+   fn static_evaluation() {
+      let start = State::from_start().position;
+      assert!(start.evaluate().abs() <= 50, "{}", start.evaluate());
+      assert_eq!(start.evaluate_white_pov(), start.evaluate());
+
+      // white is a queen up, whoever is to move
+      let white_to_move = State::from_fen("3qk3/8/8/8/8/8/8/2QQK3 w - - 0 1").unwrap().position;
+      let black_to_move = State::from_fen("3qk3/8/8/8/8/8/8/2QQK3 b - - 0 1").unwrap().position;
+      assert!(white_to_move.evaluate() >= 700, "{}", white_to_move.evaluate());
+      assert_eq!(black_to_move.evaluate(), -black_to_move.evaluate_white_pov());
+      assert!(black_to_move.evaluate() <= -700, "{}", black_to_move.evaluate());
+      assert_eq!(white_to_move.evaluate_white_pov(), black_to_move.evaluate_white_pov());
+   }
+
+   #[test]
+   fn status_reasons() {
+      let status = |fen: &str| {
+         let state = State::from_fen(fen).unwrap();
+         let mut moves = Vec::new();
+         state.gen_moves(&mut moves);
+         state.status(&moves)
+      };
+      // fool's mate
+      assert_eq!(
+         status("rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3"),
+         GameStatus::Victory(Color::Black, VictoryReason::Checkmate)
+      );
+      assert_eq!(status("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1"), GameStatus::Draw(DrawReason::Stalemate));
+      assert_eq!(status("7k/8/6K1/8/8/8/8/8 b - - 0 1"), GameStatus::Draw(DrawReason::InsufficientMaterial));
+      assert_eq!(status("7k/8/6K1/8/8/8/8/R7 b - - 100 90"), GameStatus::Draw(DrawReason::FiftyMoveRule));
+   }
+
+   #[test]
+   fn legal_moves() {
+      let state = State::from_start();
+      assert!(state.is_legal("e2e4".parse().unwrap()));
+      assert!(state.is_legal("g1f3".parse().unwrap()));
+      assert!(!state.is_legal("e2e5".parse().unwrap()));
+      assert!(!state.is_legal("e1e2".parse().unwrap()));
+      assert!(!state.is_legal("e7e5".parse().unwrap()));
+      assert!(!state.is_legal(Move::null()));
+
+      // pinned to the king
+      let pinned = State::from_fen("4k3/4r3/8/8/8/8/4B3/4K3 w - - 0 1").unwrap();
+      assert!(!pinned.is_legal("e2d3".parse().unwrap()));
+      assert!(pinned.is_legal("e1d1".parse().unwrap()));
+   }
+
+   #[test]
+   fn derived_bitboards_stay_consistent() {
+      fn assert_consistent(board: &Board) {
+         let mut rebuilt = *board;
+         rebuilt.update_derived_bitboards();
+         assert!(rebuilt == *board);
+      }
+
+      // captures, castling, en passant and a promotion
+      let moves = "e2e4 d7d5 e4d5 g8f6 g1f3 e7e5 d5e6 f8d6 f1c4 e8g8 e6f7 g8h8 e1g1 f8e8 f7e8q";
+      let mut state = State::from_start();
+      let mut undos = Vec::new();
+      for a_move in moves.split_whitespace() {
+         let a_move = a_move.parse().unwrap();
+         assert!(state.is_legal(a_move));
+         undos.push(state.make_move(a_move));
+         assert_consistent(&state.position.squares);
+      }
+      while let Some(undo) = undos.pop() {
+         state.unmake_move(undo);
+         assert_consistent(&state.position.squares);
+      }
+      assert!(state == State::from_start());
+   }
+
+   #[test]
+   fn rook_takes_rook_revokes_both_rights() {
+      let mut state = State::from_fen("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1").unwrap();
+      state.apply_move("a1a8".parse().unwrap());
+      assert!(!state.position.white_queenside_castle);
+      assert!(!state.position.black_queenside_castle);
+      assert!(state.position.white_kingside_castle);
+      assert!(state.position.black_kingside_castle);
+      assert_eq!(state.position.zobrist, state.position.compute_zobrist());
+   }
+
+   #[test]
+   fn en_passant_capture() {
+      let mut state = State::from_fen("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1").unwrap();
+      let before = state.clone();
+      let undo = state.make_move("e5d6".parse().unwrap());
+      assert_eq!(state.position.squares.piece_at(35), Square::Empty);
+      assert_eq!(state.position.squares.piece_at(43), Square::WhitePawn);
+      assert_eq!(state.position.squares.pieces[BLACK][PAWN], 0);
+      state.unmake_move(undo);
+      assert!(state == before);
+
+      let mut state = State::from_fen("4k3/2p5/8/1P6/8/8/8/4K3 b - - 0 1").unwrap();
+      state.apply_move("c7c5".parse().unwrap());
+      state.apply_move("b5c6".parse().unwrap());
+      assert_eq!(state.position.squares.pieces[BLACK][PAWN], 0);
+   }
+
+   #[test]
+   fn only_pawns_capture_en_passant() {
+      let mut state = State::from_fen("4k3/8/8/3pP3/8/Q7/8/4K3 w - d6 0 1").unwrap();
+      let a_move = "a3d6".parse().unwrap();
+      assert!(state.is_legal(a_move));
+      state.apply_move(a_move);
+      assert_eq!(state.position.squares.piece_at(35), Square::BlackPawn);
+      assert_eq!(state.position.squares.piece_at(43), Square::WhiteQueen);
+      assert_eq!(state.position.en_passant_square, 0);
+   }
+
    #[test]
    fn movegen_test() {
       let mut moves: Vec<CompressedMove> = Vec::new();
@@ -2123,4 +3749,474 @@ mod tests {
 
       assert!(moves.contains(&a_move.compress()));
    }
+
+   #[test]
+   fn checkmate_beats_fifty_move_rule() {
+      let mut moves = Vec::new();
+      let game = State::from_fen("7k/8/6K1/8/8/8/8/R7 w - - 99 80").unwrap();
+
+      let mate = game.apply_moves_from_uci("a1a8");
+      assert_eq!(mate.halfmove_clock, 100);
+      mate.gen_moves(&mut moves);
+      assert_eq!(mate.status(&moves), GameStatus::Victory(Color::White, VictoryReason::Checkmate));
+
+      let no_mate = game.apply_moves_from_uci("a1a7");
+      assert_eq!(no_mate.halfmove_clock, 100);
+      no_mate.gen_moves(&mut moves);
+      assert_eq!(no_mate.status(&moves), GameStatus::Draw(DrawReason::FiftyMoveRule));
+   }
+
+   #[test]
+   fn moves_with_states() {
+      let state = State::from_start();
+      let pairs = state.legal_moves_with_states();
+      assert_eq!(pairs.len(), 20);
+      for (a_move, new_state) in pairs.iter() {
+         assert_eq!(new_state.position.side_to_move, Color::Black);
+         assert!(*new_state == state.apply_moves_from_uci(&a_move.to_string()));
+      }
+      let mut moves = Vec::new();
+      state.gen_moves(&mut moves);
+      assert!(pairs.iter().map(|x| x.0).eq(moves.iter().map(|x| x.extract())));
+   }
+
+   #[test]
+   fn attackers_of_a_square() {
+      // the bishop on b2 is behind its own pawn, so only the pawn counts
+      let state = State::from_fen("4k3/4r3/2n2p2/Q7/3P4/5N2/1B6/4RK2 w - - 0 1").unwrap();
+      let squares = |names: &[&str]| names.iter().fold(0u64, |acc, x| acc | 1 << algebraic_to_index(x).unwrap());
+      let e5 = algebraic_to_index("e5").unwrap() as usize;
+      assert_eq!(state.position.attackers_of(e5, Color::White), squares(&["a5", "d4", "f3", "e1"]));
+      assert_eq!(state.position.attackers_of(e5, Color::Black), squares(&["e7", "c6", "f6"]));
+      assert!(state.position.square_is_attacked(Color::Black, e5));
+      // an occupied square counts its defenders
+      let d4 = algebraic_to_index("d4").unwrap() as usize;
+      assert_eq!(state.position.attackers_of(d4, Color::White), squares(&["b2", "f3"]));
+      assert_eq!(state.position.attackers_of(d4, Color::Black), squares(&["c6"]));
+   }
+
+   #[test]
+   fn checking_moves() {
+      let checks = |fen: &str| {
+         let position = State::from_fen(fen).unwrap().position;
+         let mut moves = Vec::new();
+         position.gen_checks_color(position.side_to_move, &mut moves);
+         let mut moves: Vec<String> = moves.iter().map(|x| x.extract().to_string()).collect();
+         moves.sort();
+         moves
+      };
+      // every knight move uncovers the rook, and nothing else checks
+      assert_eq!(
+         checks("4k3/8/8/8/8/8/4N3/4R1K1 w - - 0 1"),
+         ["e2c1", "e2c3", "e2d4", "e2f4", "e2g3"]
+      );
+      assert_eq!(checks("4k3/8/8/8/4N3/8/8/6K1 w - - 0 1"), ["e4d6", "e4f6"]);
+      // the rook gives check from its castled square
+      assert_eq!(checks("5k2/8/8/8/8/8/8/4K2R w K - 0 1"), ["e1g1", "h1f1", "h1h8"]);
+      assert!(checks(START_FEN).is_empty());
+      // the king leaving e1 opens the first rank
+      assert!(checks("8/8/8/8/8/8/8/R3K2k w Q - 0 1").contains(&"e1c1".to_owned()));
+      // so does the pawn leaving g7, for the new piece on h8
+      assert_eq!(checks("7n/6P1/8/8/8/8/8/k5K1 w - - 0 1"), ["g7h8b", "g7h8q"]);
+      // only en passant takes both pawns off the fifth rank
+      assert_eq!(checks("8/8/8/RPp3k1/8/8/8/7K w - c6 0 1"), ["b5c6"]);
+
+      // the same moves as making each one and looking
+      for fen in [
+         START_FEN,
+         "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+         "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R b KQkq - 0 1",
+         "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+         "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+         "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+         "4k3/8/8/8/1b6/8/3N4/R3K2r w Q - 0 1",
+      ] {
+         let position = State::from_fen(fen).unwrap().position;
+         let mut moves = Vec::new();
+         position.gen_moves_color(position.side_to_move, &mut moves);
+         let expected: Vec<CompressedMove> = moves
+            .into_iter()
+            .filter(|x| {
+               let mut after = position.clone();
+               after.make_move(x.extract());
+               after.in_check(!position.side_to_move)
+            })
+            .collect();
+         let mut moves = Vec::new();
+         position.gen_checks_color(position.side_to_move, &mut moves);
+         assert!(moves == expected, "{}", fen);
+      }
+   }
+
+   #[test]
+   fn pins() {
+      let squares = |names: &[&str]| names.iter().fold(0u64, |acc, x| acc | 1 << algebraic_to_index(x).unwrap());
+      let square = |name: &str| algebraic_to_index(name).unwrap() as usize;
+
+      let knight = State::from_fen("4k3/8/8/8/4r3/8/4N3/4K3 w - - 0 1").unwrap().position;
+      assert_eq!(knight.pinned_pieces(Color::White), squares(&["e2"]));
+      assert_eq!(knight.pin_ray(square("e2")), squares(&["e2", "e3", "e4"]));
+      assert_eq!(knight.pinned_pieces(Color::Black), 0);
+
+      let bishop = State::from_fen("4k3/8/8/8/8/2b5/3B4/4K3 w - - 0 1").unwrap().position;
+      assert_eq!(bishop.pinned_pieces(Color::White), squares(&["d2"]));
+      assert_eq!(bishop.pin_ray(square("d2")), squares(&["d2", "c3"]));
+
+      // two of our pieces in the way, so neither is pinned
+      let blocked = State::from_fen("4k3/8/8/8/4r3/4P3/4N3/4K3 w - - 0 1").unwrap().position;
+      assert_eq!(blocked.pinned_pieces(Color::White), 0);
+      assert_eq!(blocked.pin_ray(square("e2")), 0);
+      // a rook can't pin along a diagonal
+      let rook = State::from_fen("4k3/8/8/8/8/2r5/3B4/4K3 w - - 0 1").unwrap().position;
+      assert_eq!(rook.pinned_pieces(Color::White), 0);
+      // and a piece of the other side in front isn't ours to be pinned
+      let theirs = State::from_fen("4k3/8/8/8/4r3/8/4n3/4K3 w - - 0 1").unwrap().position;
+      assert_eq!(theirs.pinned_pieces(Color::White), 0);
+   }
+
+   #[test]
+   fn mirrored_positions() {
+      let mirror = |fen: &str| State::from_fen(fen).unwrap().position.mirror_vertical();
+      let black_to_start = State::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQkq - 0 1").unwrap();
+      assert!(mirror(START_FEN) == black_to_start.position);
+      let en_passant = State::from_fen("4k3/8/8/3Pp3/8/8/8/4K3 w - e6 0 1").unwrap();
+      assert!(mirror("4k3/8/8/8/3pP3/8/8/4K3 b - e3 0 1") == en_passant.position);
+      let castling = State::from_fen("4k2r/8/8/8/8/8/8/R3K3 b Qk - 0 1").unwrap();
+      assert!(mirror("r3k3/8/8/8/8/8/8/4K2R w Kq - 0 1") == castling.position);
+      let kiwipete = State::from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1").unwrap();
+      assert!(kiwipete.position.mirror_vertical().mirror_vertical() == kiwipete.position);
+   }
+
+   #[test]
+   fn insufficient_material() {
+      let draw = |fen: &str| State::from_fen(fen).unwrap().position.insufficient_material();
+      assert!(draw("4k3/8/8/8/8/8/8/4K3 w - - 0 1"));
+      assert!(draw("4k3/8/8/8/8/8/8/2B1K3 w - - 0 1"));
+      assert!(draw("4k3/8/8/8/8/8/8/1N2K3 w - - 0 1"));
+      // c1 and f8 are both dark
+      assert!(draw("4kb2/8/8/8/8/8/8/2B1K3 w - - 0 1"));
+
+      // f1 is light, so mate is possible
+      assert!(!draw("4k3/8/8/8/8/8/8/2B1KB2 w - - 0 1"));
+      assert!(!draw("4kb2/8/8/8/8/8/8/4KB2 w - - 0 1"));
+      assert!(!draw("4k3/8/8/8/8/8/8/2B1Kn2 w - - 0 1"));
+      assert!(!draw("4k3/8/8/8/8/8/8/1NN1K3 w - - 0 1"));
+      assert!(!draw("4k3/8/8/8/8/8/8/1N2Kn2 w - - 0 1"));
+      assert!(!draw("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1"));
+
+      let mut moves = Vec::new();
+      let state = State::from_fen("4kb2/8/8/8/8/8/8/2B1K3 w - - 0 1").unwrap();
+      state.gen_moves(&mut moves);
+      assert_eq!(state.status(&moves), GameStatus::Draw(DrawReason::InsufficientMaterial));
+      let state = State::from_fen("4k3/8/8/8/8/8/8/2B1Kn2 w - - 0 1").unwrap();
+      state.gen_moves(&mut moves);
+      assert_eq!(state.status(&moves), GameStatus::Ongoing);
+      // a knight each can still mate
+      let state = State::from_fen("7k/5K1n/6N1/8/8/8/8/8 b - - 0 1").unwrap();
+      state.gen_moves(&mut moves);
+      assert_eq!(state.status(&moves), GameStatus::Victory(Color::White, VictoryReason::Checkmate));
+   }
+
+   #[test]
+   fn san_formatting() {
+      let cases = [
+         // disambiguation by file, then by rank
+         ("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1", "b1d2", "Nbd2"),
+         ("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1", "f1d2", "Nfd2"),
+         ("4k3/8/8/N7/8/8/8/N3K3 w - - 0 1", "a1b3", "N1b3"),
+         ("4k3/8/8/N7/8/8/8/N3K3 w - - 0 1", "a5c6", "Nc6"),
+         ("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", "e5d6", "exd6"),
+         ("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", "e5e6", "e6"),
+         ("7k/8/6K1/8/8/8/8/R7 w - - 0 1", "a1a8", "Ra8#"),
+         ("k7/4P3/8/8/8/8/8/4K3 w - - 0 1", "e7e8q", "e8=Q+"),
+         ("k7/4P3/8/8/8/8/8/4K3 w - - 0 1", "e7e8n", "e8=N"),
+         ("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1g1", "O-O"),
+         ("r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1", "e8c8", "O-O-O"),
+         ("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "a1a8", "Rxa8+"),
+      ];
+      for (fen, uci, san) in cases.iter() {
+         let position = State::from_fen(fen).unwrap().position;
+         assert_eq!(position.move_to_san(uci.parse().unwrap()), *san, "{} in {}", uci, fen);
+      }
+   }
+
+   #[test]
+   fn san_parsing() {
+      // round trip through a short game with a capture, a castle and a check
+      let mut position = State::from_start().position;
+      for uci in "e2e4 e7e5 g1f3 b8c6 f1c4 g8f6 e1g1 f6e4 f1e1 d7d5 f3e5 c6e5 e1e4 d5c4 e4e5".split_whitespace() {
+         let a_move: Move = uci.parse().unwrap();
+         let san = position.move_to_san(a_move);
+         assert_eq!(position.san_to_move(&san), Ok(a_move), "{}", san);
+         position.apply_move(a_move);
+      }
+
+      let cases = [
+         ("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1", "Nbd2", "b1d2"),
+         ("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", "exd6 e.p.", "e5d6"),
+         ("r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1", "O-O-O", "e8c8"),
+         ("4k3/8/8/8/7Q/8/8/4r1K1 w - - 0 1", "Qh4xe1", "h4e1"),
+         ("4k3/P7/8/8/8/8/8/4K3 w - - 0 1", "a8=N", "a7a8n"),
+         ("4k3/P7/8/8/8/8/8/4K3 w - - 0 1", "a8Q+", "a7a8q"),
+      ];
+      for (fen, san, uci) in cases.iter() {
+         let position = State::from_fen(fen).unwrap().position;
+         assert_eq!(position.san_to_move(san), Ok(uci.parse().unwrap()), "{} in {}", san, fen);
+      }
+
+      let start = State::from_start().position;
+      assert!(start.san_to_move("Ke2").is_err());
+      assert!(start.san_to_move("e5").is_err());
+      assert!(start.san_to_move("O-O").is_err());
+      let two_knights = State::from_fen("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1").unwrap().position;
+      assert!(two_knights.san_to_move("Nd2").is_err());
+   }
+
+   #[test]
+   fn capture_only_movegen() {
+      // exd6 e.p., bxa8 and bxc8 with each promotion, Qxd5
+      let fens = [
+         "r1n1k3/1P6/8/3pP3/8/8/8/3QK3 w - d6 0 1",
+         "3qk3/8/8/8/3Pp3/8/1p6/R1N1K3 b - d3 0 1",
+      ];
+      for fen in fens.iter() {
+         let position = State::from_fen(fen).unwrap().position;
+         let mut moves = Vec::new();
+         position.gen_captures_color(position.side_to_move, &mut moves);
+         assert_eq!(moves.len(), 10);
+
+         let enemies = position.squares.all_pieces[(!position.side_to_move).as_num()];
+         for a_move in moves.iter().map(|x| x.extract()) {
+            let destination = 1 << a_move.destination;
+            assert!(destination & enemies != 0 || destination == position.en_passant_square);
+         }
+      }
+   }
+
+   fn perft_clone(state: &State, depth: u64) -> u64 {
+      if depth == 0 {
+         return 1;
+      }
+      let mut moves = Vec::new();
+      state.gen_moves(&mut moves);
+      let mut nodes = 0;
+      for a_move in moves {
+         let mut new_state = state.clone();
+         new_state.apply_move(a_move.extract());
+         nodes += perft_clone(&new_state, depth - 1);
+      }
+      nodes
+   }
+
+   fn perft_make_unmake(state: &mut State, depth: u64) -> u64 {
+      if depth == 0 {
+         return 1;
+      }
+      let mut moves = Vec::new();
+      state.gen_moves(&mut moves);
+      let mut nodes = 0;
+      for a_move in moves {
+         let before = state.clone();
+         let undo = state.make_move(a_move.extract());
+         nodes += perft_make_unmake(state, depth - 1);
+         state.unmake_move(undo);
+         assert!(*state == before, "{} was not taken back cleanly", a_move.extract());
+      }
+      nodes
+   }
+
+   #[test]
+   fn make_unmake_perft_matches_clone() {
+      let cases = [
+         (START_FEN, 3, 8902),
+         ("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", 2, 2039),
+         ("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1", 3, 2812),
+         ("rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8", 2, 1486),
+      ];
+      for (fen, depth, nodes) in cases.iter() {
+         let mut state = State::from_fen(fen).unwrap();
+         assert_eq!(perft_clone(&state, *depth), *nodes, "{}", fen);
+         assert_eq!(perft_make_unmake(&mut state, *depth), *nodes, "{}", fen);
+      }
+   }
+
+   #[test]
+   fn underpromotions() {
+      // d7xc8 is the only promotion
+      let mut state = State::from_fen("rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8").unwrap();
+      let mut all = Vec::new();
+      state.gen_moves_with(Promotions::All, &mut all);
+      let mut moves = Vec::new();
+      state.gen_moves(&mut moves);
+      assert_eq!(all, moves);
+      assert_eq!(all.len(), 44);
+      assert_eq!(perft_make_unmake(&mut state, 2), 1486);
+
+      let mut reduced = Vec::new();
+      state.gen_moves_with(Promotions::QueenAndKnight, &mut reduced);
+      let is_underpromotion =
+         |x: &CompressedMove| matches!(x.extract().promotion, PromotionTarget::Bishop | PromotionTarget::Rook);
+      all.retain(|x| !is_underpromotion(x));
+      assert_eq!(reduced, all);
+      assert_eq!(reduced.len(), 42);
+      assert!(reduced.contains(&"d7c8n".parse::<Move>().unwrap().compress()));
+
+      let mut captures = Vec::new();
+      state.position.gen_captures_color_with(Color::White, Promotions::QueenAndKnight, &mut captures);
+      assert!(captures.iter().any(|x| x.extract() == "d7c8q".parse().unwrap()));
+      assert!(!captures.iter().any(is_underpromotion));
+   }
+
+   #[test]
+   fn chess960_perft() {
+      let cases = [
+         ("bqnb1rkr/pp3ppp/3ppn2/2p5/5P2/P2P4/NPP1P1PP/BQ1BNRKR w HFhf - 2 9", 3, 12189),
+         ("b1q1rrkb/pppppppp/3nn3/8/P7/1PPP4/4PPPP/BQNNRKRB w GE - 1 9", 3, 10471),
+         ("qbbnnrkr/2pp2pp/p7/1p2pp2/8/P3PP2/1PPP1KPP/QBBNNR1R w hf - 0 9", 3, 13440),
+         ("1rqbkrbn/1ppppp1p/1n6/p1N3p1/8/2P4P/PP1PPPP1/1RQBKRBN w FBfb - 0 9", 3, 14569),
+         // standard chess is a Chess960 position too
+         ("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", 2, 2039),
+      ];
+      for (fen, depth, nodes) in cases.iter() {
+         let mut state = State::from_fen_variant(fen, Variant::Chess960).unwrap();
+         assert_eq!(perft_clone(&state, *depth), *nodes, "{}", fen);
+         assert_eq!(perft_make_unmake(&mut state, *depth), *nodes, "{}", fen);
+      }
+   }
+
+   #[test]
+   fn chess960_castling() {
+      let state = State::from_fen_variant("4k3/8/8/8/8/8/8/R5KR w HA - 0 1", Variant::Chess960).unwrap();
+      assert_eq!(state.to_fen(), "4k3/8/8/8/8/8/8/R5KR w HA - 0 1");
+      let mut moves = Vec::new();
+      state.gen_moves(&mut moves);
+      let cases = [
+         // the king doesn't move at all
+         ("g1h1", "O-O", "4k3/8/8/8/8/8/8/R4RK1 b - - 1 1"),
+         // the rook ends up on the far side of the king's starting square
+         ("g1a1", "O-O-O", "4k3/8/8/8/8/8/8/2KR3R b - - 1 1"),
+      ];
+      for (uci, san, fen) in cases.iter() {
+         let a_move: Move = uci.parse().unwrap();
+         assert!(moves.iter().any(|x| x.extract() == a_move), "{}", uci);
+         assert_eq!(state.position.move_to_san(a_move), *san);
+         assert_eq!(state.position.san_to_move(san), Ok(a_move));
+         let after = state.apply_moves_from_uci(uci);
+         assert_eq!(after.to_fen(), *fen);
+         assert_eq!(after.position.hash_key(), after.position.compute_zobrist());
+      }
+
+      // castling rights need a rook to castle with
+      assert!(State::from_fen_variant("4k3/8/8/8/8/8/8/R5K1 w H - 0 1", Variant::Chess960).is_err());
+      assert!(State::from_fen("4k3/8/8/8/8/8/8/R5KR w HA - 0 1").is_err());
+   }
+
+   #[test]
+   fn zobrist_transpositions_match() {
+      let a = Position::from_moves("g1f3 g8f6 b1c3 b8c6").unwrap();
+      let b = Position::from_moves("b1c3 b8c6 g1f3 g8f6").unwrap();
+      assert_eq!(a.hash_key(), b.hash_key());
+      assert_ne!(a.hash_key(), State::from_start().position.hash_key());
+   }
+
+   #[test]
+   fn after_move_matches_apply_move() {
+      // castling, en passant, a capture and repeated positions
+      let moves = "e2e4 g8f6 e4e5 d7d5 e5d6 e7d6 g1f3 f8e7 f1e2 e8g8 e1g1 f6g4 f3g5 g4f6 g5f3 f6g4 f3g5 g4f6";
+      let mut in_place = State::from_start();
+      let mut copied = State::from_start();
+      for a_move in moves.split_whitespace().map(|x| x.parse().unwrap()) {
+         copied = copied.after_move(a_move);
+         in_place.apply_move(a_move);
+         assert!(in_place == copied, "{}", a_move);
+      }
+      assert_eq!(in_place.position_keys.len(), 18);
+      assert_eq!(in_place.repetitions(), 1);
+   }
+
+   #[test]
+   fn threefold_repetition() {
+      let mut moves = Vec::new();
+      let mut state = State::from_moves("g1f3 g8f6 f3g1 f6g8 g1f3 g8f6 f3g1").unwrap();
+      state.gen_moves(&mut moves);
+      assert_eq!(state.repetitions(), 1);
+      assert_eq!(state.status(&moves), GameStatus::Ongoing);
+      state.apply_move("f6g8".parse().unwrap());
+      state.gen_moves(&mut moves);
+      assert_eq!(state.repetitions(), 2);
+      assert_eq!(state.status(&moves), GameStatus::Draw(DrawReason::Repetition));
+   }
+
+   #[test]
+   fn threefold_right_after_a_pawn_move() {
+      // the position after 2... e5 is the oldest one the halfmove clock covers, and the first of the three
+      let mut moves = Vec::new();
+      let mut state = State::from_moves("e2e4 e7e5 g1f3 g8f6 f3g1 f6g8 g1f3 g8f6 f3g1").unwrap();
+      state.gen_moves(&mut moves);
+      assert_eq!(state.repetitions(), 1);
+      assert_eq!(state.status(&moves), GameStatus::Ongoing);
+      state.apply_move("f6g8".parse().unwrap());
+      state.gen_moves(&mut moves);
+      assert_eq!(state.halfmove_clock, 8);
+      assert_eq!(state.repetitions(), 2);
+      assert_eq!(state.status(&moves), GameStatus::Draw(DrawReason::Repetition));
+   }
+
+   #[test]
+   fn threefold_through_uncapturable_en_passant() {
+      // the position after 1. e4 comes up three times. The first time it has an en passant square,
+      // but as no black pawn can use it, that's still the same position
+      let mut moves = Vec::new();
+      let mut state = State::from_moves("e2e4 g8f6 g1f3 f6g8 f3g1 g8f6 g1f3 f6g8").unwrap();
+      state.gen_moves(&mut moves);
+      assert_eq!(state.status(&moves), GameStatus::Ongoing);
+      state.apply_move("f3g1".parse().unwrap());
+      state.gen_moves(&mut moves);
+      assert_eq!(state.repetitions(), 2);
+      assert_eq!(state.status(&moves), GameStatus::Draw(DrawReason::Repetition));
+   }
+
+   #[test]
+   fn capturable_en_passant_is_not_a_repetition() {
+      // after 3. e4 black could take en passant, so that position isn't the same as the two after it
+      let state = State::from_moves("g1f3 d7d5 b1c3 d5d4 e2e4 g8f6 f3g1 f6g8 g1f3 g8f6 f3g1 f6g8 g1f3").unwrap();
+      assert_eq!(state.repetitions(), 1);
+      assert_eq!(state.position_keys.len(), 13);
+   }
+
+   #[test]
+   fn zobrist_castling_rights_differ() {
+      let a = State::from_fen("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1").unwrap();
+      let b = State::from_fen("r3k2r/8/8/8/8/8/8/R3K2R w Kkq - 0 1").unwrap();
+      assert_ne!(a.position.hash_key(), b.position.hash_key());
+   }
+
+   #[test]
+   fn zobrist_incremental_matches_scratch() {
+      // castling, captures, en passant and promotion all in one line
+      let a = Position::from_moves("e2e4 d7d5 e4e5 f7f5 e5f6 g8h6 f6g7 e8f7 g7h8q b8c6 g1f3 c8e6 f1c4 d8d7 e1g1")
+         .unwrap();
+      assert_eq!(a.hash_key(), a.compute_zobrist());
+   }
+
+   #[cfg(feature = "serde")]
+   #[test]
+   fn serde_round_trip() {
+      let mid_game = State::from_start().apply_moves_from_uci("e2e4 d7d5 e4d5 d8d5 b1c3 d5a5 g1f3 c8g4 f1e2 b8c6 e1g1");
+      for state in [State::from_start(), mid_game] {
+         let json = serde_json::to_string(&state).unwrap();
+         let round_tripped: State = serde_json::from_str(&json).unwrap();
+         assert!(round_tripped == state);
+         assert_eq!(round_tripped.position.hash_key(), state.position.hash_key());
+      }
+
+      let a_move: Move = "e7e8q".parse().unwrap();
+      assert_eq!(serde_json::from_str::<Move>(&serde_json::to_string(&a_move).unwrap()).unwrap(), a_move);
+
+      // two pieces on one square
+      let json = serde_json::to_string(&State::from_start().position).unwrap();
+      let pawns = State::from_start().position.squares.pieces[WHITE][PAWN];
+      let bogus = json.replacen(&pawns.to_string(), &(pawns | 1).to_string(), 1);
+      assert!(serde_json::from_str::<Position>(&bogus).is_err());
+   }
 }
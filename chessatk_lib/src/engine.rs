@@ -1,43 +1,190 @@
-use crate::board::{Color, CompressedMove, Move, Position, State, WHITE, PAWN, KNIGHT, BISHOP, ROOK, QUEEN, BLACK, RANK_8, RANK_5, RANK_6, RANK_7, RANK_4, RANK_3, RANK_2, KING, RANK_1};
+use crate::board::{
+   CompressedMove, Move, Position, PromotionTarget, Promotions, State, PAWN, KNIGHT, BISHOP, ROOK, QUEEN, KING,
+};
+use crate::book::Book;
+use crate::eval::{evaluate, to_centipawns, to_white_pov, EvalParams};
 use crate::messages::{EngineMessage, InterfaceMessage};
-use log::trace;
-use rayon::prelude::*;
-use std::sync::mpsc;
+use crate::tt::{Bound, Entry, TranspositionTable, DEFAULT_HASH_MB};
+use log::{log_enabled, trace, warn, Level};
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
 use std::time::{Duration, Instant};
 
+// Infinite searches stop here. Deeper than the transposition table can record anyway
+const MAX_DEPTH: u64 = u8::MAX as u64;
+
+// How many nodes a search thread expands between checks of the stop flag
+const STOP_CHECK_INTERVAL: u64 = 1024;
+
+// Null move pruning searches this many plies shallower than a real move would be searched,
+// and is only tried with at least this much depth left
+const NULL_MOVE_REDUCTION: u64 = 2;
+const NULL_MOVE_MIN_DEPTH: u64 = 3;
+
+// Width of the window for searches that only ask whether a score beats a bound
+const NULL_WINDOW: f64 = 0.001;
+
+// Late move reductions search quiet moves after this many others one ply shallower,
+// when there is at least this much depth left
+const LMR_MIN_MOVES: usize = 3;
+const LMR_MIN_DEPTH: u64 = 3;
+
+// No line gets extended for checks more than this many times
+const MAX_CHECK_EXTENSIONS: u64 = 8;
+
+// Each depth is expected to take this many times as long as the one before. Measured from the last two depths when
+// they're long enough to time, but kept within bounds, as shallow depths are noisy
+const DEFAULT_BRANCHING_FACTOR: f64 = 3.0;
+const MIN_BRANCHING_FACTOR: f64 = 1.5;
+const MAX_BRANCHING_FACTOR: f64 = 10.0;
+
+/// Score for delivering checkmate at the root. Being mated `n` plies from the root scores `-(MATE - n)`,
+/// so faster mates score higher and slower ones lower
+pub const MATE: f64 = 10000.0;
+
+// Scores beyond this are mates rather than evaluations. No line is long enough to get under it
+const MATE_BOUND: f64 = MATE - 1000.0;
+
+/// How many moves until mate, if `score` is a mate score. Negative when the side to move is the one getting mated
+pub fn moves_to_mate(score: f64) -> Option<i64> {
+   if score >= MATE_BOUND {
+      Some(((MATE - score) as i64 + 1) / 2)
+   } else if score <= -MATE_BOUND {
+      Some(-(((MATE + score) as i64) / 2))
+   } else {
+      None
+   }
+}
+
+// The table is shared by every node, so mate scores go in counted from the node rather than from the root
+fn score_to_tt(score: f64, dist_from_root: u64) -> f64 {
+   if score >= MATE_BOUND {
+      score + dist_from_root as f64
+   } else if score <= -MATE_BOUND {
+      score - dist_from_root as f64
+   } else {
+      score
+   }
+}
+
+fn score_from_tt(score: f64, dist_from_root: u64) -> f64 {
+   if score >= MATE_BOUND {
+      score - dist_from_root as f64
+   } else if score <= -MATE_BOUND {
+      score + dist_from_root as f64
+   } else {
+      score
+   }
+}
+
 pub fn start(receiver: mpsc::Receiver<InterfaceMessage>, sender: mpsc::Sender<EngineMessage>) {
    let mut state = State::from_start();
    let mut last_eval = 0.0f64;
+   let mut tt = Arc::new(TranspositionTable::new(DEFAULT_HASH_MB));
+   let stop = Arc::new(AtomicBool::new(false));
+   let mut options = SearchOptions {
+      multi_pv: 1,
+      eval_params: EvalParams::default(),
+      threads: available_threads(),
+   };
+   let mut clock = Arc::new(Mutex::new(Clock::new(None)));
+   let mut book: Option<Book> = None;
+   // root moves for the next search only, from RestrictNextSearch
+   let mut search_moves: Option<Vec<Move>> = None;
+   let mut verbose = false;
+   // Searches run on their own thread so that we can still hear a Stop
+   let mut running_search: Option<thread::JoinHandle<f64>> = None;
+   // whether that search only ends once stopped, being infinite or pondering without a budget to run out
+   let mut unbounded = false;
    while let Ok(message) = receiver.recv() {
+      if let InterfaceMessage::Quit = message {
+         // nobody wants the result, and whoever asked for it may not be listening anymore
+         stop.store(true, Ordering::Relaxed);
+         if let Some(handle) = running_search.take() {
+            let _ = handle.join();
+         }
+         break;
+      }
+      if !matches!(message, InterfaceMessage::Stop | InterfaceMessage::PonderHit) {
+         // everything else waits for the search in progress to finish, which an unbounded search never would
+         if let Some(handle) = running_search.take() {
+            if unbounded {
+               stop.store(true, Ordering::Relaxed);
+            }
+            // the search's eval is relative to the side to move, but we report it + for white and - for black
+            last_eval = to_white_pov(handle.join().unwrap(), state.position.side_to_move);
+         }
+      }
+      // Book moves are played without searching. Infinite and ponder searches can't answer until they're
+      // stopped, so they search anyway, as do searches restricted to certain moves
+      let book_move = match message {
+         InterfaceMessage::GoDepth(_) | InterfaceMessage::GoTime(_) if search_moves.is_none() => {
+            book.as_ref().and_then(|x| x.pick_move(&state.position, &mut rand::thread_rng()))
+         }
+         _ => None,
+      };
+      if let Some(a_move) = book_move {
+         sender.send(EngineMessage::BestMove(Some(a_move))).unwrap();
+         continue;
+      }
       match message {
          InterfaceMessage::GoDepth(depth) => {
-            let (eval, best_move) = search(depth, &state);
-            if state.position.side_to_move == Color::Black {
-               // eval is always relative to side to move, but we want eval to be + for white and - for black
-               last_eval = -eval;
-            }
-            sender.send(EngineMessage::BestMove(best_move)).unwrap();
+            unbounded = false;
+            clock = Arc::new(Mutex::new(Clock::new(None)));
+            let limits = SearchLimits {
+               depth: Some(depth),
+               restrict_to: search_moves.take(),
+               verbose,
+               ..SearchLimits::default()
+            };
+            running_search = Some(spawn_search(&state, &tt, &stop, limits, &clock, &options, &sender));
          }
          InterfaceMessage::GoTime(time_budget) => {
-            let mut used_time = Duration::from_secs(0);
-            let mut depth = 1;
-            let (mut overall_eval, mut overall_best_move) = (0.0, None);
-            while used_time * 2 < time_budget {
-               let start = Instant::now();
-               let (eval, best_move) = search(depth, &state);
-               overall_eval = eval;
-               overall_best_move = best_move;
-               depth += 1;
-               used_time += start.elapsed();
-            }
-            if state.position.side_to_move == Color::Black {
-               // eval is always relative to side to move, but we want eval to be + for white and - for black
-               last_eval = -overall_eval;
-            }
-            sender.send(EngineMessage::BestMove(overall_best_move)).unwrap();
+            unbounded = false;
+            clock = Arc::new(Mutex::new(Clock::new(Some(time_budget))));
+            let limits = SearchLimits {
+               restrict_to: search_moves.take(),
+               verbose,
+               ..SearchLimits::default()
+            };
+            running_search = Some(spawn_search(&state, &tt, &stop, limits, &clock, &options, &sender));
+         }
+         InterfaceMessage::GoInfinite => {
+            unbounded = true;
+            clock = Arc::new(Mutex::new(Clock::new(None)));
+            let limits = SearchLimits {
+               restrict_to: search_moves.take(),
+               verbose,
+               ..SearchLimits::default()
+            };
+            running_search = Some(spawn_search(&state, &tt, &stop, limits, &clock, &options, &sender));
+         }
+         InterfaceMessage::GoPonder(time_budget) => {
+            unbounded = true;
+            clock = Arc::new(Mutex::new(Clock::pondering(time_budget)));
+            let limits = SearchLimits {
+               restrict_to: search_moves.take(),
+               verbose,
+               ..SearchLimits::default()
+            };
+            running_search = Some(spawn_search(&state, &tt, &stop, limits, &clock, &options, &sender));
+         }
+         InterfaceMessage::RestrictNextSearch(moves) => {
+            search_moves = Some(moves);
+         }
+         InterfaceMessage::PonderHit => {
+            // the search carries on from wherever it got to, now against the clock
+            let mut clock = clock.lock();
+            clock.started = Some(Instant::now());
+            unbounded = clock.budget.is_none();
+         }
+         InterfaceMessage::Stop => {
+            stop.store(true, Ordering::Relaxed);
          }
          InterfaceMessage::QueryEval => {
-            sender.send(EngineMessage::CurrentEval(last_eval)).unwrap();
+            sender.send(EngineMessage::CurrentEval(to_centipawns(last_eval))).unwrap();
          }
          InterfaceMessage::SetState(new_state) => {
             state = new_state;
@@ -45,120 +192,805 @@ pub fn start(receiver: mpsc::Receiver<InterfaceMessage>, sender: mpsc::Sender<En
          InterfaceMessage::ApplyMove(m) => {
             state.apply_move(m);
          }
+         InterfaceMessage::NewGame => {
+            state = State::from_start();
+            last_eval = 0.0;
+            // killers and history already start over with every search
+            tt.clear();
+         }
+         InterfaceMessage::SetHashSize(size_mb) => {
+            tt = Arc::new(TranspositionTable::new(size_mb));
+         }
+         InterfaceMessage::SetThreads(threads) => {
+            options.threads = threads.max(1);
+         }
+         InterfaceMessage::SetMultiPv(lines) => {
+            options.multi_pv = lines.max(1);
+         }
+         InterfaceMessage::SetEvalParams(eval_params) => {
+            options.eval_params = eval_params;
+         }
+         InterfaceMessage::LoadBook(path) => match Book::load(&path) {
+            Ok(new_book) => book = Some(new_book),
+            Err(e) => warn!("{}", e),
+         },
+         InterfaceMessage::SetMctsParams(_) => {
+            // only for mcts
+         }
+         InterfaceMessage::SetVerbosity(on) => {
+            verbose = on;
+         }
+         InterfaceMessage::Quit => unreachable!(),
       }
-      //eprintln!("{} -> {} @ {}. {}", best_move.unwrap(), eval, target_depth, board.fullmove_number);
-      //board = board.apply_move(best_move.unwrap());
-      //target_depth += 1;
    }
 }
 
-fn search(depth: u64, state: &State) -> (f64, Option<Move>) {
-   if state.prior_positions.iter().filter(|x| **x == state.position).count() >= 2 {
-      return (0.0, None);
+/// How long a search may take. Shared with the search thread, so that a ponder hit can start the clock
+/// on a search that is already running
+struct Clock {
+   budget: Option<Duration>,
+   started: Option<Instant>, // None while pondering
+}
+
+impl Clock {
+   fn new(budget: Option<Duration>) -> Clock {
+      Clock {
+         budget,
+         started: Some(Instant::now()),
+      }
    }
-   let search_time_start = Instant::now();
-   let mut max: f64 = std::f64::NEG_INFINITY;
-   let mut best_move = None;
+
+   /// Searching on the opponent's time. The clock doesn't start until the move we expected is played
+   fn pondering(budget: Option<Duration>) -> Clock {
+      Clock { budget, started: None }
+   }
+
+   /// Whether a depth expected to take `predicted` would run past the budget, and so isn't worth starting
+   fn cannot_finish(&self, predicted: Duration) -> bool {
+      match (self.budget, self.started) {
+         (Some(budget), Some(started)) => started.elapsed() + predicted >= budget,
+         _ => false,
+      }
+   }
+
+   /// Whether the whole budget is gone, so that the depth in progress has to be abandoned
+   fn expired(&self) -> bool {
+      match (self.budget, self.started) {
+         (Some(budget), Some(started)) => started.elapsed() >= budget,
+         _ => false,
+      }
+   }
+}
+
+/// Settings that apply to every search, until the interface changes them
+#[derive(Clone)]
+struct SearchOptions {
+   multi_pv: usize,
+   eval_params: EvalParams,
+   // how many threads search each position, sharing the transposition table
+   threads: usize,
+}
+
+impl SearchOptions {
+   /// One line with the default eval, on the calling thread
+   fn single() -> SearchOptions {
+      SearchOptions {
+         multi_pv: 1,
+         eval_params: EvalParams::default(),
+         threads: 1,
+      }
+   }
+}
+
+fn available_threads() -> usize {
+   thread::available_parallelism().map_or(1, |x| x.get())
+}
+
+/// What `analyze` may spend on a search, and which moves it may play. Limits left as `None` don't apply
+#[derive(Clone, Debug, Default)]
+pub struct SearchLimits {
+   pub depth: Option<u64>,
+   /// No new depth is started when it's predicted not to finish in the time left.
+   /// Once all of it has gone by, the depth in progress is abandoned
+   pub movetime: Option<Duration>,
+   /// No new depth is started once this many nodes have been searched
+   pub nodes: Option<u64>,
+   /// Search on one thread, rather than on every core. Threads share the transposition table, so in parallel
+   /// the scores depend on which thread gets where first. Together with a depth limit, this makes the same
+   /// position always get the same result
+   pub deterministic: bool,
+   /// Only these root moves are searched. Any that aren't legal are left out, and if none are, every move is
+   pub restrict_to: Option<Vec<Move>>,
+   /// Trace what every thread finds at every depth, if the log level allows it. Off by default, as that's a lot
+   /// of lines for a search on many threads
+   pub verbose: bool,
+}
+
+/// The outcome of a search, from the deepest depth that finished
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchResult {
+   /// None if the game is already over. Of moves that score the same, the one with the lowest origin square,
+   /// and then destination square, is picked
+   pub best_move: Option<Move>,
+   /// In pawns, for the side to move
+   pub score: f64,
+   pub depth: u64,
+   pub pv: Vec<Move>,
+   /// Over every depth, including one that was cut short
+   pub nodes: u64,
+}
+
+/// Searches until one of the `limits` is reached, without going through messages. Unless the search is
+/// deterministic, it's helped by a thread for every other core
+pub fn analyze(state: &State, limits: SearchLimits) -> SearchResult {
+   let tt = TranspositionTable::new(DEFAULT_HASH_MB);
+   let clock = Mutex::new(Clock::new(limits.movetime));
+   let options = SearchOptions {
+      multi_pv: 1,
+      eval_params: EvalParams::default(),
+      threads: if limits.deterministic { 1 } else { available_threads() },
+   };
+   // nobody's listening for the reports
+   let (sender, _receiver) = mpsc::channel();
+   iterative_deepening(state, &tt, &AtomicBool::new(false), &limits, &clock, &options, &sender)
+}
+
+/// Searches on a new thread, which sends the best move when done and returns the eval
+fn spawn_search(
+   state: &State,
+   tt: &Arc<TranspositionTable>,
+   stop: &Arc<AtomicBool>,
+   limits: SearchLimits,
+   clock: &Arc<Mutex<Clock>>,
+   options: &SearchOptions,
+   sender: &mpsc::Sender<EngineMessage>,
+) -> thread::JoinHandle<f64> {
+   stop.store(false, Ordering::Relaxed);
+   let state = state.clone();
+   let tt = Arc::clone(tt);
+   let stop = Arc::clone(stop);
+   let clock = Arc::clone(clock);
+   let options = options.clone();
+   let sender = sender.clone();
+   thread::spawn(move || {
+      let result = iterative_deepening(&state, &tt, &stop, &limits, &clock, &options, &sender);
+      sender.send(EngineMessage::BestMove(result.best_move)).unwrap();
+      result.score
+   })
+}
+
+/// How long the depth after `last` is expected to take, going by how much longer `last` took than `before_last`
+fn predict_next_depth(before_last: Duration, last: Duration) -> Duration {
+   let branching_factor = if before_last < Duration::from_millis(1) {
+      DEFAULT_BRANCHING_FACTOR
+   } else {
+      (last.as_secs_f64() / before_last.as_secs_f64()).clamp(MIN_BRANCHING_FACTOR, MAX_BRANCHING_FACTOR)
+   };
+   last.mul_f64(branching_factor)
+}
+
+/// What every thread of a search shares
+struct SharedSearch<'a> {
+   state: &'a State,
+   tt: &'a TranspositionTable,
+   stop: &'a AtomicBool,
+   limits: &'a SearchLimits,
+   clock: &'a Mutex<Clock>,
+   options: &'a SearchOptions,
+}
+
+/// Searches with `options.threads` threads until reaching the depth or node limit, running out of time, or being
+/// stopped. This is Lazy SMP: every thread runs its own iterative deepening on the same position, and the helpers
+/// mostly help by filling the shared transposition table with results the main thread can use. The main thread
+/// decides when the search is over, and the deepest result of any thread is played, the main thread's on a tie.
+/// The time comes from `clock` rather than `limits`, as pondering starts it late. Each depth the main thread
+/// finishes reports its best `multi_pv` lines, and the search as a whole reports its node count and speed at the end
+fn iterative_deepening(
+   state: &State,
+   tt: &TranspositionTable,
+   stop: &AtomicBool,
+   limits: &SearchLimits,
+   clock: &Mutex<Clock>,
+   options: &SearchOptions,
+   sender: &mpsc::Sender<EngineMessage>,
+) -> SearchResult {
+   let report = |depth: u64, lines: &[(f64, Vec<Move>)]| {
+      for (i, (score, pv)) in lines.iter().take(options.multi_pv).enumerate() {
+         sender
+            .send(EngineMessage::Info {
+               depth,
+               multipv: i + 1,
+               score: *score,
+               pv: pv.clone(),
+            })
+            .unwrap();
+      }
+   };
+   let search = SharedSearch {
+      state,
+      tt,
+      stop,
+      limits,
+      clock,
+      options,
+   };
+   let search_start = Instant::now();
+   let (main_result, helper_results) = thread::scope(|s| {
+      let helpers: Vec<_> = (1..options.threads)
+         .map(|i| {
+            let search = &search;
+            s.spawn(move || deepen(search, i, |_, _| ()))
+         })
+         .collect();
+      let result = deepen(&search, 0, report);
+      // helpers that are still partway through a depth, or a depth behind, only stop once told
+      stop.store(true, Ordering::Relaxed);
+      let helper_results: Vec<SearchResult> = helpers.into_iter().map(|x| x.join().unwrap()).collect();
+      (result, helper_results)
+   });
+   let nodes = main_result.nodes + helper_results.iter().map(|x| x.nodes).sum::<u64>();
+   let mut result = main_result;
+   for helper_result in helper_results {
+      if helper_result.depth > result.depth {
+         result = helper_result;
+         // the line being played should be the last one reported
+         report(result.depth, &[(result.score, result.pv.clone())]);
+      }
+   }
+   result.nodes = nodes;
+   let elapsed = search_start.elapsed();
+   let nps = if elapsed.is_zero() {
+      0
+   } else {
+      (nodes as f64 / elapsed.as_secs_f64()) as u64
+   };
+   sender
+      .send(EngineMessage::SearchStats {
+         depth: result.depth,
+         nodes,
+         nps,
+         time_ms: elapsed.as_millis() as u64,
+      })
+      .unwrap();
+   result
+}
+
+/// One thread's share of a search: one depth deeper at a time, until a limit is reached or `stop` is set. Running
+/// out of time stops the search partway through a depth, while the depth and node limits are only checked between
+/// depths. A depth that isn't predicted to finish in the time left isn't started at all. Odd numbered helpers stay
+/// a depth ahead, so that the threads aren't all searching the same thing. `report` hears of every finished depth
+fn deepen(search: &SharedSearch, thread_index: usize, report: impl Fn(u64, &[(f64, Vec<Move>)])) -> SearchResult {
+   let SharedSearch {
+      state,
+      tt,
+      stop,
+      limits,
+      clock,
+      options,
+   } = *search;
+   let search_start = Instant::now();
+   // the first depth can't be stopped, so that there is always a move to play
+   let (mut eval, lines, mut nodes) =
+      search_root(1, state, tt, &AtomicBool::new(false), None, options, limits);
+   report(1, &lines);
+   let mut pv = lines.into_iter().next().map(|x| x.1).unwrap_or_default();
+   let max_depth = limits.depth.unwrap_or(MAX_DEPTH);
+   let mut finished_depth = 1;
+   // how long the last two finished depths took
+   let mut depth_times = (Duration::ZERO, search_start.elapsed());
+   let mut depth = 2 + (thread_index % 2) as u64;
+   while depth <= max_depth && !stop.load(Ordering::Relaxed) {
+      let predicted = predict_next_depth(depth_times.0, depth_times.1);
+      if clock.lock().cannot_finish(predicted) || limits.nodes.is_some_and(|x| nodes >= x) {
+         break;
+      }
+      let depth_start = Instant::now();
+      // the workers stop the search themselves once the clock runs out
+      let (result, lines, depth_nodes) =
+         search_root(depth, state, tt, stop, Some(clock), options, limits);
+      // even a depth that was cut short took time, so its nodes count towards the speed
+      nodes += depth_nodes;
+      if stop.load(Ordering::Relaxed) {
+         // this depth was cut short, so its result can't be trusted
+         break;
+      }
+      report(depth, &lines);
+      depth_times = (depth_times.1, depth_start.elapsed());
+      eval = result;
+      pv = lines.into_iter().next().map(|x| x.1).unwrap_or_default();
+      finished_depth = depth;
+      depth += 1;
+   }
+   SearchResult {
+      best_move: pv.first().copied(),
+      score: eval,
+      depth: finished_depth,
+      pv,
+      nodes,
+   }
+}
+
+/// Triangular table of principal variations. Row `n` holds the best line found
+/// from the node `n` plies from the root, and is built from row `n + 1`
+struct PvTable {
+   lines: Vec<Vec<CompressedMove>>,
+}
+
+impl PvTable {
+   fn new() -> PvTable {
+      PvTable { lines: Vec::new() }
+   }
+
+   fn clear(&mut self, dist_from_root: u64) {
+      let ply = dist_from_root as usize;
+      if self.lines.len() < ply + 2 {
+         self.lines.resize_with(ply + 2, Vec::new);
+      }
+      self.lines[ply].clear();
+   }
+
+   /// `a_move` is the new best move at this ply, followed by whatever line the child just found
+   fn update(&mut self, dist_from_root: u64, a_move: CompressedMove) {
+      let ply = dist_from_root as usize;
+      let (parents, children) = self.lines.split_at_mut(ply + 1);
+      parents[ply].clear();
+      parents[ply].push(a_move);
+      parents[ply].extend_from_slice(&children[0]);
+   }
+
+   fn line(&self, dist_from_root: u64) -> &[CompressedMove] {
+      &self.lines[dist_from_root as usize]
+   }
+}
+
+/// How often each quiet move, by color, piece and destination, has caused a cutoff, weighted by depth
+type HistoryTable = [[[u64; 64]; 6]; 2];
+
+/// Everything a single search thread carries from node to node
+struct SearchContext<'a> {
+   tt: &'a TranspositionTable,
+   stop: &'a AtomicBool,
+   // when set, the search sets `stop` itself once the clock expires
+   clock: Option<&'a Mutex<Clock>>,
+   eval_params: &'a EvalParams,
+   pv: PvTable,
+   nodes_expanded: u64,
+   nodes_generated: u64,
+   // plies of check extension in the line being searched
+   extensions: u64,
+   // the last two quiet moves to cause a cutoff at each ply
+   killers: Vec<[Option<CompressedMove>; 2]>,
+   history: HistoryTable,
+   // for measuring move ordering: how many cutoffs there were, and how many moves it took to find them in total
+   cutoffs: u64,
+   moves_before_cutoffs: u64,
+   // these are only turned off to measure what they save
+   null_move_pruning: bool,
+   principal_variation_search: bool,
+   quiet_move_ordering: bool,
+}
+
+impl<'a> SearchContext<'a> {
+   fn new(tt: &'a TranspositionTable, stop: &'a AtomicBool, eval_params: &'a EvalParams) -> SearchContext<'a> {
+      SearchContext {
+         tt,
+         stop,
+         clock: None,
+         eval_params,
+         pv: PvTable::new(),
+         nodes_expanded: 0,
+         nodes_generated: 0,
+         extensions: 0,
+         killers: Vec::new(),
+         history: [[[0; 64]; 6]; 2],
+         cutoffs: 0,
+         moves_before_cutoffs: 0,
+         null_move_pruning: true,
+         principal_variation_search: true,
+         quiet_move_ordering: true,
+      }
+   }
+
+   /// Checking moves are searched a ply deeper, so that forcing lines are seen through to the end.
+   /// Returns the extension, which the caller takes back out of `extensions` once the move is searched
+   fn check_extension(&mut self, gives_check: bool) -> u64 {
+      if gives_check && self.extensions < MAX_CHECK_EXTENSIONS {
+         self.extensions += 1;
+         1
+      } else {
+         0
+      }
+   }
+
+   fn killers(&self, dist_from_root: u64) -> [Option<CompressedMove>; 2] {
+      self.killers.get(dist_from_root as usize).copied().unwrap_or([None; 2])
+   }
+
+   /// Remember a quiet move that caused a cutoff, to try it early in sibling nodes and elsewhere in the tree
+   fn record_quiet_cutoff(
+      &mut self,
+      dist_from_root: u64,
+      depth: u64,
+      color: usize,
+      piece: usize,
+      a_move: CompressedMove,
+   ) {
+      if !self.quiet_move_ordering {
+         return;
+      }
+      let ply = dist_from_root as usize;
+      if self.killers.len() <= ply {
+         self.killers.resize(ply + 1, [None; 2]);
+      }
+      let killers = &mut self.killers[ply];
+      if killers[0] != Some(a_move) {
+         killers[1] = killers[0];
+         killers[0] = Some(a_move);
+      }
+      self.history[color][piece][a_move.extract().destination as usize] += depth * depth;
+   }
+
+   /// A draw is worth `contempt` less than even to the side at the root, and so that much more to the other side
+   fn draw_score(&self, dist_from_root: u64) -> f64 {
+      if dist_from_root.is_multiple_of(2) {
+         -self.eval_params.contempt
+      } else {
+         self.eval_params.contempt
+      }
+   }
+
+   fn stopped(&self) -> bool {
+      self.stop.load(Ordering::Relaxed)
+   }
+
+   /// Polls the stop flag and the clock every so often. Every node that counts itself as expanded must call this
+   /// first, so that no multiple of the interval is skipped
+   fn should_stop(&self) -> bool {
+      if !self.nodes_expanded.is_multiple_of(STOP_CHECK_INTERVAL) {
+         return false;
+      }
+      if self.clock.is_some_and(|x| x.lock().expired()) {
+         // every other thread on this search sees it too
+         self.stop.store(true, Ordering::Relaxed);
+      }
+      self.stopped()
+   }
+}
+
+/// Returns the score and principal variation, best move first.
+/// If `stop` gets set partway through, the result is meaningless
+#[cfg(test)]
+fn search(depth: u64, state: &State, tt: &TranspositionTable, stop: &AtomicBool) -> (f64, Vec<Move>) {
+   let limits = SearchLimits::default();
+   let (score, lines, _) = search_root(depth, state, tt, stop, None, &SearchOptions::single(), &limits);
+   (score, lines.into_iter().next().map(|x| x.1).unwrap_or_default())
+}
+
+/// Searches to `depth` on the calling thread, one depth at a time. Uses neither threads nor the clock, so it also
+/// runs where there are no threads, like in the browser. Returns the score for the side to move and the best move
+pub fn search_blocking(state: &State, depth: u64) -> (f64, Option<Move>) {
+   let tt = TranspositionTable::new(DEFAULT_HASH_MB);
+   let stop = AtomicBool::new(false);
+   let limits = SearchLimits::default();
+   let options = SearchOptions::single();
+   let mut result = (0.0, None);
+   for depth in 1..=depth {
+      let (score, lines, _) = search_root(depth, state, &tt, &stop, None, &options, &limits);
+      result = (score, lines.first().and_then(|x| x.1.first().copied()));
+   }
+   result
+}
+
+/// Returns the score, the score and principal variation of the root moves with exact scores (best first), and the
+/// nodes expanded. The moves are searched one after another on the calling thread, hash move first. The first
+/// `multi_pv` get a full window, and every later one only has to be proven worse than the `multi_pv`th best so far,
+/// unless it isn't, in which case it gets a full window too. So at least the best `multi_pv` lines are exact, and
+/// the moves that were proven worse are left out. The limits are left to the caller, except for which root moves
+/// to search. A position that's already drawn by repetition or the fifty-move rule scores 0 for every move, but the
+/// moves are still searched and ranked, so that there's something to play if nobody claims the draw
+fn search_root(
+   depth: u64,
+   state: &State,
+   tt: &TranspositionTable,
+   stop: &AtomicBool,
+   clock: Option<&Mutex<Clock>>,
+   options: &SearchOptions,
+   limits: &SearchLimits,
+) -> (f64, Vec<(f64, Vec<Move>)>, u64) {
+   let verbose = limits.verbose && log_enabled!(Level::Trace);
+   // only timed for the trace log, as not every platform has a clock
+   let search_time_start = if verbose { Some(Instant::now()) } else { None };
+   let elapsed = || search_time_start.map(|x| x.elapsed().as_secs_f64()).unwrap_or_default();
+   let mut max: f64 = f64::NEG_INFINITY;
    let mut moves: Vec<CompressedMove> = Vec::new();
+   // every promotion at the root, where an underpromotion that dodges stalemate is worth finding
    state.gen_moves(&mut moves);
+   if let Some(ref restrict_to) = limits.restrict_to {
+      if moves.iter().any(|x| restrict_to.contains(&x.extract())) {
+         moves.retain(|x| restrict_to.contains(&x.extract()));
+      }
+   }
    let mut nodes_expanded = 1;
    let mut nodes_generated = 1 + moves.len() as u64;
    if moves.is_empty() && !state.position.in_check(state.position.side_to_move) {
-      return (0.0, None);
+      return (0.0, Vec::new(), nodes_expanded);
    }
-   if !moves.is_empty() && state.halfmove_clock >= 100 {
-      return (0.0, None);
-   }
-   let scores: Vec<_> = moves
-      .into_par_iter()
-      .map(|a_move| {
-         let mut new_state = state.clone();
-         new_state.apply_move(a_move.extract());
-         let mut ne = 0;
-         let mut ng = 0;
-         let score = -nega_max(
-            depth - 1,
-            1,
-            new_state,
-            std::f64::NEG_INFINITY,
-            std::f64::INFINITY,
-            &mut ne,
-            &mut ng,
-         );
-         (a_move, score, ne, ng)
-      })
-      .collect();
-   for (a_move, score, ne, ng) in scores {
-      nodes_expanded += ne;
-      nodes_generated += ng;
-      if score >= max {
-         max = score;
-         best_move = Some(a_move);
+   // checkmate trumps the fifty-move rule
+   let drawn = !moves.is_empty() && (state.repetitions() >= 2 || state.halfmove_clock >= 100);
+   let key = state.position.hash_key();
+   let hash_move = tt.probe(key).and_then(|x| x.best_move);
+   order_moves(&state.position, &mut moves, hash_move, [None; 2], &[[[0; 64]; 6]; 2]);
+   // searches one root move in the window (alpha, beta)
+   let search_move = |a_move: CompressedMove, alpha: f64, beta: f64| {
+      let mut new_state = state.clone();
+      new_state.apply_move(a_move.extract());
+      // a fresh context for each root move, so no killers or history carry over from the last search
+      let mut ctx = SearchContext::new(tt, stop, &options.eval_params);
+      ctx.clock = clock;
+      let extension = ctx.check_extension(new_state.position.in_check(new_state.position.side_to_move));
+      let score = -nega_max(depth - 1 + extension, 1, &mut new_state, -beta, -alpha, &mut ctx);
+      ctx.pv.clear(0);
+      ctx.pv.update(0, a_move);
+      (ctx, score)
+   };
+   // among equal scores, the lowest origin and then destination square goes first, so that the choice doesn't
+   // depend on the order the moves were searched in. Compressed moves sort in just that order
+   let square_order = |line: &Vec<Move>| line.first().map(|x| x.compress().0);
+   let mut lines: Vec<(f64, Vec<Move>)> = Vec::with_capacity(moves.len());
+   for a_move in moves {
+      let (ctx, score) = match lines.get(options.multi_pv - 1) {
+         None => search_move(a_move, f64::NEG_INFINITY, f64::INFINITY),
+         Some((worst_score, worst_line)) => {
+            // a tie only has to be proven against a move that sorts after this one
+            let alpha = if Some(a_move.0) < square_order(worst_line) {
+               worst_score - NULL_WINDOW
+            } else {
+               *worst_score
+            };
+            let (ctx, score) = search_move(a_move, alpha, alpha + NULL_WINDOW);
+            nodes_expanded += ctx.nodes_expanded;
+            nodes_generated += ctx.nodes_generated;
+            if score <= alpha {
+               // no better than the lines we already have, and not exact
+               continue;
+            }
+            search_move(a_move, f64::NEG_INFINITY, f64::INFINITY)
+         }
+      };
+      nodes_expanded += ctx.nodes_expanded;
+      nodes_generated += ctx.nodes_generated;
+      max = max.max(score);
+      lines.push((score, ctx.pv.line(0).iter().map(|x| x.extract()).collect()));
+      lines.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap().then_with(|| square_order(&a.1).cmp(&square_order(&b.1))));
+   }
+   if !drawn && !stop.load(Ordering::Relaxed) {
+      // for the next depth to search the best move first
+      let best_move = lines.first().and_then(|x| x.1.first()).map(|x| x.compress());
+      tt.store(
+         key,
+         Entry {
+            depth: depth.min(u64::from(u8::MAX)) as u8,
+            score: score_to_tt(max, 0),
+            bound: Bound::Exact,
+            best_move,
+         },
+      );
+   }
+   if drawn {
+      // still sorted by what they'd be worth if play went on
+      max = 0.0;
+      for line in lines.iter_mut() {
+         line.0 = 0.0;
       }
    }
+   if !verbose {
+      return (max, lines, nodes_expanded);
+   }
    trace!(
       "nodes generated: {} nodes expanded: {}",
       nodes_generated,
       nodes_expanded
    );
-   if let Some(b) = best_move {
+   if let Some(b) = lines.first().and_then(|x| x.1.first()) {
       trace!(
          "search @ depth {} took {}. best move: {}",
          depth,
-         search_time_start.elapsed().as_secs_f64(),
-         b.extract()
+         elapsed(),
+         b
       );
    } else {
       trace!(
          "search @ depth {} took {}. game over",
          depth,
-         search_time_start.elapsed().as_secs_f64(),
+         elapsed(),
       );
    }
-   (max, best_move.map(|x| x.extract()))
+   (max, lines, nodes_expanded)
 }
 
 fn nega_max(
    depth: u64,
    dist_from_root: u64,
-   state: State,
+   state: &mut State,
    mut alpha: f64,
-   beta: f64,
-   nodes_expanded: &mut u64,
-   nodes_generated: &mut u64,
+   mut beta: f64,
+   ctx: &mut SearchContext,
 ) -> f64 {
-   if state.prior_positions.iter().filter(|x| **x == state.position).count() >= 2 {
+   ctx.pv.clear(dist_from_root);
+   if ctx.should_stop() {
       return 0.0;
    }
+   if state.repetitions() >= 2 {
+      return ctx.draw_score(dist_from_root);
+   }
    if depth == 0 {
-      return evaluate(&state.position, state.position.side_to_move);
+      return quiesce(&mut state.position, alpha, beta, ctx);
+   }
+   let key = state.position.hash_key();
+   let original_alpha = alpha;
+   let tt_entry = ctx.tt.probe(key);
+   if let Some(entry) = tt_entry {
+      if u64::from(entry.depth) >= depth {
+         let score = score_from_tt(entry.score, dist_from_root);
+         match entry.bound {
+            Bound::Exact => return score,
+            Bound::Lower => alpha = alpha.max(score),
+            Bound::Upper => beta = beta.min(score),
+         }
+         if alpha >= beta {
+            return score;
+         }
+      }
+   }
+   // If we could pass and the opponent still couldn't bring the score under beta, a real move will surely
+   // do at least as well. Passing is illegal in check, and with only pawns left zugzwang is too common to assume
+   let us = state.position.side_to_move.as_num();
+   let has_pieces = state.position.squares.all_pieces[us]
+      & !(state.position.squares.pieces[us][PAWN] | state.position.squares.pieces[us][KING])
+      != 0;
+   if ctx.null_move_pruning
+      && depth >= NULL_MOVE_MIN_DEPTH
+      && has_pieces
+      && !state.position.in_check(state.position.side_to_move)
+      && evaluate(&state.position, state.position.side_to_move, ctx.eval_params) >= beta
+   {
+      // a pass isn't a repetition of anything, and nothing below it may pass again
+      let position_keys = std::mem::take(&mut state.position_keys);
+      let en_passant_square = state.position.make_null_move();
+      ctx.null_move_pruning = false;
+      let score = -nega_max(
+         depth - 1 - NULL_MOVE_REDUCTION,
+         dist_from_root + 1,
+         state,
+         -beta,
+         -beta + NULL_WINDOW,
+         ctx,
+      );
+      ctx.null_move_pruning = true;
+      state.position.unmake_null_move(en_passant_square);
+      state.position_keys = position_keys;
+      if score >= beta {
+         return beta;
+      }
    }
-   let mut max: f64 = -10000.0 + dist_from_root as f64;
+   // checkmate, unless a legal move turns up
+   let mut max: f64 = -MATE + dist_from_root as f64;
+   let mut best_move = None;
    let mut moves: Vec<CompressedMove> = Vec::new();
-   state.gen_moves(&mut moves);
-   //moves.sort_unstable_by(|x, y| evaluate(&x.1).partial_cmp(&evaluate(&y.1)).unwrap());
-   *nodes_expanded += 1;
-   *nodes_generated += moves.len() as u64;
+   state.gen_moves_with(Promotions::QueenAndKnight, &mut moves);
+   order_moves(
+      &state.position,
+      &mut moves,
+      tt_entry.and_then(|x| x.best_move),
+      ctx.killers(dist_from_root),
+      &ctx.history,
+   );
+   ctx.nodes_expanded += 1;
+   ctx.nodes_generated += moves.len() as u64;
    if moves.is_empty() && !state.position.in_check(state.position.side_to_move) {
       // stalemate
-      return 0.0;
+      return ctx.draw_score(dist_from_root);
    }
    if !moves.is_empty() && state.halfmove_clock >= 100 {
+      return ctx.draw_score(dist_from_root);
+   }
+   let in_check = state.position.in_check(state.position.side_to_move);
+   for (i, a_move) in moves.iter().copied().enumerate() {
+      let quiet = mvv_lva(&state.position, a_move.extract()) == 0;
+      let undo = state.make_move(a_move.extract());
+      let gives_check = state.position.in_check(state.position.side_to_move);
+      let extension = ctx.check_extension(gives_check);
+      let child_depth = depth - 1 + extension;
+      let score = if i == 0 || !ctx.principal_variation_search {
+         -nega_max(child_depth, dist_from_root + 1, state, -beta, -alpha, ctx)
+      } else {
+         // With good ordering the first move is usually best, so only prove each later move worse than alpha.
+         // Quiet moves late in the list are unlikely to be good and get proven worse at less depth
+         let reduce = quiet && i >= LMR_MIN_MOVES && depth >= LMR_MIN_DEPTH && !in_check && !gives_check;
+         let mut score = if reduce {
+            -nega_max(child_depth - 1, dist_from_root + 1, state, -alpha - NULL_WINDOW, -alpha, ctx)
+         } else {
+            alpha + NULL_WINDOW
+         };
+         if score > alpha {
+            score = -nega_max(child_depth, dist_from_root + 1, state, -alpha - NULL_WINDOW, -alpha, ctx);
+         }
+         if score > alpha && score < beta {
+            score = -nega_max(child_depth, dist_from_root + 1, state, -beta, -alpha, ctx);
+         }
+         score
+      };
+      ctx.extensions -= extension;
+      state.unmake_move(undo);
+      // only a strictly better move takes over, so a tie goes to the move that was ordered first
+      if score > max {
+         max = score;
+         best_move = Some(a_move);
+         ctx.pv.update(dist_from_root, a_move);
+      }
+      if max > alpha {
+         alpha = max;
+      }
+      if alpha >= beta {
+         if quiet {
+            let piece = piece_kind_at(&state.position, us, a_move.extract().origin).unwrap_or(PAWN);
+            ctx.record_quiet_cutoff(dist_from_root, depth, us, piece, a_move);
+         }
+         ctx.cutoffs += 1;
+         ctx.moves_before_cutoffs += i as u64 + 1;
+         break;
+      }
+   }
+   if ctx.stopped() {
+      // some of the scores above are junk from unwinding, keep them out of the table
+      return max;
+   }
+   let bound = if max <= original_alpha {
+      Bound::Upper
+   } else if max >= beta {
+      Bound::Lower
+   } else {
+      Bound::Exact
+   };
+   ctx.tt.store(
+      key,
+      Entry {
+         depth: depth.min(u64::from(u8::MAX)) as u8,
+         score: score_to_tt(max, dist_from_root),
+         bound,
+         best_move,
+      },
+   );
+   max
+}
+
+/// Search captures until the position is quiet, so that
+/// we never evaluate a position in the middle of an exchange
+fn quiesce(position: &mut Position, mut alpha: f64, beta: f64, ctx: &mut SearchContext) -> f64 {
+   if ctx.should_stop() {
       return 0.0;
    }
-   for a_move in moves {
-      let mut state = state.clone();
-      state.apply_move(a_move.extract());
+   // the side to move can usually do at least as well as the static evaluation
+   // by declining to capture anything
+   let stand_pat = evaluate(position, position.side_to_move, ctx.eval_params);
+   if stand_pat >= beta {
+      return stand_pat;
+   }
+   if stand_pat > alpha {
+      alpha = stand_pat;
+   }
 
-      let score = -nega_max(
-         depth - 1,
-         dist_from_root + 1,
-         state,
-         -beta,
-         -alpha,
-         nodes_expanded,
-         nodes_generated,
-      );
+   let mut moves: Vec<CompressedMove> = Vec::new();
+   position.gen_captures_color_with(position.side_to_move, Promotions::QueenAndKnight, &mut moves);
+   order_moves(position, &mut moves, None, [None; 2], &ctx.history);
+   ctx.nodes_expanded += 1;
+   ctx.nodes_generated += moves.len() as u64;
+
+   let mut max = stand_pat;
+   for a_move in moves {
+      let undo = position.make_move(a_move.extract());
+      let score = -quiesce(position, -beta, -alpha, ctx);
+      position.unmake_move(undo);
       if score > max {
          max = score;
       }
@@ -172,78 +1004,827 @@ fn nega_max(
    max
 }
 
-use crate::board::Piece;
+// Values for ordering purposes only. The king is never a victim, and as an attacker it goes last
+const ORDERING_VALUE: [i32; 6] = {
+   let mut values = [0; 6];
+   values[PAWN] = 1;
+   values[KNIGHT] = 3;
+   values[BISHOP] = 3;
+   values[ROOK] = 5;
+   values[QUEEN] = 9;
+   values[KING] = 10;
+   values
+};
 
-fn mat_val(piece: Piece) -> f64 {
-   match piece {
-      Piece::Pawn => 1.0,
-      Piece::Knight => 3.0,
-      Piece::Bishop => 3.0,
-      Piece::Rook => 5.0,
-      Piece::Queen => 9.0,
-      Piece::King => 0.0,
+fn piece_kind_at(position: &Position, color: usize, index: u8) -> Option<usize> {
+   [PAWN, KNIGHT, BISHOP, ROOK, QUEEN, KING]
+      .iter()
+      .copied()
+      .find(|x| position.squares.pieces[color][*x] & (1 << index) != 0)
+}
+
+/// Most Valuable Victim / Least Valuable Attacker.
+/// Captures score above zero, best first; quiet moves score zero.
+fn mvv_lva(position: &Position, a_move: Move) -> i32 {
+   let us = position.side_to_move.as_num();
+   let attacker = piece_kind_at(position, us, a_move.origin).unwrap_or(PAWN);
+   let victim = if let Some(victim) = piece_kind_at(position, us ^ 1, a_move.destination) {
+      Some(victim)
+   } else if attacker == PAWN && position.en_passant_square == 1 << a_move.destination {
+      Some(PAWN)
+   } else {
+      None
+   };
+   let promotion = match a_move.promotion {
+      PromotionTarget::Queen => ORDERING_VALUE[QUEEN],
+      PromotionTarget::None => 0,
+      _ => -1, // underpromotions last
+   };
+   match victim {
+      Some(victim) => 100 + ORDERING_VALUE[victim] * 10 - ORDERING_VALUE[attacker] + promotion * 10,
+      None if promotion > 0 => 100 + promotion * 10,
+      None => promotion,
    }
 }
 
-fn evaluate(position: &Position, side_to_move: Color) -> f64 {
-   let mut white_mat_score = 0.0;
-   let mut black_mat_score = 0.0;
-   let mut white_dist_score = 0.0;
-   let mut black_dist_score = 0.0;
+/// Put the hash move first, then captures by MVV-LVA, then the killers, then quiet moves by history,
+/// and underpromotions last
+fn order_moves(
+   position: &Position,
+   moves: &mut [CompressedMove],
+   hash_move: Option<CompressedMove>,
+   killers: [Option<CompressedMove>; 2],
+   history: &HistoryTable,
+) {
+   let us = position.side_to_move.as_num();
+   moves.sort_by_cached_key(|x| {
+      let a_move = x.extract();
+      let mvv_lva = mvv_lva(position, a_move);
+      if Some(*x) == hash_move {
+         (0, 0)
+      } else if mvv_lva > 0 {
+         (1, -i64::from(mvv_lva))
+      } else if Some(*x) == killers[0] {
+         (2, 0)
+      } else if Some(*x) == killers[1] {
+         (3, 0)
+      } else if mvv_lva == 0 {
+         let piece = piece_kind_at(position, us, a_move.origin).unwrap_or(PAWN);
+         (4, -(history[us][piece][a_move.destination as usize] as i64))
+      } else {
+         (5, 0)
+      }
+   });
+}
+
+#[cfg(test)]
+mod tests {
+   use crate::board::{Color, GameStatus, State, VictoryReason};
+   use crate::engine::*;
 
-   {
-      white_mat_score += position.squares.pieces[WHITE][PAWN].count_ones() as f64 * mat_val(Piece::Pawn);
-      white_mat_score += position.squares.pieces[WHITE][KNIGHT].count_ones() as f64 * mat_val(Piece::Knight);
-      white_mat_score += position.squares.pieces[WHITE][BISHOP].count_ones() as f64 * mat_val(Piece::Bishop);
-      white_mat_score += position.squares.pieces[WHITE][ROOK].count_ones() as f64 * mat_val(Piece::Rook);
-      white_mat_score += position.squares.pieces[WHITE][QUEEN].count_ones() as f64 * mat_val(Piece::Queen);
+   /// Runs `start` on its own thread, with the channels to talk to it and the thread to join once it quits
+   fn spawn_engine(
+      start: fn(mpsc::Receiver<InterfaceMessage>, mpsc::Sender<EngineMessage>),
+   ) -> (mpsc::Sender<InterfaceMessage>, mpsc::Receiver<EngineMessage>, thread::JoinHandle<()>) {
+      let (ite_tx, ite_rx) = mpsc::channel();
+      let (eti_tx, eti_rx) = mpsc::channel();
+      let engine = thread::spawn(move || start(ite_rx, eti_tx));
+      (ite_tx, eti_rx, engine)
    }
 
-   {
-      black_mat_score += position.squares.pieces[BLACK][PAWN].count_ones() as f64 * mat_val(Piece::Pawn);
-      black_mat_score += position.squares.pieces[BLACK][KNIGHT].count_ones() as f64 * mat_val(Piece::Knight);
-      black_mat_score += position.squares.pieces[BLACK][BISHOP].count_ones() as f64 * mat_val(Piece::Bishop);
-      black_mat_score += position.squares.pieces[BLACK][ROOK].count_ones() as f64 * mat_val(Piece::Rook);
-      black_mat_score += position.squares.pieces[BLACK][QUEEN].count_ones() as f64 * mat_val(Piece::Queen);
+   /// Skips the search's reports until its best move, which has to come within `timeout`
+   fn wait_for_best_move(receiver: &mpsc::Receiver<EngineMessage>, timeout: Duration) -> Option<Move> {
+      let deadline = Instant::now() + timeout;
+      loop {
+         match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(EngineMessage::BestMove(best_move)) => return best_move,
+            Ok(EngineMessage::Info { .. }) | Ok(EngineMessage::SearchStats { .. }) => (),
+            _ => panic!("no best move within {:?}", timeout),
+         }
+      }
    }
 
-   {
-      white_dist_score += (position.squares.all_pieces[WHITE] & !position.squares.pieces[WHITE][KING] & RANK_8).count_ones() as f64 * 7.0;
-      white_dist_score += (position.squares.all_pieces[WHITE] & !position.squares.pieces[WHITE][KING] & RANK_7).count_ones() as f64 * 6.0;
-      white_dist_score += (position.squares.all_pieces[WHITE] & !position.squares.pieces[WHITE][KING] & RANK_6).count_ones() as f64 * 5.0;
-      white_dist_score += (position.squares.all_pieces[WHITE] & !position.squares.pieces[WHITE][KING] & RANK_5).count_ones() as f64 * 4.0;
-      white_dist_score += (position.squares.all_pieces[WHITE] & !position.squares.pieces[WHITE][KING] & RANK_4).count_ones() as f64 * 3.0;
-      white_dist_score += (position.squares.all_pieces[WHITE] & !position.squares.pieces[WHITE][KING] & RANK_3).count_ones() as f64 * 2.0;
-      white_dist_score += (position.squares.all_pieces[WHITE] & !position.squares.pieces[WHITE][KING] & RANK_2).count_ones() as f64;
+   fn default_eval(position: &Position) -> f64 {
+      evaluate(position, position.side_to_move, &EvalParams::default())
    }
 
-   {
-      black_dist_score += (position.squares.all_pieces[BLACK] & !position.squares.pieces[BLACK][KING] & RANK_1).count_ones() as f64 * 7.0;
-      black_dist_score += (position.squares.all_pieces[BLACK] & !position.squares.pieces[BLACK][KING] & RANK_2).count_ones() as f64 * 6.0;
-      black_dist_score += (position.squares.all_pieces[BLACK] & !position.squares.pieces[BLACK][KING] & RANK_3).count_ones() as f64 * 5.0;
-      black_dist_score += (position.squares.all_pieces[BLACK] & !position.squares.pieces[BLACK][KING] & RANK_4).count_ones() as f64 * 4.0;
-      black_dist_score += (position.squares.all_pieces[BLACK] & !position.squares.pieces[BLACK][KING] & RANK_5).count_ones() as f64 * 3.0;
-      black_dist_score += (position.squares.all_pieces[BLACK] & !position.squares.pieces[BLACK][KING] & RANK_6).count_ones() as f64 * 2.0;
-      black_dist_score += (position.squares.all_pieces[BLACK] & !position.squares.pieces[BLACK][KING] & RANK_7).count_ones() as f64;
+   fn nodes_expanded(state: &State, depth: u64, tt: &TranspositionTable) -> u64 {
+      run_nega_max(state, depth, tt, |_| ()).2
    }
 
-   let mat_score = white_mat_score as f64 - black_mat_score as f64;
-   let dist_score = white_dist_score - black_dist_score;
+   /// Score, best move and nodes expanded, after `configure` gets a chance to turn parts of the search off
+   fn run_nega_max(
+      state: &State,
+      depth: u64,
+      tt: &TranspositionTable,
+      configure: fn(&mut SearchContext),
+   ) -> (f64, Option<Move>, u64) {
+      let stop = AtomicBool::new(false);
+      let eval_params = EvalParams::default();
+      let mut ctx = SearchContext::new(tt, &stop, &eval_params);
+      configure(&mut ctx);
+      let score = nega_max(depth, 0, &mut state.clone(), f64::NEG_INFINITY, f64::INFINITY, &mut ctx);
+      (score, ctx.pv.line(0).first().map(|x| x.extract()), ctx.nodes_expanded)
+   }
 
+   #[test]
+   fn transposition_table_reduces_nodes() {
+      let state = State::from_start();
+      let without_tt = nodes_expanded(&state, 3, &TranspositionTable::new(0));
+      // as in iterative deepening, the shallower searches leave entries behind to order moves with
+      let tt = TranspositionTable::new(DEFAULT_HASH_MB);
+      nodes_expanded(&state, 1, &tt);
+      nodes_expanded(&state, 2, &tt);
+      let with_tt = nodes_expanded(&state, 3, &tt);
+      assert!(with_tt * 3 < without_tt * 2, "{} vs {}", with_tt, without_tt);
+   }
 
-   let mut move_buf = Vec::new();
-   position.gen_moves_color(Color::White, &mut move_buf);
-   let white_mobility_score = move_buf.len();
-   move_buf.clear();
-   position.gen_moves_color(Color::Black, &mut move_buf);
-   let black_mobility_score = move_buf.len();
-   let mobility_score: f64 = white_mobility_score as f64 - black_mobility_score as f64;
+   // Nxe5 or Bxe5 win a pawn, since Qxe5 always loses the queen to the other piece.
+   // A plain depth 2 search stops right after Qxe5 and thinks the capture loses a piece.
+   const LOOSE_PAWN: &str = "6k1/p3qppp/8/4p3/8/5N2/PB3PPP/6K1 w - - 0 1";
 
-   let final_score = dist_score * 0.04 + mobility_score * 0.06 + mat_score * 0.9;
+   #[test]
+   fn quiescence_sees_recapture() {
+      let mut state = State::from_fen(LOOSE_PAWN).unwrap().apply_moves_from_uci("f3e5 e7e5");
+      let static_eval = default_eval(&state.position);
+      let quiet_eval = quiesce(
+         &mut state.position,
+         f64::NEG_INFINITY,
+         f64::INFINITY,
+         &mut SearchContext::new(&TranspositionTable::new(0), &AtomicBool::new(false), &EvalParams::default()),
+      );
+      assert!(static_eval < 0.0);
+      assert!(quiet_eval > 0.0);
+   }
 
-   if side_to_move == Color::White {
-      final_score
-   } else {
-      -final_score
+   #[test]
+   fn quiescence_wins_loose_pawn() {
+      let state = State::from_fen(LOOSE_PAWN).unwrap();
+      let static_eval = default_eval(&state.position);
+      let (score, pv) = search(2, &state, &TranspositionTable::new(DEFAULT_HASH_MB), &AtomicBool::new(false));
+      assert_eq!(pv[0].destination, 36); // e5
+      assert!(score > static_eval + 0.5);
+   }
+
+   const KIWIPETE: &str = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1";
+
+   #[test]
+   fn mvv_lva_ordering() {
+      // the e4 pawn can take the queen on d5 or the knight on f5, the queen on d1 can also take d5
+      let state = State::from_fen("4k3/8/8/3q1n2/4P3/8/8/3QK3 w - - 0 1").unwrap();
+      let mut moves = Vec::new();
+      state.gen_moves(&mut moves);
+      let history = [[[0; 64]; 6]; 2];
+      order_moves(&state.position, &mut moves, None, [None; 2], &history);
+      let ordered: Vec<String> = moves.iter().map(|x| x.extract().to_string()).collect();
+      assert_eq!(&ordered[..3], &["e4d5", "d1d5", "e4f5"]);
+
+      let hash_move = "e1f1".parse::<Move>().unwrap().compress();
+      order_moves(&state.position, &mut moves, Some(hash_move), [None; 2], &history);
+      assert_eq!(moves[0], hash_move);
+   }
+
+   #[test]
+   fn mvv_lva_tames_quiescence() {
+      // without ordering, this expands around 38 million nodes in quiescence
+      let state = State::from_fen(KIWIPETE).unwrap();
+      let nodes = nodes_expanded(&state, 1, &TranspositionTable::new(0));
+      assert!(nodes < 10_000, "{}", nodes);
+   }
+
+   #[test]
+   fn contempt_scores_draws_against_the_root() {
+      let repeated = State::from_moves("g1f3 g8f6 f3g1 f6g8 g1f3 g8f6 f3g1 f6g8").unwrap();
+      assert!(repeated.repetitions() >= 2);
+      let tt = TranspositionTable::new(0);
+      let stop = AtomicBool::new(false);
+      let eval_params = EvalParams {
+         contempt: 0.5,
+         ..EvalParams::default()
+      };
+      let mut ctx = SearchContext::new(&tt, &stop, &eval_params);
+      let mut state = repeated.clone();
+      // the side at the root is unhappy with a draw, and the other side is happy with it
+      assert_eq!(nega_max(3, 0, &mut state, f64::NEG_INFINITY, f64::INFINITY, &mut ctx), -0.5);
+      assert_eq!(nega_max(3, 1, &mut state, f64::NEG_INFINITY, f64::INFINITY, &mut ctx), 0.5);
+   }
+
+   #[test]
+   fn drawn_root_still_has_a_move() {
+      // black can take the draw by repetition, but a move should still come out of the search
+      let state = State::from_moves("g1f3 g8f6 f3g1 f6g8 g1f3 g8f6 f3g1 f6g8").unwrap();
+      assert!(state.repetitions() >= 2);
+      let (score, best_move) = search_blocking(&state, 3);
+      assert_eq!(score, 0.0);
+      assert!(best_move.is_some_and(|x| state.is_legal(x)));
+
+      let state = State::from_fen("7k/8/8/8/8/8/8/2Q1K3 w - - 100 80").unwrap();
+      let result = analyze(
+         &state,
+         SearchLimits {
+            depth: Some(2),
+            ..SearchLimits::default()
+         },
+      );
+      assert_eq!(result.score, 0.0);
+      assert!(result.best_move.is_some_and(|x| state.is_legal(x)));
+   }
+
+   #[test]
+   fn winning_side_avoids_repetition() {
+      // a queen up, but c2c1 would let black repeat the position for the third time
+      let state = State::from_fen("7k/8/8/8/8/8/8/2Q1K3 w - - 0 1")
+         .unwrap()
+         .apply_moves_from_uci("c1c2 h8g8 c2c1 g8h8 c1c2 h8g8");
+      let result = analyze(
+         &state,
+         SearchLimits {
+            depth: Some(3),
+            deterministic: true,
+            ..SearchLimits::default()
+         },
+      );
+      assert!(result.best_move.is_some());
+      assert!(result.best_move != Some("c2c1".parse().unwrap()));
+      assert!(result.score > 5.0, "{}", result.score);
+   }
+
+   #[test]
+   fn null_move_pruning_reduces_nodes() {
+      let state = State::from_fen("4r1k1/pp1n1ppp/8/8/8/8/PP1N1PPP/4R1K1 w - - 0 1").unwrap();
+      // measured against plain alpha-beta, as the null window searches of PVS leave it less to prune
+      let off = |ctx: &mut SearchContext| {
+         ctx.principal_variation_search = false;
+         ctx.null_move_pruning = false;
+      };
+      let on = |ctx: &mut SearchContext| ctx.principal_variation_search = false;
+      let (_, _, without) = run_nega_max(&state, 6, &TranspositionTable::new(DEFAULT_HASH_MB), off);
+      let (_, _, with) = run_nega_max(&state, 6, &TranspositionTable::new(DEFAULT_HASH_MB), on);
+      trace!("null move pruning: {} nodes vs {} without", with, without);
+      assert!(with * 3 < without * 2, "{} vs {}", with, without);
+   }
+
+   #[test]
+   fn null_move_pruning_respects_zugzwang() {
+      // whoever moves has to let go of their pawn, so passing would look far better than anything legal
+      let state = State::from_fen("8/8/8/2pK4/1kP5/8/8/8 w - - 0 1").unwrap();
+      let off = |ctx: &mut SearchContext| ctx.null_move_pruning = false;
+      let (without, _, _) = run_nega_max(&state, 5, &TranspositionTable::new(0), off);
+      let (with, _, _) = run_nega_max(&state, 5, &TranspositionTable::new(0), |_| ());
+      assert!(with < -0.5, "{}", with);
+      assert_eq!(with, without);
+   }
+
+   #[test]
+   fn principal_variation_search_reduces_nodes() {
+      // deeper searches save more, but take too long in a debug build
+      let state = State::from_fen(KIWIPETE).unwrap();
+      let off = |ctx: &mut SearchContext| ctx.principal_variation_search = false;
+      let (_, full_best, full) = run_nega_max(&state, 4, &TranspositionTable::new(DEFAULT_HASH_MB), off);
+      let (_, pvs_best, pvs) = run_nega_max(&state, 4, &TranspositionTable::new(DEFAULT_HASH_MB), |_| ());
+      assert!(pvs * 3 < full * 2, "{} vs {}", pvs, full);
+      assert_eq!(pvs_best, full_best);
+   }
+
+   #[test]
+   fn root_moves_past_multi_pv_get_null_windows() {
+      let state = State::from_fen(KIWIPETE).unwrap();
+      let stop = AtomicBool::new(false);
+      let limits = SearchLimits::default();
+      // iterative deepening, so that the hash move comes first at the root
+      let run = |multi_pv: usize| {
+         let tt = TranspositionTable::new(DEFAULT_HASH_MB);
+         let options = SearchOptions {
+            multi_pv,
+            ..SearchOptions::single()
+         };
+         let mut nodes = 0;
+         let mut result = (0.0, Vec::new());
+         for depth in 1..=4 {
+            let (score, lines, depth_nodes) = search_root(depth, &state, &tt, &stop, None, &options, &limits);
+            nodes += depth_nodes;
+            result = (score, lines);
+         }
+         (result, nodes)
+      };
+      // as many lines as there are moves gives every move a full window
+      let ((full_score, full_lines), full_nodes) = run(256);
+      let ((score, lines), nodes) = run(1);
+      assert_eq!(score, full_score);
+      assert_eq!(lines[0], full_lines[0]);
+      assert!(nodes < full_nodes, "{} vs {}", nodes, full_nodes);
+      // only the lines that were searched with a full window are returned
+      assert!(!lines.is_empty() && lines.len() < full_lines.len());
+      assert!(lines.windows(2).all(|x| x[0].0 >= x[1].0));
+      let ((_, three_lines), _) = run(3);
+      assert!(three_lines.len() >= 3);
+      assert_eq!(three_lines[..3], full_lines[..3]);
+   }
+
+   #[test]
+   fn killers_and_history_find_cutoffs_sooner() {
+      let fens = [
+         "r1bqk2r/pppp1ppp/2n2n2/2b1p3/2B1P3/3P1N2/PPP2PPP/RNBQK2R w KQkq - 1 5",
+         "r4rk1/1p3ppp/p1n1pn2/8/3P4/2N2N2/PP3PPP/R4RK1 w - - 0 1",
+         "2r2rk1/pp2bppp/2n1pn2/8/3P4/2N1BN2/PP2BPPP/2R2RK1 w - - 0 1",
+      ];
+      // average number of moves searched per cutoff
+      let moves_per_cutoff = |quiet_move_ordering: bool| {
+         let (mut cutoffs, mut moves) = (0, 0);
+         for fen in fens.iter() {
+            let state = State::from_fen(fen).unwrap();
+            let tt = TranspositionTable::new(DEFAULT_HASH_MB);
+            let stop = AtomicBool::new(false);
+            let eval_params = EvalParams::default();
+            let mut ctx = SearchContext::new(&tt, &stop, &eval_params);
+            ctx.quiet_move_ordering = quiet_move_ordering;
+            nega_max(3, 0, &mut state.clone(), f64::NEG_INFINITY, f64::INFINITY, &mut ctx);
+            cutoffs += ctx.cutoffs;
+            moves += ctx.moves_before_cutoffs;
+         }
+         moves as f64 / cutoffs as f64
+      };
+      let without = moves_per_cutoff(false);
+      let with = moves_per_cutoff(true);
+      assert!(with < without * 0.75, "{} vs {}", with, without);
+   }
+
+   #[test]
+   fn check_extension_finds_mate() {
+      // mate in 3, all checks: Nh6+ Kh8 Qg8+ Rxg8 Nf7#
+      let state = State::from_fen("r5k1/5Npp/8/8/2Q5/8/6PP/7K w - - 0 1").unwrap();
+      let (score, pv) = search(4, &state, &TranspositionTable::new(DEFAULT_HASH_MB), &AtomicBool::new(false));
+      assert_eq!(moves_to_mate(score), Some(3), "{}", score);
+      assert_eq!(pv[0], "f7h6".parse().unwrap());
+   }
+
+   #[test]
+   fn prefers_shorter_mate() {
+      // Ra8# and Rb8# mate at once, and almost anything else mates next move.
+      // The table is shared between depths, as it is in a real search, so mates found deeper in the tree must
+      // not come back out of it looking closer or further than they are
+      let state = State::from_fen("7k/8/6K1/8/8/8/8/RR6 w - - 0 1").unwrap();
+      let tt = TranspositionTable::new(DEFAULT_HASH_MB);
+      for depth in 1..=4 {
+         let (score, pv) = search(depth, &state, &tt, &AtomicBool::new(false));
+         assert_eq!(score, MATE - 1.0);
+         assert_eq!(pv.len(), 1);
+         // both rooks mate, and ties go to the lower square
+         assert!(pv[0] == "a1a8".parse().unwrap());
+      }
+   }
+
+   #[test]
+   fn moves_to_mate_counts_moves() {
+      assert_eq!(moves_to_mate(MATE - 1.0), Some(1));
+      assert_eq!(moves_to_mate(MATE - 3.0), Some(2));
+      assert_eq!(moves_to_mate(-MATE + 2.0), Some(-1));
+      assert_eq!(moves_to_mate(-MATE + 4.0), Some(-2));
+      assert_eq!(moves_to_mate(8.5), None);
+      assert_eq!(moves_to_mate(-8.5), None);
+   }
+
+   #[test]
+   fn pv_ends_in_mate() {
+      // ladder mate in 2, e.g. Ra7 Kg8 Rb8#
+      let mut state = State::from_fen("7k/8/8/8/8/8/R7/1R4K1 w - - 0 1").unwrap();
+      let (_, pv) = search(4, &state, &TranspositionTable::new(DEFAULT_HASH_MB), &AtomicBool::new(false));
+      assert_eq!(pv.len(), 3);
+      let mut moves = Vec::new();
+      for a_move in pv {
+         state.gen_moves(&mut moves);
+         assert!(moves.contains(&a_move.compress()));
+         state.apply_move(a_move);
+      }
+      state.gen_moves(&mut moves);
+      assert_eq!(state.status(&moves), GameStatus::Victory(Color::White, VictoryReason::Checkmate));
+   }
+
+   #[test]
+   fn stop_ends_infinite_search() {
+      let (ite_tx, eti_rx, _) = spawn_engine(start);
+      ite_tx.send(InterfaceMessage::GoInfinite).unwrap();
+      thread::sleep(Duration::from_millis(100));
+      let stopped_at = Instant::now();
+      ite_tx.send(InterfaceMessage::Stop).unwrap();
+      assert!(wait_for_best_move(&eti_rx, Duration::from_secs(5)).is_some());
+      assert!(stopped_at.elapsed() < Duration::from_secs(1));
+   }
+
+   #[test]
+   fn other_messages_end_infinite_search() {
+      let (ite_tx, eti_rx, _) = spawn_engine(start);
+      // a message that waits for the search must not leave the Stop behind it unheard
+      ite_tx.send(InterfaceMessage::GoInfinite).unwrap();
+      ite_tx.send(InterfaceMessage::SetState(State::from_start())).unwrap();
+      ite_tx.send(InterfaceMessage::Stop).unwrap();
+      assert!(wait_for_best_move(&eti_rx, Duration::from_secs(5)).is_some());
+   }
+
+   #[test]
+   fn quit_ends_the_engine() {
+      let (ite_tx, _eti_rx, engine) = spawn_engine(start);
+      // even in the middle of a search that would never end by itself
+      ite_tx.send(InterfaceMessage::GoInfinite).unwrap();
+      ite_tx.send(InterfaceMessage::Quit).unwrap();
+      let started = Instant::now();
+      while !engine.is_finished() {
+         assert!(started.elapsed() < Duration::from_secs(5), "the engine didn't quit");
+         thread::sleep(Duration::from_millis(10));
+      }
+      engine.join().unwrap();
+   }
+
+   #[test]
+   fn applied_moves_are_kept() {
+      let (ite_tx, eti_rx, _) = spawn_engine(start);
+      let search = |expected: &State| {
+         ite_tx.send(InterfaceMessage::GoDepth(1)).unwrap();
+         let mut score = None;
+         loop {
+            match eti_rx.recv().unwrap() {
+               EngineMessage::Info { score: x, .. } => score = Some(x),
+               EngineMessage::BestMove(best_move) => {
+                  assert!(best_move.is_some_and(|x| expected.is_legal(x)));
+                  return score.unwrap();
+               }
+               _ => (),
+            }
+         }
+      };
+      ite_tx.send(InterfaceMessage::SetState(State::from_start())).unwrap();
+      ite_tx.send(InterfaceMessage::ApplyMove("e2e4".parse().unwrap())).unwrap();
+      // black to move
+      search(&State::from_moves("e2e4").unwrap());
+
+      // the repetition history carries over too, so this is a draw
+      let moves = "g1f3 g8f6 f3g1 f6g8 g1f3 g8f6 f3g1 f6g8";
+      ite_tx.send(InterfaceMessage::SetState(State::from_start())).unwrap();
+      for a_move in moves.split_whitespace() {
+         ite_tx.send(InterfaceMessage::ApplyMove(a_move.parse().unwrap())).unwrap();
+      }
+      assert_eq!(search(&State::from_moves(moves).unwrap()), 0.0);
+      // while the same position without the history isn't
+      ite_tx.send(InterfaceMessage::SetState(State::from_start())).unwrap();
+      assert_ne!(search(&State::from_start()), 0.0);
+   }
+
+   #[test]
+   fn time_budget_cuts_a_depth_short() {
+      let (ite_tx, eti_rx, _) = spawn_engine(start);
+      // a middlegame where the third depth takes well past the budget
+      let state = State::from_fen("r1bq1rk1/pp2bppp/2n1pn2/3p4/2PP4/2N2N2/PP2BPPP/R2QKB1R w KQ - 0 8").unwrap();
+      ite_tx.send(InterfaceMessage::SetState(state)).unwrap();
+      // setting up the hash table takes a while, which mustn't count against the budget
+      ite_tx.send(InterfaceMessage::QueryEval).unwrap();
+      assert!(matches!(eti_rx.recv().unwrap(), EngineMessage::CurrentEval(_)));
+      let started = Instant::now();
+      ite_tx.send(InterfaceMessage::GoTime(Duration::from_millis(200))).unwrap();
+      assert!(wait_for_best_move(&eti_rx, Duration::from_secs(5)).is_some());
+      assert!(started.elapsed() < Duration::from_millis(500), "{:?}", started.elapsed());
+   }
+
+   #[test]
+   fn predicted_depth_times() {
+      let ms = Duration::from_millis;
+      // the last two depths give the branching factor
+      assert_eq!(predict_next_depth(ms(10), ms(40)), ms(160));
+      // within bounds
+      assert_eq!(predict_next_depth(ms(10), ms(11)), ms(11).mul_f64(MIN_BRANCHING_FACTOR));
+      assert_eq!(predict_next_depth(ms(1), ms(500)), ms(500).mul_f64(MAX_BRANCHING_FACTOR));
+      // and a guess when the depth before was too quick to time
+      assert_eq!(predict_next_depth(Duration::ZERO, ms(2)), ms(2).mul_f64(DEFAULT_BRANCHING_FACTOR));
+   }
+
+   #[test]
+   fn depths_that_cannot_finish_are_not_started() {
+      let ms = Duration::from_millis;
+      // 40ms into a 100ms budget, with the last depths taking 10ms and then 30ms
+      let clock = Clock {
+         budget: Some(ms(100)),
+         started: Some(Instant::now() - ms(40)),
+      };
+      // the next depth should take around 90ms, which there isn't time for
+      assert!(clock.cannot_finish(predict_next_depth(ms(10), ms(30))));
+      // while a quick one still fits
+      assert!(!clock.cannot_finish(predict_next_depth(ms(2), ms(5))));
+      // and without a running clock, anything goes
+      assert!(!Clock::new(None).cannot_finish(ms(1000)));
+      assert!(!Clock::pondering(Some(ms(100))).cannot_finish(ms(1000)));
+
+      // so a search with a small budget stops well short of a depth it can't finish, rather than abandoning it
+      let state = State::from_fen("r1bq1rk1/pp2bppp/2n1pn2/3p4/2PP4/2N2N2/PP2BPPP/R2QKB1R w KQ - 0 8").unwrap();
+      let budget = ms(300);
+      let limits = SearchLimits {
+         movetime: Some(budget),
+         ..SearchLimits::default()
+      };
+      let started = Instant::now();
+      let result = analyze(&state, limits);
+      assert!(result.best_move.is_some());
+      assert!(started.elapsed() < budget, "{:?}", started.elapsed());
+   }
+
+   #[test]
+   fn ponder_hit_continues_the_search() {
+      let (ite_tx, eti_rx, _) = spawn_engine(start);
+      ite_tx.send(InterfaceMessage::GoPonder(Some(Duration::from_millis(200)))).unwrap();
+      // far longer than the budget, which doesn't count until the ponder hit
+      thread::sleep(Duration::from_millis(500));
+      ite_tx.send(InterfaceMessage::PonderHit).unwrap();
+      let mut depths = Vec::new();
+      loop {
+         match eti_rx.recv_timeout(Duration::from_secs(30)) {
+            Ok(EngineMessage::Info { depth, .. }) => depths.push(depth),
+            Ok(EngineMessage::SearchStats { .. }) => (),
+            Ok(EngineMessage::BestMove(best_move)) => {
+               assert!(best_move.is_some());
+               break;
+            }
+            _ => panic!("no best move after ponder hit"),
+         }
+      }
+      // one search all along, rather than a new one from depth 1
+      assert_eq!(depths.iter().filter(|x| **x == 1).count(), 1);
+      assert!(depths.windows(2).all(|x| x[0] < x[1]), "{:?}", depths);
+   }
+
+   #[test]
+   fn multi_pv_reports_distinct_lines() {
+      let (ite_tx, eti_rx, _) = spawn_engine(start);
+      ite_tx.send(InterfaceMessage::SetMultiPv(3)).unwrap();
+      ite_tx.send(InterfaceMessage::GoDepth(2)).unwrap();
+      let mut lines = Vec::new();
+      let best_move = loop {
+         match eti_rx.recv().unwrap() {
+            EngineMessage::Info {
+               depth: 2,
+               multipv,
+               score,
+               pv,
+            } => lines.push((multipv, score, pv[0])),
+            EngineMessage::BestMove(best_move) => break best_move,
+            _ => (),
+         }
+      };
+      assert_eq!(lines.len(), 3);
+      assert_eq!(lines.iter().map(|x| x.0).collect::<Vec<_>>(), vec![1, 2, 3]);
+      assert!(lines[0].1 >= lines[1].1 && lines[1].1 >= lines[2].1);
+      assert!(lines[0].2 != lines[1].2 && lines[1].2 != lines[2].2 && lines[0].2 != lines[2].2);
+      assert_eq!(best_move, Some(lines[0].2));
+   }
+
+   #[test]
+   fn analyze_finds_the_winning_capture() {
+      // Nxd5 wins the queen
+      let state = State::from_fen("4k3/8/8/3q4/8/4N3/8/4K3 w - - 0 1").unwrap();
+      let result = analyze(
+         &state,
+         SearchLimits {
+            depth: Some(4),
+            ..SearchLimits::default()
+         },
+      );
+      assert!(result.best_move == Some("e3d5".parse().unwrap()));
+      assert!(result.pv.first() == result.best_move.as_ref());
+      assert_eq!(result.depth, 4);
+      assert!(result.score > 2.0, "{}", result.score);
+      assert!(result.nodes > 0);
+
+      // the node cap stops it long before the depth limit
+      let capped = analyze(
+         &state,
+         SearchLimits {
+            depth: Some(20),
+            nodes: Some(1),
+            ..SearchLimits::default()
+         },
+      );
+      assert_eq!(capped.depth, 1);
+      assert!(capped.best_move.is_some());
+   }
+
+   #[test]
+   fn restricted_analysis_plays_the_move_it_is_given() {
+      // Nc4 hangs the knight, but it's the only move allowed
+      let state = State::from_fen("4k3/8/8/3q4/8/4N3/8/4K3 w - - 0 1").unwrap();
+      let bad_move: Move = "e3c4".parse().unwrap();
+      let result = analyze(
+         &state,
+         SearchLimits {
+            depth: Some(2),
+            restrict_to: Some(vec![bad_move, "e3e4".parse().unwrap()]),
+            ..SearchLimits::default()
+         },
+      );
+      assert_eq!(result.best_move, Some(bad_move));
+      assert!(result.score < -5.0, "{}", result.score);
+      // with nothing legal to restrict to, every move is searched
+      let result = analyze(
+         &state,
+         SearchLimits {
+            depth: Some(2),
+            restrict_to: Some(vec!["e3e4".parse().unwrap()]),
+            ..SearchLimits::default()
+         },
+      );
+      assert_eq!(result.best_move, Some("e3d5".parse().unwrap()));
+   }
+
+   /// The result of searching to `depth` with `threads` threads
+   fn search_with_threads(state: &State, depth: u64, threads: usize) -> SearchResult {
+      let tt = TranspositionTable::new(DEFAULT_HASH_MB);
+      let clock = Mutex::new(Clock::new(None));
+      let options = SearchOptions {
+         multi_pv: 1,
+         eval_params: EvalParams::default(),
+         threads,
+      };
+      let limits = SearchLimits {
+         depth: Some(depth),
+         ..SearchLimits::default()
+      };
+      let (sender, _receiver) = mpsc::channel();
+      iterative_deepening(state, &tt, &AtomicBool::new(false), &limits, &clock, &options, &sender)
+   }
+
+   #[test]
+   fn lazy_smp_agrees_with_one_thread() {
+      let state = State::from_fen(LOOSE_PAWN).unwrap();
+      let one_thread = search_with_threads(&state, 5, 1);
+      let four_threads = search_with_threads(&state, 5, 4);
+      assert_eq!(one_thread.depth, 5);
+      assert_eq!(four_threads.depth, 5);
+      assert_eq!(four_threads.best_move, one_thread.best_move);
+      let winning_captures: [Move; 2] = ["b2e5".parse().unwrap(), "f3e5".parse().unwrap()];
+      assert!(one_thread.best_move.is_some_and(|x| winning_captures.contains(&x)));
+      // the helpers' nodes count too. How much faster they are is measured by `bench` instead, where nothing
+      // else is running
+      assert!(four_threads.nodes > one_thread.nodes);
+   }
+
+   /// Keeps the engine's trace records from each thread, so that a test only sees its own
+   struct TraceRecorder {
+      records: std::sync::Mutex<Vec<(thread::ThreadId, String)>>,
+   }
+
+   impl log::Log for TraceRecorder {
+      fn enabled(&self, metadata: &log::Metadata) -> bool {
+         metadata.target().starts_with("chessatk_lib::engine")
+      }
+
+      fn log(&self, record: &log::Record) {
+         if self.enabled(record.metadata()) {
+            let message = record.args().to_string();
+            self.records.lock().unwrap().push((thread::current().id(), message));
+         }
+      }
+
+      fn flush(&self) {}
+   }
+
+   static TRACE_RECORDER: TraceRecorder = TraceRecorder {
+      records: std::sync::Mutex::new(Vec::new()),
+   };
+
+   #[test]
+   fn traces_only_when_verbose() {
+      // no other test sets a logger
+      log::set_logger(&TRACE_RECORDER).unwrap();
+      log::set_max_level(log::LevelFilter::Trace);
+      let traces = || {
+         let this_thread = thread::current().id();
+         TRACE_RECORDER.records.lock().unwrap().iter().filter(|x| x.0 == this_thread).count()
+      };
+      let state = State::from_fen(LOOSE_PAWN).unwrap();
+      // on one thread, so the search happens on this one
+      let limits = SearchLimits {
+         depth: Some(3),
+         deterministic: true,
+         ..SearchLimits::default()
+      };
+      analyze(&state, limits.clone());
+      assert_eq!(traces(), 0);
+      analyze(
+         &state,
+         SearchLimits {
+            verbose: true,
+            ..limits
+         },
+      );
+      // the node counts and the best move of each depth
+      assert_eq!(traces(), 6);
+   }
+
+   #[test]
+   fn deterministic_analysis_repeats() {
+      let state = State::from_fen(LOOSE_PAWN).unwrap();
+      let limits = SearchLimits {
+         depth: Some(3),
+         deterministic: true,
+         ..SearchLimits::default()
+      };
+      let first = analyze(&state, limits.clone());
+      assert!(first.best_move.is_some());
+      assert_eq!(analyze(&state, limits), first);
+   }
+
+   #[test]
+   fn equal_scores_prefer_the_lowest_squares() {
+      // either rook mates
+      let state = State::from_fen("7k/8/6K1/8/8/8/8/RR6 w - - 0 1").unwrap();
+      let result = analyze(
+         &state,
+         SearchLimits {
+            depth: Some(2),
+            deterministic: true,
+            ..SearchLimits::default()
+         },
+      );
+      assert!(result.best_move == Some("a1a8".parse().unwrap()));
+      assert!(search_blocking(&state, 2).1 == Some("a1a8".parse().unwrap()));
+   }
+
+   #[test]
+   fn blocking_search_agrees_with_parallel_search() {
+      // Rxd5 wins the queen
+      let state = State::from_fen("4k3/8/8/3q4/8/8/8/3RK3 w - - 0 1").unwrap();
+      let (_, pv) = search(3, &state, &TranspositionTable::new(DEFAULT_HASH_MB), &AtomicBool::new(false));
+      let (score, best_move) = search_blocking(&state, 3);
+      assert!(best_move == Some(pv[0]));
+      assert!(best_move == Some("d1d5".parse().unwrap()));
+      assert!(score > 4.0, "{}", score);
+   }
+
+   #[test]
+   fn plays_book_move() {
+      // a book with only 1. e4
+      let mut bytes = Vec::new();
+      bytes.extend_from_slice(&crate::book::polyglot_key(&State::from_start().position).to_be_bytes());
+      bytes.extend_from_slice(&(28u16 | 12 << 6).to_be_bytes());
+      bytes.extend_from_slice(&[0, 1, 0, 0, 0, 0]);
+      let path = std::env::temp_dir().join(format!("chessatk_book_{}.bin", std::process::id()));
+      std::fs::write(&path, bytes).unwrap();
+
+      let (ite_tx, eti_rx, _) = spawn_engine(start);
+      ite_tx.send(InterfaceMessage::LoadBook(path.clone())).unwrap();
+      ite_tx.send(InterfaceMessage::GoDepth(MAX_DEPTH)).unwrap();
+      // straight from the book, without searching
+      match eti_rx.recv().unwrap() {
+         EngineMessage::BestMove(best_move) => assert!(best_move == Some("e2e4".parse().unwrap())),
+         _ => panic!("expected the book move"),
+      }
+      // out of book, so search
+      ite_tx.send(InterfaceMessage::ApplyMove("e2e4".parse().unwrap())).unwrap();
+      ite_tx.send(InterfaceMessage::GoDepth(1)).unwrap();
+      assert!(matches!(eti_rx.recv().unwrap(), EngineMessage::Info { .. }));
+      std::fs::remove_file(&path).unwrap();
+   }
+
+   #[test]
+   fn new_game_forgets_the_last_one() {
+      let (ite_tx, eti_rx, _) = spawn_engine(start);
+      // on one thread the node counts only depend on what's in the table
+      ite_tx.send(InterfaceMessage::SetThreads(1)).unwrap();
+      let search_nodes = |fen: &str| {
+         ite_tx.send(InterfaceMessage::SetState(State::from_fen(fen).unwrap())).unwrap();
+         ite_tx.send(InterfaceMessage::GoDepth(3)).unwrap();
+         let mut nodes = 0;
+         loop {
+            match eti_rx.recv().unwrap() {
+               EngineMessage::SearchStats { nodes: x, .. } => nodes = x,
+               EngineMessage::BestMove(_) => break nodes,
+               _ => (),
+            }
+         }
+      };
+      let fresh = search_nodes(LOOSE_PAWN);
+      // within a game, the table remembers the last search
+      assert!(search_nodes(LOOSE_PAWN) < fresh);
+      ite_tx.send(InterfaceMessage::NewGame).unwrap();
+      assert_eq!(search_nodes(LOOSE_PAWN), fresh);
+   }
+
+   #[test]
+   fn reports_search_stats() {
+      let (ite_tx, eti_rx, _) = spawn_engine(start);
+      ite_tx.send(InterfaceMessage::GoDepth(4)).unwrap();
+      let mut stats = None;
+      loop {
+         match eti_rx.recv().unwrap() {
+            EngineMessage::SearchStats {
+               depth,
+               nodes,
+               nps,
+               time_ms,
+            } => stats = Some((depth, nodes, nps, time_ms)),
+            EngineMessage::BestMove(_) => break,
+            _ => (),
+         }
+      }
+      let (depth, nodes, nps, _) = stats.expect("no stats before the best move");
+      assert_eq!(depth, 4);
+      assert!(nodes > 0);
+      assert!(nps > 0);
    }
 }
@@ -0,0 +1,228 @@
+use crate::board::{Move, ParseError, State};
+use crate::engine::{analyze, SearchLimits};
+use std::path::Path;
+
+/// The operations of an EPD record that matter for test suites
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EpdOps {
+   /// `bm`: playing any of these solves the position
+   pub best_moves: Vec<Move>,
+   /// `am`: none of these should be played
+   pub avoid_moves: Vec<Move>,
+   pub id: Option<String>,
+}
+
+/// Parses an EPD record: the first four fields of a FEN, then operations ended by `;`, as in
+/// `... w - - bm Qxh7+; id "WAC.001";`. `hmvc` and `fmvn` set the clocks, and other operations are ignored.
+/// Moves can be given in SAN or UCI notation
+pub fn parse_epd(line: &str) -> Result<(State, EpdOps), ParseError> {
+   let mut rest = line.trim();
+   let mut fen_fields = [""; 4];
+   for field in fen_fields.iter_mut() {
+      let (x, remainder) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+      if x.is_empty() {
+         return Err(ParseError::BadSection(format!(
+            "malformed EPD; expected 4 position fields before the operations in {}",
+            line
+         )));
+      }
+      *field = x;
+      rest = remainder.trim_start();
+   }
+
+   let operations = epd_operations(rest)?;
+   let operand = |opcode: &str| {
+      operations
+         .iter()
+         .find(|x| x[0] == opcode)
+         .and_then(|x| x.get(1))
+         .map(String::as_str)
+   };
+   let fen = format!(
+      "{} {} {}",
+      fen_fields.join(" "),
+      operand("hmvc").unwrap_or("0"),
+      operand("fmvn").unwrap_or("1")
+   );
+   let state = State::from_fen(&fen)?;
+
+   let mut ops = EpdOps::default();
+   for operation in operations.iter() {
+      match operation[0].as_str() {
+         "bm" => {
+            for x in operation[1..].iter() {
+               ops.best_moves.push(parse_epd_move(&state, x)?);
+            }
+         }
+         "am" => {
+            for x in operation[1..].iter() {
+               ops.avoid_moves.push(parse_epd_move(&state, x)?);
+            }
+         }
+         "id" => ops.id = operation.get(1).cloned(),
+         _ => (),
+      }
+   }
+   Ok((state, ops))
+}
+
+/// How the engine did on one record of a test suite
+#[derive(Clone, Debug, PartialEq)]
+pub struct PositionReport {
+   pub id: Option<String>,
+   pub fen: String,
+   /// What the engine played, None if the game was already over
+   pub best_move: Option<Move>,
+   pub solved: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SuiteReport {
+   /// In the order of the file
+   pub positions: Vec<PositionReport>,
+}
+
+impl SuiteReport {
+   pub fn solved(&self) -> usize {
+      self.positions.iter().filter(|x| x.solved).count()
+   }
+
+   pub fn total(&self) -> usize {
+      self.positions.len()
+   }
+}
+
+/// Searches every record of an EPD file under `limits`. A position is solved if the engine plays one of its `bm`
+/// moves (when it has any) and none of its `am` moves. Blank lines are skipped
+pub fn run_epd_suite(path: impl AsRef<Path>, limits: &SearchLimits) -> Result<SuiteReport, String> {
+   let path = path.as_ref();
+   let text = std::fs::read_to_string(path).map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+   let mut report = SuiteReport::default();
+   for (i, line) in text.lines().enumerate() {
+      if line.trim().is_empty() {
+         continue;
+      }
+      let (state, ops) = parse_epd(line).map_err(|e| format!("{} line {}: {}", path.display(), i + 1, e))?;
+      let best_move = analyze(&state, limits.clone()).best_move;
+      let solved = best_move.is_some_and(|x| {
+         (ops.best_moves.is_empty() || ops.best_moves.contains(&x)) && !ops.avoid_moves.contains(&x)
+      });
+      report.positions.push(PositionReport {
+         id: ops.id,
+         fen: state.to_fen(),
+         best_move,
+         solved,
+      });
+   }
+   Ok(report)
+}
+
+fn parse_epd_move(state: &State, text: &str) -> Result<Move, ParseError> {
+   if let Ok(a_move) = state.position.san_to_move(text) {
+      return Ok(a_move);
+   }
+   match text.parse::<Move>() {
+      Ok(a_move) if state.is_legal(a_move) => Ok(a_move),
+      _ => Err(ParseError::BadSection(format!(
+         "EPD move {} isn't a legal move in {}",
+         text,
+         state.to_fen()
+      ))),
+   }
+}
+
+/// Splits operations into their opcode and operands. Quoted operands keep their whitespace and semicolons
+fn epd_operations(text: &str) -> Result<Vec<Vec<String>>, ParseError> {
+   let mut operations = Vec::new();
+   let mut operation = Vec::new();
+   let mut token = String::new();
+   let mut chars = text.chars();
+   while let Some(c) = chars.next() {
+      match c {
+         '"' => {
+            let mut closed = false;
+            for x in chars.by_ref() {
+               if x == '"' {
+                  closed = true;
+                  break;
+               }
+               token.push(x);
+            }
+            if !closed {
+               return Err(ParseError::BadSection(format!("unterminated string in EPD operations {}", text)));
+            }
+            operation.push(std::mem::take(&mut token));
+         }
+         ';' => {
+            if !token.is_empty() {
+               operation.push(std::mem::take(&mut token));
+            }
+            if !operation.is_empty() {
+               operations.push(std::mem::take(&mut operation));
+            }
+         }
+         c if c.is_whitespace() => {
+            if !token.is_empty() {
+               operation.push(std::mem::take(&mut token));
+            }
+         }
+         c => token.push(c),
+      }
+   }
+   // be lenient about a missing semicolon after the last operation
+   if !token.is_empty() {
+      operation.push(token);
+   }
+   if !operation.is_empty() {
+      operations.push(operation);
+   }
+   Ok(operations)
+}
+
+#[cfg(test)]
+mod tests {
+   use crate::epd::*;
+
+   #[test]
+   fn win_at_chess() {
+      let (state, ops) =
+         parse_epd("2rr3k/pp3pp1/1nnqbN1p/3pN3/2pP4/2P3Q1/PPB4P/R4RK1 w - - bm Qg6; id \"WAC.001\";").unwrap();
+      assert_eq!(state.to_fen(), "2rr3k/pp3pp1/1nnqbN1p/3pN3/2pP4/2P3Q1/PPB4P/R4RK1 w - - 0 1");
+      assert_eq!(ops.best_moves, vec!["g3g6".parse().unwrap()]);
+      assert!(ops.avoid_moves.is_empty());
+      assert_eq!(ops.id.as_deref(), Some("WAC.001"));
+
+      let epd = "r1b2k1r/ppppq3/5N1p/4P2Q/4PP2/1B6/PP5P/n2K2R1 w - - \
+                 id \"a; b\"; am g1g2 Qg4; bm Qxh6+ Qh5h6; hmvc 3; fmvn 20";
+      let (state, ops) = parse_epd(epd).unwrap();
+      assert_eq!(state.halfmove_clock, 3);
+      assert_eq!(state.fullmove_number, 20);
+      assert_eq!(ops.avoid_moves, vec!["g1g2".parse().unwrap(), "h5g4".parse().unwrap()]);
+      assert_eq!(ops.best_moves, vec!["h5h6".parse().unwrap(), "h5h6".parse().unwrap()]);
+      assert_eq!(ops.id.as_deref(), Some("a; b"));
+   }
+
+   #[test]
+   fn bad_epd() {
+      assert!(parse_epd("8/8/8/8 w").is_err());
+      assert!(parse_epd("4k3/8/8/8/8/8/8/4K3 w - - bm Kd3;").is_err());
+      assert!(parse_epd("4k3/8/8/8/8/8/8/4K3 w - - id \"oops;").is_err());
+   }
+
+   #[test]
+   fn win_at_chess_suite() {
+      let limits = SearchLimits {
+         depth: Some(2),
+         deterministic: true,
+         ..SearchLimits::default()
+      };
+      let report = run_epd_suite("tests/wac.epd", &limits).unwrap();
+      assert_eq!(report.total(), 10);
+      assert_eq!(report.solved(), 7);
+      // the queen needs more than two plies to see the mate coming
+      assert_eq!(report.positions[0].id.as_deref(), Some("WAC.001"));
+      assert!(!report.positions[0].solved);
+      assert!(report.positions[9].solved);
+      assert!(report.positions[9].best_move == Some("e3d5".parse().unwrap()));
+   }
+}
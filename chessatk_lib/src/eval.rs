@@ -0,0 +1,626 @@
+use crate::board::{Color, Position, WHITE, PAWN, KNIGHT, BISHOP, ROOK, QUEEN, BLACK, KING, RANK_1, DARK_SQUARES};
+
+// Piece-square tables, in centipawns. Laid out as seen from white's side of the board,
+// so the first row is rank 8. Black pieces look up the mirrored square.
+// There's one set for the middlegame and one for the endgame, see `game_phase`
+const PST_MG: [[i32; 64]; 6] = {
+   let mut tables = [[0; 64]; 6];
+   tables[PAWN] = [
+      0, 0, 0, 0, 0, 0, 0, 0, //
+      50, 50, 50, 50, 50, 50, 50, 50, //
+      10, 10, 20, 30, 30, 20, 10, 10, //
+      5, 5, 10, 25, 25, 10, 5, 5, //
+      0, 0, 0, 20, 20, 0, 0, 0, //
+      5, -5, -10, 0, 0, -10, -5, 5, //
+      5, 10, 10, -20, -20, 10, 10, 5, //
+      0, 0, 0, 0, 0, 0, 0, 0, //
+   ];
+   tables[KNIGHT] = [
+      -50, -40, -30, -30, -30, -30, -40, -50, //
+      -40, -20, 0, 0, 0, 0, -20, -40, //
+      -30, 0, 10, 15, 15, 10, 0, -30, //
+      -30, 5, 15, 20, 20, 15, 5, -30, //
+      -30, 0, 15, 20, 20, 15, 0, -30, //
+      -30, 5, 10, 15, 15, 10, 5, -30, //
+      -40, -20, 0, 5, 5, 0, -20, -40, //
+      -50, -40, -30, -30, -30, -30, -40, -50, //
+   ];
+   tables[BISHOP] = [
+      -20, -10, -10, -10, -10, -10, -10, -20, //
+      -10, 0, 0, 0, 0, 0, 0, -10, //
+      -10, 0, 5, 10, 10, 5, 0, -10, //
+      -10, 5, 5, 10, 10, 5, 5, -10, //
+      -10, 0, 10, 10, 10, 10, 0, -10, //
+      -10, 10, 10, 10, 10, 10, 10, -10, //
+      -10, 5, 0, 0, 0, 0, 5, -10, //
+      -20, -10, -10, -10, -10, -10, -10, -20, //
+   ];
+   tables[ROOK] = [
+      0, 0, 0, 0, 0, 0, 0, 0, //
+      5, 10, 10, 10, 10, 10, 10, 5, //
+      -5, 0, 0, 0, 0, 0, 0, -5, //
+      -5, 0, 0, 0, 0, 0, 0, -5, //
+      -5, 0, 0, 0, 0, 0, 0, -5, //
+      -5, 0, 0, 0, 0, 0, 0, -5, //
+      -5, 0, 0, 0, 0, 0, 0, -5, //
+      0, 0, 0, 5, 5, 0, 0, 0, //
+   ];
+   tables[QUEEN] = [
+      -20, -10, -10, -5, -5, -10, -10, -20, //
+      -10, 0, 0, 0, 0, 0, 0, -10, //
+      -10, 0, 5, 5, 5, 5, 0, -10, //
+      -5, 0, 5, 5, 5, 5, 0, -5, //
+      0, 0, 5, 5, 5, 5, 0, -5, //
+      -10, 5, 5, 5, 5, 5, 0, -10, //
+      -10, 0, 5, 0, 0, 0, 0, -10, //
+      -20, -10, -10, -5, -5, -10, -10, -20, //
+   ];
+   // tucked away behind the pawns
+   tables[KING] = [
+      -30, -40, -40, -50, -50, -40, -40, -30, //
+      -30, -40, -40, -50, -50, -40, -40, -30, //
+      -30, -40, -40, -50, -50, -40, -40, -30, //
+      -30, -40, -40, -50, -50, -40, -40, -30, //
+      -20, -30, -30, -40, -40, -30, -30, -20, //
+      -10, -20, -20, -20, -20, -20, -20, -10, //
+      20, 20, 0, 0, 0, 0, 20, 20, //
+      20, 30, 10, 0, 0, 10, 30, 20, //
+   ];
+   tables
+};
+
+const PST_EG: [[i32; 64]; 6] = {
+   let mut tables = PST_MG;
+   // with fewer pieces around to stop them, every step forward counts
+   tables[PAWN] = [
+      0, 0, 0, 0, 0, 0, 0, 0, //
+      80, 80, 80, 80, 80, 80, 80, 80, //
+      50, 50, 50, 50, 50, 50, 50, 50, //
+      30, 30, 30, 30, 30, 30, 30, 30, //
+      20, 20, 20, 20, 20, 20, 20, 20, //
+      10, 10, 10, 10, 10, 10, 10, 10, //
+      10, 10, 10, 10, 10, 10, 10, 10, //
+      0, 0, 0, 0, 0, 0, 0, 0, //
+   ];
+   // nothing left to fear, so the king should get involved
+   tables[KING] = [
+      -50, -40, -30, -20, -20, -30, -40, -50, //
+      -30, -20, -10, 0, 0, -10, -20, -30, //
+      -30, -10, 20, 30, 30, 20, -10, -30, //
+      -30, -10, 30, 40, 40, 30, -10, -30, //
+      -30, -10, 30, 40, 40, 30, -10, -30, //
+      -30, -10, 20, 30, 30, 20, -10, -30, //
+      -30, -30, 0, 0, 0, 0, -30, -30, //
+      -50, -30, -30, -30, -30, -30, -30, -50, //
+   ];
+   tables
+};
+
+// How much each piece counts towards the game still being in the middlegame
+const PHASE_WEIGHT: [i32; 6] = {
+   let mut weights = [0; 6];
+   weights[KNIGHT] = 1;
+   weights[BISHOP] = 1;
+   weights[ROOK] = 2;
+   weights[QUEEN] = 4;
+   weights
+};
+
+// The phase of the starting position
+const MAX_PHASE: i32 = 24;
+
+/// From `MAX_PHASE` with all the pieces on the board down to 0 with only kings and pawns left
+fn game_phase(position: &Position) -> i32 {
+   let mut phase = 0;
+   for piece in [KNIGHT, BISHOP, ROOK, QUEEN].iter().copied() {
+      let count = (position.squares.pieces[WHITE][piece] | position.squares.pieces[BLACK][piece]).count_ones();
+      phase += count as i32 * PHASE_WEIGHT[piece];
+   }
+   // promotions can take us past the start
+   phase.min(MAX_PHASE)
+}
+
+/// Sum of the piece-square table entries, white minus black, in centipawns.
+/// Blends the middlegame and endgame tables by game phase
+fn piece_square_score(position: &Position) -> i32 {
+   let mut mg_score = 0;
+   let mut eg_score = 0;
+   for piece in [PAWN, KNIGHT, BISHOP, ROOK, QUEEN, KING].iter().copied() {
+      let mut white = position.squares.pieces[WHITE][piece];
+      while white > 0 {
+         let index = white.trailing_zeros() as usize;
+         white &= white - 1;
+         mg_score += PST_MG[piece][index ^ 56];
+         eg_score += PST_EG[piece][index ^ 56];
+      }
+      let mut black = position.squares.pieces[BLACK][piece];
+      while black > 0 {
+         let index = black.trailing_zeros() as usize;
+         black &= black - 1;
+         mg_score -= PST_MG[piece][index];
+         eg_score -= PST_EG[piece][index];
+      }
+   }
+   let phase = game_phase(position);
+   (mg_score * phase + eg_score * (MAX_PHASE - phase)) / MAX_PHASE
+}
+
+const FILE_A: u64 = 0x0101_0101_0101_0101;
+
+// Pawn structure terms, in centipawns. The passed pawn bonus goes by how many ranks the pawn has advanced
+const PASSED_PAWN_BONUS: [i32; 8] = [0, 10, 15, 25, 40, 65, 100, 0];
+const DOUBLED_PAWN_PENALTY: i32 = 15;
+const ISOLATED_PAWN_PENALTY: i32 = 15;
+
+fn adjacent_files(file: u32) -> u64 {
+   let mask = FILE_A << file;
+   (mask << 1 & !FILE_A) | (mask >> 1 & !(FILE_A << 7))
+}
+
+/// Every rank in front of `rank`, from `color`'s point of view
+fn ranks_ahead(color: usize, rank: u32) -> u64 {
+   if color == WHITE {
+      (!0u64).checked_shl(8 * (rank + 1)).unwrap_or(0)
+   } else {
+      (1u64 << (8 * rank)) - 1
+   }
+}
+
+/// Passed, doubled and isolated pawns, white minus black, in centipawns
+fn pawn_structure_score(position: &Position) -> i32 {
+   let mut score = 0;
+   for color in [WHITE, BLACK].iter().copied() {
+      let ours = position.squares.pieces[color][PAWN];
+      let theirs = position.squares.pieces[color ^ 1][PAWN];
+      let mut side_score = 0;
+      for file in 0..8 {
+         let on_file = (ours & FILE_A << file).count_ones() as i32;
+         if on_file > 1 {
+            side_score -= DOUBLED_PAWN_PENALTY * (on_file - 1);
+         }
+         if ours & adjacent_files(file) == 0 {
+            side_score -= ISOLATED_PAWN_PENALTY * on_file;
+         }
+      }
+      let mut pawns = ours;
+      while pawns > 0 {
+         let index = pawns.trailing_zeros();
+         pawns &= pawns - 1;
+         let rank = index / 8;
+         let advanced = if color == WHITE { rank } else { 7 - rank };
+         let lanes = FILE_A << (index % 8) | adjacent_files(index % 8);
+         if theirs & lanes & ranks_ahead(color, rank) == 0 {
+            side_score += PASSED_PAWN_BONUS[advanced as usize];
+         }
+      }
+      score += if color == WHITE { side_score } else { -side_score };
+   }
+   score
+}
+
+// King safety terms, in centipawns. Each enemy piece that attacks the king or a square next to it costs
+// by its kind, as does each file by the king without one of our pawns just in front
+const KING_ATTACKER_PENALTY: [i32; 6] = {
+   let mut penalties = [0; 6];
+   penalties[PAWN] = 10;
+   penalties[KNIGHT] = 20;
+   penalties[BISHOP] = 20;
+   penalties[ROOK] = 40;
+   penalties[QUEEN] = 80;
+   penalties
+};
+const MISSING_SHIELD_PENALTY: i32 = 25;
+
+/// Attackers around each king and holes in its pawn shield, white minus black, in centipawns.
+/// Fades out with the game phase, as there's little left to attack a king with in the endgame
+fn king_safety_score(position: &Position) -> i32 {
+   let mut score = 0;
+   for color in [WHITE, BLACK].iter().copied() {
+      let king = position.squares.pieces[color][KING];
+      if king == 0 {
+         continue;
+      }
+      let king_index = king.trailing_zeros();
+      let them = if color == WHITE { Color::Black } else { Color::White };
+      let zone = king | position.piece_attacks(them, KING, king_index as usize);
+      let mut danger = 0;
+      for piece in [PAWN, KNIGHT, BISHOP, ROOK, QUEEN].iter().copied() {
+         let mut attackers = position.squares.pieces[color ^ 1][piece];
+         while attackers > 0 {
+            let index = attackers.trailing_zeros() as usize;
+            attackers &= attackers - 1;
+            if position.piece_attacks(them, piece, index) & zone != 0 {
+               danger += KING_ATTACKER_PENALTY[piece];
+            }
+         }
+      }
+      // the shield is the two ranks in front of the king
+      let rank = king_index / 8;
+      let behind_shield = if color == WHITE { rank + 2 } else { rank.saturating_sub(2) };
+      let shield = ranks_ahead(color, rank) & !ranks_ahead(color, behind_shield);
+      let files = FILE_A << (king_index % 8) | adjacent_files(king_index % 8);
+      let mut holes = files;
+      let mut shield_pawns = position.squares.pieces[color][PAWN] & shield & files;
+      while shield_pawns > 0 {
+         holes &= !(FILE_A << (shield_pawns.trailing_zeros() % 8));
+         shield_pawns &= shield_pawns - 1;
+      }
+      danger += (holes & RANK_1).count_ones() as i32 * MISSING_SHIELD_PENALTY;
+      score += if color == WHITE { -danger } else { danger };
+   }
+   score * game_phase(position) / MAX_PHASE
+}
+
+// Centipawns for each square a piece attacks that isn't taken by one of its own pieces.
+// Pawns and kings are left to the other terms
+const MOBILITY_BONUS: [i32; 6] = {
+   let mut bonuses = [0; 6];
+   bonuses[KNIGHT] = 8;
+   bonuses[BISHOP] = 10;
+   bonuses[ROOK] = 6;
+   bonuses[QUEEN] = 3;
+   bonuses
+};
+
+/// Squares each piece attacks, weighted by kind, white minus black, in centipawns.
+/// Counting attacks is much cheaper than generating moves, and this runs at every leaf
+fn mobility_score(position: &Position) -> i32 {
+   let mut score = 0;
+   for (color, sign) in [(Color::White, 1), (Color::Black, -1)].iter().copied() {
+      let own = position.squares.all_pieces[color.as_num()];
+      for piece in [KNIGHT, BISHOP, ROOK, QUEEN].iter().copied() {
+         let mut pieces = position.squares.pieces[color.as_num()][piece];
+         while pieces > 0 {
+            let index = pieces.trailing_zeros() as usize;
+            pieces &= pieces - 1;
+            let squares = (position.piece_attacks(color, piece, index) & !own).count_ones() as i32;
+            score += sign * squares * MOBILITY_BONUS[piece];
+         }
+      }
+   }
+   score
+}
+
+// Centipawns for having bishops on both colors of square
+const BISHOP_PAIR_BONUS: i32 = 50;
+
+/// White minus black, in centipawns
+fn bishop_pair_score(position: &Position) -> i32 {
+   let has_pair = |color: usize| {
+      let bishops = position.squares.pieces[color][BISHOP];
+      bishops & DARK_SQUARES != 0 && bishops & !DARK_SQUARES != 0
+   };
+   match (has_pair(WHITE), has_pair(BLACK)) {
+      (true, false) => BISHOP_PAIR_BONUS,
+      (false, true) => -BISHOP_PAIR_BONUS,
+      _ => 0,
+   }
+}
+
+/// Material values and how much each term of the evaluation counts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EvalParams {
+   /// In pawns, by piece kind
+   pub material: [f64; 6],
+   pub material_weight: f64,
+   // the rest of the terms are in centipawns
+   pub mobility_weight: f64,
+   pub piece_square_weight: f64,
+   pub pawn_structure_weight: f64,
+   pub king_safety_weight: f64,
+   pub bishop_pair_weight: f64,
+   /// In pawns. How much worse than an even position a draw is to the side that's searching, so that it
+   /// plays on rather than repeating. Not part of the static evaluation; the search scores draws with it
+   pub contempt: f64,
+}
+
+impl Default for EvalParams {
+   fn default() -> EvalParams {
+      let mut material = [0.0; 6];
+      material[PAWN] = 1.0;
+      material[KNIGHT] = 3.0;
+      material[BISHOP] = 3.0;
+      material[ROOK] = 5.0;
+      material[QUEEN] = 9.0;
+      EvalParams {
+         material,
+         material_weight: 0.9,
+         mobility_weight: 0.005,
+         piece_square_weight: 0.005,
+         pawn_structure_weight: 0.005,
+         king_safety_weight: 0.005,
+         bishop_pair_weight: 0.005,
+         contempt: 0.1,
+      }
+   }
+}
+
+/// Static evaluation in pawns, relative to `side_to_move`: positive when `side_to_move` is better off.
+/// The terms are all worked out white minus black and flipped at the end for black, so a position and its
+/// `mirror_vertical` score the same for the side to move
+pub(crate) fn evaluate(position: &Position, side_to_move: Color, params: &EvalParams) -> f64 {
+   let mut mat_score = 0.0;
+   for piece in [PAWN, KNIGHT, BISHOP, ROOK, QUEEN].iter().copied() {
+      let count = f64::from(position.squares.pieces[WHITE][piece].count_ones())
+         - f64::from(position.squares.pieces[BLACK][piece].count_ones());
+      mat_score += count * params.material[piece];
+   }
+
+   let mobility_score = f64::from(mobility_score(position));
+   let pst_score = f64::from(piece_square_score(position));
+   let pawn_score = f64::from(pawn_structure_score(position));
+   let king_score = f64::from(king_safety_score(position));
+   let bishop_pair_score = f64::from(bishop_pair_score(position));
+
+   let final_score = mat_score * params.material_weight
+      + mobility_score * params.mobility_weight
+      + pst_score * params.piece_square_weight
+      + pawn_score * params.pawn_structure_weight
+      + king_score * params.king_safety_weight
+      + bishop_pair_score * params.bishop_pair_weight;
+
+   if side_to_move == Color::White {
+      final_score
+   } else {
+      -final_score
+   }
+}
+
+/// Static evaluation with the default parameters, + for white and - for black.
+/// This is the convention for every eval that leaves the engines, whatever they use internally
+pub fn static_eval_white_pov(position: &Position) -> f64 {
+   evaluate(position, Color::White, &EvalParams::default())
+}
+
+/// Rounds a score in pawns to the nearest centipawn, the unit evals are given in outside the engines.
+/// Mate scores come out in the hundreds of thousands, so check for them with `moves_to_mate` first
+pub fn to_centipawns(pawns: f64) -> i32 {
+   (pawns * 100.0).round() as i32
+}
+
+/// Converts a score relative to `side_to_move`, which is what the searches work with, to white's point of view
+pub fn to_white_pov(score: f64, side_to_move: Color) -> f64 {
+   match side_to_move {
+      Color::White => score,
+      Color::Black => -score,
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use crate::board::{Move, State};
+   use crate::eval::*;
+   use crate::mcts::{MctsParams, Rollout};
+   use crate::messages::{EngineMessage, InterfaceMessage};
+   use std::sync::mpsc;
+   use std::thread;
+   use std::time::{Duration, Instant};
+
+   fn default_eval(position: &Position) -> f64 {
+      evaluate(position, position.side_to_move, &EvalParams::default())
+   }
+
+   #[test]
+   fn mirrored_positions_evaluate_opposite() {
+      let assert_symmetric = |position: &Position| {
+         let mirrored = position.mirror_vertical();
+         // each side sees the same score, so from white's side they're opposite
+         assert_eq!(static_eval_white_pov(position), -static_eval_white_pov(&mirrored));
+         let params = EvalParams::default();
+         assert_eq!(
+            evaluate(position, Color::White, &params),
+            evaluate(&mirrored, Color::Black, &params)
+         );
+      };
+      let fens = std::fs::read_to_string("tests/positions.fen").unwrap();
+      for fen in fens.lines() {
+         let state = State::from_fen(fen).unwrap();
+         assert_symmetric(&state.position);
+         // and a ply deeper, for more lopsided positions
+         let mut moves = Vec::new();
+         state.gen_moves(&mut moves);
+         for a_move in moves {
+            assert_symmetric(&state.after_move(a_move.extract()).position);
+         }
+      }
+   }
+
+   #[test]
+   fn knight_prefers_the_center() {
+      let center = State::from_fen("4k3/8/8/8/4N3/8/8/4K3 w - - 0 1").unwrap().position;
+      let corner = State::from_fen("4k3/8/8/8/8/8/8/N3K3 w - - 0 1").unwrap().position;
+      assert!(piece_square_score(&center) > piece_square_score(&corner));
+      assert!(default_eval(&center) > default_eval(&corner));
+      // and the same goes for black
+      let center = State::from_fen("4k3/8/8/4n3/8/8/8/4K3 w - - 0 1").unwrap().position;
+      let corner = State::from_fen("n3k3/8/8/8/8/8/8/4K3 w - - 0 1").unwrap().position;
+      assert!(piece_square_score(&center) < piece_square_score(&corner));
+   }
+
+   #[test]
+   fn passed_pawn_beats_blocked_pawn() {
+      let passed = State::from_fen("4k3/p7/4P3/8/8/8/8/4K3 w - - 0 1").unwrap().position;
+      let blocked = State::from_fen("4k3/4p3/4P3/8/8/8/8/4K3 w - - 0 1").unwrap().position;
+      assert!(pawn_structure_score(&passed) > pawn_structure_score(&blocked));
+      assert!(default_eval(&passed) > default_eval(&blocked));
+   }
+
+   #[test]
+   fn doubled_pawns_are_penalized() {
+      let doubled = State::from_fen("4k3/8/8/8/8/3P4/3P4/4K3 w - - 0 1").unwrap().position;
+      let side_by_side = State::from_fen("4k3/8/8/8/8/3P4/2P5/4K3 w - - 0 1").unwrap().position;
+      assert!(pawn_structure_score(&doubled) < pawn_structure_score(&side_by_side));
+      // and the same goes for black
+      let doubled = State::from_fen("4k3/3p4/3p4/8/8/8/8/4K3 w - - 0 1").unwrap().position;
+      let side_by_side = State::from_fen("4k3/2p5/3p4/8/8/8/8/4K3 w - - 0 1").unwrap().position;
+      assert!(pawn_structure_score(&doubled) > pawn_structure_score(&side_by_side));
+   }
+
+   #[test]
+   fn pawn_shield_protects_the_king() {
+      let sheltered = State::from_fen("3qr1k1/5ppp/8/8/8/8/5PPP/3QR1K1 w - - 0 1").unwrap().position;
+      let exposed = State::from_fen("3qr1k1/5ppp/8/8/5PPP/8/8/3QR1K1 w - - 0 1").unwrap().position;
+      assert!(king_safety_score(&sheltered) > king_safety_score(&exposed));
+      assert!(default_eval(&sheltered) > default_eval(&exposed));
+      // attackers around the king count against it too
+      let attacked = State::from_fen("4r1k1/5ppp/8/8/6q1/8/5PPP/3QR1K1 w - - 0 1").unwrap().position;
+      let quiet = State::from_fen("2q1r1k1/5ppp/8/8/8/8/5PPP/3QR1K1 w - - 0 1").unwrap().position;
+      assert!(king_safety_score(&attacked) < king_safety_score(&quiet));
+      // with nothing left to attack with, it doesn't matter
+      let endgame_sheltered = State::from_fen("6k1/5ppp/8/8/8/8/5PPP/6K1 w - - 0 1").unwrap().position;
+      assert_eq!(king_safety_score(&endgame_sheltered), 0);
+   }
+
+   #[test]
+   fn bishop_pair_beats_bishop_and_knight() {
+      let pair = State::from_fen("4k3/8/8/8/8/8/8/2B1KB2 w - - 0 1").unwrap().position;
+      let mixed = State::from_fen("4k3/8/8/8/8/8/8/2B1KN2 w - - 0 1").unwrap().position;
+      assert_eq!(bishop_pair_score(&pair), BISHOP_PAIR_BONUS);
+      assert_eq!(bishop_pair_score(&mixed), 0);
+      assert!(default_eval(&pair) > default_eval(&mixed));
+      // two bishops on the same color of square aren't a pair
+      let same_color = State::from_fen("4k3/8/8/8/8/8/8/1B2KB2 w - - 0 1").unwrap().position;
+      assert_eq!(bishop_pair_score(&same_color), 0);
+   }
+
+   #[test]
+   fn mobility_favors_active_pieces() {
+      let active = State::from_fen("4k3/8/8/8/3B4/8/8/4K3 w - - 0 1").unwrap().position;
+      let hemmed_in = State::from_fen("4k3/8/8/8/8/8/1P6/B3K3 w - - 0 1").unwrap().position;
+      assert!(mobility_score(&active) > mobility_score(&hemmed_in));
+      assert_eq!(mobility_score(&hemmed_in), 0);
+   }
+
+   #[test]
+   fn eval_params_change_the_evaluation() {
+      // a knight up, with every term but material turned off
+      let position = State::from_fen("4k3/8/8/8/8/8/8/N3K3 w - - 0 1").unwrap().position;
+      let mut params = EvalParams {
+         mobility_weight: 0.0,
+         piece_square_weight: 0.0,
+         pawn_structure_weight: 0.0,
+         king_safety_weight: 0.0,
+         bishop_pair_weight: 0.0,
+         ..EvalParams::default()
+      };
+      params.material_weight = 1.0;
+      assert_eq!(evaluate(&position, Color::White, &params), 3.0);
+      assert_eq!(evaluate(&position, Color::Black, &params), -3.0);
+      params.material[KNIGHT] = 3.5;
+      assert_eq!(evaluate(&position, Color::White, &params), 3.5);
+      // the knight is out of play in the corner
+      params.piece_square_weight = 0.01;
+      assert!(evaluate(&position, Color::White, &params) < 3.5);
+   }
+
+   #[test]
+   fn king_safety_gives_way_to_centralization() {
+      let middlegame_safe = State::from_fen("rnbqkbnr/pppppppp/8/8/8/3B1N2/PPPPPPPP/RNBQ1RK1 w kq - 0 1")
+         .unwrap()
+         .position;
+      let middlegame_central = State::from_fen("rnbqkbnr/pppppppp/8/8/4K3/3B1N2/PPPPPPPP/RNBQ1R2 w kq - 0 1")
+         .unwrap()
+         .position;
+      assert_eq!(game_phase(&middlegame_safe), MAX_PHASE);
+      assert!(piece_square_score(&middlegame_safe) > piece_square_score(&middlegame_central));
+
+      let endgame_safe = State::from_fen("4k3/4p3/8/8/8/8/4P3/6K1 w - - 0 1").unwrap().position;
+      let endgame_central = State::from_fen("4k3/4p3/8/8/4K3/8/4P3/8 w - - 0 1").unwrap().position;
+      assert_eq!(game_phase(&endgame_safe), 0);
+      assert!(piece_square_score(&endgame_safe) < piece_square_score(&endgame_central));
+   }
+
+   /// Runs `start` on its own thread, with the channels to talk to it and the thread to join once it quits
+   fn spawn_engine(
+      start: fn(mpsc::Receiver<InterfaceMessage>, mpsc::Sender<EngineMessage>),
+   ) -> (mpsc::Sender<InterfaceMessage>, mpsc::Receiver<EngineMessage>, thread::JoinHandle<()>) {
+      let (ite_tx, ite_rx) = mpsc::channel();
+      let (eti_tx, eti_rx) = mpsc::channel();
+      let engine = thread::spawn(move || start(ite_rx, eti_tx));
+      (ite_tx, eti_rx, engine)
+   }
+
+   /// Skips the search's reports until its best move, which has to come within `timeout`
+   fn wait_for_best_move(receiver: &mpsc::Receiver<EngineMessage>, timeout: Duration) -> Option<Move> {
+      let deadline = Instant::now() + timeout;
+      loop {
+         match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(EngineMessage::BestMove(best_move)) => return best_move,
+            Ok(EngineMessage::Info { .. }) | Ok(EngineMessage::SearchStats { .. }) => (),
+            _ => panic!("no best move within {:?}", timeout),
+         }
+      }
+   }
+
+   /// Runs a search through the engine's messages, then asks for the eval
+   fn reported_eval(
+      start: fn(mpsc::Receiver<InterfaceMessage>, mpsc::Sender<EngineMessage>),
+      state: &State,
+      setup: Vec<InterfaceMessage>,
+      go: InterfaceMessage,
+   ) -> i32 {
+      let (ite_tx, eti_rx, _) = spawn_engine(start);
+      for message in setup {
+         ite_tx.send(message).unwrap();
+      }
+      ite_tx.send(InterfaceMessage::SetState(state.clone())).unwrap();
+      ite_tx.send(go).unwrap();
+      wait_for_best_move(&eti_rx, Duration::from_secs(60));
+      ite_tx.send(InterfaceMessage::QueryEval).unwrap();
+      loop {
+         if let EngineMessage::CurrentEval(eval) = eti_rx.recv().unwrap() {
+            return eval;
+         }
+      }
+   }
+
+   #[test]
+   fn engines_agree_on_eval_sign() {
+      // black is a queen up. With black to move, forgetting to flip the sign would show
+      let state = State::from_fen("3qk3/8/8/8/8/8/8/4K3 b - - 0 1").unwrap();
+      let negamax = reported_eval(crate::engine::start, &state, Vec::new(), InterfaceMessage::GoDepth(3));
+      let mcts_params = MctsParams {
+         threads: 2,
+         rollout: Rollout {
+            eval_guided: false,
+            max_plies: Some(4),
+            win_margin: None,
+         },
+         ..Default::default()
+      };
+      let mcts = reported_eval(
+         crate::mcts::start,
+         &state,
+         vec![InterfaceMessage::SetMctsParams(mcts_params)],
+         InterfaceMessage::GoTime(Duration::from_millis(200)),
+      );
+      assert!(static_eval_white_pov(&state.position) < 0.0);
+      assert!(negamax < 0, "{}", negamax);
+      assert!(mcts < 0, "{}", mcts);
+   }
+
+   #[test]
+   fn reported_in_centipawns() {
+      assert_eq!(to_centipawns(2.7000000001), 270);
+      assert_eq!(to_centipawns(-0.015), -2);
+      assert_eq!(to_centipawns(0.0), 0);
+      // a pawn up, whoever is to move
+      for fen in ["4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", "4k3/8/8/8/8/8/4P3/4K3 b - - 0 1"] {
+         let state = State::from_fen(fen).unwrap();
+         let eval = reported_eval(crate::engine::start, &state, Vec::new(), InterfaceMessage::GoDepth(1));
+         assert!((50..=150).contains(&eval), "{}: {}", fen, eval);
+      }
+   }
+
+   #[test]
+   fn white_pov_ignores_side_to_move() {
+      let white_to_move = State::from_fen("3qk3/8/8/8/8/8/8/4K3 w - - 0 1").unwrap().position;
+      let black_to_move = State::from_fen("3qk3/8/8/8/8/8/8/4K3 b - - 0 1").unwrap().position;
+      assert!(static_eval_white_pov(&white_to_move) < 0.0);
+      assert_eq!(static_eval_white_pov(&white_to_move), static_eval_white_pov(&black_to_move));
+      assert_eq!(
+         to_white_pov(default_eval(&black_to_move), Color::Black),
+         static_eval_white_pov(&black_to_move)
+      );
+   }
+}
@@ -0,0 +1,116 @@
+use crate::board::{GameStatus, Move, State, StateUndo};
+use crate::pgn::game_to_pgn;
+
+/// A game from some starting position, along with every move played since, so that moves can be taken back
+#[derive(Clone)]
+pub struct Game {
+   start: State,
+   moves: Vec<Move>,
+   current: State,
+   // one for each of `moves`
+   undos: Vec<StateUndo>,
+}
+
+impl Game {
+   pub fn new(start: State) -> Game {
+      Game {
+         current: start.clone(),
+         start,
+         moves: Vec::new(),
+         undos: Vec::new(),
+      }
+   }
+
+   pub fn start(&self) -> &State {
+      &self.start
+   }
+
+   pub fn moves(&self) -> &[Move] {
+      &self.moves
+   }
+
+   pub fn current(&self) -> &State {
+      &self.current
+   }
+
+   /// Plays `a_move`, which has to be legal in the current position
+   pub fn push(&mut self, a_move: Move) -> Result<(), String> {
+      if !self.current.is_legal(a_move) {
+         return Err(format!("{} is illegal in {}", a_move, self.current.to_fen()));
+      }
+      self.undos.push(self.current.make_move(a_move));
+      self.moves.push(a_move);
+      Ok(())
+   }
+
+   /// Takes back the last move and returns it, or None if no moves have been played
+   pub fn pop(&mut self) -> Option<Move> {
+      let a_move = self.moves.pop()?;
+      self.current.unmake_move(self.undos.pop().unwrap());
+      Some(a_move)
+   }
+
+   pub fn status(&self) -> GameStatus {
+      let mut moves = Vec::new();
+      self.current.gen_moves(&mut moves);
+      self.current.status(&moves)
+   }
+
+   /// As `game_to_pgn`
+   pub fn to_pgn(&self, tags: &[(String, String)]) -> String {
+      game_to_pgn(&self.start, &self.moves, tags)
+   }
+}
+
+impl Default for Game {
+   fn default() -> Game {
+      Game::new(State::from_start())
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use crate::board::{Color, VictoryReason};
+   use crate::game::*;
+   use crate::pgn::pgn_to_game;
+
+   fn play(game: &mut Game, moves: &str) {
+      for a_move in moves.split_whitespace() {
+         game.push(a_move.parse().unwrap()).unwrap();
+      }
+   }
+
+   #[test]
+   fn push_and_pop() {
+      let mut game = Game::default();
+      play(&mut game, "e2e4 e7e5 g1f3");
+      assert!(*game.current() == State::from_moves("e2e4 e7e5 g1f3").unwrap());
+      assert_eq!(game.pop(), Some("g1f3".parse().unwrap()));
+      assert!(*game.current() == State::from_moves("e2e4 e7e5").unwrap());
+      assert_eq!(game.pop(), Some("e7e5".parse().unwrap()));
+      assert_eq!(game.pop(), Some("e2e4".parse().unwrap()));
+      assert_eq!(game.pop(), None);
+      assert!(game.current() == game.start());
+      assert!(game.moves().is_empty());
+
+      // an illegal move changes nothing
+      assert!(game.push("e2e5".parse().unwrap()).is_err());
+      assert!(*game.current() == State::from_start());
+   }
+
+   #[test]
+   fn scholars_mate() {
+      let mut game = Game::default();
+      play(&mut game, "e2e4 e7e5 d1h5 b8c6 f1c4 g8f6");
+      assert_eq!(game.status(), GameStatus::Ongoing);
+      play(&mut game, "h5f7");
+      assert_eq!(game.status(), GameStatus::Victory(Color::White, VictoryReason::Checkmate));
+
+      let (start, moves) = pgn_to_game(&game.to_pgn(&[])).unwrap();
+      assert!(start == *game.start());
+      assert_eq!(moves, game.moves());
+
+      game.pop();
+      assert_eq!(game.status(), GameStatus::Ongoing);
+   }
+}
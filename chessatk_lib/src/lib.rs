@@ -1,6 +1,10 @@
-#![feature(drain_filter)]
-
 pub mod board;
+pub mod book;
 pub mod engine;
+pub mod epd;
+pub mod eval;
+pub mod game;
 pub mod mcts;
 pub mod messages;
+pub mod pgn;
+pub mod tt;
@@ -1,15 +1,29 @@
-use crate::board::{Color, CompressedMove, GameStatus, Move, PromotionTarget, State};
+use crate::board::{Color, CompressedMove, GameStatus, Move, Position, PromotionTarget, State};
+use crate::eval::{evaluate, static_eval_white_pov, to_centipawns, to_white_pov, EvalParams};
 use crate::messages::{EngineMessage, InterfaceMessage};
 use log::trace;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use noisy_float::prelude::*;
 use rand::prelude::SliceRandom;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::fs::File;
-use std::hint::unreachable_unchecked;
 use std::io::{BufWriter, Write};
-use std::sync::atomic::AtomicU64;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64};
 use std::sync::mpsc;
 use std::time::{Duration, Instant};
 
+// How many plies below the root to look for a new state, before giving up on the tree
+const REROOT_PLIES: usize = 2;
+
+// mcts has no depth, so `go depth` gets this many simulations for each ply asked for
+const SIMULATIONS_PER_DEPTH: u64 = 1000;
+
+// Softmax temperature for evaluation guided rollouts, in pawns. Lower plays the best looking move more often
+const ROLLOUT_TEMPERATURE: f64 = 0.5;
+
 static DRAWS: AtomicU64 = AtomicU64::new(0);
 static I_WIN: AtomicU64 = AtomicU64::new(0);
 static I_LOSE: AtomicU64 = AtomicU64::new(0);
@@ -18,49 +32,182 @@ pub fn start(receiver: mpsc::Receiver<InterfaceMessage>, sender: mpsc::Sender<En
    let mut state = State::from_start();
    let mut last_eval = 0.0f64;
    let mut mcts_state = MctsState::init();
-   while let Ok(message) = receiver.recv() {
+   let mut params = MctsParams::default();
+   // a message that ended a search early, still to be handled
+   let mut pending: Option<InterfaceMessage> = None;
+   loop {
+      let message = match pending.take() {
+         Some(message) => message,
+         None => match receiver.recv() {
+            Ok(message) => message,
+            Err(_) => break,
+         },
+      };
       match message {
-         InterfaceMessage::GoDepth(_depth) => {
-            // doesn't make sense for mcts
-            unreachable!()
+         InterfaceMessage::GoDepth(depth) => {
+            let simulations_before = mcts_state.tree.lock().get(mcts_state.root).map_or(0, |x| x.stats.simulations);
+            let search_start = Instant::now();
+            let budget = Budget::Simulations(SIMULATIONS_PER_DEPTH.saturating_mul(depth.max(1)));
+            let result = mcts(&mut mcts_state, budget, &state, &params);
+            last_eval = report_search(&mcts_state, &state, &params, result, search_start, simulations_before, &sender);
          }
-         InterfaceMessage::GoTime(time_budget) => {
-            let result = mcts(&mut mcts_state, &time_budget, &state, 0.3);
-
-            if let Some(res) = result {
-               if state.position.side_to_move == Color::Black {
-                  // eval is always relative to side to move, but we want eval to be + for white and - for black
-                  last_eval = 1.0 - res.1;
-               }
-            }
-
-            {
-               let tree = mcts_state.tree.lock();
-               trace!(
-                  "finished thinking after {} simulations. odds of victory: {}%",
-                  tree[mcts_state.root].stats.simulations,
-                  (1.0 - (tree[mcts_state.root].stats.score / tree[mcts_state.root].stats.simulations as f64)) * 100.0,
-               );
+         InterfaceMessage::GoInfinite | InterfaceMessage::GoPonder(_) => {
+            let time_budget = match message {
+               InterfaceMessage::GoPonder(time_budget) => time_budget,
+               _ => None,
+            };
+            let simulations_before = mcts_state.tree.lock().get(mcts_state.root).map_or(0, |x| x.stats.simulations);
+            let search_start = Instant::now();
+            let (result, interruption) =
+               search_until_stopped(&mut mcts_state, &state, &params, time_budget, &receiver);
+            if let Some(InterfaceMessage::Quit) = interruption {
+               break;
             }
-            emit_debug_tree(&mcts_state);
-
-            sender.send(EngineMessage::BestMove(result.map(|x| x.0))).unwrap();
+            pending = interruption;
+            last_eval = report_search(&mcts_state, &state, &params, result, search_start, simulations_before, &sender);
+         }
+         InterfaceMessage::RestrictNextSearch(_) => {
+            // mcts always searches every move
+         }
+         InterfaceMessage::Stop | InterfaceMessage::PonderHit => {
+            // only searches that are still running can be stopped, and those take their own messages
+         }
+         InterfaceMessage::GoTime(time_budget) => {
+            let simulations_before = mcts_state.tree.lock().get(mcts_state.root).map_or(0, |x| x.stats.simulations);
+            let search_start = Instant::now();
+            let result = mcts(&mut mcts_state, Budget::Time(time_budget), &state, &params);
+            last_eval = report_search(&mcts_state, &state, &params, result, search_start, simulations_before, &sender);
          }
          InterfaceMessage::QueryEval => {
-            sender.send(EngineMessage::CurrentEval(last_eval)).unwrap();
+            sender.send(EngineMessage::CurrentEval(to_centipawns(last_eval))).unwrap();
          }
          InterfaceMessage::SetState(new_state) => {
-            mcts_state.reset();
+            // interfaces like lichess send the whole state every move, which is usually just ahead of ours
+            if !mcts_state.reroot(&state, &new_state) {
+               mcts_state.reset();
+            }
             state = new_state;
          }
          InterfaceMessage::ApplyMove(m) => {
             mcts_state.move_root_down(m);
             state.apply_move(m);
          }
+         InterfaceMessage::NewGame => {
+            mcts_state.reset();
+            state = State::from_start();
+            last_eval = 0.0;
+         }
+         InterfaceMessage::SetHashSize(_) => {
+            // no transposition table in mcts
+         }
+         InterfaceMessage::SetThreads(threads) => {
+            params.threads = threads.max(1);
+         }
+         InterfaceMessage::SetMultiPv(_) => {
+            // mcts only ever reports its best move
+         }
+         InterfaceMessage::SetEvalParams(_) => {
+            // mcts scores positions by playing them out, not by evaluating them
+         }
+         InterfaceMessage::LoadBook(_) => {
+            // mcts always searches
+         }
+         InterfaceMessage::SetMctsParams(new_params) => {
+            params = new_params;
+         }
+         InterfaceMessage::SetVerbosity(_) => {
+            // mcts only traces once per search
+         }
+         InterfaceMessage::Quit => {
+            // searches finish before the next message is read, so there is nothing to abandon
+            break;
+         }
       }
    }
 }
 
+/// Searches on another thread until this one hears a Stop, or until `time_budget` runs out after a PonderHit.
+/// Any other message ends the search too, and is returned to be handled afterwards, with a Quit standing in
+/// for the interface hanging up
+fn search_until_stopped(
+   mcts_state: &mut MctsState,
+   state: &State,
+   params: &MctsParams,
+   time_budget: Option<Duration>,
+   receiver: &mpsc::Receiver<InterfaceMessage>,
+) -> (Option<Move>, Option<InterfaceMessage>) {
+   let stop = AtomicBool::new(false);
+   std::thread::scope(|s| {
+      let search = s.spawn(|| mcts(mcts_state, Budget::UntilStopped(&stop), state, params));
+      // the clock doesn't start until the move we pondered on is played
+      let mut deadline: Option<Instant> = None;
+      let interruption = loop {
+         let message = match deadline {
+            Some(deadline) => receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => receiver.recv().map_err(mpsc::RecvTimeoutError::from),
+         };
+         match message {
+            Ok(InterfaceMessage::Stop) | Err(mpsc::RecvTimeoutError::Timeout) => break None,
+            Ok(InterfaceMessage::PonderHit) => deadline = time_budget.map(|x| Instant::now() + x),
+            Ok(message) => break Some(message),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break Some(InterfaceMessage::Quit),
+         }
+      };
+      stop.store(true, std::sync::atomic::Ordering::Relaxed);
+      (search.join().unwrap(), interruption)
+   })
+}
+
+/// Tells the interface what a search found, and returns its eval, + for white
+fn report_search(
+   mcts_state: &MctsState,
+   state: &State,
+   params: &MctsParams,
+   result: Option<Move>,
+   search_start: Instant,
+   simulations_before: u64,
+   sender: &mpsc::Sender<EngineMessage>,
+) -> f64 {
+   let elapsed = search_start.elapsed();
+
+   let report = root_report(mcts_state);
+   trace!(
+      "finished thinking after {} simulations. odds of victory: {}%",
+      report.simulations,
+      report.win_probability * 100.0,
+   );
+   if let Some(path) = params.debug_tree.as_deref() {
+      emit_debug_tree(mcts_state, path);
+   }
+
+   let score = win_probability_to_eval(report.win_probability);
+   // score is relative to side to move, but we report it + for white and - for black
+   let last_eval = to_white_pov(score, state.position.side_to_move);
+   sender
+      .send(EngineMessage::Info {
+         depth: report.pv.len() as u64,
+         multipv: 1,
+         score,
+         pv: report.pv.clone(),
+      })
+      .unwrap();
+   let simulations = report.simulations - simulations_before;
+   sender
+      .send(EngineMessage::SearchStats {
+         depth: report.pv.len() as u64,
+         nodes: simulations,
+         nps: if elapsed.is_zero() {
+            0
+         } else {
+            (simulations as f64 / elapsed.as_secs_f64()) as u64
+         },
+         time_ms: elapsed.as_millis() as u64,
+      })
+      .unwrap();
+   sender.send(EngineMessage::BestMove(result)).unwrap();
+   last_eval
+}
+
 struct Node {
    last_move: CompressedMove,
    last_player: Color,
@@ -93,19 +240,56 @@ fn ucb1(exploration_val: f64, node_stats: &NodeStats, parent_stats: &NodeStats)
    win_rate + exploration_score
 }
 
-struct MctsState {
+/// The search tree, kept from one search to the next
+pub struct MctsState {
    tree: parking_lot::Mutex<Vec<Node>>,
    root: usize,
 }
 
+/// A copy of the top of the search tree, for looking at what a search found
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TreeSnapshot {
+   /// The move that led here, None at the root
+   pub last_move: Option<Move>,
+   pub simulations: u64,
+   /// Summed over the simulations, for the side that played `last_move`. Infinite once the result is proven
+   pub score: f64,
+   /// Most simulated first
+   pub children: Vec<TreeSnapshot>,
+}
+
 impl MctsState {
-   fn init() -> MctsState {
+   pub fn init() -> MctsState {
       MctsState {
          tree: parking_lot::Mutex::new(Vec::new()),
          root: 0,
       }
    }
 
+   /// Runs `simulations` playouts into this tree on the calling thread, as `mcts_blocking` does
+   pub fn search_blocking(&mut self, state: &State, simulations: u64, params: &MctsParams) -> Option<Move> {
+      let params = MctsParams {
+         threads: 1,
+         ..params.clone()
+      };
+      mcts(self, Budget::Simulations(simulations), state, &params)
+   }
+
+   /// The tree down to `max_depth` plies below the root
+   pub fn snapshot(&self, max_depth: usize) -> TreeSnapshot {
+      let tree = self.tree.lock();
+      if tree.is_empty() {
+         return TreeSnapshot {
+            last_move: None,
+            simulations: 0,
+            score: 0.0,
+            children: Vec::new(),
+         };
+      }
+      snapshot_node(&tree, self.root, true, max_depth)
+   }
+
    fn move_root_down(&mut self, a_move: Move) {
       let mut tree = self.tree.lock();
 
@@ -114,21 +298,38 @@ impl MctsState {
          .and_then(|x| x.children.iter().find(|y| tree[**y].last_move == a_move.compress()));
 
       if let Some(n) = new_root {
-         self.root = *n;
+         let n = *n;
+         compact(&mut tree, n);
       } else {
          tree.clear();
-         self.root = 0;
       }
+      self.root = 0;
 
       trace!(
-         "Moved MCTS root. New root has {} simulations",
-         tree.get(self.root).map(|x| x.stats.simulations).unwrap_or(0)
+         "Moved MCTS root. New root has {} simulations and {} nodes under it",
+         tree.get(self.root).map(|x| x.stats.simulations).unwrap_or(0),
+         tree.len(),
       );
+   }
 
-      // we could in theory try to "garbage collect" the
-      // now dead branches of the tree - not sure if reducing
-      // memory usage would help at all, or if it would just
-      // be overhead
+   /// If `new_state` is in the tree a few plies below the root (which is at `state`), moves the root down to it
+   /// and keeps what we know about it. Returns whether it did
+   fn reroot(&mut self, state: &State, new_state: &State) -> bool {
+      let path = {
+         let tree = self.tree.lock();
+         if tree.is_empty() {
+            return false;
+         }
+         let mut path = Vec::new();
+         if !find_path(&tree, self.root, &mut state.clone(), &new_state.position, REROOT_PLIES, &mut path) {
+            return false;
+         }
+         path
+      };
+      for a_move in path {
+         self.move_root_down(a_move);
+      }
+      true
    }
 
    fn reset(&mut self) {
@@ -139,19 +340,262 @@ impl MctsState {
    }
 }
 
-fn mcts(
-   mcts_state: &mut MctsState,
-   time_budget: &Duration,
-   state: &State,
-   exploration_val: f64,
-) -> Option<(Move, f64)> {
+fn snapshot_node(tree: &[Node], i: usize, is_root: bool, depth_left: usize) -> TreeSnapshot {
+   let node = &tree[i];
+   let mut children = Vec::new();
+   if depth_left > 0 {
+      let mut sorted = node.children.clone();
+      sorted.sort_by_key(|x| std::cmp::Reverse(tree[*x].stats.simulations));
+      children = sorted.into_iter().map(|x| snapshot_node(tree, x, false, depth_left - 1)).collect();
+   }
+   TreeSnapshot {
+      last_move: if is_root { None } else { Some(node.last_move.extract()) },
+      simulations: node.stats.simulations,
+      score: node.stats.score,
+      children,
+   }
+}
+
+/// Looks for `target` at or below `node`, whose position is `state`. On success, `path` holds the moves to it
+fn find_path(
+   tree: &[Node],
+   node: usize,
+   state: &mut State,
+   target: &Position,
+   plies_left: usize,
+   path: &mut Vec<Move>,
+) -> bool {
+   if state.position == *target {
+      return true;
+   }
+   if plies_left == 0 {
+      return false;
+   }
+   for child in tree[node].children.iter() {
+      let a_move = tree[*child].last_move.extract();
+      let undo = state.make_move(a_move);
+      path.push(a_move);
+      let found = find_path(tree, *child, state, target, plies_left - 1, path);
+      state.unmake_move(undo);
+      if found {
+         return true;
+      }
+      path.pop();
+   }
+   false
+}
+
+/// Throws away every node that isn't under `new_root`, which becomes node 0.
+/// Otherwise the branches we didn't go down would stay in memory for the rest of the game
+fn compact(tree: &mut Vec<Node>, new_root: usize) {
+   // parents before children, so the new root stays at the front
+   let mut order = vec![new_root];
+   let mut i = 0;
+   while i < order.len() {
+      order.extend_from_slice(&tree[order[i]].children);
+      i += 1;
+   }
+   let mut new_index = vec![usize::MAX; tree.len()];
+   for (new, old) in order.iter().enumerate() {
+      new_index[*old] = new;
+   }
+   let mut old_tree: Vec<Option<Node>> = tree.drain(..).map(Some).collect();
+   for old in order {
+      let mut node = old_tree[old].take().unwrap();
+      node.parent = if old == new_root { 0 } else { new_index[node.parent] };
+      for child in node.children.iter_mut() {
+         *child = new_index[*child];
+      }
+      tree.push(node);
+   }
+}
+
+/// How long each search thread keeps simulating
+#[derive(Clone, Copy)]
+enum Budget<'a> {
+   Time(Duration),
+   Simulations(u64),
+   /// Until another thread sets the flag
+   UntilStopped(&'a AtomicBool),
+}
+
+/// How a simulation plays the game out from the node it added to the tree
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rollout {
+   /// Pick each move with a softmax over the evaluations of the positions it leads to, instead of uniformly
+   pub eval_guided: bool,
+   /// Stop after this many plies and score the position by its evaluation, instead of playing to the end
+   pub max_plies: Option<u32>,
+   /// How a rollout cut short by `max_plies` is scored. With a margin in pawns, whoever's that far ahead wins and
+   /// anything closer is a draw. Without one, the evaluation is turned into a chance of winning
+   pub win_margin: Option<f64>,
+}
+
+/// Settings for mcts searches, until the interface changes them
+#[derive(Clone, Debug, PartialEq)]
+pub struct MctsParams {
+   /// How much ucb1 favors less explored moves over ones that have scored well
+   pub exploration: f64,
+   /// Each search thread builds the same tree
+   pub threads: usize,
+   /// Simulations between checks of the clock, as checking it is slow
+   pub batch_size: u64,
+   pub rollout: Rollout,
+   /// Seeds the random numbers, so that a search can be repeated. Thread `i` is seeded with `seed + i`, but
+   /// only a single thread on a simulation budget is reproducible, as threads race each other for the tree
+   pub seed: Option<u64>,
+   /// After each search, write the top of the tree to this html file
+   pub debug_tree: Option<PathBuf>,
+}
+
+impl Default for MctsParams {
+   fn default() -> MctsParams {
+      MctsParams {
+         exploration: 0.3,
+         threads: 16,
+         batch_size: 100,
+         rollout: Rollout::default(),
+         seed: None,
+         debug_tree: None,
+      }
+   }
+}
+
+/// Inverse of `eval_to_white_score`, for any side: an evaluation in pawns given the chance of winning
+fn win_probability_to_eval(win_probability: f64) -> f64 {
+   let p = win_probability.clamp(0.001, 0.999);
+   4.0 * (p / (1.0 - p)).log10()
+}
+
+/// What the tree says about the root
+struct RootReport {
+   /// Chance that the side to move wins, over all simulations through the root
+   win_probability: f64,
+   /// Most visited line
+   pv: Vec<Move>,
+   simulations: u64,
+}
+
+fn root_report(mcts_state: &MctsState) -> RootReport {
+   let tree = mcts_state.tree.lock();
+   let root = match tree.get(mcts_state.root) {
+      Some(x) => x,
+      None => {
+         return RootReport {
+            win_probability: 0.5,
+            pv: Vec::new(),
+            simulations: 0,
+         }
+      }
+   };
+   // each child's score is for the side to move at the root. Proven results are infinite, so they're handled apart
+   let children = || root.children.iter().map(|x| &tree[*x].stats);
+   let win_probability = if children().any(|x| x.score == f64::INFINITY) {
+      1.0
+   } else if !root.children.is_empty() && children().all(|x| x.score == f64::NEG_INFINITY) {
+      0.0
+   } else {
+      let (score, simulations) = children()
+         .filter(|x| x.score.is_finite())
+         .fold((0.0, 0), |acc, x| (acc.0 + x.score, acc.1 + x.simulations));
+      if simulations == 0 {
+         0.5
+      } else {
+         score / simulations as f64
+      }
+   };
+   let mut pv = Vec::new();
+   let mut node = mcts_state.root;
+   while let Some(child) = tree[node].children.iter().copied().max_by_key(|x| tree[*x].stats.simulations) {
+      if tree[child].stats.simulations == 0 {
+         break;
+      }
+      pv.push(tree[child].last_move.extract());
+      node = child;
+   }
+   RootReport {
+      win_probability,
+      pv,
+      simulations: root.stats.simulations,
+   }
+}
+
+/// Chance that white wins, going by an evaluation in pawns (+ for white)
+fn eval_to_white_score(white_eval: f64) -> f64 {
+   1.0 / (1.0 + 10f64.powf(-white_eval / 4.0))
+}
+
+/// Plays the game out from `g`, whose legal moves are `moves` and status is `g_status`.
+/// Returns white's score: 1 for a win, 0.5 for a draw, 0 for a loss, or somewhere in between if cut short
+fn rollout<R: Rng>(
+   g: &mut State,
+   moves: &mut Vec<CompressedMove>,
+   mut g_status: GameStatus,
+   policy: Rollout,
+   rng: &mut R,
+) -> f64 {
+   let eval_params = EvalParams::default();
+   let mut plies = 0;
+   while g_status == GameStatus::Ongoing {
+      if policy.max_plies.is_some_and(|x| plies >= x) {
+         let white_eval = static_eval_white_pov(&g.position);
+         return match policy.win_margin {
+            Some(margin) if white_eval >= margin => 1.0,
+            Some(margin) if white_eval <= -margin => 0.0,
+            Some(_) => 0.5,
+            None => eval_to_white_score(white_eval),
+         };
+      }
+      let a_move = if policy.eval_guided {
+         let mover = g.position.side_to_move;
+         let weights: Vec<f64> = moves
+            .iter()
+            .map(|x| {
+               let mut position = g.position.clone();
+               position.make_move(x.extract());
+               (evaluate(&position, mover, &eval_params) / ROLLOUT_TEMPERATURE).exp()
+            })
+            .collect();
+         let mut pick = rng.gen::<f64>() * weights.iter().sum::<f64>();
+         let mut choice = moves[moves.len() - 1];
+         for (a_move, weight) in moves.iter().zip(weights.iter()) {
+            pick -= weight;
+            if pick <= 0.0 {
+               choice = *a_move;
+               break;
+            }
+         }
+         choice
+      } else {
+         *moves.choose(rng).unwrap()
+      };
+      g.apply_move(a_move.extract());
+      g.gen_moves(moves);
+      g_status = g.status(moves);
+      plies += 1;
+   }
+   match g_status {
+      GameStatus::Victory(Color::White, _) => 1.0,
+      GameStatus::Victory(Color::Black, _) => 0.0,
+      _ => 0.5,
+   }
+}
+
+/// Runs `simulations` playouts on the calling thread and returns the best move. `params.threads` is ignored.
+/// Uses neither threads nor the clock, so it also runs where there are none, like in the browser
+pub fn mcts_blocking(state: &State, simulations: u64, params: &MctsParams) -> Option<Move> {
+   MctsState::init().search_blocking(state, simulations, params)
+}
+
+/// Each of `params.threads` threads searches until it's out of `budget`
+fn mcts(mcts_state: &mut MctsState, budget: Budget, state: &State, params: &MctsParams) -> Option<Move> {
    DRAWS.store(0, std::sync::atomic::Ordering::Relaxed);
    I_LOSE.store(0, std::sync::atomic::Ordering::Relaxed);
    I_WIN.store(0, std::sync::atomic::Ordering::Relaxed);
 
    {
       let mut tree = mcts_state.tree.lock();
-      if tree.len() == 0 {
+      if tree.is_empty() {
          tree.push(Node {
             last_move: Move {
                origin: 0,
@@ -170,13 +614,23 @@ fn mcts(
          });
       }
    }
-   std::thread::scope(|s| {
-      for _ in 0..16 {
-         s.spawn(|| {
-            mcts_inner(mcts_state, time_budget, state, exploration_val);
-         });
-      }
-   });
+   let rng = |thread: u64| match params.seed {
+      Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(thread)),
+      None => StdRng::from_entropy(),
+   };
+   let mcts_state: &MctsState = mcts_state;
+   if params.threads <= 1 {
+      mcts_inner(mcts_state, budget, state, params, rng(0));
+   } else {
+      std::thread::scope(|s| {
+         for thread in 0..params.threads {
+            let rng = rng(thread as u64);
+            s.spawn(move || {
+               mcts_inner(mcts_state, budget, state, params, rng);
+            });
+         }
+      });
+   }
 
    trace!(
       "DRAWS: {} I WIN: {} I LOSE: {}",
@@ -190,24 +644,36 @@ fn mcts(
    let best_child = tree[mcts_state.root]
       .children
       .iter()
-      .max_by_key(|x| r64(tree[**x].stats.score/tree[**x].stats.simulations as f64 + ((1.0/tree[**x].stats.simulations as f64).sqrt())));
+      .max_by_key(|x| n64(tree[**x].stats.score/tree[**x].stats.simulations as f64 + ((1.0/tree[**x].stats.simulations as f64).sqrt())));
 
-   best_child.map(|x| {
-      (
-         tree[*x].last_move.extract(),
-         tree[*x].stats.score / tree[*x].stats.simulations as f64,
-      )
-   })
+   best_child.map(|x| tree[*x].last_move.extract())
 }
 
-fn mcts_inner(mcts_state: &MctsState, time_budget: &Duration, state: &State, exploration_val: f64) {
-   let mut rng = rand::thread_rng();
-
-   let start = Instant::now();
+fn mcts_inner(mcts_state: &MctsState, budget: Budget, state: &State, params: &MctsParams, mut rng: StdRng) {
+   let start = match budget {
+      Budget::Time(_) => Some(Instant::now()),
+      Budget::Simulations(_) | Budget::UntilStopped(_) => None,
+   };
+   let mut simulations = 0;
    let mut moves = Vec::with_capacity(218);
 
-   while start.elapsed() < *time_budget {
-      for _ in 0..100 {
+   loop {
+      let out_of_budget = match budget {
+         Budget::Time(time_budget) => start.map(|x| x.elapsed() >= time_budget).unwrap_or(true),
+         Budget::Simulations(limit) => simulations >= limit,
+         Budget::UntilStopped(stop) => stop.load(std::sync::atomic::Ordering::Relaxed),
+      };
+      if out_of_budget {
+         break;
+      }
+      // checking the clock is slow, so budgets are only checked every batch
+      let batch_size = params.batch_size.max(1);
+      let batch = match budget {
+         Budget::Time(_) | Budget::UntilStopped(_) => batch_size,
+         Budget::Simulations(limit) => (limit - simulations).min(batch_size),
+      };
+      simulations += batch;
+      for _ in 0..batch {
          // determine state
          let mut g = state.clone();
 
@@ -259,29 +725,24 @@ fn mcts_inner(mcts_state: &MctsState, time_budget: &Duration, state: &State, exp
                cur_node = *tree[cur_node]
                   .children
                   .iter()
-                  .max_by_key(|x| r64(ucb1(exploration_val, &tree[**x].stats, &tree[cur_node].stats)))
+                  // unvisited nodes and proven wins score infinity, which n64 allows and r64 doesn't
+                  .max_by_key(|x| n64(ucb1(params.exploration, &tree[**x].stats, &tree[cur_node].stats)))
                   .unwrap();
                g.apply_move(tree[cur_node].last_move.extract());
             }
          }
 
-         // simulate (random rollout)
-         if did_simulate {
-            while g_status == GameStatus::Ongoing {
-               let rand_move = *moves.choose(&mut rng).unwrap();
-               g.apply_move(rand_move.extract());
-               g.gen_moves(&mut moves);
-               g_status = g.status(&moves);
-            }
-         }
+         // simulate
+         let white_score = rollout(&mut g, &mut moves, g_status, params.rollout, &mut rng);
+         g_status = g.status(&moves);
 
          let mut tree = mcts_state.tree.lock();
 
          match g_status {
-            GameStatus::Draw => {
+            GameStatus::Draw(_) => {
                DRAWS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             }
-            GameStatus::Victory(x) => {
+            GameStatus::Victory(x, _) => {
                if tree[mcts_state.root].last_player == x {
                   I_LOSE.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                } else {
@@ -292,7 +753,7 @@ fn mcts_inner(mcts_state: &MctsState, time_budget: &Duration, state: &State, exp
          }
 
          if !did_simulate {
-            if let GameStatus::Victory(ref p) = g_status {
+            if let GameStatus::Victory(ref p, _) = g_status {
                tree[cur_node].stats.score = if tree[cur_node].last_player == *p {
                   f64::INFINITY
                } else {
@@ -308,17 +769,10 @@ fn mcts_inner(mcts_state: &MctsState, time_budget: &Duration, state: &State, exp
             } else if !did_simulate && !tree[cur_node].children.is_empty() && tree[cur_node].children.iter().all(|x| tree[*x].stats.score == f64::NEG_INFINITY) {
                tree[cur_node].stats.score = f64::INFINITY;
             } else {
-               match g_status {
-                  GameStatus::Draw => {
-                     tree[cur_node].stats.score += 0.5;
-                  }
-                  GameStatus::Victory(ref p) => {
-                     if tree[cur_node].last_player == *p {
-                        tree[cur_node].stats.score += 1.0;
-                     }
-                  }
-                  GameStatus::Ongoing => unsafe { unreachable_unchecked() },
-               }
+               tree[cur_node].stats.score += match tree[cur_node].last_player {
+                  Color::White => white_score,
+                  Color::Black => 1.0 - white_score,
+               };
             }
 
             tree[cur_node].stats.simulations += 1;
@@ -332,8 +786,8 @@ fn mcts_inner(mcts_state: &MctsState, time_budget: &Duration, state: &State, exp
    }
 }
 
-fn emit_debug_tree(mcts_state: &MctsState) {
-   let out_f = std::fs::File::create("mcts.html").unwrap();
+fn emit_debug_tree(mcts_state: &MctsState, path: &Path) {
+   let out_f = std::fs::File::create(path).unwrap();
    let mut out = BufWriter::new(out_f);
    writeln!(
       out,
@@ -349,32 +803,385 @@ fn emit_debug_tree(mcts_state: &MctsState) {
    .unwrap();
    writeln!(out, "<ul class=\"tree\">").unwrap();
 
-   let tree = mcts_state.tree.lock();
-   emit_debug_node(&mut out, mcts_state.root, &tree, 0);
+   emit_debug_node(&mut out, &mcts_state.snapshot(2));
 
    writeln!(out, "</body>\n</html>").unwrap();
 }
 
-fn emit_debug_node(out: &mut BufWriter<File>, i: usize, tree: &[Node], depth: usize) {
-   if depth > 2 {
-      return;
-   }
-   let node = &tree[i];
+fn emit_debug_node(out: &mut BufWriter<File>, node: &TreeSnapshot) {
+   let last_move = node.last_move.map_or_else(|| "root".to_string(), |x| x.to_string());
    writeln!(
       out,
       "<li><span>{}</span><br><span>score «{}» simulations «{}»</span>",
-      node.last_move.extract(),
-      node.stats.score,
-      node.stats.simulations
+      last_move, node.score, node.simulations
    )
    .unwrap();
    writeln!(out, "<ul>").unwrap();
 
-   let mut sorted = node.children.clone();
-   sorted.sort_by_key(|x| std::cmp::Reverse(tree[*x].stats.simulations));
-   for child in sorted.into_iter() {
-      emit_debug_node(out, child, tree, depth + 1);
+   for child in node.children.iter() {
+      emit_debug_node(out, child);
    }
 
    writeln!(out, "</ul></li>").unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+   use crate::mcts::*;
+   use rand::rngs::StdRng;
+   use rand::SeedableRng;
+
+   /// Runs `start` on its own thread, with the channels to talk to it and the thread to join once it quits
+   fn spawn_engine(
+      start: fn(mpsc::Receiver<InterfaceMessage>, mpsc::Sender<EngineMessage>),
+   ) -> (mpsc::Sender<InterfaceMessage>, mpsc::Receiver<EngineMessage>, std::thread::JoinHandle<()>) {
+      let (ite_tx, ite_rx) = mpsc::channel();
+      let (eti_tx, eti_rx) = mpsc::channel();
+      let engine = std::thread::spawn(move || start(ite_rx, eti_tx));
+      (ite_tx, eti_rx, engine)
+   }
+
+   /// Skips the search's reports until its best move, which has to come within `timeout`
+   fn wait_for_best_move(receiver: &mpsc::Receiver<EngineMessage>, timeout: Duration) -> Option<Move> {
+      let deadline = Instant::now() + timeout;
+      loop {
+         match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(EngineMessage::BestMove(best_move)) => return best_move,
+            Ok(EngineMessage::Info { .. }) | Ok(EngineMessage::SearchStats { .. }) => (),
+            _ => panic!("no best move within {:?}", timeout),
+         }
+      }
+   }
+
+   /// One thread and short rollouts, to keep the tests quick
+   fn test_params() -> MctsParams {
+      MctsParams {
+         threads: 1,
+         rollout: Rollout {
+            eval_guided: false,
+            max_plies: Some(4),
+            win_margin: None,
+         },
+         ..MctsParams::default()
+      }
+   }
+
+   #[test]
+   fn quit_ends_the_engine() {
+      let (ite_tx, _eti_rx, engine) = spawn_engine(start);
+      ite_tx.send(InterfaceMessage::SetState(State::from_start())).unwrap();
+      ite_tx.send(InterfaceMessage::Quit).unwrap();
+      let started = Instant::now();
+      while !engine.is_finished() {
+         assert!(started.elapsed() < Duration::from_secs(5), "the engine didn't quit");
+         std::thread::sleep(Duration::from_millis(10));
+      }
+      engine.join().unwrap();
+   }
+
+   #[test]
+   fn stop_ends_infinite_search() {
+      let (ite_tx, eti_rx, engine) = spawn_engine(start);
+      let params = test_params();
+      ite_tx.send(InterfaceMessage::SetMctsParams(params)).unwrap();
+      ite_tx.send(InterfaceMessage::GoInfinite).unwrap();
+      std::thread::sleep(Duration::from_millis(100));
+      let stopped_at = Instant::now();
+      ite_tx.send(InterfaceMessage::Stop).unwrap();
+      assert!(wait_for_best_move(&eti_rx, Duration::from_secs(5)).is_some());
+      assert!(stopped_at.elapsed() < Duration::from_secs(1));
+
+      // quitting also ends the search, without a best move
+      ite_tx.send(InterfaceMessage::GoInfinite).unwrap();
+      ite_tx.send(InterfaceMessage::Quit).unwrap();
+      engine.join().unwrap();
+      assert!(eti_rx.try_recv().is_err());
+   }
+
+   #[test]
+   fn ponder_hit_starts_the_clock() {
+      let (ite_tx, eti_rx, _) = spawn_engine(start);
+      let params = test_params();
+      ite_tx.send(InterfaceMessage::SetMctsParams(params)).unwrap();
+      ite_tx.send(InterfaceMessage::GoPonder(Some(Duration::from_millis(50)))).unwrap();
+      // far longer than the budget, which doesn't count until the ponder hit
+      std::thread::sleep(Duration::from_millis(200));
+      assert!(eti_rx.try_recv().is_err());
+      ite_tx.send(InterfaceMessage::PonderHit).unwrap();
+      assert!(wait_for_best_move(&eti_rx, Duration::from_secs(5)).is_some());
+
+      // mcts has no depth, but a depth search still answers
+      ite_tx.send(InterfaceMessage::GoDepth(1)).unwrap();
+      assert!(wait_for_best_move(&eti_rx, Duration::from_secs(30)).is_some());
+   }
+
+   #[test]
+   fn blocking_mcts_plays_a_legal_move() {
+      let state = State::from_start();
+      let best_move = mcts_blocking(&state, 200, &MctsParams::default()).unwrap();
+      let mut moves = Vec::new();
+      state.gen_moves(&mut moves);
+      assert!(moves.iter().any(|x| x.extract() == best_move));
+   }
+
+   #[test]
+   fn seeded_searches_repeat() {
+      let state = State::from_start();
+      let params = MctsParams {
+         seed: Some(7),
+         rollout: Rollout {
+            max_plies: Some(10),
+            ..test_params().rollout
+         },
+         ..test_params()
+      };
+      let search = || {
+         let mut mcts_state = MctsState::init();
+         let best_move = mcts(&mut mcts_state, Budget::Simulations(300), &state, &params);
+         let tree = mcts_state.tree.lock();
+         let scores: Vec<f64> = tree[mcts_state.root].children.iter().map(|x| tree[*x].stats.score).collect();
+         (best_move, scores)
+      };
+      assert_eq!(search(), search());
+   }
+
+   #[test]
+   fn every_thread_searches() {
+      let state = State::from_start();
+      let params = MctsParams {
+         exploration: 0.5,
+         threads: 3,
+         batch_size: 7,
+         rollout: Rollout {
+            max_plies: Some(10),
+            ..test_params().rollout
+         },
+         ..test_params()
+      };
+      let mut mcts_state = MctsState::init();
+      assert!(mcts(&mut mcts_state, Budget::Simulations(20), &state, &params).is_some());
+      // each thread runs the whole budget into the same tree
+      let tree = mcts_state.tree.lock();
+      assert_eq!(tree[mcts_state.root].stats.simulations, 60);
+   }
+
+   fn reachable(tree: &[Node], node: usize) -> usize {
+      1 + tree[node].children.iter().map(|x| reachable(tree, *x)).sum::<usize>()
+   }
+
+   #[test]
+   fn moving_the_root_frees_dead_branches() {
+      let mut state = State::from_start();
+      // short rollouts, as only the shape of the tree matters here
+      let params = test_params();
+      let mut mcts_state = MctsState::init();
+      for _ in 0..3 {
+         mcts(&mut mcts_state, Budget::Simulations(300), &state, &params);
+         let (best_move, simulations, before) = {
+            let tree = mcts_state.tree.lock();
+            let best = *tree[mcts_state.root]
+               .children
+               .iter()
+               .max_by_key(|x| tree[**x].stats.simulations)
+               .unwrap();
+            (tree[best].last_move.extract(), tree[best].stats.simulations, tree.len())
+         };
+         mcts_state.move_root_down(best_move);
+         state.apply_move(best_move);
+
+         let tree = mcts_state.tree.lock();
+         assert_eq!(mcts_state.root, 0);
+         assert_eq!(tree.len(), reachable(&tree, 0));
+         assert!(tree.len() < before);
+         // what we learned about the new root is kept
+         assert_eq!(tree[0].stats.simulations, simulations);
+         assert!(tree[0].last_move.extract() == best_move);
+         assert!(tree[0].children.iter().all(|x| tree[*x].parent == 0));
+      }
+   }
+
+   #[test]
+   fn set_state_keeps_the_subtree() {
+      let state = State::from_start();
+      let params = test_params();
+      let mut mcts_state = MctsState::init();
+      mcts(&mut mcts_state, Budget::Simulations(300), &state, &params);
+      let (a_move, simulations) = {
+         let tree = mcts_state.tree.lock();
+         let child = tree[mcts_state.root].children[0];
+         (tree[child].last_move.extract(), tree[child].stats.simulations)
+      };
+      let new_state = state.after_move(a_move);
+      assert!(mcts_state.reroot(&state, &new_state));
+      assert_eq!(mcts_state.tree.lock()[mcts_state.root].stats.simulations, simulations);
+
+      // a position that isn't in the tree
+      let elsewhere = State::from_fen("4k3/8/8/8/8/8/8/4K2R w K - 0 1").unwrap();
+      assert!(!mcts_state.reroot(&new_state, &elsewhere));
+   }
+
+   #[test]
+   fn winning_side_scores_positive() {
+      let params = test_params();
+      // black is a queen up, and it's black's move
+      let state = State::from_fen("3qk3/8/8/8/8/8/8/4K3 b - - 0 1").unwrap();
+      let mut mcts_state = MctsState::init();
+      mcts(&mut mcts_state, Budget::Simulations(300), &state, &params);
+      let report = root_report(&mcts_state);
+      assert!(report.win_probability > 0.5, "{}", report.win_probability);
+      assert!(win_probability_to_eval(report.win_probability) > 0.0);
+      assert_eq!(report.simulations, 300);
+      assert!(!report.pv.is_empty());
+
+      // and white, to move, is losing
+      let state = State::from_fen("3qk3/8/8/8/8/8/8/4K3 w - - 0 1").unwrap();
+      let mut mcts_state = MctsState::init();
+      mcts(&mut mcts_state, Budget::Simulations(300), &state, &params);
+      assert!(win_probability_to_eval(root_report(&mcts_state).win_probability) < 0.0);
+   }
+
+   #[test]
+   fn win_probability_round_trips() {
+      for eval in [-3.0, -0.5, 0.0, 1.0, 5.0] {
+         assert!((win_probability_to_eval(eval_to_white_score(eval)) - eval).abs() < 1e-9);
+      }
+   }
+
+   fn average_rollout(state: &State, policy: Rollout, rollouts: u64) -> f64 {
+      let mut rng = StdRng::seed_from_u64(0);
+      let mut moves = Vec::new();
+      let mut total = 0.0;
+      for _ in 0..rollouts {
+         let mut g = state.clone();
+         g.gen_moves(&mut moves);
+         let status = g.status(&moves);
+         total += rollout(&mut g, &mut moves, status, policy, &mut rng);
+      }
+      total / rollouts as f64
+   }
+
+   #[test]
+   fn guided_rollouts_take_the_hanging_queen() {
+      // Rxd5 wins the queen
+      let state = State::from_fen("4k3/8/8/3q4/8/8/8/3RK3 w - - 0 1").unwrap();
+      let random = average_rollout(&state, Rollout::default(), 50);
+      let guided = average_rollout(
+         &state,
+         Rollout {
+            eval_guided: true,
+            max_plies: Some(8),
+            win_margin: None,
+         },
+         50,
+      );
+      assert!(guided > 0.7, "{}", guided);
+      assert!(guided > random, "guided {} random {}", guided, random);
+   }
+
+   #[test]
+   fn capped_rollouts_are_faster() {
+      // Ra8 is mate, which the tree proves whatever the rollouts say
+      let state = State::from_fen("6k1/5ppp/8/8/8/8/5PPP/R5K1 w - - 0 1").unwrap();
+      let search = |max_plies| {
+         let params = MctsParams {
+            batch_size: 10,
+            rollout: Rollout {
+               max_plies,
+               win_margin: Some(3.0),
+               ..test_params().rollout
+            },
+            ..test_params()
+         };
+         let mut mcts_state = MctsState::init();
+         let best_move = mcts(&mut mcts_state, Budget::Time(Duration::from_millis(200)), &state, &params);
+         (best_move, root_report(&mcts_state).simulations)
+      };
+      let (capped_move, capped) = search(Some(4));
+      let (uncapped_move, uncapped) = search(None);
+      assert!(capped > uncapped * 3, "{} vs {}", capped, uncapped);
+      assert!(capped_move == Some("a1a8".parse().unwrap()));
+      assert!(uncapped_move == capped_move);
+   }
+
+   #[test]
+   fn adjudicated_rollouts() {
+      // black is a queen up
+      let state = State::from_fen("3qk3/8/8/8/8/8/8/4K3 w - - 0 1").unwrap();
+      let policy = |win_margin| Rollout {
+         eval_guided: false,
+         max_plies: Some(0),
+         win_margin,
+      };
+      assert_eq!(average_rollout(&state, policy(Some(3.0)), 5), 0.0);
+      assert_eq!(average_rollout(&state, policy(Some(20.0)), 5), 0.5);
+      let score = average_rollout(&state, policy(None), 5);
+      assert!(score > 0.0 && score < 0.5, "{}", score);
+   }
+
+   fn check_simulation_counts(node: &TreeSnapshot, is_root: bool) {
+      if node.children.is_empty() {
+         return;
+      }
+      let through_children: u64 = node.children.iter().map(|x| x.simulations).sum();
+      // every simulation goes on to a child, except the one that added this node, and visits to it once proven
+      if is_root {
+         assert_eq!(node.simulations, through_children);
+      } else if node.score.is_finite() {
+         assert_eq!(node.simulations, through_children + 1);
+      } else {
+         assert!(node.simulations > through_children);
+      }
+      for child in node.children.iter() {
+         check_simulation_counts(child, false);
+      }
+   }
+
+   #[test]
+   fn snapshot_of_the_tree() {
+      let params = MctsParams {
+         seed: Some(3),
+         ..test_params()
+      };
+      let mut mcts_state = MctsState::init();
+      assert_eq!(mcts_state.snapshot(3).simulations, 0);
+      let state = State::from_start();
+      let best_move = mcts_state.search_blocking(&state, 500, &params);
+
+      let snapshot = mcts_state.snapshot(3);
+      assert_eq!(snapshot.last_move, None);
+      assert_eq!(snapshot.simulations, 500);
+      assert_eq!(snapshot.children.len(), 20);
+      assert!(snapshot.children.windows(2).all(|x| x[0].simulations >= x[1].simulations));
+      assert!(snapshot.children.iter().any(|x| x.last_move == best_move));
+      check_simulation_counts(&snapshot, true);
+
+      // nothing deeper than asked for
+      let shallow = mcts_state.snapshot(1);
+      assert!(shallow.children.iter().all(|x| x.children.is_empty()));
+      assert!(snapshot.children.iter().any(|x| !x.children.is_empty()));
+   }
+
+   #[test]
+   fn debug_tree_only_when_asked() {
+      assert_eq!(MctsParams::default().debug_tree, None);
+      let path = std::env::temp_dir().join(format!("chessatk_mcts_{}.html", std::process::id()));
+      let _ = std::fs::remove_file(&path);
+      let (ite_tx, eti_rx, engine) = spawn_engine(start);
+      ite_tx.send(InterfaceMessage::SetMctsParams(test_params())).unwrap();
+      ite_tx.send(InterfaceMessage::GoTime(Duration::from_millis(20))).unwrap();
+      wait_for_best_move(&eti_rx, Duration::from_secs(5));
+      assert!(!path.exists());
+
+      let params = MctsParams {
+         debug_tree: Some(path.clone()),
+         ..test_params()
+      };
+      ite_tx.send(InterfaceMessage::SetMctsParams(params)).unwrap();
+      ite_tx.send(InterfaceMessage::GoTime(Duration::from_millis(20))).unwrap();
+      wait_for_best_move(&eti_rx, Duration::from_secs(5));
+      drop(ite_tx);
+      engine.join().unwrap();
+      assert!(std::fs::read_to_string(&path).unwrap().contains("<ul class=\"tree\">"));
+      std::fs::remove_file(&path).unwrap();
+   }
+}
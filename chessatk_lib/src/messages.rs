@@ -1,19 +1,51 @@
 use crate::board::{Move, State};
+use crate::eval::EvalParams;
+use crate::mcts::MctsParams;
+use std::path::PathBuf;
 use std::time::Duration;
 
 // Intraprocess Communication Messages
 
 // Interface to Engine
+#[allow(clippy::large_enum_variant)]
 pub enum InterfaceMessage {
    GoDepth(u64), // Calculate until depth and respond with the best move
    GoTime(Duration),
+   GoInfinite, // Calculate until told to stop
+   GoPonder(Option<Duration>), // Calculate on the opponent's time, until told to stop or the expected move is played
+   RestrictNextSearch(Vec<Move>), // Only search these root moves in the next search. Illegal ones are ignored
+   PonderHit,  // The expected move was played. Continue the ponder search with its time budget, starting now
+   Stop,       // Respond with the best move found so far as soon as possible
    QueryEval,       // Query the evaluation of the current game state
    ApplyMove(Move), // Incremental state update (for engine optimizations)
    SetState(State), // Full state update
+   NewGame,         // Back to the starting position, forgetting everything learned in the last game
+   SetHashSize(usize), // Transposition table size, in megabytes
+   SetThreads(usize),  // How many threads to search with
+   SetMultiPv(usize),  // How many of the best lines to report
+   SetEvalParams(EvalParams), // Material values and term weights for the evaluation
+   LoadBook(PathBuf), // Polyglot opening book to play from before searching
+   SetMctsParams(MctsParams), // Exploration, threads and rollouts for mcts
+   SetVerbosity(bool), // Whether searches trace each depth, when trace logging is on. Off to begin with
+   Quit, // Abandon any search and return from start, without answering
 }
 
 // Engine to Interface
 pub enum EngineMessage {
    BestMove(Option<Move>),
-   CurrentEval(f64),
+   CurrentEval(i32), // In centipawns, + for white
+   // One of the best lines found at a finished depth, ranked by multipv from 1. Score is for the side to move
+   Info {
+      depth: u64,
+      multipv: usize,
+      score: f64,
+      pv: Vec<Move>,
+   },
+   // Sent once a search is over, just before its best move. Counts every depth, including one that was cut short
+   SearchStats {
+      depth: u64, // the deepest depth that finished
+      nodes: u64,
+      nps: u64,
+      time_ms: u64,
+   },
 }
@@ -0,0 +1,276 @@
+use crate::board::{Color, GameStatus, Move, State, START_FEN};
+use std::fmt::Write;
+
+// Movetext lines are kept under this many bytes, as the PGN spec recommends
+const LINE_LENGTH: usize = 80;
+
+// Tags every PGN must have, in order, with their values when unknown
+const SEVEN_TAG_ROSTER: [(&str, &str); 7] = [
+   ("Event", "?"),
+   ("Site", "?"),
+   ("Date", "????.??.??"),
+   ("Round", "?"),
+   ("White", "?"),
+   ("Black", "?"),
+   ("Result", "*"),
+];
+
+/// Exports a game as PGN. Any of the seven required tags not in `tags` are filled in as unknown.
+/// The result comes from the final position if the game is over there, and otherwise from the `Result` tag
+pub fn game_to_pgn(start: &State, moves: &[Move], tags: &[(String, String)]) -> String {
+   let mut state = start.clone();
+   let mut tokens = Vec::with_capacity(moves.len() * 3 / 2 + 1);
+   for (i, a_move) in moves.iter().enumerate() {
+      if state.position.side_to_move == Color::White {
+         tokens.push(format!("{}.", state.fullmove_number));
+      } else if i == 0 {
+         tokens.push(format!("{}...", state.fullmove_number));
+      }
+      tokens.push(state.position.move_to_san(*a_move));
+      state.apply_move(*a_move);
+   }
+
+   let given_tag = |name: &str| tags.iter().find(|(x, _)| x == name).map(|(_, value)| value.as_str());
+   let mut move_buf = Vec::new();
+   state.gen_moves(&mut move_buf);
+   let result = match state.status(&move_buf) {
+      GameStatus::Victory(Color::White, _) => "1-0",
+      GameStatus::Victory(Color::Black, _) => "0-1",
+      GameStatus::Draw(_) => "1/2-1/2",
+      GameStatus::Ongoing => given_tag("Result").unwrap_or("*"),
+   };
+   tokens.push(result.to_string());
+
+   let mut pgn = String::new();
+   for (name, default) in SEVEN_TAG_ROSTER.iter() {
+      let value = if *name == "Result" {
+         result
+      } else {
+         given_tag(name).unwrap_or(default)
+      };
+      write_tag(&mut pgn, name, value);
+   }
+   let start_fen = start.to_fen();
+   if start_fen != START_FEN {
+      write_tag(&mut pgn, "SetUp", "1");
+      write_tag(&mut pgn, "FEN", &start_fen);
+   }
+   for (name, value) in tags {
+      if !SEVEN_TAG_ROSTER.iter().any(|(x, _)| x == name) && name != "SetUp" && name != "FEN" {
+         write_tag(&mut pgn, name, value);
+      }
+   }
+   pgn.push('\n');
+
+   let mut line_length = 0;
+   for token in tokens {
+      if line_length > 0 && line_length + 1 + token.len() > LINE_LENGTH {
+         pgn.push('\n');
+         line_length = 0;
+      } else if line_length > 0 {
+         pgn.push(' ');
+         line_length += 1;
+      }
+      line_length += token.len();
+      pgn.push_str(&token);
+   }
+   pgn.push('\n');
+   pgn
+}
+
+fn write_tag(pgn: &mut String, name: &str, value: &str) {
+   let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+   writeln!(pgn, "[{} \"{}\"]", name, escaped).unwrap();
+}
+
+/// Reads the first game of a PGN, returning the position it starts from and the moves played.
+/// Comments, NAGs and variations are skipped
+pub fn pgn_to_game(pgn: &str) -> Result<(State, Vec<Move>), String> {
+   let mut fen = None;
+   let mut movetext = String::new();
+   for line in pgn.lines() {
+      let line = line.trim();
+      if line.starts_with('[') && movetext.trim().is_empty() {
+         let (name, value) = parse_tag(line)?;
+         if name == "FEN" {
+            fen = Some(value);
+         }
+      } else if !line.starts_with('%') {
+         movetext.push_str(line);
+         movetext.push('\n');
+      }
+   }
+
+   let start = match fen {
+      Some(fen) => State::from_fen(&fen)?,
+      None => State::from_start(),
+   };
+   let mut state = start.clone();
+   let mut moves = Vec::new();
+   for token in movetext_tokens(&movetext)? {
+      if matches!(token.as_str(), "1-0" | "0-1" | "1/2-1/2" | "*") {
+         break;
+      }
+      // move numbers, possibly stuck to the move as in 1.e4
+      let san = token.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+      if san.is_empty() {
+         continue;
+      }
+      let a_move = state.position.san_to_move(san).map_err(|e| {
+         let dots = if state.position.side_to_move == Color::White { "." } else { "..." };
+         format!("move {}{} {}: {}", state.fullmove_number, dots, san, e)
+      })?;
+      state.apply_move(a_move);
+      moves.push(a_move);
+   }
+   Ok((start, moves))
+}
+
+// [Name "value"]
+fn parse_tag(line: &str) -> Result<(String, String), String> {
+   let inner = line
+      .strip_prefix('[')
+      .and_then(|x| x.strip_suffix(']'))
+      .ok_or_else(|| format!("malformed PGN tag {}", line))?;
+   let (name, value) = inner
+      .split_once(char::is_whitespace)
+      .ok_or_else(|| format!("malformed PGN tag {}", line))?;
+   let value = value
+      .trim()
+      .strip_prefix('"')
+      .and_then(|x| x.strip_suffix('"'))
+      .ok_or_else(|| format!("PGN tag value should be quoted in {}", line))?;
+   Ok((name.to_string(), value.replace("\\\"", "\"").replace("\\\\", "\\")))
+}
+
+/// Splits movetext on whitespace, dropping comments, NAGs and variations
+fn movetext_tokens(movetext: &str) -> Result<Vec<String>, String> {
+   let mut tokens = Vec::new();
+   let mut token = String::new();
+   let mut variation_depth = 0;
+   let mut chars = movetext.chars();
+   while let Some(c) = chars.next() {
+      let ends_token = match c {
+         '{' => {
+            if !chars.any(|x| x == '}') {
+               return Err("unterminated { comment in PGN".into());
+            }
+            true
+         }
+         ';' => {
+            chars.any(|x| x == '\n');
+            true
+         }
+         '(' => {
+            variation_depth += 1;
+            true
+         }
+         ')' => {
+            if variation_depth == 0 {
+               return Err("unbalanced ) in PGN".into());
+            }
+            variation_depth -= 1;
+            true
+         }
+         c if c.is_whitespace() => true,
+         c => {
+            if variation_depth == 0 {
+               token.push(c);
+            }
+            false
+         }
+      };
+      if ends_token && !token.is_empty() {
+         tokens.push(std::mem::take(&mut token));
+      }
+   }
+   if !token.is_empty() {
+      tokens.push(token);
+   }
+   tokens.retain(|x| !x.starts_with('$'));
+   Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+   use crate::pgn::*;
+
+   fn parse_moves(moves: &str) -> Vec<Move> {
+      moves.split_whitespace().map(|x| x.parse().unwrap()).collect()
+   }
+
+   #[test]
+   fn scholars_mate() {
+      let moves = parse_moves("e2e4 e7e5 d1h5 b8c6 f1c4 g8f6 h5f7");
+      let tags = [("White".to_string(), "Scholar".to_string())];
+      let pgn = game_to_pgn(&State::from_start(), &moves, &tags);
+      assert_eq!(
+         pgn,
+         "[Event \"?\"]\n\
+          [Site \"?\"]\n\
+          [Date \"????.??.??\"]\n\
+          [Round \"?\"]\n\
+          [White \"Scholar\"]\n\
+          [Black \"?\"]\n\
+          [Result \"1-0\"]\n\
+          \n\
+          1. e4 e5 2. Qh5 Nc6 3. Bc4 Nf6 4. Qxf7# 1-0\n"
+      );
+   }
+
+   #[test]
+   fn from_position() {
+      let fen = "4k3/8/8/8/8/8/4P3/4K3 b - - 0 30";
+      let moves = parse_moves("e8d7 e2e4");
+      let tags = [
+         ("Event".to_string(), "Say \"hi\"".to_string()),
+         ("Annotator".to_string(), "me".to_string()),
+      ];
+      let pgn = game_to_pgn(&State::from_fen(fen).unwrap(), &moves, &tags);
+      assert!(pgn.starts_with("[Event \"Say \\\"hi\\\"\"]\n"));
+      assert!(pgn.contains("[Result \"*\"]\n[SetUp \"1\"]\n[FEN \"4k3/8/8/8/8/8/4P3/4K3 b - - 0 30\"]\n[Annotator \"me\"]\n"));
+      assert!(pgn.ends_with("\n\n30... Kd7 31. e4 *\n"));
+   }
+
+   #[test]
+   fn import_annotated() {
+      let pgn = r#"[Event "Casual game"]
+[Site "?"]
+[Result "*"]
+[SetUp "1"]
+[FEN "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3"]
+
+3. Bb5 {the Ruy Lopez} a6 $6 (3... Nf6 4. O-O (4. d3) Nxe4) 4.Ba4 Nf6
+5. O-O!? ; castling into it
+Be7 6. Re1 b5 7. Bb3 d6 *
+"#;
+      let (start, moves) = pgn_to_game(pgn).unwrap();
+      assert_eq!(start.fullmove_number, 3);
+      assert_eq!(moves.len(), 10);
+      let mut state = start;
+      for a_move in moves {
+         state.apply_move(a_move);
+      }
+      assert_eq!(
+         state.to_fen(),
+         "r1bqk2r/2p1bppp/p1np1n2/1p2p3/4P3/1B3N2/PPPP1PPP/RNBQR1K1 w kq - 0 8"
+      );
+   }
+
+   #[test]
+   fn import_round_trip() {
+      let moves = parse_moves("e2e4 e7e5 d1h5 b8c6 f1c4 g8f6 h5f7");
+      let pgn = game_to_pgn(&State::from_start(), &moves, &[]);
+      let (start, imported) = pgn_to_game(&pgn).unwrap();
+      assert!(start == State::from_start());
+      assert_eq!(imported, moves);
+   }
+
+   #[test]
+   fn import_reports_bad_move() {
+      let err = pgn_to_game("1. e4 e5 2. Ke3 Nc6").err().unwrap();
+      assert!(err.starts_with("move 2. Ke3"), "{}", err);
+      let err = pgn_to_game("1. e4 e5 2. Nf3 Ke6").err().unwrap();
+      assert!(err.starts_with("move 2... Ke6"), "{}", err);
+   }
+}
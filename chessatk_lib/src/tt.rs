@@ -0,0 +1,140 @@
+use crate::board::CompressedMove;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const DEFAULT_HASH_MB: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bound {
+   Exact,
+   Lower, // score is at least this much (failed high)
+   Upper, // score is at most this much (failed low)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Entry {
+   pub depth: u8,
+   pub score: f64,
+   pub bound: Bound,
+   pub best_move: Option<CompressedMove>,
+}
+
+// Data layout of a packed entry:
+// bits 0..32  score (as f32 bits)
+// bits 32..40 depth
+// bits 40..42 bound
+// bits 42..58 best move (0 meaning none, as a1a1 can never be a move)
+impl Entry {
+   fn pack(&self) -> u64 {
+      let bound: u64 = match self.bound {
+         Bound::Exact => 0,
+         Bound::Lower => 1,
+         Bound::Upper => 2,
+      };
+      let best_move = self.best_move.map(|x| u64::from(x.0)).unwrap_or(0);
+      u64::from((self.score as f32).to_bits()) | u64::from(self.depth) << 32 | bound << 40 | best_move << 42
+   }
+
+   fn unpack(data: u64) -> Entry {
+      let bound = match (data >> 40) & 0b11 {
+         0 => Bound::Exact,
+         1 => Bound::Lower,
+         _ => Bound::Upper,
+      };
+      let best_move = ((data >> 42) & 0xffff) as u16;
+      Entry {
+         score: f64::from(f32::from_bits(data as u32)),
+         depth: (data >> 32) as u8,
+         bound,
+         best_move: if best_move == 0 {
+            None
+         } else {
+            Some(CompressedMove(best_move))
+         },
+      }
+   }
+}
+
+/// A fixed size, always-replace hash table of search results.
+///
+/// Entries are stored lockless as a (key ^ data, data) pair of atomics, so that
+/// any number of search threads can share a table. A torn read will fail the key
+/// check and just look like a miss.
+pub struct TranspositionTable {
+   entries: Vec<[AtomicU64; 2]>,
+}
+
+impl TranspositionTable {
+   /// A table using roughly `size_mb` megabytes. A size of 0 gives a table that never hits.
+   pub fn new(size_mb: usize) -> TranspositionTable {
+      let num_entries = size_mb * 1024 * 1024 / std::mem::size_of::<[AtomicU64; 2]>();
+      let mut entries = Vec::with_capacity(num_entries);
+      entries.resize_with(num_entries, || [AtomicU64::new(0), AtomicU64::new(0)]);
+      TranspositionTable { entries }
+   }
+
+   pub fn probe(&self, key: u64) -> Option<Entry> {
+      if self.entries.is_empty() {
+         return None;
+      }
+      let slot = &self.entries[(key % self.entries.len() as u64) as usize];
+      let checked_key = slot[0].load(Ordering::Relaxed);
+      let data = slot[1].load(Ordering::Relaxed);
+      if checked_key ^ data == key && data != 0 {
+         Some(Entry::unpack(data))
+      } else {
+         None
+      }
+   }
+
+   pub fn store(&self, key: u64, entry: Entry) {
+      if self.entries.is_empty() {
+         return;
+      }
+      let slot = &self.entries[(key % self.entries.len() as u64) as usize];
+      let data = entry.pack();
+      slot[0].store(key ^ data, Ordering::Relaxed);
+      slot[1].store(data, Ordering::Relaxed);
+   }
+
+   /// Forgets every entry, keeping the size
+   pub fn clear(&self) {
+      for slot in self.entries.iter() {
+         slot[0].store(0, Ordering::Relaxed);
+         slot[1].store(0, Ordering::Relaxed);
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use crate::board::Move;
+   use crate::tt::*;
+
+   #[test]
+   fn entry_round_trip() {
+      let entry = Entry {
+         depth: 7,
+         score: -3.25,
+         bound: Bound::Lower,
+         best_move: Some("e7e8q".parse::<Move>().unwrap().compress()),
+      };
+      assert_eq!(Entry::unpack(entry.pack()), entry);
+   }
+
+   #[test]
+   fn probe_after_store() {
+      let tt = TranspositionTable::new(1);
+      let entry = Entry {
+         depth: 3,
+         score: 0.5,
+         bound: Bound::Exact,
+         best_move: None,
+      };
+      tt.store(0xdead_beef, entry);
+      assert_eq!(tt.probe(0xdead_beef), Some(entry));
+      assert_eq!(tt.probe(0xdead_beee), None);
+      assert_eq!(TranspositionTable::new(0).probe(0xdead_beef), None);
+      tt.clear();
+      assert_eq!(tt.probe(0xdead_beef), None);
+   }
+}